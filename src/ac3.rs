@@ -0,0 +1,390 @@
+//! Parsing of AC-3 and E-AC-3 syncframe headers, per ETSI TS 102 366, to the extent needed to
+//! split the elementary stream into frames and to describe it in `dac3` / `dec3` boxes.
+
+/// Every syncframe starts with these bytes
+pub const SYNC_WORD: [u8; 2] = [0x0b, 0x77];
+
+/// The number of samples per channel in an AC-3 syncframe, and in an E-AC-3 audio frame once
+/// the frames of any shorter blocks are combined
+pub const SAMPLES_PER_FRAME: u32 = 1536;
+
+/// Parsing of a header is not attempted with fewer bytes than this
+pub const MIN_HEADER_SIZE: usize = 6;
+
+/// Nominal bit rates in kbit/s, indexed by `frmsizecod >> 1`
+const AC3_BIT_RATES: [u16; 19] = [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 576, 640];
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    /// The buffer doesn't start with `SYNC_WORD`
+    NoSync,
+    /// More data is needed to parse the header
+    TooShort,
+    /// The `bsid` is neither that of AC-3 nor E-AC-3
+    UnsupportedBsid(u8),
+    Reserved { field: &'static str, value: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubstreamType {
+    /// AC-3, or an E-AC-3 independent substream
+    Independent,
+    /// Carries channels to be added to those of the preceding independent substream
+    Dependent,
+    /// E-AC-3 converted from an AC-3 stream
+    Ac3Convert,
+}
+
+/// The fields of an AC-3 or E-AC-3 syncframe header
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameHeader {
+    pub enhanced: bool,
+    pub substream_type: SubstreamType,
+    pub substream_id: u8,
+    /// size of the whole syncframe in bytes
+    pub frame_size: usize,
+    pub fscod: u8,
+    pub sample_rate: u32,
+    /// the number of 256-sample audio blocks in the syncframe
+    pub blocks: u8,
+    pub bsid: u8,
+    /// bit stream mode, which is only parsed for AC-3 (being buried among optional metadata in
+    /// E-AC-3), and otherwise taken to be 'complete main'
+    pub bsmod: u8,
+    pub acmod: u8,
+    pub lfeon: bool,
+    /// `frmsizecod` of AC-3, or zero for E-AC-3
+    pub frmsizecod: u8,
+    /// `chanmap` of an E-AC-3 dependent substream, if given
+    pub chanmap: Option<u16>,
+}
+impl FrameHeader {
+    pub fn parse(buf: &[u8]) -> Result<FrameHeader, HeaderError> {
+        if buf.len() < MIN_HEADER_SIZE {
+            return Err(HeaderError::TooShort);
+        }
+        if buf[..2] != SYNC_WORD {
+            return Err(HeaderError::NoSync);
+        }
+        // bsid is at the same position in both formats
+        match buf[5] >> 3 {
+            0..=10 => Self::parse_ac3(buf),
+            11..=16 => Self::parse_eac3(buf),
+            bsid => Err(HeaderError::UnsupportedBsid(bsid)),
+        }
+    }
+
+    fn parse_ac3(buf: &[u8]) -> Result<FrameHeader, HeaderError> {
+        let mut r = BitReader::new(&buf[4..]);
+        let fscod = r.get(2) as u8;
+        let frmsizecod = r.get(6) as u8;
+        let bsid = r.get(5) as u8;
+        let bsmod = r.get(3) as u8;
+        let acmod = r.get(3) as u8;
+        if acmod & 1 != 0 && acmod != 1 {
+            r.get(2);  // cmixlev
+        }
+        if acmod & 4 != 0 {
+            r.get(2);  // surmixlev
+        }
+        if acmod == 2 {
+            r.get(2);  // dsurmod
+        }
+        let lfeon = r.get(1) == 1;
+        if r.overrun() {
+            return Err(HeaderError::TooShort);
+        }
+        let bit_rate = match AC3_BIT_RATES.get(usize::from(frmsizecod >> 1)) {
+            Some(&bit_rate) => u32::from(bit_rate),
+            None => return Err(HeaderError::Reserved { field: "frmsizecod", value: frmsizecod }),
+        };
+        // in 16-bit words
+        let frame_words = match fscod {
+            0 => bit_rate * 2,
+            // 44.1kHz frames don't divide evenly, so alternate frame sizes are padded
+            1 => bit_rate * 320 / 147 + u32::from(frmsizecod & 1),
+            2 => bit_rate * 3,
+            _ => return Err(HeaderError::Reserved { field: "fscod", value: fscod }),
+        };
+        Ok(FrameHeader {
+            enhanced: false,
+            substream_type: SubstreamType::Independent,
+            substream_id: 0,
+            frame_size: frame_words as usize * 2,
+            fscod,
+            sample_rate: [48000, 44100, 32000][usize::from(fscod)],
+            blocks: 6,
+            bsid,
+            bsmod,
+            acmod,
+            lfeon,
+            frmsizecod,
+            chanmap: None,
+        })
+    }
+
+    fn parse_eac3(buf: &[u8]) -> Result<FrameHeader, HeaderError> {
+        let mut r = BitReader::new(&buf[2..]);
+        let substream_type = match r.get(2) {
+            0 => SubstreamType::Independent,
+            1 => SubstreamType::Dependent,
+            2 => SubstreamType::Ac3Convert,
+            v => return Err(HeaderError::Reserved { field: "strmtyp", value: v as u8 }),
+        };
+        let substream_id = r.get(3) as u8;
+        let frmsiz = r.get(11);
+        let fscod = r.get(2) as u8;
+        let (sample_rate, blocks) = if fscod == 3 {
+            // reduced sampling rates always have six blocks
+            let fscod2 = r.get(2) as u8;
+            match fscod2 {
+                0 => (24000, 6),
+                1 => (22050, 6),
+                2 => (16000, 6),
+                _ => return Err(HeaderError::Reserved { field: "fscod2", value: fscod2 }),
+            }
+        } else {
+            let numblkscod = r.get(2) as usize;
+            ([48000, 44100, 32000][usize::from(fscod)], [1, 2, 3, 6][numblkscod])
+        };
+        let acmod = r.get(3) as u8;
+        let lfeon = r.get(1) == 1;
+        let bsid = r.get(5) as u8;
+        r.get(5);  // dialnorm
+        if r.get(1) == 1 {
+            r.get(8);  // compr
+        }
+        if acmod == 0 {
+            // dual mono
+            r.get(5);  // dialnorm2
+            if r.get(1) == 1 {
+                r.get(8);  // compr2
+            }
+        }
+        let chanmap = if substream_type == SubstreamType::Dependent && r.get(1) == 1 {
+            Some(r.get(16) as u16)
+        } else {
+            None
+        };
+        if r.overrun() {
+            return Err(HeaderError::TooShort);
+        }
+        Ok(FrameHeader {
+            enhanced: true,
+            substream_type,
+            substream_id,
+            frame_size: (frmsiz as usize + 1) * 2,
+            fscod,
+            sample_rate,
+            blocks,
+            bsid,
+            bsmod: 0,
+            acmod,
+            lfeon,
+            frmsizecod: 0,
+            chanmap,
+        })
+    }
+
+    /// The number of full-bandwidth channels given by `acmod`, plus any LFE channel
+    pub fn channels(&self) -> u32 {
+        let full = [2, 1, 2, 3, 3, 4, 4, 5][usize::from(self.acmod)];
+        full + if self.lfeon { 1 } else { 0 }
+    }
+}
+
+/// Describes an independent substream of an E-AC-3 stream (or the whole of an AC-3 stream), and
+/// the dependent substreams that go with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Substream {
+    pub fscod: u8,
+    pub bsid: u8,
+    pub bsmod: u8,
+    pub acmod: u8,
+    pub lfeon: bool,
+    pub num_dep_sub: u8,
+    /// the locations of the channels added by the dependent substreams, as signalled in `dec3`
+    pub chan_loc: u16,
+    /// including the channels of any dependent substreams
+    pub channels: u32,
+}
+
+/// The configuration of an AC-3 or E-AC-3 stream, as signalled in the `dac3` or `dec3` box of
+/// the sample entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub enhanced: bool,
+    pub sample_rate: u32,
+    /// `frmsizecod >> 1` of AC-3
+    pub bit_rate_code: u8,
+    /// in kbit/s: the nominal bit rate of AC-3, or as determined from the sizes of the
+    /// syncframes of E-AC-3
+    pub data_rate: u16,
+    /// never empty
+    pub substreams: Vec<Substream>,
+}
+impl StreamInfo {
+    /// Describes the stream made from the given syncframes, which together make up a single
+    /// audio frame (i.e. any E-AC-3 substreams, and any syncframes of fewer than six blocks have
+    /// already been combined).  Returns `None` if there is no independent substream.
+    pub fn from_frames(frames: &[FrameHeader]) -> Option<StreamInfo> {
+        let first = frames.iter().find(|f| f.substream_type != SubstreamType::Dependent )?;
+        let mut substreams: Vec<Substream> = vec![];
+        let mut bytes = 0;
+        for frame in frames {
+            bytes += frame.frame_size as u32;
+            match frame.substream_type {
+                SubstreamType::Independent | SubstreamType::Ac3Convert => {
+                    // syncframes of fewer than six blocks repeat the same substream
+                    if substreams.len() > usize::from(frame.substream_id) {
+                        continue;
+                    }
+                    substreams.push(Substream {
+                        fscod: frame.fscod,
+                        bsid: frame.bsid,
+                        bsmod: frame.bsmod,
+                        acmod: frame.acmod,
+                        lfeon: frame.lfeon,
+                        num_dep_sub: 0,
+                        chan_loc: 0,
+                        channels: frame.channels(),
+                    });
+                },
+                SubstreamType::Dependent => {
+                    if let Some(parent) = substreams.last_mut() {
+                        parent.num_dep_sub += 1;
+                        if let Some(chanmap) = frame.chanmap {
+                            let chan_loc = chan_loc(chanmap);
+                            parent.channels += chan_loc_channels(chan_loc & !parent.chan_loc);
+                            parent.chan_loc |= chan_loc;
+                        }
+                    }
+                },
+            }
+        }
+        let data_rate = if first.enhanced {
+            (u64::from(bytes) * 8 * u64::from(first.sample_rate) / u64::from(SAMPLES_PER_FRAME) / 1000) as u16
+        } else {
+            // rather than from the frame size, which for 44.1kHz alternates with padding
+            AC3_BIT_RATES[usize::from(first.frmsizecod >> 1)]
+        };
+        Some(StreamInfo {
+            enhanced: first.enhanced,
+            sample_rate: first.sample_rate,
+            bit_rate_code: first.frmsizecod >> 1,
+            data_rate,
+            substreams,
+        })
+    }
+
+    /// Compares everything but `data_rate`, which for E-AC-3 may vary from one frame to the next
+    /// without any change to the configuration of the stream
+    pub fn is_same_config(&self, other: &StreamInfo) -> bool {
+        self.enhanced == other.enhanced
+            && self.sample_rate == other.sample_rate
+            && self.bit_rate_code == other.bit_rate_code
+            && self.substreams == other.substreams
+    }
+
+    /// The number of channels of the main presentation (including any LFE channels)
+    pub fn channels(&self) -> u32 {
+        self.substreams[0].channels
+    }
+
+    pub fn rfc6381_codec(&self) -> &'static str {
+        if self.enhanced { "ec-3" } else { "ac-3" }
+    }
+}
+
+/// Maps the `chanmap` of a dependent substream onto the `chan_loc` field of `dec3`, which omits
+/// the channels that an independent substream could carry, and the Lts/Rts pair
+fn chan_loc(chanmap: u16) -> u16 {
+    // chanmap bit 0 (the MSB) is L, and bits 5 to 12 are Lc/Rc to Cvh, in the same order as the
+    // top 8 bits of chan_loc; chanmap bit 14 is LFE2
+    (chanmap >> 2) & 0x1fe | (chanmap >> 1) & 1
+}
+
+fn chan_loc_channels(chan_loc: u16) -> u32 {
+    // Cs, Ts, Cvh and LFE2 are single channels; the others are pairs
+    const SINGLE: u16 = 0b0_0110_0011;
+    (chan_loc & SINGLE).count_ones() + (chan_loc & !SINGLE & 0x1ff).count_ones() * 2
+}
+
+struct BitReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+impl<'a> BitReader<'a> {
+    fn new(buf: &'a [u8]) -> BitReader<'a> {
+        BitReader { buf, pos: 0 }
+    }
+
+    /// Reads zeros once the end of the buffer is reached; see `overrun()`
+    fn get(&mut self, bits: usize) -> u32 {
+        let mut val = 0;
+        for _ in 0..bits {
+            let bit = self.buf.get(self.pos / 8)
+                .map(|b| b >> (7 - self.pos % 8) & 1 )
+                .unwrap_or(0);
+            val = val << 1 | u32::from(bit);
+            self.pos += 1;
+        }
+        val
+    }
+
+    fn overrun(&self) -> bool {
+        self.pos > self.buf.len() * 8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ac3_header() {
+        // 48kHz, 384kbit/s, bsid 8, 3/2 + LFE
+        let buf = [0x0b, 0x77, 0x00, 0x00, 0x1c, 0x40, 0xe1, 0xf0];
+        let header = FrameHeader::parse(&buf).unwrap();
+        assert!(!header.enhanced);
+        assert_eq!(header.sample_rate, 48000);
+        assert_eq!(header.frame_size, 1536);
+        assert_eq!(header.bsid, 8);
+        assert_eq!(header.acmod, 7);
+        assert!(header.lfeon);
+        assert_eq!(header.channels(), 6);
+
+        // 44.1kHz frames alternate in size
+        let buf = [0x0b, 0x77, 0x00, 0x00, 0x40, 0x40, 0x43, 0x00];
+        assert_eq!(FrameHeader::parse(&buf).unwrap().frame_size, 138);
+        let buf = [0x0b, 0x77, 0x00, 0x00, 0x41, 0x40, 0x43, 0x00];
+        assert_eq!(FrameHeader::parse(&buf).unwrap().frame_size, 140);
+    }
+
+    #[test]
+    fn eac3_header() {
+        // independent substream 0, 768 byte frames, 48kHz, 6 blocks, 2/0, bsid 16
+        let buf = [0x0b, 0x77, 0x01, 0x7f, 0x34, 0x80, 0x00, 0x00];
+        let header = FrameHeader::parse(&buf).unwrap();
+        assert!(header.enhanced);
+        assert_eq!(header.substream_type, SubstreamType::Independent);
+        assert_eq!(header.frame_size, 768);
+        assert_eq!(header.sample_rate, 48000);
+        assert_eq!(header.blocks, 6);
+        assert_eq!(header.channels(), 2);
+
+        let info = StreamInfo::from_frames(&[header]).unwrap();
+        assert_eq!(info.data_rate, 192);
+        assert_eq!(info.rfc6381_codec(), "ec-3");
+    }
+
+    #[test]
+    fn dependent_channels() {
+        // Lrs/Rrs
+        assert_eq!(chan_loc(0b0000_0010_0000_0000), 0b0_1000_0000);
+        assert_eq!(chan_loc_channels(0b0_1000_0000), 2);
+        // LFE2
+        assert_eq!(chan_loc(0b0000_0000_0000_0010), 1);
+        assert_eq!(chan_loc_channels(1), 1);
+    }
+}
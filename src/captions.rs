@@ -0,0 +1,436 @@
+//! Detection of the CEA-608 and CEA-708 caption services present in ATSC A/53 `cc_data()`, as
+//! carried by `user_data_registered_itu_t_t35` SEI messages.  The caption data itself is left
+//! in the video samples for the player to decode, but CEA-608 channel `CC1` is also decoded
+//! so that it can be offered as a WebVTT subtitle rendition.
+
+use std::collections::BTreeMap;
+
+/// The caption services seen in a stream
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CaptionServices {
+    /// bit `n` set if CEA-608 channel `CC{n+1}` is present
+    cea608: u8,
+    /// bit `n` set if CEA-708 service `SERVICE{n}` is present
+    cea708: u64,
+}
+impl CaptionServices {
+    pub fn is_empty(&self) -> bool {
+        self.cea608 == 0 && self.cea708 == 0
+    }
+
+    /// Values for the `INSTREAM-ID` attribute of `EXT-X-MEDIA:TYPE=CLOSED-CAPTIONS`
+    pub fn instream_ids(&self) -> Vec<String> {
+        let cea608 = (0..4)
+            .filter(|i| self.cea608 & (1 << i) != 0 )
+            .map(|i| format!("CC{}", i + 1) );
+        let cea708 = (1..64)
+            .filter(|i| self.cea708 & (1 << i) != 0 )
+            .map(|i| format!("SERVICE{}", i) );
+        cea608.chain(cea708).collect()
+    }
+}
+
+/// Accumulates the caption services found in successive SEI payloads.  CEA-708 DTVCC packets
+/// may be split across several pictures, so state is kept between calls.
+#[derive(Default)]
+pub struct CaptionDetector {
+    services: CaptionServices,
+    dtvcc_packet: Vec<u8>,
+}
+impl CaptionDetector {
+    pub fn services(&self) -> CaptionServices {
+        self.services
+    }
+
+    /// Examines the payload of a `user_data_registered_itu_t_t35` SEI message, returning true
+    /// if any previously unseen caption service was found
+    pub fn push_sei(&mut self, payload: &[u8]) -> bool {
+        let before = self.services;
+        if let Some(cc_data) = a53_cc_data(payload) {
+            for cc in cc_data.chunks(3).filter(|cc| cc.len() == 3 ) {
+                let cc_valid = cc[0] & 0b100 != 0;
+                let cc_type = cc[0] & 0b11;
+                if !cc_valid {
+                    continue;
+                }
+                match cc_type {
+                    0 | 1 => self.cea608_pair(cc_type, cc[1], cc[2]),
+                    // DTVCC_PACKET_START
+                    3 => {
+                        self.dtvcc_packet.clear();
+                        self.dtvcc_packet.extend_from_slice(&cc[1..]);
+                        self.check_dtvcc_packet();
+                    },
+                    // DTVCC_PACKET_DATA
+                    _ => {
+                        if !self.dtvcc_packet.is_empty() {
+                            self.dtvcc_packet.extend_from_slice(&cc[1..]);
+                            self.check_dtvcc_packet();
+                        }
+                    },
+                }
+            }
+        }
+        self.services != before
+    }
+
+    fn cea608_pair(&mut self, field: u8, cc_data_1: u8, cc_data_2: u8) {
+        // remove the odd-parity bit
+        let (b1, b2) = (cc_data_1 & 0x7f, cc_data_2 & 0x7f);
+        // control codes, which have the data channel in bit 3 of the first byte
+        if b1 >= 0x10 && b1 <= 0x1f && b2 >= 0x20 {
+            let channel = field * 2 + if b1 & 0x08 != 0 { 1 } else { 0 };
+            self.services.cea608 |= 1 << channel;
+        }
+    }
+
+    fn check_dtvcc_packet(&mut self) {
+        let packet_size_code = self.dtvcc_packet[0] & 0b11_1111;
+        let packet_size = if packet_size_code == 0 { 128 } else { packet_size_code as usize * 2 };
+        if self.dtvcc_packet.len() < packet_size {
+            return;
+        }
+        let mut pos = 1;
+        while pos < packet_size {
+            let header = self.dtvcc_packet[pos];
+            let mut service_number = header >> 5;
+            let block_size = (header & 0b1_1111) as usize;
+            pos += 1;
+            if service_number == 7 && block_size != 0 {
+                match self.dtvcc_packet.get(pos) {
+                    Some(ext) => service_number = ext & 0b11_1111,
+                    None => break,
+                }
+                pos += 1;
+            }
+            if service_number == 0 {
+                // the remainder of the packet is padding
+                break;
+            }
+            if block_size > 0 {
+                self.services.cea708 |= 1 << service_number;
+            }
+            pos += block_size;
+        }
+        self.dtvcc_packet.clear();
+    }
+}
+
+/// Extracts the `cc_data_pkt` triplets from an SEI `user_data_registered_itu_t_t35` payload,
+/// if it carries ATSC A/53 caption data
+pub fn a53_cc_data(payload: &[u8]) -> Option<&[u8]> {
+    // itu_t_t35_country_code for the United States
+    if *payload.first()? != 0xb5 {
+        return None;
+    }
+    let payload = &payload[1..];
+    // itu_t_t35_provider_code for ATSC, then the user_identifier and user_data_type_code
+    if payload.get(..7)? != b"\x00\x31GA94\x03" {
+        return None;
+    }
+    let flags = *payload.get(7)?;
+    let process_cc_data = flags & 0b0100_0000 != 0;
+    if !process_cc_data {
+        return None;
+    }
+    let cc_count = (flags & 0b1_1111) as usize;
+    // skip em_data
+    payload.get(9..9 + cc_count * 3)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cea608Mode {
+    PopOn,
+    RollUp(u8),
+    PaintOn,
+    /// text mode data is not caption text, and is ignored
+    Text,
+}
+
+/// A change in the caption text being displayed, at the given timestamp.  `None` means that
+/// nothing is displayed from this point on.
+pub type DisplayChange = (i64, Option<String>);
+
+/// Decodes CEA-608 channel `CC1` (i.e. the first data channel of field 1) into the sequence of
+/// texts displayed.
+///
+/// Only the basic and special North American character sets are supported.  Text that is
+/// painted-on or rolled-up a character at a time is only reported when the line is complete.
+pub struct Cea608Decoder {
+    /// `cc_data` of recent pictures, held so that it can be processed in presentation order
+    reorder: Vec<(i64, Vec<[u8; 2]>)>,
+    mode: Cea608Mode,
+    /// true while the most recent control code selected data channel 1
+    cc1_selected: bool,
+    last_control: Option<(u8, u8)>,
+    displayed: BTreeMap<u8, String>,
+    non_displayed: BTreeMap<u8, String>,
+    row: u8,
+    /// characters have been added to displayed memory since the last reported change
+    dirty: bool,
+    current_text: Option<String>,
+    changes: Vec<DisplayChange>,
+}
+impl Default for Cea608Decoder {
+    fn default() -> Self {
+        Cea608Decoder {
+            reorder: vec![],
+            mode: Cea608Mode::PopOn,
+            cc1_selected: true,
+            last_control: None,
+            displayed: BTreeMap::new(),
+            non_displayed: BTreeMap::new(),
+            row: 15,
+            dirty: false,
+            current_text: None,
+            changes: vec![],
+        }
+    }
+}
+impl Cea608Decoder {
+    /// Enough pictures to cover the reordering introduced by B-frames
+    const REORDER_DEPTH: usize = 8;
+
+    /// Accepts the `cc_data_pkt` triplets from the SEI of the picture with the given PTS,
+    /// returning any resulting changes to the displayed text
+    pub fn push(&mut self, pts: i64, cc_data: &[u8]) -> Vec<DisplayChange> {
+        let field1: Vec<[u8; 2]> = cc_data.chunks(3)
+            .filter(|cc| cc.len() == 3 && cc[0] & 0b111 == 0b100 )
+            .map(|cc| [cc[1] & 0x7f, cc[2] & 0x7f] )
+            .collect();
+        let pos = self.reorder.iter().position(|(p, _)| *p > pts ).unwrap_or(self.reorder.len());
+        self.reorder.insert(pos, (pts, field1));
+        while self.reorder.len() > Self::REORDER_DEPTH {
+            let (pts, pairs) = self.reorder.remove(0);
+            for [b1, b2] in pairs {
+                self.pair(pts, b1, b2);
+            }
+        }
+        std::mem::replace(&mut self.changes, vec![])
+    }
+
+    fn pair(&mut self, pts: i64, b1: u8, b2: u8) {
+        if b1 == 0 && b2 == 0 {
+            // padding
+            return;
+        }
+        if b1 >= 0x10 && b1 <= 0x1f {
+            // control codes are usually sent twice, in case of errors; ignore the repeat
+            if self.last_control == Some((b1, b2)) {
+                self.last_control = None;
+                return;
+            }
+            self.last_control = Some((b1, b2));
+            self.cc1_selected = b1 & 0x08 == 0;
+            if self.cc1_selected {
+                self.control(pts, b1, b2);
+            }
+        } else {
+            self.last_control = None;
+            if self.cc1_selected && b1 >= 0x20 {
+                self.char(b1);
+                if b2 >= 0x20 {
+                    self.char(b2);
+                }
+            }
+        }
+    }
+
+    fn control(&mut self, pts: i64, b1: u8, b2: u8) {
+        match (b1, b2) {
+            // special characters
+            (0x11, 0x30..=0x3f) => {
+                let c = "®°½¿™¢£♪à èâêîôû".chars().nth((b2 - 0x30) as usize).unwrap();
+                self.push_char(c);
+            },
+            // mid-row codes display as a space
+            (0x11, 0x20..=0x2f) => self.push_char(' '),
+            (0x14, 0x20) => self.mode = Cea608Mode::PopOn,
+            (0x14, 0x21) => {
+                let row = self.row;
+                if let Some(text) = self.memory().get_mut(&row) {
+                    text.pop();
+                }
+            },
+            (0x14, 0x25..=0x27) => {
+                if let Cea608Mode::RollUp(_) = self.mode {
+                } else {
+                    self.displayed.clear();
+                    self.display_changed(pts);
+                }
+                self.mode = Cea608Mode::RollUp(b2 - 0x23);
+            },
+            (0x14, 0x29) => self.mode = Cea608Mode::PaintOn,
+            (0x14, 0x2a) | (0x14, 0x2b) => self.mode = Cea608Mode::Text,
+            // erase displayed memory
+            (0x14, 0x2c) => {
+                self.displayed.clear();
+                self.display_changed(pts);
+            },
+            // carriage return
+            (0x14, 0x2d) => {
+                if let Cea608Mode::RollUp(rows) = self.mode {
+                    self.display_changed(pts);
+                    let base = self.row;
+                    self.displayed = std::mem::replace(&mut self.displayed, BTreeMap::new())
+                        .into_iter()
+                        .filter(|(row, _)| *row > base.saturating_sub(rows) + 1 && *row <= base )
+                        .map(|(row, text)| (row - 1, text) )
+                        .collect();
+                }
+            },
+            // erase non-displayed memory
+            (0x14, 0x2e) => self.non_displayed.clear(),
+            // end of caption
+            (0x14, 0x2f) => {
+                std::mem::swap(&mut self.displayed, &mut self.non_displayed);
+                self.display_changed(pts);
+            },
+            // preamble address codes
+            (0x10..=0x17, 0x40..=0x7f) => {
+                const ROWS: [(u8, u8); 8] = [(11, 11), (1, 2), (3, 4), (12, 13), (14, 15), (5, 6), (7, 8), (9, 10)];
+                let (first, second) = ROWS[(b1 & 0x07) as usize];
+                let row = if b2 & 0x20 == 0 { first } else { second };
+                if self.dirty {
+                    self.display_changed(pts);
+                }
+                if let Cea608Mode::RollUp(_) = self.mode {
+                    // the base row moves, bringing the rolled-up text with it
+                    if row != self.row {
+                        let delta = row as i16 - self.row as i16;
+                        self.displayed = std::mem::replace(&mut self.displayed, BTreeMap::new())
+                            .into_iter()
+                            .map(|(r, text)| ((r as i16 + delta) as u8, text) )
+                            .collect();
+                    }
+                }
+                self.row = row;
+            },
+            _ => (),
+        }
+    }
+
+    fn char(&mut self, b: u8) {
+        let c = match b {
+            0x2a => 'á',
+            0x5c => 'é',
+            0x5e => 'í',
+            0x5f => 'ó',
+            0x60 => 'ú',
+            0x7b => 'ç',
+            0x7c => '÷',
+            0x7d => 'Ñ',
+            0x7e => 'ñ',
+            0x7f => '█',
+            _ => b as char,
+        };
+        self.push_char(c);
+    }
+
+    fn push_char(&mut self, c: char) {
+        if self.mode == Cea608Mode::Text {
+            return;
+        }
+        let row = self.row;
+        self.memory().entry(row).or_insert_with(String::new).push(c);
+        if self.mode != Cea608Mode::PopOn {
+            self.dirty = true;
+        }
+    }
+
+    /// The memory that characters are currently written to
+    fn memory(&mut self) -> &mut BTreeMap<u8, String> {
+        match self.mode {
+            Cea608Mode::PopOn => &mut self.non_displayed,
+            _ => &mut self.displayed,
+        }
+    }
+
+    fn display_changed(&mut self, pts: i64) {
+        self.dirty = false;
+        let lines: Vec<&str> = self.displayed.values()
+            .map(|line| line.trim() )
+            .filter(|line| !line.is_empty() )
+            .collect();
+        let text = if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        };
+        if text != self.current_text {
+            self.current_text = text.clone();
+            self.changes.push((pts, text));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sei(cc: &[[u8; 3]]) -> Vec<u8> {
+        let mut data = vec![0xb5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03];
+        data.push(0b1100_0000 | cc.len() as u8);
+        data.push(0xff);
+        for c in cc {
+            data.extend_from_slice(&c[..]);
+        }
+        data.push(0xff);
+        data
+    }
+
+    #[test]
+    fn cea608() {
+        let mut detector = CaptionDetector::default();
+        // padding only
+        assert!(!detector.push_sei(&sei(&[[0xfc, 0x80, 0x80]])[..]));
+        // 'resume caption loading' on channel 1 of field 1, and channel 2 of field 2
+        assert!(detector.push_sei(&sei(&[[0xfc, 0x94, 0x20], [0xfd, 0x1d, 0x20]])[..]));
+        assert_eq!(vec!["CC1", "CC4"], detector.services().instream_ids());
+        // repeats don't count as a change
+        assert!(!detector.push_sei(&sei(&[[0xfc, 0x94, 0x20]])[..]));
+    }
+
+    #[test]
+    fn cea708() {
+        let mut detector = CaptionDetector::default();
+        // a 4 byte packet (packet_size_code = 2) split across two SEI messages, holding a
+        // 2 byte block for service 1
+        assert!(!detector.push_sei(&sei(&[[0xff, 0x02, 0x22]])[..]));
+        assert!(detector.push_sei(&sei(&[[0xfe, 0x41, 0x42]])[..]));
+        assert_eq!(vec!["SERVICE1"], detector.services().instream_ids());
+    }
+
+    #[test]
+    fn not_a53() {
+        let mut detector = CaptionDetector::default();
+        let mut data = sei(&[[0xfc, 0x94, 0x20]]);
+        data[4] = b'X';
+        assert!(!detector.push_sei(&data[..]));
+        assert!(detector.services().is_empty());
+    }
+
+    #[test]
+    fn cea608_pop_on() {
+        let mut decoder = Cea608Decoder::default();
+        let mut changes = vec![];
+        let frames: Vec<Vec<u8>> = vec![
+            // RCL, PAC row 15, "Hi", EOC
+            vec![0xfc, 0x94, 0x20],
+            vec![0xfc, 0x94, 0x20],
+            vec![0xfc, 0x94, 0x70],
+            vec![0xfc, 0x48, 0x69],
+            vec![0xfc, 0x94, 0x2f],
+            // EDM
+            vec![0xfc, 0x94, 0x2c],
+        ];
+        for (i, cc) in frames.iter().enumerate() {
+            changes.extend(decoder.push(i as i64 * 3000, &cc[..]));
+        }
+        // flush the reorder buffer
+        for i in frames.len()..frames.len() + Cea608Decoder::REORDER_DEPTH {
+            changes.extend(decoder.push(i as i64 * 3000, &[]));
+        }
+        assert_eq!(vec![(12000, Some("Hi".to_string())), (15000, None)], changes);
+    }
+}
@@ -0,0 +1,597 @@
+//! Just enough H.265 parsing to be able to package an HEVC elementary stream, since the
+//! `h264-reader` crate we use for AVC has no support for HEVC.
+
+use std::cmp;
+
+/// The two-byte header at the start of every HEVC NAL unit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NalHeader(u16);
+impl NalHeader {
+    pub const VPS: u8 = 32;
+    pub const SPS: u8 = 33;
+    pub const PPS: u8 = 34;
+    pub const AUD: u8 = 35;
+
+    pub fn new(header: &[u8]) -> Result<NalHeader, HevcError> {
+        if header.len() < 2 {
+            return Err(HevcError::NotEnoughData);
+        }
+        if header[0] & 0b1000_0000 != 0 {
+            return Err(HevcError::ForbiddenZeroBit);
+        }
+        Ok(NalHeader(u16::from(header[0]) << 8 | u16::from(header[1])))
+    }
+
+    pub fn nal_unit_type(&self) -> u8 {
+        ((self.0 >> 9) & 0b11_1111) as u8
+    }
+
+    /// Video Coding Layer NAL units carry the actual slice data
+    pub fn is_vcl(&self) -> bool {
+        self.nal_unit_type() < 32
+    }
+
+    /// Intra Random Access Point pictures (BLA, IDR and CRA) are where decoding may start, and
+    /// so are where segments may start
+    pub fn is_irap(&self) -> bool {
+        let t = self.nal_unit_type();
+        t >= 16 && t <= 23
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HevcError {
+    NotEnoughData,
+    ForbiddenZeroBit,
+    ExpGolombTooLarge,
+    /// The conformance window crops away more than the whole picture
+    BadConformanceWindow,
+}
+
+/// Splits an Annex B byte stream into NAL units, excluding the start code prefixes
+pub fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = vec![];
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let mut nals = Vec::with_capacity(starts.len());
+    for (n, &start) in starts.iter().enumerate() {
+        let mut end = if n + 1 < starts.len() {
+            starts[n + 1] - 3
+        } else {
+            data.len()
+        };
+        // remove the leading zero of any following 4-byte start code, or trailing_zero_8bits
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            nals.push(&data[start..end]);
+        }
+    }
+    nals
+}
+
+/// Removes emulation prevention bytes, giving the RBSP data of a NAL unit
+pub fn decode_rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &b in nal {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        if b == 0 {
+            zeros += 1;
+        } else {
+            zeros = 0;
+        }
+        rbsp.push(b);
+    }
+    rbsp
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0 }
+    }
+
+    fn read_bool(&mut self) -> Result<bool, HevcError> {
+        let byte = self.data.get(self.pos / 8).ok_or(HevcError::NotEnoughData)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit == 1)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u64, HevcError> {
+        let mut val = 0;
+        for _ in 0..count {
+            val = val << 1 | self.read_bool()? as u64;
+        }
+        Ok(val)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), HevcError> {
+        if self.pos + count > self.data.len() * 8 {
+            return Err(HevcError::NotEnoughData);
+        }
+        self.pos += count;
+        Ok(())
+    }
+
+    fn read_ue(&mut self) -> Result<u32, HevcError> {
+        let mut leading_zeros = 0;
+        while !self.read_bool()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(HevcError::ExpGolombTooLarge);
+            }
+        }
+        Ok(((1u64 << leading_zeros) - 1 + self.read_bits(leading_zeros)?) as u32)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProfileTierLevel {
+    pub profile_space: u8,
+    pub tier_flag: bool,
+    pub profile_idc: u8,
+    pub profile_compatibility_flags: u32,
+    /// 48 bits, starting with `general_progressive_source_flag`
+    pub constraint_indicator_flags: u64,
+    pub level_idc: u8,
+}
+impl ProfileTierLevel {
+    fn read(r: &mut BitReader<'_>, max_sub_layers_minus1: u8) -> Result<ProfileTierLevel, HevcError> {
+        let ptl = ProfileTierLevel {
+            profile_space: r.read_bits(2)? as u8,
+            tier_flag: r.read_bool()?,
+            profile_idc: r.read_bits(5)? as u8,
+            profile_compatibility_flags: r.read_bits(32)? as u32,
+            constraint_indicator_flags: r.read_bits(48)?,
+            level_idc: r.read_bits(8)? as u8,
+        };
+        let mut sub_layer_profile_present = vec![];
+        let mut sub_layer_level_present = vec![];
+        for _ in 0..max_sub_layers_minus1 {
+            sub_layer_profile_present.push(r.read_bool()?);
+            sub_layer_level_present.push(r.read_bool()?);
+        }
+        if max_sub_layers_minus1 > 0 {
+            for _ in max_sub_layers_minus1..8 {
+                r.skip(2)?;  // reserved_zero_2bits
+            }
+        }
+        for (profile, level) in sub_layer_profile_present.into_iter().zip(sub_layer_level_present) {
+            if profile {
+                r.skip(88)?;
+            }
+            if level {
+                r.skip(8)?;
+            }
+        }
+        Ok(ptl)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConformanceWindow {
+    pub left_offset: u32,
+    pub right_offset: u32,
+    pub top_offset: u32,
+    pub bottom_offset: u32,
+}
+
+/// The `vui_num_units_in_tick` and `vui_time_scale` from the VUI of an SPS, so that each picture
+/// lasts `num_units_in_tick / time_scale` seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimingInfo {
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+}
+
+/// The fields of an HEVC Sequence Parameter Set that we need; the remainder is skipped, or not
+/// parsed at all
+#[derive(Debug, Clone)]
+pub struct SeqParameterSet {
+    pub max_sub_layers_minus1: u8,
+    pub temporal_id_nesting_flag: bool,
+    pub profile_tier_level: ProfileTierLevel,
+    pub seq_parameter_set_id: u32,
+    pub chroma_format_idc: u32,
+    pub pic_width_in_luma_samples: u32,
+    pub pic_height_in_luma_samples: u32,
+    pub conformance_window: Option<ConformanceWindow>,
+    pub bit_depth_luma_minus8: u32,
+    pub bit_depth_chroma_minus8: u32,
+    pub timing_info: Option<TimingInfo>,
+}
+impl SeqParameterSet {
+    /// Parses the given RBSP data, which should not include the NAL unit header
+    pub fn from_bytes(rbsp: &[u8]) -> Result<SeqParameterSet, HevcError> {
+        let mut r = BitReader::new(rbsp);
+        r.skip(4)?;  // sps_video_parameter_set_id
+        let max_sub_layers_minus1 = r.read_bits(3)? as u8;
+        let temporal_id_nesting_flag = r.read_bool()?;
+        let profile_tier_level = ProfileTierLevel::read(&mut r, max_sub_layers_minus1)?;
+        let seq_parameter_set_id = r.read_ue()?;
+        let chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc == 3 {
+            r.skip(1)?;  // separate_colour_plane_flag
+        }
+        let pic_width_in_luma_samples = r.read_ue()?;
+        let pic_height_in_luma_samples = r.read_ue()?;
+        let conformance_window = if r.read_bool()? {
+            Some(ConformanceWindow {
+                left_offset: r.read_ue()?,
+                right_offset: r.read_ue()?,
+                top_offset: r.read_ue()?,
+                bottom_offset: r.read_ue()?,
+            })
+        } else {
+            None
+        };
+        let sps = SeqParameterSet {
+            max_sub_layers_minus1,
+            temporal_id_nesting_flag,
+            profile_tier_level,
+            seq_parameter_set_id,
+            chroma_format_idc,
+            pic_width_in_luma_samples,
+            pic_height_in_luma_samples,
+            conformance_window,
+            bit_depth_luma_minus8: r.read_ue()?,
+            bit_depth_chroma_minus8: r.read_ue()?,
+            // the remainder is only needed for the frame rate, so an SPS that can't be parsed
+            // beyond this point is still usable
+            timing_info: Self::read_timing_info(&mut r, max_sub_layers_minus1).unwrap_or(None),
+        };
+        if sps.cropped_dimensions().is_none() {
+            return Err(HevcError::BadConformanceWindow);
+        }
+        Ok(sps)
+    }
+
+    /// Skips the fields following `bit_depth_chroma_minus8` to reach the `vui_parameters()`, and
+    /// from those reads any `vui_timing_info`
+    fn read_timing_info(r: &mut BitReader<'_>, max_sub_layers_minus1: u8) -> Result<Option<TimingInfo>, HevcError> {
+        let log2_max_pic_order_cnt_lsb = r.read_ue()? + 4;
+        let sub_layer_ordering_info_present_flag = r.read_bool()?;
+        let first_sub_layer = if sub_layer_ordering_info_present_flag { 0 } else { max_sub_layers_minus1 };
+        for _ in first_sub_layer..=max_sub_layers_minus1 {
+            r.read_ue()?;  // sps_max_dec_pic_buffering_minus1
+            r.read_ue()?;  // sps_max_num_reorder_pics
+            r.read_ue()?;  // sps_max_latency_increase_plus1
+        }
+        // log2_min_luma_coding_block_size_minus3 through max_transform_hierarchy_depth_intra
+        for _ in 0..6 {
+            r.read_ue()?;
+        }
+        if r.read_bool()? && r.read_bool()? {
+            // scaling_list_enabled_flag and sps_scaling_list_data_present_flag
+            skip_scaling_list_data(r)?;
+        }
+        r.skip(2)?;  // amp_enabled_flag, sample_adaptive_offset_enabled_flag
+        if r.read_bool()? {
+            // pcm_enabled_flag
+            r.skip(8)?;  // pcm_sample_bit_depth_luma_minus1, pcm_sample_bit_depth_chroma_minus1
+            r.read_ue()?;  // log2_min_pcm_luma_coding_block_size_minus3
+            r.read_ue()?;  // log2_diff_max_min_pcm_luma_coding_block_size
+            r.skip(1)?;  // pcm_loop_filter_disabled_flag
+        }
+        let num_short_term_ref_pic_sets = r.read_ue()?;
+        let mut num_delta_pocs: Vec<u64> = vec![];
+        for i in 0..num_short_term_ref_pic_sets as usize {
+            num_delta_pocs.push(skip_st_ref_pic_set(r, i, &num_delta_pocs)?);
+        }
+        if r.read_bool()? {
+            // long_term_ref_pics_present_flag
+            let num_long_term_ref_pics_sps = r.read_ue()?;
+            for _ in 0..num_long_term_ref_pics_sps {
+                // lt_ref_pic_poc_lsb_sps and used_by_curr_pic_lt_sps_flag
+                r.skip(log2_max_pic_order_cnt_lsb as usize + 1)?;
+            }
+        }
+        r.skip(2)?;  // sps_temporal_mvp_enabled_flag, strong_intra_smoothing_enabled_flag
+        if !r.read_bool()? {
+            // vui_parameters_present_flag
+            return Ok(None);
+        }
+        if r.read_bool()? {
+            // aspect_ratio_info_present_flag
+            if r.read_bits(8)? == 255 {
+                r.skip(32)?;  // sar_width, sar_height
+            }
+        }
+        if r.read_bool()? {
+            r.skip(1)?;  // overscan_appropriate_flag
+        }
+        if r.read_bool()? {
+            // video_signal_type_present_flag
+            r.skip(4)?;  // video_format, video_full_range_flag
+            if r.read_bool()? {
+                r.skip(24)?;  // colour_primaries, transfer_characteristics, matrix_coeffs
+            }
+        }
+        if r.read_bool()? {
+            // chroma_loc_info_present_flag
+            r.read_ue()?;
+            r.read_ue()?;
+        }
+        r.skip(3)?;  // neutral_chroma_indication_flag, field_seq_flag, frame_field_info_present_flag
+        if r.read_bool()? {
+            // default_display_window_flag
+            for _ in 0..4 {
+                r.read_ue()?;
+            }
+        }
+        if !r.read_bool()? {
+            // vui_timing_info_present_flag
+            return Ok(None);
+        }
+        Ok(Some(TimingInfo {
+            num_units_in_tick: r.read_bits(32)? as u32,
+            time_scale: r.read_bits(32)? as u32,
+        }))
+    }
+
+    /// Picture dimensions, after applying the conformance window
+    pub fn dimensions(&self) -> (u32, u32) {
+        // from_bytes() rejects any SPS with a conformance window that doesn't fit
+        self.cropped_dimensions()
+            .unwrap_or((self.pic_width_in_luma_samples, self.pic_height_in_luma_samples))
+    }
+
+    /// `None` if the conformance window is larger than the picture
+    fn cropped_dimensions(&self) -> Option<(u32, u32)> {
+        let (sub_width, sub_height) = match self.chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        if let Some(ref win) = self.conformance_window {
+            let crop_width = win.left_offset.checked_add(win.right_offset)?.checked_mul(sub_width)?;
+            let crop_height = win.top_offset.checked_add(win.bottom_offset)?.checked_mul(sub_height)?;
+            Some((
+                self.pic_width_in_luma_samples.checked_sub(crop_width)?,
+                self.pic_height_in_luma_samples.checked_sub(crop_height)?,
+            ))
+        } else {
+            Some((self.pic_width_in_luma_samples, self.pic_height_in_luma_samples))
+        }
+    }
+
+    /// The `CODECS` value for this stream, per ISO/IEC 14496-15 Annex E
+    pub fn rfc6381_codec(&self) -> String {
+        let ptl = &self.profile_tier_level;
+        let profile_space = match ptl.profile_space {
+            1 => "A",
+            2 => "B",
+            3 => "C",
+            _ => "",
+        };
+        let mut codec = format!(
+            "hvc1.{}{}.{:X}.{}{}",
+            profile_space,
+            ptl.profile_idc,
+            ptl.profile_compatibility_flags.reverse_bits(),
+            if ptl.tier_flag { 'H' } else { 'L' },
+            ptl.level_idc
+        );
+        let mut constraints: Vec<u8> = (0..6)
+            .map(|i| (ptl.constraint_indicator_flags >> (40 - i * 8)) as u8 )
+            .collect();
+        while constraints.last() == Some(&0) {
+            constraints.pop();
+        }
+        for b in constraints {
+            codec.push_str(&format!(".{:X}", b));
+        }
+        codec
+    }
+}
+
+/// Skips a `scaling_list_data()` structure
+fn skip_scaling_list_data(r: &mut BitReader<'_>) -> Result<(), HevcError> {
+    for size_id in 0..4 {
+        let matrix_step = if size_id == 3 { 3 } else { 1 };
+        for _ in (0..6).step_by(matrix_step) {
+            if !r.read_bool()? {
+                r.read_ue()?;  // scaling_list_pred_matrix_id_delta
+            } else {
+                let coef_num = cmp::min(64, 1 << (4 + (size_id << 1)));
+                if size_id > 1 {
+                    r.read_ue()?;  // scaling_list_dc_coef_minus8, se(v) being the same length
+                }
+                for _ in 0..coef_num {
+                    r.read_ue()?;  // scaling_list_delta_coef, se(v) being the same length
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Skips the `st_ref_pic_set(idx)` of an SPS, returning its `NumDeltaPocs`, which later sets
+/// predicted from it depend on
+fn skip_st_ref_pic_set(r: &mut BitReader<'_>, idx: usize, num_delta_pocs: &[u64]) -> Result<u64, HevcError> {
+    let inter_ref_pic_set_prediction_flag = idx != 0 && r.read_bool()?;
+    if inter_ref_pic_set_prediction_flag {
+        r.skip(1)?;  // delta_rps_sign
+        r.read_ue()?;  // abs_delta_rps_minus1
+        let mut count = 0;
+        for _ in 0..=num_delta_pocs[idx - 1] {
+            // use_delta_flag is only present when used_by_curr_pic_flag is not set
+            let used_by_curr_pic_flag = r.read_bool()?;
+            if used_by_curr_pic_flag || r.read_bool()? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    } else {
+        let num_negative_pics = u64::from(r.read_ue()?);
+        let num_positive_pics = u64::from(r.read_ue()?);
+        for _ in 0..num_negative_pics + num_positive_pics {
+            r.read_ue()?;  // delta_poc_s0_minus1 / delta_poc_s1_minus1
+            r.skip(1)?;  // used_by_curr_pic_s0_flag / used_by_curr_pic_s1_flag
+        }
+        Ok(num_negative_pics + num_positive_pics)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct BitWriter {
+        data: Vec<u8>,
+        bits: usize,
+    }
+    impl BitWriter {
+        fn write(&mut self, count: u32, val: u64) {
+            for i in (0..count).rev() {
+                if self.bits % 8 == 0 {
+                    self.data.push(0);
+                }
+                let bit = (val >> i) & 1;
+                *self.data.last_mut().unwrap() |= (bit as u8) << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+        fn write_ue(&mut self, val: u32) {
+            let v = val as u64 + 1;
+            let len = 64 - v.leading_zeros();
+            self.write(len - 1, 0);
+            self.write(len, v);
+        }
+    }
+
+    fn main_profile_sps() -> Vec<u8> {
+        let mut w = BitWriter { data: vec![], bits: 0 };
+        write_main_profile_sps(&mut w);
+        w.data
+    }
+
+    /// The fields of a 1080p Main profile SPS, up to `bit_depth_chroma_minus8`
+    fn write_main_profile_sps(w: &mut BitWriter) {
+        w.write(4, 0);  // sps_video_parameter_set_id
+        w.write(3, 0);  // sps_max_sub_layers_minus1
+        w.write(1, 1);  // sps_temporal_id_nesting_flag
+        w.write(2, 0);  // general_profile_space
+        w.write(1, 0);  // general_tier_flag
+        w.write(5, 1);  // general_profile_idc
+        w.write(32, 0x6000_0000);
+        w.write(48, 0x9000_0000_0000);
+        w.write(8, 93);  // general_level_idc
+        w.write_ue(0);  // sps_seq_parameter_set_id
+        w.write_ue(1);  // chroma_format_idc
+        w.write_ue(1920);
+        w.write_ue(1088);
+        w.write(1, 1);  // conformance_window_flag
+        w.write_ue(0);
+        w.write_ue(0);
+        w.write_ue(0);
+        w.write_ue(4);
+        w.write_ue(0);  // bit_depth_luma_minus8
+        w.write_ue(0);  // bit_depth_chroma_minus8
+    }
+
+    #[test]
+    fn sps() {
+        let sps = SeqParameterSet::from_bytes(&main_profile_sps()).unwrap();
+        assert_eq!(1, sps.profile_tier_level.profile_idc);
+        assert_eq!(93, sps.profile_tier_level.level_idc);
+        assert_eq!((1920, 1080), sps.dimensions());
+        assert_eq!("hvc1.1.6.L93.90", sps.rfc6381_codec());
+        // the SPS ends before the VUI
+        assert_eq!(None, sps.timing_info);
+    }
+
+    #[test]
+    fn vui_timing_info() {
+        let mut w = BitWriter { data: vec![], bits: 0 };
+        write_main_profile_sps(&mut w);
+        w.write_ue(4);  // log2_max_pic_order_cnt_lsb_minus4
+        w.write(1, 1);  // sps_sub_layer_ordering_info_present_flag
+        w.write_ue(4);
+        w.write_ue(2);
+        w.write_ue(0);
+        for _ in 0..6 {
+            w.write_ue(1);  // coding and transform block sizes, transform hierarchy depths
+        }
+        w.write(1, 0);  // scaling_list_enabled_flag
+        w.write(2, 0b01);  // amp_enabled_flag, sample_adaptive_offset_enabled_flag
+        w.write(1, 0);  // pcm_enabled_flag
+        w.write_ue(2);  // num_short_term_ref_pic_sets
+        // the first set has one negative picture
+        w.write_ue(1);  // num_negative_pics
+        w.write_ue(0);  // num_positive_pics
+        w.write_ue(0);  // delta_poc_s0_minus1
+        w.write(1, 1);  // used_by_curr_pic_s0_flag
+        // the second is predicted from the first
+        w.write(1, 1);  // inter_ref_pic_set_prediction_flag
+        w.write(1, 0);  // delta_rps_sign
+        w.write_ue(0);  // abs_delta_rps_minus1
+        w.write(1, 1);  // used_by_curr_pic_flag
+        w.write(2, 0b00);  // used_by_curr_pic_flag, use_delta_flag
+        w.write(1, 0);  // long_term_ref_pics_present_flag
+        w.write(2, 0b11);  // sps_temporal_mvp_enabled_flag, strong_intra_smoothing_enabled_flag
+        w.write(1, 1);  // vui_parameters_present_flag
+        w.write(1, 1);  // aspect_ratio_info_present_flag
+        w.write(8, 1);  // aspect_ratio_idc
+        w.write(2, 0b00);  // overscan_info_present_flag, video_signal_type_present_flag
+        w.write(1, 0);  // chroma_loc_info_present_flag
+        w.write(3, 0);  // neutral_chroma_indication_flag, field_seq_flag, frame_field_info_present_flag
+        w.write(1, 0);  // default_display_window_flag
+        w.write(1, 1);  // vui_timing_info_present_flag
+        w.write(32, 1001);
+        w.write(32, 60000);
+        let sps = SeqParameterSet::from_bytes(&w.data).unwrap();
+        assert_eq!(Some(TimingInfo { num_units_in_tick: 1001, time_scale: 60000 }), sps.timing_info);
+    }
+
+    #[test]
+    fn bad_conformance_window() {
+        let mut w = BitWriter { data: vec![], bits: 0 };
+        w.write(4, 0);  // sps_video_parameter_set_id
+        w.write(3, 0);  // sps_max_sub_layers_minus1
+        w.write(1, 1);  // sps_temporal_id_nesting_flag
+        w.write(96, 0);  // profile_tier_level
+        w.write_ue(0);  // sps_seq_parameter_set_id
+        w.write_ue(1);  // chroma_format_idc
+        w.write_ue(1920);
+        w.write_ue(1088);
+        w.write(1, 1);  // conformance_window_flag
+        w.write_ue(0);
+        w.write_ue(0);
+        w.write_ue(0);
+        w.write_ue(545);  // cropping 1090 rows from the bottom
+        w.write_ue(0);  // bit_depth_luma_minus8
+        w.write_ue(0);  // bit_depth_chroma_minus8
+        assert_eq!(Err(HevcError::BadConformanceWindow), SeqParameterSet::from_bytes(&w.data).map(|_| () ));
+    }
+
+    #[test]
+    fn annexb() {
+        let data = [0, 0, 0, 1, 0x40, 0x01, 0xaa, 0, 0, 1, 0x42, 0x01, 0xbb, 0];
+        let nals = split_annexb(&data);
+        assert_eq!(vec![&[0x40, 0x01, 0xaa][..], &[0x42, 0x01, 0xbb][..]], nals);
+        let header = NalHeader::new(nals[1]).unwrap();
+        assert_eq!(NalHeader::SPS, header.nal_unit_type());
+    }
+
+    #[test]
+    fn rbsp() {
+        assert_eq!(vec![0, 0, 3, 0, 0, 1], decode_rbsp(&[0, 0, 3, 3, 0, 0, 3, 1]));
+    }
+}
@@ -2,18 +2,21 @@ use hyper::{Body, Request, Response, Server, StatusCode, Uri};
 use futures::future::{Future, Either};
 use hyper::service::Service;
 use crate::store;
+use crate::mp4;
+use crate::ac3;
+use crate::ts;
 use futures::future;
 use std::{error, fmt};
 use std::fmt::Display;
+use std::collections::HashSet;
+use std::cmp;
 use std::fmt::Write as FmtWrite;
-use std::io::Write as IoWrite;
-use mse_fmp4::{fmp4, aac};
-use mse_fmp4::io::WriteTo;
-use mse_fmp4::fmp4::common::Mp4Box;
+use mse_fmp4::aac;
 use mpeg2ts_reader::pes::Timestamp;
-use byteorder::WriteBytesExt;
 use url::Url;
 use futures::stream::Stream;
+use futures::{Async, Poll};
+use tokio_sync::watch;
 use crate::store::SegmentError;
 use chrono::offset::TimeZone;
 
@@ -23,6 +26,62 @@ type MediaManifestFut = Box<dyn Future<Item=Response<Body>, Error=HlsServiceErro
 struct HlsService {
     store: store::Store,
 }
+
+/// The container used for the segments of a track
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SegmentFormat {
+    Fmp4,
+    /// MPEG-2 TS, for players without fMP4 support
+    Ts,
+    WebVtt,
+    /// WebVTT with timestamps mapped onto those of `Ts` segments
+    TsWebVtt,
+}
+impl SegmentFormat {
+    /// The format used by the given track, where audio and video tracks are available in either
+    /// of the given `av_format` (`Fmp4` or `Ts`)
+    fn for_track(track: &store::Track, av_format: SegmentFormat) -> SegmentFormat {
+        match track {
+            store::Track::Subtitle(_) if av_format == SegmentFormat::Ts => SegmentFormat::TsWebVtt,
+            store::Track::Subtitle(_) => SegmentFormat::WebVtt,
+            _ => av_format,
+        }
+    }
+
+    fn segment_name(&self) -> &'static str {
+        match self {
+            SegmentFormat::Fmp4 => "seg.mp4",
+            SegmentFormat::Ts => "seg.ts",
+            SegmentFormat::WebVtt => "seg.vtt",
+            SegmentFormat::TsWebVtt => "seg-ts.vtt",
+        }
+    }
+
+    /// The file extension of parts, for formats which have them
+    fn part_extension(&self) -> Option<&'static str> {
+        match self {
+            SegmentFormat::Fmp4 => Some("mp4"),
+            SegmentFormat::Ts => Some("ts"),
+            SegmentFormat::WebVtt | SegmentFormat::TsWebVtt => None,
+        }
+    }
+
+    /// The name of the media manifest listing segments in this format
+    fn manifest_name(&self) -> &'static str {
+        match self {
+            SegmentFormat::Fmp4 | SegmentFormat::WebVtt => "media.m3u8",
+            SegmentFormat::Ts | SegmentFormat::TsWebVtt => "media-ts.m3u8",
+        }
+    }
+
+    /// How samples are packaged in this format, which affects the bitrates measured for it
+    fn packaging(&self) -> store::Packaging {
+        match self {
+            SegmentFormat::Fmp4 | SegmentFormat::WebVtt => store::Packaging::Fmp4,
+            SegmentFormat::Ts | SegmentFormat::TsWebVtt => store::Packaging::Ts,
+        }
+    }
+}
 impl Service for HlsService {
     type ReqBody = Body;
     type ResBody = Body;
@@ -32,7 +91,11 @@ impl Service for HlsService {
     fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
         let path = req.uri().path();
         if path.starts_with("/master.m3u8") {
-            Either::A(self.master_manifest(req))
+            Either::A(self.master_manifest(req, SegmentFormat::Fmp4))
+        } else if path.starts_with("/master-ts.m3u8") {
+            Either::A(self.master_manifest(req, SegmentFormat::Ts))
+        } else if path.starts_with("/manifest.mpd") {
+            Either::A(self.dash_manifest())
         } else if path.starts_with("/track/") {
             let mut parts = path["/track/".len()..].splitn(2, "/");
             let id = parts.next();
@@ -58,8 +121,13 @@ impl Service for HlsService {
 
 
 impl HlsService {
+    /// The spec requires that `CAN-SKIP-UNTIL` be at least six times the target duration
+    const SKIP_TARGET_DURATIONS: u32 = 6;
+
+    const SUBTITLE_GROUP: &'static str = "subtitles";
 
-    fn master_manifest(&mut self, req: Request<Body>) -> ImmediateFut {
+    /// Audio and video renditions are listed with segments in the given `av_format`
+    fn master_manifest(&mut self, req: Request<Body>, av_format: SegmentFormat) -> ImmediateFut {
         let mut text = String::new();
         writeln!(text, "#EXTM3U").unwrap();
         // TODO: validate correct version vs. used HSL features
@@ -67,38 +135,101 @@ impl HlsService {
         writeln!(text, "#EXT-X-INDEPENDENT-SEGMENTS").unwrap();
         writeln!(text, "").unwrap();
 
-        // TODO: audio, keyframes
+        // TODO: keyframes
 
+        let default_audio = self.default_audio_track();
+        let audio_codecs = self.audio_codecs();
+        let packaging = av_format.packaging();
+        let (audio_bandwidth, audio_average_bandwidth) = self.audio_bandwidth(packaging);
+        let subtitles = if self.has_subtitles() { Some(Self::SUBTITLE_GROUP) } else { None };
+        let mut audio_names = HashSet::new();
         for track in self.store.track_list() {
-            match self.store.get_track(track.track_id).unwrap().track() {
+            let mut track_ref = self.store.get_track(track.track_id).unwrap();
+            let media_track = track_ref.track();
+            match media_track {
                 store::Track::Avc(avc_track) => {
-                    // TODO:
-                    //  - FRAMERATE
-                    let (width, height) = avc_track.dimensions();
-                    write!(text, "#EXT-X-STREAM-INF:").unwrap();
-                    if let Some(bandwidth) = avc_track.bandwidth() {
-                        // BANDWITH is mandatory; but if we don't know it, what to do!
-                        write!(text, "BANDWIDTH={},", bandwidth).unwrap();
+                    let instream_ids = avc_track.captions().instream_ids();
+                    let cc_group = format!("cc-{}", track.track_id.0);
+                    for instream_id in instream_ids.iter() {
+                        writeln!(text,
+                                 "#EXT-X-MEDIA:TYPE=CLOSED-CAPTIONS,GROUP-ID=\"{}\",NAME=\"{}\",INSTREAM-ID=\"{}\",DEFAULT={},AUTOSELECT=YES",
+                                 cc_group,
+                                 instream_id,
+                                 instream_id,
+                                 if instream_id == &instream_ids[0] { "YES" } else { "NO" })
+                            .unwrap();
                     }
-                    writeln!(text,
-                             "RESOLUTION={}x{},AUDIO=\"default-audio-group\"",
-                             width,
-                             height)
-                        .unwrap();
-                    writeln!(text, "track/{}/media.m3u8", track.track_id.0).unwrap();
+                    Self::write_stream_inf(
+                        &mut text,
+                        track.track_id,
+                        av_format,
+                        &Self::variant_codecs(avc_track.rfc6381_codec(), &audio_codecs),
+                        Self::variant_bandwidth(media_track.bandwidth(packaging), audio_bandwidth),
+                        Self::variant_bandwidth(media_track.average_bandwidth(packaging), audio_average_bandwidth),
+                        avc_track.frame_rate(),
+                        avc_track.dimensions(),
+                        if instream_ids.is_empty() { None } else { Some(&cc_group[..]) },
+                        subtitles,
+                    );
+                },
+                store::Track::Hevc(hevc_track) => {
+                    Self::write_stream_inf(
+                        &mut text,
+                        track.track_id,
+                        av_format,
+                        &Self::variant_codecs(hevc_track.rfc6381_codec(), &audio_codecs),
+                        Self::variant_bandwidth(media_track.bandwidth(packaging), audio_bandwidth),
+                        Self::variant_bandwidth(media_track.average_bandwidth(packaging), audio_average_bandwidth),
+                        hevc_track.frame_rate(),
+                        hevc_track.dimensions(),
+                        None,
+                        subtitles,
+                    );
                 },
                 store::Track::Aac(aac_track) => {
+                    Self::write_audio_media(
+                        &mut text,
+                        track.track_id,
+                        av_format,
+                        aac_track.language(),
+                        aac_track.audio_type(),
+                        aac_track.channels(),
+                        default_audio == Some(track.track_id),
+                        &mut audio_names,
+                    );
+                },
+                store::Track::Ac3(ac3_track) => {
+                    Self::write_audio_media(
+                        &mut text,
+                        track.track_id,
+                        av_format,
+                        ac3_track.language(),
+                        ac3_track.audio_type(),
+                        Some(ac3_track.channels()),
+                        default_audio == Some(track.track_id),
+                        &mut audio_names,
+                    );
+                },
+                store::Track::Subtitle(subtitle_track) => {
+                    let info = subtitle_track.info();
                     write!(text,
-                             "#EXT-X-MEDIA:TYPE=AUDIO,URI=\"track/{}/media.m3u8\",GROUP-ID=\"default-audio-group\",LANGUAGE=\"es\",NAME=\"stream_9\",AUTOSELECT=YES",
-                             track.track_id.0,
-                    )
-                    .unwrap();
-                    if let Some(channels) = aac_track.channels() {
-                        write!(text, ",CHANNELS=\"{}\"", channels)
+                           "#EXT-X-MEDIA:TYPE=SUBTITLES,URI=\"track/{}/{}\",GROUP-ID=\"{}\",NAME=\"{}\"",
+                           track.track_id.0,
+                           av_format.manifest_name(),
+                           Self::SUBTITLE_GROUP,
+                           info.name)
                         .unwrap();
+                    if let Some(ref language) = info.language {
+                        if language != "und" {
+                            write!(text, ",LANGUAGE=\"{}\"", language).unwrap();
+                        }
+                    }
+                    write!(text, ",DEFAULT=NO,AUTOSELECT=YES").unwrap();
+                    if info.hearing_impaired {
+                        write!(text, ",CHARACTERISTICS=\"public.accessibility.transcribes-spoken-dialog,public.accessibility.describes-music-and-sound\"").unwrap();
                     }
                     writeln!(text).unwrap();
-                }
+                },
             }
         }
         futures::future::ok(Response::builder()
@@ -108,6 +239,453 @@ impl HlsService {
             .unwrap())
     }
 
+    /// A low-latency MPEG-DASH manifest, addressing the same fMP4 segments as the HLS media
+    /// manifests.  Segments are advertised as available from the end of their first part, rather
+    /// than only once complete.  A new `Period` starts wherever the initialisation segment of
+    /// some track changes, or the input was discontinuous.
+    fn dash_manifest(&mut self) -> ImmediateFut {
+        let now = chrono::Utc::now().timestamp_millis() * Timestamp::TIMEBASE as i64 / 1_000;
+        // fixed by the store, so that it doesn't move between one request and the next
+        let availability_start = self.store.availability_start().unwrap_or(now);
+        let default_audio = self.default_audio_track();
+        let (period_starts, tolerance) = self.dash_period_starts();
+        let mut max_segment_duration = 0;
+        let mut part_target: f64 = 0.0;
+        let mut segment_target = 0;
+        let mut periods = vec![String::new(); period_starts.len()];
+        for info in self.store.track_list() {
+            let mut track_ref = self.store.get_track(info.track_id).unwrap();
+            let track = track_ref.track();
+            if let store::Track::Subtitle(_) = track {
+                // WebVTT segments aren't fMP4, so can't be addressed from a DASH manifest
+                continue;
+            }
+            // bandwidth is mandatory, so the track is held back until it can be estimated
+            let bandwidth = match track.bandwidth(store::Packaging::Fmp4) {
+                Some(bandwidth) => bandwidth,
+                None => continue,
+            };
+            let timeline = track.timeline();
+            max_segment_duration = cmp::max(max_segment_duration, timeline.max_chunk_duration());
+            part_target = part_target.max(timeline.part_target_duration());
+            segment_target = cmp::max(segment_target, timeline.targets().segment);
+            for (i, text) in periods.iter_mut().enumerate() {
+                let segments: Vec<_> = timeline.segments()
+                    .filter(|seg| Self::dash_period_index(&period_starts, tolerance, seg.id()) == i )
+                    .collect();
+                let first_dts = match segments.first() {
+                    Some(first) => first.id(),
+                    None => continue,
+                };
+                let representation = match track {
+                    store::Track::Avc(avc_track) => {
+                        let parameter_sets = avc_track.parameter_sets().at(first_dts);
+                        let (width, height) = parameter_sets.dimensions();
+                        Self::dash_video_representation(parameter_sets.rfc6381_codec(), bandwidth, avc_track.frame_rate(), width, height)
+                    },
+                    store::Track::Hevc(hevc_track) => {
+                        let parameter_sets = hevc_track.parameter_sets().at(first_dts);
+                        let (width, height) = parameter_sets.dimensions();
+                        Self::dash_video_representation(parameter_sets.rfc6381_codec(), bandwidth, hevc_track.frame_rate(), width, height)
+                    },
+                    store::Track::Aac(aac_track) => {
+                        let config = aac_track.configs().at(first_dts);
+                        DashRepresentation {
+                            content_type: "audio",
+                            role: Self::dash_audio_role(aac_track.audio_type(), default_audio == Some(info.track_id)),
+                            language: aac_track.language(),
+                            attributes: Self::dash_audio_attributes(config.rfc6381_codec(), bandwidth, config.sample_rate()),
+                            channels: config.channels(),
+                        }
+                    },
+                    store::Track::Ac3(ac3_track) => {
+                        let config = ac3_track.configs().at(first_dts);
+                        DashRepresentation {
+                            content_type: "audio",
+                            role: Self::dash_audio_role(ac3_track.audio_type(), default_audio == Some(info.track_id)),
+                            language: ac3_track.language(),
+                            attributes: Self::dash_audio_attributes(config.rfc6381_codec().to_string(), bandwidth, config.sample_rate),
+                            channels: Some(config.channels()),
+                        }
+                    },
+                    store::Track::Subtitle(_) => unreachable!(),
+                };
+                Self::write_adaptation_set(text, info.track_id, &representation, track, period_starts[i], &segments[..]);
+            }
+        }
+
+        let mut text = String::new();
+        writeln!(text, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+        writeln!(text,
+                 "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" profiles=\"urn:mpeg:dash:profile:isoff-live:2011,http://www.dashif.org/guidelines/low-latency-live-v5\" type=\"dynamic\" availabilityStartTime=\"{}\" publishTime=\"{}\" minimumUpdatePeriod=\"PT{:.3}S\" timeShiftBufferDepth=\"PT{}S\" maxSegmentDuration=\"PT{}S\" minBufferTime=\"PT{:.3}S\">",
+                 Self::format_date(availability_start),
+                 Self::format_date(now),
+                 segment_target as f64 / Timestamp::TIMEBASE as f64,
+                 store::ARCHIVE_LIMIT / Timestamp::TIMEBASE,
+                 max_segment_duration,
+                 part_target)
+            .unwrap();
+        // as with PART-HOLD-BACK, three times the part duration
+        writeln!(text, "  <ServiceDescription id=\"0\">").unwrap();
+        writeln!(text, "    <Latency target=\"{}\"/>", (part_target * 3000.0).round()).unwrap();
+        writeln!(text, "  </ServiceDescription>").unwrap();
+        for (start, adaptation_sets) in period_starts.iter().zip(periods.iter()) {
+            // media timestamps are relative to availabilityStartTime, so the period starts at the
+            // timestamp of its first media
+            writeln!(text, "  <Period id=\"{}\" start=\"PT{:.6}S\">", start, *start as f64 / Timestamp::TIMEBASE as f64).unwrap();
+            text.push_str(adaptation_sets);
+            writeln!(text, "  </Period>").unwrap();
+        }
+        writeln!(text, "  <UTCTiming schemeIdUri=\"urn:mpeg:dash:utc:direct:2014\" value=\"{}\"/>", Self::format_date(now)).unwrap();
+        writeln!(text, "</MPD>").unwrap();
+
+        futures::future::ok(Response::builder()
+            .header("Content-Type", "application/dash+xml")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(text))
+            .unwrap())
+    }
+
+    /// The timestamps at which each DASH `Period` starts, being the first segment of any track,
+    /// followed by each discontinuity or change of initialisation segment.  Since the tracks'
+    /// segments don't start at exactly the same time, changes within the returned tolerance of
+    /// one another are taken to be the same.
+    fn dash_period_starts(&mut self) -> (Vec<i64>, i64) {
+        let mut first = None;
+        let mut changes = vec![];
+        let mut tolerance = 0;
+        for info in self.store.track_list() {
+            let mut track_ref = self.store.get_track(info.track_id).unwrap();
+            let track = track_ref.track();
+            if let store::Track::Subtitle(_) = track {
+                continue;
+            }
+            tolerance = cmp::max(tolerance, track.timeline().targets().segment / 2);
+            let mut prev_init = None;
+            for seg in track.timeline().segments() {
+                let init = Self::init_uri(track, seg.id());
+                match prev_init {
+                    None => first = Some(first.map_or(seg.id(), |f: i64| f.min(seg.id()) )),
+                    Some(ref prev) if !seg.is_continuous() || *prev != init => changes.push(seg.id()),
+                    Some(_) => (),
+                }
+                prev_init = Some(init);
+            }
+        }
+        changes.sort();
+        let mut starts = vec![first.unwrap_or(0)];
+        for change in changes {
+            if change > *starts.last().unwrap() + tolerance {
+                starts.push(change);
+            }
+        }
+        (starts, tolerance)
+    }
+
+    /// The index of the `Period` within which a segment starting at the given timestamp belongs
+    fn dash_period_index(period_starts: &[i64], tolerance: i64, dts: i64) -> usize {
+        period_starts.iter()
+            .rposition(|&start| start <= dts + tolerance )
+            .unwrap_or(0)
+    }
+
+    /// The timescale of the fMP4 media of the given track: the sample rate for audio, or 90kHz
+    /// otherwise
+    fn media_timescale(track: &store::Track, dts: i64) -> u32 {
+        match track {
+            store::Track::Aac(aac_track) => aac_track.configs().at(dts).sample_rate(),
+            store::Track::Ac3(ac3_track) => ac3_track.configs().at(dts).sample_rate,
+            _ => Timestamp::TIMEBASE as u32,
+        }
+    }
+
+    fn dash_video_representation(codecs: String, bandwidth: u32, frame_rate: Option<store::FrameRate>, width: u32, height: u32) -> DashRepresentation<'static> {
+        let mut attributes = format!(
+            "codecs=\"{}\" bandwidth=\"{}\" width=\"{}\" height=\"{}\"",
+            codecs,
+            bandwidth,
+            width,
+            height,
+        );
+        if let Some(frame_rate) = frame_rate {
+            write!(attributes, " frameRate=\"{}/{}\"", frame_rate.num, frame_rate.den).unwrap();
+        }
+        DashRepresentation {
+            content_type: "video",
+            role: "main",
+            language: None,
+            attributes,
+            channels: None,
+        }
+    }
+
+    fn dash_audio_attributes(codecs: String, bandwidth: u32, sample_rate: u32) -> String {
+        format!(
+            "codecs=\"{}\" bandwidth=\"{}\" audioSamplingRate=\"{}\"",
+            codecs,
+            bandwidth,
+            sample_rate,
+        )
+    }
+
+    /// The `urn:mpeg:dash:role:2011` value equivalent to the HLS `DEFAULT` / `CHARACTERISTICS`
+    /// of the track
+    fn dash_audio_role(audio_type: store::AudioType, is_default: bool) -> &'static str {
+        match audio_type {
+            store::AudioType::VisualImpairedCommentary => "description",
+            store::AudioType::HearingImpaired => "enhanced-audio-intelligibility",
+            store::AudioType::Main | store::AudioType::CleanEffects if is_default => "main",
+            store::AudioType::Main | store::AudioType::CleanEffects => "alternate",
+        }
+    }
+
+    /// A single-`Representation` `AdaptationSet` for the given track's segments within a
+    /// `Period`, including the one in progress.  Times are in the timescale of the fMP4 media, so
+    /// that `$Time$` matches the `tfdt` of the segment, and identifies it in the media URL.
+    fn write_adaptation_set(
+        text: &mut String,
+        track_id: store::TrackId,
+        representation: &DashRepresentation<'_>,
+        track: &store::Track,
+        period_start: i64,
+        segments: &[store::SegmentInfo],
+    ) {
+        let first_dts = match segments.first() {
+            Some(first) => first.id(),
+            None => return,
+        };
+        let timeline = track.timeline();
+        let timescale = Self::media_timescale(track, first_dts);
+        write!(text,
+               "    <AdaptationSet id=\"{}\" contentType=\"{}\" mimeType=\"{}/mp4\" segmentAlignment=\"true\" startWithSAP=\"1\"",
+               track_id.0,
+               representation.content_type,
+               representation.content_type)
+            .unwrap();
+        if let Some(language) = representation.language {
+            if language != "und" {
+                write!(text, " lang=\"{}\"", language).unwrap();
+            }
+        }
+        writeln!(text, ">").unwrap();
+        writeln!(text, "      <Role schemeIdUri=\"urn:mpeg:dash:role:2011\" value=\"{}\"/>", representation.role).unwrap();
+        writeln!(text, "      <Representation id=\"{}\" {}>", track_id.0, representation.attributes).unwrap();
+        if let Some(channels) = representation.channels {
+            writeln!(text,
+                     "        <AudioChannelConfiguration schemeIdUri=\"urn:mpeg:dash:23003:3:audio_channel_configuration:2011\" value=\"{}\"/>",
+                     channels)
+                .unwrap();
+        }
+        // the segment is produced part by part, so may be requested once its first part is
+        // available
+        let targets = timeline.targets();
+        let availability_offset = ((targets.segment as f64 / Timestamp::TIMEBASE as f64) - timeline.part_target_duration()).max(0.0);
+        writeln!(text,
+                 "        <SegmentTemplate timescale=\"{}\" presentationTimeOffset=\"{}\" initialization=\"track/{}/{}\" media=\"track/{}/dash/$Time$.mp4\" availabilityTimeOffset=\"{:.3}\" availabilityTimeComplete=\"false\">",
+                 timescale,
+                 mp4::media_time(period_start, timescale),
+                 track_id.0,
+                 Self::init_uri(track, first_dts),
+                 track_id.0,
+                 availability_offset)
+            .unwrap();
+        writeln!(text, "          <SegmentTimeline>").unwrap();
+        let mut next_time = None;
+        for (i, seg) in segments.iter().enumerate() {
+            // the in-progress segment is expected to last for the target duration
+            let end_dts = match segments.get(i + 1) {
+                Some(next) => next.id(),
+                None => match seg.duration_seconds() {
+                    Some(duration) => seg.id() + (duration * Timestamp::TIMEBASE as f64).round() as i64,
+                    None => seg.id() + targets.segment,
+                },
+            };
+            let time = mp4::media_time(seg.id(), timescale);
+            let duration = mp4::media_time(end_dts, timescale) - time;
+            if next_time == Some(time) {
+                writeln!(text, "            <S d=\"{}\"/>", duration).unwrap();
+            } else {
+                writeln!(text, "            <S t=\"{}\" d=\"{}\"/>", time, duration).unwrap();
+            }
+            next_time = Some(time + duration);
+        }
+        writeln!(text, "          </SegmentTimeline>").unwrap();
+        writeln!(text, "        </SegmentTemplate>").unwrap();
+        writeln!(text, "      </Representation>").unwrap();
+        writeln!(text, "    </AdaptationSet>").unwrap();
+    }
+
+    /// The first audio track intended for a general audience, or failing that, the first audio
+    /// track of any kind
+    fn default_audio_track(&mut self) -> Option<store::TrackId> {
+        let store = &mut self.store;
+        let audio: Vec<_> = store.track_list()
+            .filter_map(|track| match store.get_track(track.track_id).unwrap().track() {
+                store::Track::Aac(aac_track) => Some((track.track_id, aac_track.audio_type())),
+                store::Track::Ac3(ac3_track) => Some((track.track_id, ac3_track.audio_type())),
+                _ => None,
+            })
+            .collect();
+        audio.iter()
+            .find(|(_, audio_type)| *audio_type == store::AudioType::Main )
+            .or_else(|| audio.first() )
+            .map(|(track_id, _)| *track_id )
+    }
+
+    /// The distinct codecs of the audio renditions, any of which may be played with the video
+    fn audio_codecs(&mut self) -> Vec<String> {
+        let store = &mut self.store;
+        let mut codecs = vec![];
+        for track in store.track_list() {
+            let codec = match store.get_track(track.track_id).unwrap().track() {
+                store::Track::Aac(aac_track) => aac_track.rfc6381_codec(),
+                store::Track::Ac3(ac3_track) => ac3_track.rfc6381_codec(),
+                _ => continue,
+            };
+            if !codecs.contains(&codec) {
+                codecs.push(codec);
+            }
+        }
+        codecs
+    }
+
+    /// The highest peak and average bitrates of any audio rendition once packaged as given, one
+    /// of which will be played alongside the video
+    fn audio_bandwidth(&mut self, packaging: store::Packaging) -> (Option<u32>, Option<u32>) {
+        let store = &mut self.store;
+        let mut peak = None;
+        let mut average = None;
+        for track in store.track_list() {
+            let mut track_ref = store.get_track(track.track_id).unwrap();
+            let media_track = track_ref.track();
+            let (track_peak, track_average) = match media_track {
+                store::Track::Aac(_) | store::Track::Ac3(_) => (media_track.bandwidth(packaging), media_track.average_bandwidth(packaging)),
+                _ => continue,
+            };
+            peak = cmp::max(peak, track_peak);
+            average = cmp::max(average, track_average);
+        }
+        (peak, average)
+    }
+
+    fn variant_bandwidth(video: Option<u32>, audio: Option<u32>) -> Option<u32> {
+        video.map(|video| video + audio.unwrap_or(0) )
+    }
+
+    fn variant_codecs(video_codec: String, audio_codecs: &[String]) -> String {
+        let mut codecs = video_codec;
+        for codec in audio_codecs {
+            codecs.push(',');
+            codecs.push_str(codec);
+        }
+        codecs
+    }
+
+    fn has_subtitles(&mut self) -> bool {
+        let store = &mut self.store;
+        store.track_list()
+            .any(|track| match store.get_track(track.track_id).unwrap().track() {
+                store::Track::Subtitle(_) => true,
+                _ => false,
+            })
+    }
+
+    fn write_audio_media(
+        text: &mut String,
+        track_id: store::TrackId,
+        av_format: SegmentFormat,
+        language: Option<&str>,
+        audio_type: store::AudioType,
+        channels: Option<u32>,
+        is_default: bool,
+        audio_names: &mut HashSet<String>,
+    ) {
+        let mut name = Self::audio_name(language, audio_type);
+        if audio_names.contains(&name) {
+            // NAME must be unique within the group
+            write!(name, " {}", track_id.0).unwrap();
+        }
+        write!(text,
+                 "#EXT-X-MEDIA:TYPE=AUDIO,URI=\"track/{}/{}\",GROUP-ID=\"default-audio-group\",NAME=\"{}\"",
+                 track_id.0,
+                 av_format.manifest_name(),
+                 name,
+        )
+        .unwrap();
+        audio_names.insert(name);
+        if let Some(language) = language {
+            if language != "und" {
+                write!(text, ",LANGUAGE=\"{}\"", language).unwrap();
+            }
+        }
+        write!(text, ",DEFAULT={},AUTOSELECT=YES", if is_default { "YES" } else { "NO" }).unwrap();
+        match audio_type {
+            store::AudioType::VisualImpairedCommentary => {
+                write!(text, ",CHARACTERISTICS=\"public.accessibility.describes-video\"").unwrap();
+            },
+            store::AudioType::HearingImpaired => {
+                write!(text, ",CHARACTERISTICS=\"public.accessibility.enhances-speech-intelligibility\"").unwrap();
+            },
+            store::AudioType::Main | store::AudioType::CleanEffects => (),
+        }
+        if let Some(channels) = channels {
+            write!(text, ",CHANNELS=\"{}\"", channels)
+            .unwrap();
+        }
+        writeln!(text).unwrap();
+    }
+
+    fn audio_name(language: Option<&str>, audio_type: store::AudioType) -> String {
+        let mut name = language.unwrap_or("Audio").to_string();
+        match audio_type {
+            store::AudioType::Main => (),
+            store::AudioType::CleanEffects => name.push_str(" (clean effects)"),
+            store::AudioType::HearingImpaired => name.push_str(" (hearing impaired)"),
+            store::AudioType::VisualImpairedCommentary => name.push_str(" (audio description)"),
+        }
+        name
+    }
+
+    fn write_stream_inf(
+        text: &mut String,
+        track_id: store::TrackId,
+        av_format: SegmentFormat,
+        codecs: &str,
+        bandwidth: Option<u32>,
+        average_bandwidth: Option<u32>,
+        frame_rate: Option<store::FrameRate>,
+        (width, height): (u32, u32),
+        closed_captions: Option<&str>,
+        subtitles: Option<&str>,
+    ) {
+        // BANDWIDTH is mandatory, so if the input didn't signal it, the variant is held back
+        // until enough media has been received to estimate it
+        let bandwidth = match bandwidth {
+            Some(bandwidth) => bandwidth,
+            None => return,
+        };
+        write!(text, "#EXT-X-STREAM-INF:BANDWIDTH={},", bandwidth).unwrap();
+        if let Some(average_bandwidth) = average_bandwidth {
+            write!(text, "AVERAGE-BANDWIDTH={},", average_bandwidth).unwrap();
+        }
+        write!(text, "CODECS=\"{}\",", codecs).unwrap();
+        if let Some(frame_rate) = frame_rate {
+            write!(text, "FRAMERATE={:.3},", frame_rate.as_f64()).unwrap();
+        }
+        if let Some(group) = closed_captions {
+            write!(text, "CLOSED-CAPTIONS=\"{}\",", group).unwrap();
+        }
+        if let Some(group) = subtitles {
+            write!(text, "SUBTITLES=\"{}\",", group).unwrap();
+        }
+        writeln!(text,
+                 "RESOLUTION={}x{},AUDIO=\"default-audio-group\"",
+                 width,
+                 height)
+            .unwrap();
+        writeln!(text, "track/{}/{}", track_id.0, av_format.manifest_name()).unwrap();
+    }
+
     fn track(&mut self, req: Request<Body>, track_id: String, rest: Option<String>) -> Either<ImmediateFut, MediaManifestFut> {
         if let Ok(id) = track_id.parse() {
             let track_id = store::TrackId(id);
@@ -119,9 +697,19 @@ impl HlsService {
             }
             if let Some(rest) = rest {
                 if "media.m3u8" == rest {
-                    Self::media_manifest(req, &mut self.store, track_id)
+                    Self::media_manifest(req, &mut self.store, track_id, SegmentFormat::Fmp4)
+                } else if "media-ts.m3u8" == rest {
+                    Self::media_manifest(req, &mut self.store, track_id, SegmentFormat::Ts)
                 } else if "init.mp4" == rest {
-                    Either::A(Self::initialisation_segment(req, self.store.get_track(track_id).unwrap()))
+                    Either::A(Self::initialisation_segment(req, self.store.get_track(track_id).unwrap(), None))
+                } else if rest.starts_with("init/") && rest.ends_with(".mp4") {
+                    match rest["init/".len()..rest.len()-".mp4".len()].parse() {
+                        Ok(version) => Either::A(Self::initialisation_segment(req, self.store.get_track(track_id).unwrap(), Some(version))),
+                        Err(_) => Either::A(futures::future::ok(Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::from("Bad initialisation segment version"))
+                            .unwrap())),
+                    }
                 } else if rest.starts_with("segment/") {
                     let mut parts = rest["segment/".len()..].splitn(2, "/");
                     let id = parts.next();
@@ -129,13 +717,23 @@ impl HlsService {
                     if let Some(id) = id {
                         let id = id.to_string();
                         let rest = rest.map(|s| s.to_string() );
-                        Either::A(futures::future::ok(Self::fmp4_segment(req, self.store.get_track(track_id).unwrap(), id, rest)))
+                        self.segment(req, track_id, id, rest)
                     } else {
                         Either::A(futures::future::ok(Response::builder()
                             .status(StatusCode::BAD_REQUEST)
                             .body(Body::from("Need a segment id"))
                             .unwrap()))
                     }
+                } else if rest.starts_with("dash/") && rest.ends_with(".mp4") {
+                    let time = rest["dash/".len()..rest.len()-".mp4".len()].parse();
+                    let segment_dts = time.ok().and_then(|time| Self::dash_segment_dts(self.store.get_track(track_id).unwrap(), time) );
+                    match segment_dts {
+                        Some(segment_dts) => self.segment(req, track_id, segment_dts.to_string(), Some("seg.mp4".to_string())),
+                        None => Either::A(futures::future::ok(Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("No such segment"))
+                            .unwrap())),
+                    }
                 } else {
                     Either::A(futures::future::ok(Response::builder()
                         .status(StatusCode::BAD_REQUEST)
@@ -156,6 +754,27 @@ impl HlsService {
         }
     }
 
+    fn segment(&mut self, req: Request<Body>, track_id: store::TrackId, id: String, rest: Option<String>) -> Either<ImmediateFut, MediaManifestFut> {
+        if let Some(pending) = Self::pending_part(self.store.get_track(track_id).unwrap(), &id, rest.as_ref()) {
+            return Either::B(Box::new(Self::block_for_part(req, &mut self.store, track_id, pending, id, rest)));
+        }
+        if let Some(segment_dts) = Self::in_progress_segment(self.store.get_track(track_id).unwrap(), &id, rest.as_ref()) {
+            return Either::A(futures::future::ok(Self::chunked_segment(&mut self.store, track_id, segment_dts)));
+        }
+        Either::A(futures::future::ok(Self::fmp4_segment(req, self.store.get_track(track_id).unwrap(), id, rest)))
+    }
+
+    /// The DTS of the segment addressed by the `$Time$` of a DASH media URL, which is in the
+    /// timescale of the track's fMP4 media
+    fn dash_segment_dts(track_ref: store::TrackRef, time: u64) -> Option<i64> {
+        let mut track_ref = track_ref;
+        let track = track_ref.track();
+        track.timeline()
+            .segments()
+            .map(|seg| seg.id() )
+            .find(|&dts| mp4::media_time(dts, Self::media_timescale(track, dts)) == time )
+    }
+
     fn hls_request_params(uri: &Uri) -> HlsRequest {
         // hack to turn the relative URL into a qualified one that Url::parse() will accept,
         let url = Url::parse(&format!("http://localhost{}", uri)).unwrap();
@@ -173,9 +792,16 @@ impl HlsService {
                         req.part = Some(part);
                     }
                 },
-                "_HLS_push" => {
-                    if let Ok(push) = value.parse() {
-                        req.push = Some(push);
+                "_HLS_skip" => {
+                    req.skip = match &*value {
+                        "YES" => HlsSkip::Yes,
+                        "v2" => HlsSkip::V2,
+                        _ => HlsSkip::No,
+                    };
+                },
+                "_HLS_report" => {
+                    if let Some(track_id) = Self::parse_report_uri(&value) {
+                        req.report.get_or_insert_with(Vec::new).push(track_id);
                     }
                 },
                 _ => {}
@@ -184,15 +810,45 @@ impl HlsService {
         req
     }
 
-    fn media_manifest(req: Request<Body>, store: &mut store::Store, id: store::TrackId) -> Either<ImmediateFut, MediaManifestFut> {
+    /// Rendition report URIs are expected to take the form `../{track_id}/media.m3u8` (or
+    /// `media-ts.m3u8`), relative to the requested media manifest
+    fn parse_report_uri(uri: &str) -> Option<store::TrackId> {
+        let dir = if uri.ends_with("/media.m3u8") {
+            &uri[..uri.len()-"/media.m3u8".len()]
+        } else if uri.ends_with("/media-ts.m3u8") {
+            &uri[..uri.len()-"/media-ts.m3u8".len()]
+        } else {
+            return None;
+        };
+        dir.rsplit('/')
+            .next()
+            .and_then(|id| id.parse().ok() )
+            .map(store::TrackId)
+    }
+
+    /// The sequence reached by each of the other tracks, along with the name of its media
+    /// manifest in the given `av_format`
+    fn rendition_reports(store: &mut store::Store, id: store::TrackId, req: &HlsRequest, av_format: SegmentFormat) -> Vec<(store::TrackId, &'static str, store::TrackSequence)> {
+        let track_ids: Vec<_> = store.track_list()
+            .map(|track| track.track_id )
+            .filter(|&track_id| track_id != id )
+            .filter(|track_id| req.report.as_ref().map(|r| r.contains(track_id) ).unwrap_or(true) )
+            .collect();
+        track_ids.into_iter()
+            .filter_map(|track_id| {
+                let seq = store.track_sequence(track_id)?;
+                let format = SegmentFormat::for_track(store.get_track(track_id)?.track(), av_format);
+                Some((track_id, format.manifest_name(), seq))
+            })
+            .collect()
+    }
+
+    fn media_manifest(req: Request<Body>, store: &mut store::Store, id: store::TrackId, av_format: SegmentFormat) -> Either<ImmediateFut, MediaManifestFut> {
         let hls_request = Self::hls_request_params(req.uri());
         if let Some(request_msn) = hls_request.msn {
             let current_msn = {
                 let mut track_ref = store.get_track(id).unwrap();
-                match track_ref.track() {
-                    store::Track::Avc(ref avc_track) => avc_track.media_sequence_number(),
-                    store::Track::Aac(ref aac_track) => aac_track.media_sequence_number(),
-                }
+                track_ref.track().timeline().media_sequence_number()
             };
             if request_msn > current_msn + 1 {
                 // per the spec, return HTTP 400 error response
@@ -204,209 +860,432 @@ impl HlsService {
             }
             if request_msn == current_msn + 1 {
                 // block waiting for the next segment
-                return Either::B(Box::new(Self::block_for_media_manifest(store, id, hls_request)));
+                return Either::B(Box::new(Self::block_for_media_manifest(store, id, hls_request, av_format)));
             }
         }
         let has_pts_to_utc = store.has_pts_to_utc();
-        let mut track_ref = store.get_track(id).unwrap();
-        let text = Self::render_media_manifest(has_pts_to_utc, track_ref);
-
-        let mut b = Response::builder();
-        b.header("Content-Type", "application/vnd.apple.mpegurl");
-        b.header("Access-Control-Allow-Origin", "*");
-        if let Some(seq) = hls_request.msn {
-            if hls_request.push.map(|p| p > 0).unwrap_or(false) {
-                if let Some(part) = hls_request.part {
-                    let mut track_ref = store.get_track(id).expect("TODO: get_track()");
-                    // jump through some hoops to map from the segment number to its timestamp
-                    let segment = match track_ref.track() {
-                        store::Track::Avc(ref avc_track) => avc_track.segments().find(|s| s.sequence_number() == seq),
-                        store::Track::Aac(ref aac_track) => aac_track.segments().find(|s| s.sequence_number() == seq),
-                    }.unwrap_or_else(|| panic!("Couldn't get segment #{} of track {:?}", seq, id));
-                    b.header("Link", format!("</track/{}/segment/{}/part/{}.mp4>; rel=preload; as=video; type=video/mp4", id.0, segment.id(), part));
-                }
-                // TODO: push segment if no part was requested?
-            }
-        }
-        Either::A(futures::future::ok(b
+        let reports = Self::rendition_reports(store, id, &hls_request, av_format);
+        let date_ranges = store.date_ranges();
+        let removed_date_ranges = store.removed_date_ranges();
+        let track_ref = store.get_track(id).unwrap();
+        let text = Self::render_media_manifest(has_pts_to_utc, track_ref, av_format, &hls_request, &reports, &date_ranges, &removed_date_ranges);
+
+        Either::A(futures::future::ok(Response::builder()
+            .header("Content-Type", "application/vnd.apple.mpegurl")
+            .header("Access-Control-Allow-Origin", "*")
             .body(Body::from(text))
             .unwrap()))
     }
 
-    fn block_for_media_manifest(store: &mut store::Store, id: store::TrackId, req: HlsRequest) -> impl Future<Item=Response<Body>, Error=HlsServiceError> {
+    fn block_for_media_manifest(store: &mut store::Store, id: store::TrackId, req: HlsRequest, av_format: SegmentFormat) -> impl Future<Item=Response<Body>, Error=HlsServiceError> {
         let msn = req.msn.unwrap();
         let seq_stream = {
             let mut track_ref = store.get_track(id).unwrap();
-            match track_ref.track() {
-                store::Track::Avc(ref avc_track) => avc_track.sequence_stream(),
-                store::Track::Aac(ref aac_track) => aac_track.sequence_stream(),
-            }
+            track_ref.track().timeline().sequence_stream()
         };
+        let part = req.part;
         let mut store = store.clone();
         seq_stream
             .skip_while(move |seq| future::ok(seq.seg < msn) )
-            .skip_while(move |seq| future::ok(seq.seg == msn && req.part.map(|p| seq.part < p ).unwrap_or(false)) )
+            .skip_while(move |seq| future::ok(seq.seg == msn && part.map(|p| seq.part < p ).unwrap_or(false)) )
             .into_future()
             .map_err(|(e, _stream)| panic!("Unexpected watch error {:?}", e) )
-            .and_then(move |(seq, _stream)| {
+            .and_then(move |(_seq, _stream)| {
                 let has_pts_to_utc = store.has_pts_to_utc();
-                let mut track_ref = store.get_track(id).unwrap();
-                let text = Self::render_media_manifest(has_pts_to_utc, track_ref);
-                let mut b = Response::builder();
-                b.header("Content-Type", "application/vnd.apple.mpegurl");
-                b.header("Access-Control-Allow-Origin", "*");
-                if let Some(seq) = seq {
-                    if req.push.map(|p| p > 0).unwrap_or(false) {
-                        if let Some(part) = req.part {
-                            let mut track_ref = store.get_track(id).expect("TODO: get_track()");
-                            // jump through some hoops to map from the segment number to its timestamp
-                            let segment = match track_ref.track() {
-                                store::Track::Avc(ref avc_track) => avc_track.segments().find(|s| s.sequence_number() == seq.seg ),
-                                store::Track::Aac(ref aac_track) => aac_track.segments().find(|s| s.sequence_number() == seq.seg ),
-                            }.unwrap_or_else(|| panic!("Couldn't get segment #{} of track {:?}", seq.seg, id) );
-                            b.header("Link", format!("</track/{}/segment/{}/part/{}.mp4>; rel=preload; as=video; type=video/mp4", id.0, segment.id(), part));
-                        }
-                        // TODO: push segment if no part was requested?
-                    }
-                }
-                futures::future::ok(b.body(Body::from(text)).unwrap())
+                let reports = Self::rendition_reports(&mut store, id, &req, av_format);
+                let date_ranges = store.date_ranges();
+                let removed_date_ranges = store.removed_date_ranges();
+                let track_ref = store.get_track(id).unwrap();
+                let text = Self::render_media_manifest(has_pts_to_utc, track_ref, av_format, &req, &reports, &date_ranges, &removed_date_ranges);
+                futures::future::ok(Response::builder()
+                    .header("Content-Type", "application/vnd.apple.mpegurl")
+                    .header("Access-Control-Allow-Origin", "*")
+                    .body(Body::from(text))
+                    .unwrap())
             })
     }
 
-    fn render_media_manifest(has_pts_to_utc: bool, track_ref: store::TrackRef) -> String {
+    /// If the request is for the next part of the in-progress segment (i.e. the part most
+    /// recently advertised by `EXT-X-PRELOAD-HINT`), which has not been ingested yet, returns
+    /// the `TrackSequence` that the track must reach before the part can be served.
+    fn pending_part(track_ref: store::TrackRef, segment_id: &str, rest: Option<&String>) -> Option<store::TrackSequence> {
+        let segment_dts: i64 = segment_id.parse().ok()?;
+        let part_id = Self::parse_part_id(rest?)?;
+        let mut track_ref = track_ref;
+        let timeline = track_ref.track().timeline();
+        let segment = timeline.segments().find(|s| s.id() == segment_dts )?;
+        let available_parts = timeline.parts(segment_dts).ok()?.count();
+        if segment.duration_seconds().is_none() && part_id as usize == available_parts {
+            Some(store::TrackSequence {
+                seg: segment.sequence_number(),
+                part: part_id as u16,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn block_for_part(req: Request<Body>, store: &mut store::Store, id: store::TrackId, pending: store::TrackSequence, segment_id: String, rest: Option<String>) -> impl Future<Item=Response<Body>, Error=HlsServiceError> {
+        let seq_stream = {
+            let mut track_ref = store.get_track(id).unwrap();
+            track_ref.track().timeline().sequence_stream()
+        };
+        let mut store = store.clone();
+        // wait until either the part becomes available, or the segment is finished without ever
+        // having gained the requested part (in which case fmp4_segment() will give a 404)
+        seq_stream
+            .skip_while(move |seq| future::ok(seq.seg < pending.seg || (seq.seg == pending.seg && seq.part < pending.part)) )
+            .into_future()
+            .map_err(|(e, _stream)| panic!("Unexpected watch error {:?}", e) )
+            .and_then(move |(_seq, _stream)| {
+                futures::future::ok(Self::fmp4_segment(req, store.get_track(id).unwrap(), segment_id, rest))
+            })
+    }
+
+    fn parse_part_id(rest: &str) -> Option<u64> {
+        if !rest.starts_with("part/") {
+            return None;
+        }
+        let name = &rest["part/".len()..];
+        let id = if name.ends_with(".mp4") {
+            &name[..name.len()-".mp4".len()]
+        } else if name.ends_with(".ts") {
+            &name[..name.len()-".ts".len()]
+        } else {
+            return None;
+        };
+        id.parse().ok()
+    }
+
+    fn render_media_manifest(
+        has_pts_to_utc: bool,
+        track_ref: store::TrackRef,
+        av_format: SegmentFormat,
+        req: &HlsRequest,
+        reports: &[(store::TrackId, &str, store::TrackSequence)],
+        date_ranges: &[store::DateRange],
+        removed_date_ranges: &[String],
+    ) -> String {
         let mut text = String::new();
+        let mut track_ref = track_ref;
+        let format = SegmentFormat::for_track(track_ref.track(), av_format);
+        let track = track_ref.track();
+        let timeline = track.timeline();
+        Self::write_media_manifest(
+            &mut text,
+            format,
+            |seg| Self::init_uri(track, seg.id()),
+            has_pts_to_utc,
+            req.skip,
+            date_ranges,
+            removed_date_ranges,
+            timeline.target_duration(),
+            timeline.part_target_duration(),
+            timeline.discontinuity_sequence(),
+            timeline.segments(),
+            |seg| if format.part_extension().is_some() && timeline.has_parts(seg.id()) {
+                timeline.parts(seg.id()).ok().map(|parts| parts.collect() )
+            } else {
+                None
+            },
+        );
+        for (track_id, manifest_name, seq) in reports {
+            writeln!(text,
+                     "#EXT-X-RENDITION-REPORT:URI=\"../{}/{}\",LAST-MSN={},LAST-PART={}",
+                     track_id.0,
+                     manifest_name,
+                     seq.seg,
+                     seq.part)
+                .unwrap();
+        }
+        text
+    }
+
+    /// The initialisation segment to be used with media from the given timestamp, which for video
+    /// and audio tracks depends on the parameter sets or stream configuration in effect
+    fn init_uri(track: &store::Track, dts: i64) -> String {
+        match track {
+            store::Track::Avc(avc_track) => format!("init/{}.mp4", avc_track.parameter_sets().at(dts).version()),
+            store::Track::Hevc(hevc_track) => format!("init/{}.mp4", hevc_track.parameter_sets().at(dts).version()),
+            store::Track::Aac(aac_track) => format!("init/{}.mp4", aac_track.configs().at(dts).version()),
+            store::Track::Ac3(ac3_track) => format!("init/{}.mp4", ac3_track.configs().at(dts).version()),
+            _ => "init.mp4".to_string(),
+        }
+    }
+
+    fn write_media_manifest<F>(
+        text: &mut String,
+        format: SegmentFormat,
+        init_uri: impl Fn(&store::SegmentInfo) -> String,
+        has_pts_to_utc: bool,
+        skip: HlsSkip,
+        date_ranges: &[store::DateRange],
+        removed_date_ranges: &[String],
+        target_duration: u32,
+        part_target: f64,
+        discontinuity_sequence: u64,
+        segments: impl Iterator<Item=store::SegmentInfo>,
+        parts: F,
+    )
+        where
+            F: Fn(&store::SegmentInfo) -> Option<Vec<store::PartInfo>>
+    {
+        let segments: Vec<_> = segments.collect();
+        let can_skip_until = (target_duration * Self::SKIP_TARGET_DURATIONS) as f64;
+        let skipped = if skip.is_requested() {
+            Self::skippable_segments(&segments, can_skip_until)
+        } else {
+            0
+        };
+
         writeln!(text, "#EXTM3U").unwrap();
         // TODO: validate correct version vs. used HLS features
-        writeln!(text, "#EXT-X-VERSION:{}", 7).unwrap();
+        // (EXT-X-SKIP requires version 9, or 10 when skipping date ranges, and EXT-X-GAP version 8)
+        let version = if skipped > 0 && skip == HlsSkip::V2 {
+            10
+        } else if skipped > 0 {
+            9
+        } else if segments.iter().any(|seg| seg.is_gap() ) {
+            8
+        } else {
+            7
+        };
+        writeln!(text, "#EXT-X-VERSION:{}", version).unwrap();
         writeln!(text, "#EXT-X-INDEPENDENT-SEGMENTS").unwrap();
-        writeln!(text, "#EXT-X-PART-INF:PART-TARGET={:.3}", 0.32).unwrap();
-        writeln!(text, "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:0.3}", 0.96).unwrap();
-        let mut track_ref = track_ref;
-        match track_ref.track() {
-            store::Track::Avc(ref avc_track) => {
+        if format.part_extension().is_some() {
+            writeln!(text, "#EXT-X-PART-INF:PART-TARGET={:.3}", part_target).unwrap();
+            // PART-HOLD-BACK must be at least twice PART-TARGET, and three times is recommended
+            writeln!(text, "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,CAN-SKIP-UNTIL={:.1},CAN-SKIP-DATERANGES=YES,PART-HOLD-BACK={:0.3}", can_skip_until, part_target * 3.0).unwrap();
+        } else {
+            writeln!(text, "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,CAN-SKIP-UNTIL={:.1},CAN-SKIP-DATERANGES=YES", can_skip_until).unwrap();
+        }
+        writeln!(text,
+                 "#EXT-X-TARGETDURATION:{}",
+                 target_duration)
+            .unwrap();
+        let mut current_init = None;
+        if format == SegmentFormat::Fmp4 {
+            let uri = segments.get(skipped)
+                .map(|seg| init_uri(seg) )
+                .unwrap_or_else(|| "init.mp4".to_string() );
+            writeln!(text,
+                     "#EXT-X-MAP:URI=\"{}\"",
+                     uri)
+                .unwrap();
+            current_init = Some(uri);
+        }
+        if let Some(first) = segments.first() {
+            if first.sequence_number() > 0 {
                 writeln!(text,
-                         "#EXT-X-TARGETDURATION:{}",
-                         avc_track.max_chunk_duration())
+                         "#EXT-X-MEDIA-SEQUENCE:{}",
+                         first.sequence_number())
                     .unwrap();
+            }
+        }
+        if discontinuity_sequence > 0 {
+            writeln!(text, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", discontinuity_sequence).unwrap();
+        }
+        if skipped > 0 {
+            if skip == HlsSkip::V2 {
+                // the player may hold date ranges which have since been cancelled
                 writeln!(text,
-                         "#EXT-X-MAP:URI=\"init.mp4\"")
+                         "#EXT-X-SKIP:SKIPPED-SEGMENTS={},RECENTLY-REMOVED-DATERANGES=\"{}\"",
+                         skipped,
+                         removed_date_ranges.join("\t"))
                     .unwrap();
-                if let Some(first) = avc_track.segments().nth(0) {
-                    if first.sequence_number() > 0 {
-                        writeln!(text,
-                                 "#EXT-X-MEDIA-SEQUENCE:{}",
-                                 first.sequence_number())
-                            .unwrap();
-                    }
-                    if has_pts_to_utc {
-                        let utc_millis = first.id() * 1_000 / Timestamp::TIMEBASE as i64;
-                        let date_time = chrono::Utc.timestamp_millis(utc_millis);
-
-                        writeln!(text, "#EXT-X-PROGRAM-DATE-TIME:{}", date_time.format("%Y-%m-%dT%H:%M:%S%.3fZ")).unwrap();
-                    }
+            } else {
+                writeln!(text, "#EXT-X-SKIP:SKIPPED-SEGMENTS={}", skipped).unwrap();
+            }
+        }
+        if let Some(first) = segments.get(skipped) {
+            if has_pts_to_utc {
+                writeln!(text, "#EXT-X-PROGRAM-DATE-TIME:{}", Self::format_date(first.id())).unwrap();
+            }
+        }
+        // EXT-X-DATERANGE needs the timeline to be mapped to wall-clock time.  Only a v2 delta
+        // update may omit date ranges that fall within the skipped segments.
+        let window_start = if skip == HlsSkip::V2 {
+            segments.get(skipped)
+        } else {
+            segments.first()
+        };
+        if let (true, Some(window_start)) = (has_pts_to_utc, window_start) {
+            for range in date_ranges.iter().filter(|r| r.end.unwrap_or(r.start) >= window_start.id() ) {
+                Self::write_date_range(text, range);
+            }
+        }
+        let mut preload_hint = None;
+        for seg in segments.iter().skip(skipped) {
+            if !seg.is_continuous() {
+                writeln!(text, "#EXT-X-DISCONTINUITY").unwrap();
+            }
+            if format == SegmentFormat::Fmp4 {
+                let uri = init_uri(seg);
+                if current_init.as_ref() != Some(&uri) {
+                    writeln!(text, "#EXT-X-MAP:URI=\"{}\"", uri).unwrap();
+                    current_init = Some(uri);
                 }
-                for seg in avc_track.segments() {
-                    if !seg.is_continuous() {
-                        writeln!(text, "#EXT-X-DISCONTINUITY").unwrap();
-                    }
-                    if avc_track.has_parts(seg.id()) {
-                        match avc_track.parts(seg.id()) {
-                            Ok(parts) => {
-                                Self::part_list(&mut text, &seg, parts)
-                            },
-                            Err(e) => (),
-                        }
-                    }
-                    if let Some(duration) = seg.duration_seconds() {
-                        // only expecting the final, in-progress segment to lack duration
-                        writeln!(text, "#EXTINF:{:.3},{}", duration, "").unwrap();
-                        writeln!(text, "segment/{}/seg.mp4", seg.id()).unwrap();
-                    }
+            }
+            let parts = parts(seg);
+            if let Some(duration) = seg.duration_seconds() {
+                if let Some(parts) = parts {
+                    Self::part_list(text, format, seg, parts.into_iter())
                 }
-            },
-            store::Track::Aac(ref aac_track) => {
-                writeln!(text,
-                         "#EXT-X-TARGETDURATION:{}",
-                         aac_track.max_chunk_duration())
-                    .unwrap();
-                writeln!(text,
-                         "#EXT-X-MAP:URI=\"init.mp4\"")
-                    .unwrap();
-                if let Some(first) = aac_track.segments().nth(0) {
-                    if first.sequence_number() > 0 {
-                        writeln!(text,
-                                 "#EXT-X-MEDIA-SEQUENCE:{}",
-                                 first.sequence_number())
-                            .unwrap();
-                    }
-                    if has_pts_to_utc {
-                        let utc_millis = first.id() * 1_000 / Timestamp::TIMEBASE as i64;
-                        let date_time = chrono::Utc.timestamp_millis(utc_millis);
-
-                        writeln!(text, "#EXT-X-PROGRAM-DATE-TIME:{}", date_time.format("%Y-%m-%dT%H:%M:%S%.3fZ")).unwrap();
-                    }
+                // only expecting the final, in-progress segment to lack duration
+                if seg.is_gap() {
+                    writeln!(text, "#EXT-X-GAP").unwrap();
                 }
-                for seg in aac_track.segments() {
-                    if !seg.is_continuous() {
-                        writeln!(text, "#EXT-X-DISCONTINUITY").unwrap();
-                    }
-                    if aac_track.has_parts(seg.id()) {
-                        match aac_track.parts(seg.id()) {
-                            Ok(parts) => {
-                                Self::part_list(&mut text, &seg, parts)
-                            },
-                            Err(e) => (),
-                        }
-                    }
-                    if let Some(duration) = seg.duration_seconds() {
-                        // only expecting the final, in-progress segment to lack duration
-                        writeln!(text, "#EXTINF:{:.3},{}", duration, "").unwrap();
-                        writeln!(text, "segment/{}/seg.mp4", seg.id()).unwrap();
-                    }
+                writeln!(text, "#EXTINF:{:.3},{}", duration, "").unwrap();
+                writeln!(text, "segment/{}/{}", seg.id(), format.segment_name()).unwrap();
+            } else if let Some(extension) = format.part_extension() {
+                let next_part = parts.as_ref().map(|p| p.len() ).unwrap_or(0);
+                if let Some(parts) = parts {
+                    Self::part_list(text, format, seg, parts.into_iter())
                 }
-            },
+                preload_hint = Some((seg.id(), next_part, extension));
+            }
         }
-        text
+        if let Some((segment_id, part_id, extension)) = preload_hint {
+            writeln!(text,
+                     "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"segment/{}/part/{}.{}\"",
+                     segment_id,
+                     part_id,
+                     extension)
+                .unwrap();
+        }
+    }
+
+    fn format_date(ts: i64) -> String {
+        let utc_millis = ts * 1_000 / Timestamp::TIMEBASE as i64;
+        let date_time = chrono::Utc.timestamp_millis(utc_millis);
+        date_time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+    }
+
+    fn write_date_range(text: &mut String, range: &store::DateRange) {
+        write!(text,
+               "#EXT-X-DATERANGE:ID=\"{}\",START-DATE=\"{}\"",
+               range.id,
+               Self::format_date(range.start))
+            .unwrap();
+        if let Some(end) = range.end {
+            write!(text, ",END-DATE=\"{}\"", Self::format_date(end)).unwrap();
+        }
+        if let Some(duration) = range.planned_duration {
+            write!(text, ",PLANNED-DURATION={:.3}", duration as f64 / Timestamp::TIMEBASE as f64).unwrap();
+        }
+        let attrs = [
+            ("SCTE35-CMD", &range.scte35_cmd),
+            ("SCTE35-OUT", &range.scte35_out),
+            ("SCTE35-IN", &range.scte35_in),
+        ];
+        for (name, section) in attrs.iter() {
+            if let Some(section) = section {
+                write!(text, ",{}=0x", name).unwrap();
+                for b in section {
+                    write!(text, "{:02X}", b).unwrap();
+                }
+            }
+        }
+        writeln!(text).unwrap();
+    }
+
+    /// Count of segments at the start of the given list which are far enough from the live edge
+    /// (more than `can_skip_until` seconds) that a Playlist Delta Update may omit them.
+    fn skippable_segments(segments: &[store::SegmentInfo], can_skip_until: f64) -> usize {
+        let mut from_live_edge = 0.0;
+        for (i, seg) in segments.iter().enumerate().rev() {
+            if from_live_edge >= can_skip_until {
+                return i + 1;
+            }
+            from_live_edge += seg.duration_seconds().unwrap_or(0.0);
+        }
+        0
     }
 
-    fn part_list(text: &mut String, seg: &store::SegmentInfo, parts: impl Iterator<Item=store::PartInfo>) -> () {
+    fn part_list(text: &mut String, format: SegmentFormat, seg: &store::SegmentInfo, parts: impl Iterator<Item=store::PartInfo>) -> () {
+        let extension = format.part_extension().unwrap();
         for part in parts {
             write!(text,
-                   "#EXT-X-PART:DURATION={:.3},URI=\"segment/{}/part/{}.mp4\"",
+                   "#EXT-X-PART:DURATION={:.3},URI=\"segment/{}/part/{}.{}\"",
                    part.duration_seconds().unwrap(),
                    seg.id(),
-                   part.id()).unwrap();
+                   part.id(),
+                   extension).unwrap();
             if part.is_independent() {
                 write!(text, ",INDEPENDENT=YES").unwrap();
             }
+            if part.is_gap() {
+                write!(text, ",GAP=YES").unwrap();
+            }
             writeln!(text).unwrap();
         }
     }
 
-    fn initialisation_segment(req: Request<Body>, track_ref: store::TrackRef) -> ImmediateFut {
+    /// For video and audio tracks, `version` selects the parameter sets or configuration to use
+    /// (see `init_uri()`), defaulting to the most recent
+    fn initialisation_segment(req: Request<Body>, track_ref: store::TrackRef, version: Option<u32>) -> ImmediateFut {
         let mut track_ref = track_ref;
-        let init = match track_ref.track() {
+        let data = match track_ref.track() {
             store::Track::Avc(ref avc_track) => {
-                Self::make_avc_initialisation_segment(avc_track)
+                let parameter_sets = match version {
+                    Some(version) => avc_track.parameter_sets().version(version),
+                    None => Some(avc_track.parameter_sets().latest()),
+                };
+                match parameter_sets {
+                    Some(parameter_sets) => Self::make_avc_initialisation_segment(parameter_sets),
+                    None => return futures::future::ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::from("No such initialisation segment"))
+                        .unwrap()),
+                }
             },
-            store::Track::Aac(ref avc_track) => {
-                Self::make_aac_initialisation_segment(avc_track)
+            store::Track::Hevc(ref hevc_track) => {
+                let parameter_sets = match version {
+                    Some(version) => hevc_track.parameter_sets().version(version),
+                    None => Some(hevc_track.parameter_sets().latest()),
+                };
+                match parameter_sets {
+                    Some(parameter_sets) => Self::make_hevc_initialisation_segment(parameter_sets),
+                    None => return futures::future::ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::from("No such initialisation segment"))
+                        .unwrap()),
+                }
             },
-        };
-        let init = match init {
-            Ok(init) => init,
-            Err(e) => {
-                eprintln!("Problem creating initialisation segment of track {}: {:?}", track_ref.id().0, e);
+            store::Track::Aac(ref aac_track) => {
+                let config = match version {
+                    Some(version) => aac_track.configs().version(version),
+                    None => Some(aac_track.configs().latest()),
+                };
+                match config {
+                    Some(config) => Self::make_aac_initialisation_segment(config),
+                    None => return futures::future::ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::from("No such initialisation segment"))
+                        .unwrap()),
+                }
+            },
+            store::Track::Ac3(ref ac3_track) => {
+                let config = match version {
+                    Some(version) => ac3_track.configs().version(version),
+                    None => Some(ac3_track.configs().latest()),
+                };
+                match config {
+                    Some(config) => mp4::ac3_initialisation_segment(config),
+                    None => return futures::future::ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::from("No such initialisation segment"))
+                        .unwrap()),
+                }
+            },
+            store::Track::Subtitle(_) => {
                 return futures::future::ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(Body::from("Problem creating initialisation segment"))
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("WebVTT tracks have no initialisation segment"))
                     .unwrap())
-            }
+            },
         };
 
-        let mut data = vec![];
-        init.write_to(&mut data).unwrap();
-
         futures::future::ok(Response::builder()
             .header("Content-Type", "video/mp4")
             .header("Access-Control-Allow-Origin", "*")
@@ -414,98 +1293,48 @@ impl HlsService {
             .unwrap())
     }
 
-    fn make_avc_initialisation_segment(avc_track: &store::AvcTrack) -> Result<fmp4::InitializationSegment, mse_fmp4::Error> {
-        let mut segment = fmp4::InitializationSegment::default();
-
-        let (width, height) = avc_track.dimensions();
-        let mut track = fmp4::TrackBox::new(true);
-        track.tkhd_box.width = width << 16;
-        track.tkhd_box.height = height << 16;
-        track.tkhd_box.duration = 0;
-        //track.edts_box.elst_box.media_time = avc_track.segments().next().unwrap().id() as i32;
-        track.mdia_box.mdhd_box.timescale = 90000;
-        track.mdia_box.mdhd_box.duration = 0;
-
-        let sps = avc_track.sps();
-        let mut sps_bytes = vec![];
-        sps_bytes.extend_from_slice(avc_track.sps_bytes());
-        let mut pps_bytes = vec![];
-        pps_bytes.extend_from_slice(avc_track.pps_bytes());
-
-        let avc_sample_entry = fmp4::AvcSampleEntry {
-            width: width as u16,
-            height: height as u16,
-            avcc_box: fmp4::AvcConfigurationBox {
-                configuration: mse_fmp4::avc::AvcDecoderConfigurationRecord {
-                    profile_idc: sps.profile_idc.into(),
-                    constraint_set_flag: 0,
-                    level_idc: sps.level_idc,
-                    sequence_parameter_set: sps_bytes,
-                    picture_parameter_set: pps_bytes,
-                },
-            },
-        };
-        track
-            .mdia_box
-            .minf_box
-            .stbl_box
-            .stsd_box
-            .sample_entries
-            .push(fmp4::SampleEntry::Avc(avc_sample_entry));
-        segment.moov_box.trak_boxes.push(track);
-        segment.moov_box.mvhd_box.timescale = 1;
-        segment.moov_box.mvhd_box.duration = 0;
-        segment
-            .moov_box
-            .mvex_box
-            .trex_boxes
-            .push(fmp4::TrackExtendsBox::new(true));
-
-        Ok(segment)
-    }
-
-    fn make_aac_initialisation_segment(aac_track: &store::AacTrack) -> Result<fmp4::InitializationSegment, mse_fmp4::Error> {
-        let mut segment = fmp4::InitializationSegment::default();
-
-        let mut track = fmp4::TrackBox::new(false);
-        track.tkhd_box.duration = 0;
-        track.mdia_box.mdhd_box.timescale = 90000;
-        track.mdia_box.mdhd_box.duration = 0;
-
-        let profile = match aac_track.profile() {
-            adts_reader::AudioObjectType::AacMain => aac::AacProfile::Main,
-            adts_reader::AudioObjectType::AacLC => aac::AacProfile::Lc,
-            adts_reader::AudioObjectType::AacSSR => aac::AacProfile::Ssr,
-            adts_reader::AudioObjectType::AacLTP => aac::AacProfile::Ltp,
-        };
-        let frequency = aac::SamplingFrequency::from_index(aac_track.frequency() as u8).unwrap();
-        let channel_configuration = aac::ChannelConfiguration::from_u8(aac_track.channel_config() as u8).unwrap();
+    /// Written by our own `mp4` module, since the `avcC` record of `mse_fmp4` can only hold a
+    /// single SPS and PPS
+    fn make_avc_initialisation_segment(parameter_sets: &store::AvcParameterSets) -> Vec<u8> {
+        let (width, height) = parameter_sets.dimensions();
+        mp4::avc_initialisation_segment(&mp4::AvcConfig {
+            width,
+            height,
+            sps: parameter_sets.sps(),
+            codec_bytes: parameter_sets.codec_bytes(),
+            sps_bytes: parameter_sets.sps_bytes(),
+            pps_bytes: parameter_sets.pps_bytes(),
+        })
+    }
 
+    /// `mse_fmp4` has no HEVC sample entry, so the initialisation segment is written by our own
+    /// `mp4` module
+    fn make_hevc_initialisation_segment(parameter_sets: &store::HevcParameterSets) -> Vec<u8> {
+        let (width, height) = parameter_sets.dimensions();
+        mp4::hevc_initialisation_segment(&mp4::HevcConfig {
+            width,
+            height,
+            sps: parameter_sets.sps(),
+            vps_bytes: parameter_sets.vps_bytes(),
+            sps_bytes: parameter_sets.sps_bytes(),
+            pps_bytes: parameter_sets.pps_bytes(),
+        })
+    }
 
-        let aac_sample_entry = fmp4::AacSampleEntry {
-            esds_box: fmp4::Mpeg4EsDescriptorBox {
-                profile,
-                frequency,
-                channel_configuration,
+    /// Written by our own `mp4` module, since the `esds` of `mse_fmp4` can't describe HE-AAC
+    fn make_aac_initialisation_segment(config: &store::AdtsConfig) -> Vec<u8> {
+        mp4::aac_initialisation_segment(&mp4::AacConfig {
+            object_type: config.object_type(),
+            frequency_index: config.frequency() as u8,
+            sample_rate: config.sample_rate(),
+            channel_config: config.channel_config() as u8,
+            channels: config.channels().unwrap_or(2) as u16,
+            extension_object_type: match config.extension() {
+                store::AacExtension::None => None,
+                store::AacExtension::Sbr => Some(5),
+                store::AacExtension::SbrPs => Some(29),
             },
-        };
-        track
-            .mdia_box
-            .minf_box
-            .stbl_box
-            .stsd_box
-            .sample_entries
-            .push(fmp4::SampleEntry::Aac(aac_sample_entry));
-        segment.moov_box.trak_boxes.push(track);
-        segment.moov_box.mvhd_box.timescale = 1;
-        segment.moov_box.mvhd_box.duration = 0;
-        segment
-            .moov_box
-            .mvex_box
-            .trex_boxes
-            .push(fmp4::TrackExtendsBox::new(false));
-
-        Ok(segment)
+        })
     }
 
     fn fmp4_segment(req: Request<Body>, track_ref: store::TrackRef, sample_id: String, rest: Option<String>) -> Response<Body> {
@@ -517,17 +1346,20 @@ impl HlsService {
                 .body(Body::from("Invalid segment id"))
                 .unwrap()
         };
+        let mut track_ref = track_ref;
+        let gap = track_ref.track().timeline()
+            .segments()
+            .any(|seg| seg.id() == segment_dts && seg.is_gap() );
+        if gap {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Media for this segment was lost from the input"))
+                .unwrap()
+        }
 
         if let Some(rest) = rest {
             if rest.starts_with("part/") {
-                let rest = &rest["part/".len()..];
-                if !rest.ends_with(".mp4") {
-                    return Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(Body::from("Invalid part request"))
-                        .unwrap()
-                }
-                let part_id = if let Ok(part_id) = rest[..rest.len()-".mp4".len()].parse() {
+                let part_id = if let Some(part_id) = Self::parse_part_id(&rest) {
                     part_id
                 } else {
                     return Response::builder()
@@ -536,69 +1368,93 @@ impl HlsService {
                         .unwrap()
                 };
 
-                let mut track_ref = track_ref;
-                let segment = match track_ref.track() {
-                    store::Track::Avc(ref avc_track) => {
-                        Self::make_avc_part(avc_track, segment_dts, part_id)
-                    },
-                    store::Track::Aac(ref aac_track) => {
-                        Self::make_aac_part(aac_track, segment_dts, part_id)
+                let available = track_ref.track().timeline()
+                    .parts(segment_dts)
+                    .map(|mut p| p.any(|p| p.id() == part_id) );
+                if !available.unwrap_or(false) {
+                    return Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::from("No such part"))
+                        .unwrap()
+                }
+                if rest.ends_with(".ts") {
+                    return Self::ts_segment(track_ref, segment_dts, Some(part_id))
+                }
+                let data = match Self::fmp4_part(&mut track_ref, segment_dts, part_id) {
+                    Some(Ok(data)) => data,
+                    Some(Err(_)) => {
+                        return Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("No such part"))
+                            .unwrap()
                     },
-                };
-                let segment = match segment {
-                    Ok(segment) => segment,
-                    Err(e) => {
-                        eprintln!("Problem creating part {} of segment {} of track {}: {:?}", part_id, segment_dts, track_ref.id().0, e);
+                    None => {
                         return Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::from("Problem creating segment"))
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("WebVTT tracks have no parts"))
                             .unwrap()
-
-                    }
+                    },
                 };
 
-                let mut data = vec![];
-                segment.write_to(&mut data).unwrap();
-                //data.extend_from_slice(segment.data());
-
                 Response::builder()
                     .header("Content-Type", "video/mp4")
                     .header("Access-Control-Allow-Origin", "*")
                     .body(Body::from(data))
                     .unwrap()
             } else if "seg.mp4" == rest {
-                let mut track_ref = track_ref;
+                let mut metadata_range = None;
                 let segment = match track_ref.track() {
                     store::Track::Avc(ref avc_track) => {
-                        Self::make_avc_segment(avc_track, segment_dts)
+                        let frame_duration = Self::frame_duration(avc_track.frame_rate());
+                        metadata_range = Self::sample_range(avc_track.timeline().segment_samples(segment_dts), frame_duration);
+                        Self::make_video_segment(avc_track.timeline(), frame_duration, segment_dts)
                         //Self::make_avc_segment_ffmpeg(avc_track, segment_dts)
                     },
+                    store::Track::Hevc(ref hevc_track) => {
+                        let frame_duration = Self::frame_duration(hevc_track.frame_rate());
+                        metadata_range = Self::sample_range(hevc_track.timeline().segment_samples(segment_dts), frame_duration);
+                        Self::make_video_segment(hevc_track.timeline(), frame_duration, segment_dts)
+                    },
                     store::Track::Aac(ref aac_track) => {
-                        Self::make_aac_segment(aac_track, segment_dts)
+                        let sample_rate = aac_track.configs().at(segment_dts).sample_rate();
+                        Self::make_audio_segment(aac_track.timeline(), sample_rate, aac::SAMPLES_IN_FRAME as u32, segment_dts)
+                    },
+                    store::Track::Ac3(ref ac3_track) => {
+                        let sample_rate = ac3_track.configs().at(segment_dts).sample_rate;
+                        Self::make_audio_segment(ac3_track.timeline(), sample_rate, ac3::SAMPLES_PER_FRAME, segment_dts)
+                    },
+                    store::Track::Subtitle(_) => {
+                        return Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("WebVTT tracks have no fMP4 segments"))
+                            .unwrap()
                     },
                 };
 
                 let segment = match segment {
                     Ok(segment) => segment,
-                    Err(e) => {
-                        eprintln!("Problem creating segment {} of track {}: {:?}", segment_dts, track_ref.id().0, e);
+                    Err(_) => {
                         return Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::from("Problem creating segment"))
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("No such segment"))
                             .unwrap()
-
-                    }
+                    },
                 };
 
-                let mut data = vec![];
-                segment.write_to(&mut data).unwrap();
-                //data.extend_from_slice(segment.data());
+                let mut data = Self::id3_emsg_boxes(&track_ref, metadata_range);
+                data.extend_from_slice(&segment[..]);
 
                 Response::builder()
                     .header("Content-Type", "video/mp4")
                     .header("Access-Control-Allow-Origin", "*")
                     .body(Body::from(data))
                     .unwrap()
+            } else if "seg.ts" == rest {
+                Self::ts_segment(track_ref, segment_dts, None)
+            } else if "seg.vtt" == rest {
+                Self::webvtt_segment(track_ref, segment_dts, false)
+            } else if "seg-ts.vtt" == rest {
+                Self::webvtt_segment(track_ref, segment_dts, true)
             } else {
                 Response::builder()
                     .status(StatusCode::BAD_REQUEST)
@@ -612,6 +1468,251 @@ impl HlsService {
                 .unwrap()
         }
     }
+
+    /// The given part as an fMP4 fragment, preceded by any timed metadata it covers, or `None`
+    /// for tracks which don't have fMP4 parts
+    fn fmp4_part(track_ref: &mut store::TrackRef, segment_dts: i64, part_id: u64) -> Option<Result<Vec<u8>, SegmentError>> {
+        let mut metadata_range = None;
+        let segment = match track_ref.track() {
+            store::Track::Avc(ref avc_track) => {
+                let frame_duration = Self::frame_duration(avc_track.frame_rate());
+                metadata_range = Self::sample_range(avc_track.timeline().part_samples(segment_dts, part_id), frame_duration);
+                Self::make_video_part(avc_track.timeline(), frame_duration, segment_dts, part_id)
+            },
+            store::Track::Hevc(ref hevc_track) => {
+                let frame_duration = Self::frame_duration(hevc_track.frame_rate());
+                metadata_range = Self::sample_range(hevc_track.timeline().part_samples(segment_dts, part_id), frame_duration);
+                Self::make_video_part(hevc_track.timeline(), frame_duration, segment_dts, part_id)
+            },
+            store::Track::Aac(ref aac_track) => {
+                let sample_rate = aac_track.configs().at(segment_dts).sample_rate();
+                Self::make_audio_part(aac_track.timeline(), sample_rate, aac::SAMPLES_IN_FRAME as u32, segment_dts, part_id)
+            },
+            store::Track::Ac3(ref ac3_track) => {
+                let sample_rate = ac3_track.configs().at(segment_dts).sample_rate;
+                Self::make_audio_part(ac3_track.timeline(), sample_rate, ac3::SAMPLES_PER_FRAME, segment_dts, part_id)
+            },
+            store::Track::Subtitle(_) => return None,
+        };
+        Some(segment.map(|segment| {
+            let mut data = Self::id3_emsg_boxes(track_ref, metadata_range);
+            data.extend_from_slice(&segment[..]);
+            data
+        }))
+    }
+
+    /// If the request is for `seg.mp4` of the in-progress segment of an audio or video track,
+    /// the DTS of that segment
+    fn in_progress_segment(track_ref: store::TrackRef, segment_id: &str, rest: Option<&String>) -> Option<i64> {
+        if rest.map(|r| &r[..]) != Some("seg.mp4") {
+            return None;
+        }
+        let segment_dts: i64 = segment_id.parse().ok()?;
+        let mut track_ref = track_ref;
+        let track = track_ref.track();
+        if let store::Track::Subtitle(_) = track {
+            return None;
+        }
+        let segment = track.timeline().segments().find(|s| s.id() == segment_dts )?;
+        if segment.duration_seconds().is_none() && !segment.is_gap() {
+            Some(segment_dts)
+        } else {
+            None
+        }
+    }
+
+    /// Responds immediately, with the in-progress segment delivered by chunked transfer-encoding
+    /// a part at a time as each part is completed, finishing once the segment is complete
+    fn chunked_segment(store: &mut store::Store, id: store::TrackId, segment_dts: i64) -> Response<Body> {
+        let seq_stream = {
+            let mut track_ref = store.get_track(id).unwrap();
+            track_ref.track().timeline().sequence_stream()
+        };
+        let chunks = SegmentChunks {
+            store: store.clone(),
+            track_id: id,
+            segment_dts,
+            seq_stream,
+            next_part: 0,
+            done: false,
+        };
+        Response::builder()
+            .header("Content-Type", "video/mp4")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::wrap_stream(chunks))
+            .unwrap()
+    }
+
+    /// The span of time covered by the given video samples, from the first decode timestamp up
+    /// to the decode timestamp expected to follow the last sample
+    fn sample_range<'a>(samples: Result<impl Iterator<Item=&'a store::Sample>, SegmentError>, frame_duration: u32) -> Option<(i64, i64)> {
+        let mut samples = samples.ok()?;
+        let first = samples.next()?;
+        let last = samples.last().unwrap_or(first);
+        Some((first.dts, last.dts + i64::from(frame_duration)))
+    }
+
+    /// `emsg` boxes for any timed metadata within the given range, to precede the `moof` of the
+    /// video segment or part covering that range
+    fn id3_emsg_boxes(track_ref: &store::TrackRef, range: Option<(i64, i64)>) -> Vec<u8> {
+        let mut data = vec![];
+        if let Some((start, end)) = range {
+            for metadata in track_ref.timed_metadata_between(start, end) {
+                // on the same timeline as the base_media_decode_time of the segment
+                mp4::write_id3_emsg(&mut data, metadata.id, mp4::media_time(metadata.pts, 90000), &metadata.data[..]);
+            }
+        }
+        data
+    }
+
+    /// The given segment, or part of a segment, as MPEG-2 TS.  The codec configuration which
+    /// fMP4 would carry in the initialisation segment is repeated in-band instead.
+    fn ts_segment(track_ref: store::TrackRef, segment_dts: i64, part_id: Option<u64>) -> Response<Body> {
+        let mut track_ref = track_ref;
+        let track = track_ref.track();
+        let timeline = track.timeline();
+        let samples: Result<Vec<&store::Sample>, SegmentError> = match part_id {
+            Some(part_id) => timeline.part_samples(segment_dts, part_id).map(|s| s.collect() ),
+            None => timeline.segment_samples(segment_dts).map(|s| s.collect() ),
+        };
+        let samples = match samples {
+            Ok(samples) => samples,
+            Err(_) => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("No such segment"))
+                    .unwrap()
+            },
+        };
+        let data = match track {
+            store::Track::Avc(ref avc_track) => {
+                let mut muxer = ts::Muxer::new(ts::Stream::avc());
+                for sample in samples {
+                    let mut access_unit = vec![];
+                    ts::write_nal(&mut access_unit, &ts::AVC_ACCESS_UNIT_DELIMITER);
+                    if sample.is_sync() {
+                        let parameter_sets = avc_track.parameter_sets().at(sample.dts);
+                        for nal in parameter_sets.sps_bytes().iter().chain(parameter_sets.pps_bytes()) {
+                            ts::write_nal(&mut access_unit, nal);
+                        }
+                    }
+                    ts::write_annex_b(&mut access_unit, &sample.data[..]);
+                    muxer.write_pes(sample.pts, sample.dts, &access_unit, sample.is_sync());
+                }
+                muxer.finish()
+            },
+            store::Track::Hevc(ref hevc_track) => {
+                let mut muxer = ts::Muxer::new(ts::Stream::hevc());
+                for sample in samples {
+                    let mut access_unit = vec![];
+                    ts::write_nal(&mut access_unit, &ts::HEVC_ACCESS_UNIT_DELIMITER);
+                    if sample.is_sync() {
+                        let parameter_sets = hevc_track.parameter_sets().at(sample.dts);
+                        ts::write_nal(&mut access_unit, parameter_sets.vps_bytes());
+                        ts::write_nal(&mut access_unit, parameter_sets.sps_bytes());
+                        ts::write_nal(&mut access_unit, parameter_sets.pps_bytes());
+                    }
+                    ts::write_annex_b(&mut access_unit, &sample.data[..]);
+                    muxer.write_pes(sample.pts, sample.dts, &access_unit, sample.is_sync());
+                }
+                muxer.finish()
+            },
+            store::Track::Aac(ref aac_track) => {
+                let mut muxer = ts::Muxer::new(ts::Stream::adts());
+                for sample in samples {
+                    let config = aac_track.configs().at(sample.dts);
+                    let mut frame = ts::adts_header(
+                        config.object_type(),
+                        config.frequency() as u8,
+                        config.channel_config() as u8,
+                        sample.data.len(),
+                    ).to_vec();
+                    frame.extend_from_slice(&sample.data[..]);
+                    muxer.write_pes(sample.pts, sample.dts, &frame, true);
+                }
+                muxer.finish()
+            },
+            store::Track::Ac3(ref ac3_track) => {
+                let mut muxer = ts::Muxer::new(ts::Stream::ac3(ac3_track.configs().at(segment_dts).enhanced));
+                for sample in samples {
+                    muxer.write_pes(sample.pts, sample.dts, &sample.data[..], true);
+                }
+                muxer.finish()
+            },
+            store::Track::Subtitle(_) => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("WebVTT tracks have no TS segments"))
+                    .unwrap()
+            },
+        };
+
+        Response::builder()
+            .header("Content-Type", "video/mp2t")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(data))
+            .unwrap()
+    }
+
+    /// The cues of the given segment.  When `for_ts` is true, the segment accompanies `Ts`
+    /// segments, which carry the 33-bit MPEG-2 TS timestamp rather than that of the fMP4 `tfdt`.
+    fn webvtt_segment(track_ref: store::TrackRef, segment_dts: i64, for_ts: bool) -> Response<Body> {
+        let mut track_ref = track_ref;
+        let subtitle_track = match track_ref.track() {
+            store::Track::Subtitle(ref subtitle_track) => subtitle_track,
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("Not a subtitle track"))
+                    .unwrap()
+            }
+        };
+        let timeline = subtitle_track.timeline();
+        // the segment must be complete, so that the following one gives its end time
+        let next_dts = timeline.segments()
+            .map(|seg| seg.id() )
+            .skip_while(|&id| id != segment_dts )
+            .nth(1);
+        let (start_pts, end_dts) = match (timeline.sample(segment_dts), next_dts) {
+            (Some(first), Some(next_dts)) => (first.pts, next_dts),
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::from("No such segment"))
+                    .unwrap()
+            }
+        };
+        let end_pts = start_pts + (end_dts - segment_dts);
+
+        let mut text = String::new();
+        writeln!(text, "WEBVTT").unwrap();
+        // cue times are relative to the start of the segment, which corresponds to the PTS in
+        // the matching TS segment, or the base_media_decode_time of the matching fMP4 segment
+        let mpegts = if for_ts {
+            start_pts as u64 & crate::ts::TIMESTAMP_MASK
+        } else {
+            start_pts as u64
+        };
+        writeln!(text, "X-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000", mpegts).unwrap();
+        for cue in subtitle_track.cues_between(start_pts, end_pts) {
+            let start = cmp::max(cue.start, start_pts) - start_pts;
+            let end = cue.end.map(|end| cmp::min(end, end_pts) ).unwrap_or(end_pts) - start_pts;
+            writeln!(text).unwrap();
+            writeln!(text, "{} --> {}", Self::format_cue_time(start), Self::format_cue_time(end)).unwrap();
+            writeln!(text, "{}", cue.text).unwrap();
+        }
+
+        Response::builder()
+            .header("Content-Type", "text/vtt")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(text))
+            .unwrap()
+    }
+
+    fn format_cue_time(ts: i64) -> String {
+        let millis = ts * 1_000 / Timestamp::TIMEBASE as i64;
+        format!("{:02}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60, millis / 1_000 % 60, millis % 1_000)
+    }
 /*
     fn make_avc_segment_ffmpeg(avc_track: &store::AvcTrack, dts: i64) -> crate::fmp4::Buf {
         let mut builder = crate::fmp4::FragmentBuilder::new();
@@ -621,236 +1722,99 @@ impl HlsService {
         builder.finalize()
     }
 */
-    fn make_avc_segment(avc_track: &store::AvcTrack, dts: i64) -> Result<fmp4::MediaSegment, mse_fmp4::Error> {
-        let (initial_dts, avc_stream) = Self::create_avc_stream(avc_track, dts, 0, std::usize::MAX).unwrap(); // TODO
-
-        let mut segment = fmp4::MediaSegment::default();
-        if let Some(seq) = avc_track.segment_number_for(dts) {
-            segment.moof_box.mfhd_box.sequence_number = seq as u32;
-        }
-
-        // video traf
-        let mut traf = fmp4::TrackFragmentBox::new(true);
-        traf.tfdt_box.base_media_decode_time = initial_dts as u32;
-        traf.tfhd_box.default_sample_flags = Some(fmp4::SampleFlags {
-            is_leading: 0,
-            sample_depends_on: 1,
-            sample_is_depdended_on: 0,
-            sample_has_redundancy: 0,
-            sample_padding_value: 0,
-            sample_is_non_sync_sample: true,
-            sample_degradation_priority: 0,
-        });
-        traf.trun_box.data_offset = Some(0); // dummy
-        traf.trun_box.first_sample_flags = Some(fmp4::SampleFlags {
-            is_leading: 0,
-            sample_depends_on: 2,
-            sample_is_depdended_on: 0,
-            sample_has_redundancy: 0,
-            sample_padding_value: 0,
-            sample_is_non_sync_sample: false,
-            sample_degradation_priority: 0,
-        });
-        traf.trun_box.samples = avc_stream.samples;
-        segment.moof_box.traf_boxes.push(traf);
-
-        // mdat and offsets adjustment
-        let mut counter = mse_fmp4::io::ByteCounter::with_sink();
-        segment.moof_box.write_box(&mut counter)?;
-        segment.moof_box.traf_boxes[0].trun_box.data_offset = Some(counter.count() as i32 + 8);
-
-        segment.mdat_boxes.push(fmp4::MediaDataBox {
-            data: avc_stream.data,
-        });
-
-        Ok(segment)
-    }
-
-    fn make_avc_part(avc_track: &store::AvcTrack, dts: i64, part_id: u64) -> Result<fmp4::MediaSegment, mse_fmp4::Error> {
-        let (initial_dts, avc_stream) = Self::create_avc_stream(avc_track, dts, part_id as usize, store::AvcTrack::VIDEO_SAMPLES_PER_PART).unwrap(); // TODO
-
-        let mut segment = fmp4::MediaSegment::default();
-        if let Some(seq) = avc_track.part_number_for(dts, part_id) {
-            segment.moof_box.mfhd_box.sequence_number = seq as u32;
-        }
-
-        // video traf
-        let mut traf = fmp4::TrackFragmentBox::new(true);
-        traf.tfdt_box.base_media_decode_time = initial_dts as u32;
-        traf.tfhd_box.default_sample_flags = Some(fmp4::SampleFlags {
-            is_leading: 0,
-            sample_depends_on: 1,
-            sample_is_depdended_on: 0,
-            sample_has_redundancy: 0,
-            sample_padding_value: 0,
-            sample_is_non_sync_sample: true,
-            sample_degradation_priority: 0,
-        });
-        traf.trun_box.data_offset = Some(0); // dummy
-        traf.trun_box.first_sample_flags = Some(fmp4::SampleFlags {
-            is_leading: 0,
-            sample_depends_on: 2,
-            sample_is_depdended_on: 0,
-            sample_has_redundancy: 0,
-            sample_padding_value: 0,
-            sample_is_non_sync_sample: false,
-            sample_degradation_priority: 0,
-        });
-        traf.trun_box.samples = avc_stream.samples;
-        segment.moof_box.traf_boxes.push(traf);
-
-        // mdat and offsets adjustment
-        let mut counter = mse_fmp4::io::ByteCounter::with_sink();
-        segment.moof_box.write_box(&mut counter)?;
-        segment.moof_box.traf_boxes[0].trun_box.data_offset = Some(counter.count() as i32 + 8);
-
-        segment.mdat_boxes.push(fmp4::MediaDataBox {
-            data: avc_stream.data,
-        });
-
-        Ok(segment)
-    }
-
-    // reformat the data into the form accepted by the mse_fmp4 crate
-    fn create_avc_stream(avc_track: &store::AvcTrack, dts: i64, offset: usize, limit: usize) -> Result<(u64, AvcStream), store::SegmentError> {
+    fn make_video_segment(timeline: &store::Timeline, frame_duration: u32, dts: i64) -> Result<Vec<u8>, SegmentError> {
+        let (initial_dts, avc_stream) = Self::create_avc_stream(timeline.segment_samples(dts)?, frame_duration);
+        let seq = timeline.segment_number_for(dts).unwrap_or(0);
+        let mut data = vec![];
+        mp4::write_video_fragment(&mut data, seq as u32, mp4::media_time(initial_dts, 90000), &avc_stream.samples, &avc_stream.data);
+        Ok(data)
+    }
+
+    fn make_video_part(timeline: &store::Timeline, frame_duration: u32, dts: i64, part_id: u64) -> Result<Vec<u8>, SegmentError> {
+        let (initial_dts, avc_stream) = Self::create_avc_stream(timeline.part_samples(dts, part_id)?, frame_duration);
+        let seq = timeline.part_number_for(dts, part_id).unwrap_or(0);
+        let mut data = vec![];
+        mp4::write_video_fragment(&mut data, seq as u32, mp4::media_time(initial_dts, 90000), &avc_stream.samples, &avc_stream.data);
+        Ok(data)
+    }
+
+    fn frame_duration(frame_rate: Option<store::FrameRate>) -> u32 {
+        // assume 25fps if nothing better is known (i.e. there's only been a single sample so far)
+        frame_rate.map(|r| r.frame_duration() ).unwrap_or(3600)
+    }
+
+    /// Returns the decode timestamp of the first sample, along with the samples
+    fn create_avc_stream<'a>(samples: impl Iterator<Item=&'a store::Sample>, frame_duration: u32) -> (i64, AvcStream) {
         let mut avc_stream = AvcStream {
             samples: vec![],
             data: vec![]
         };
         let mut avc_timestamps = Vec::new();
-        let mut avc_timestamp_offset = 0;
+        let mut first_dts = None;
 
-        for sample in avc_track.segment_samples(dts)?.skip(offset * store::AvcTrack::VIDEO_SAMPLES_PER_PART).take(limit) {
+        for sample in samples {
             let i = avc_timestamps.len();
-            let mut timestamp = sample.pts;
-            if i == 0 {
-                avc_timestamp_offset = timestamp;
-            }
-            if timestamp < avc_timestamp_offset {
-                // TODO: this code for handling TS wrap is from mse_fmp4; maybe an underlying Timestamp type could handle this directly
-                timestamp += Timestamp::MAX.value() as i64;
-            }
-            avc_timestamps.push((timestamp - avc_timestamp_offset, i));
-
-            let prev_data_len = avc_stream.data.len();
-            avc_stream
-                .data
-                .write_u32::<byteorder::BigEndian>(sample.data.len() as u32)
-                .unwrap();
-            avc_stream.data.write_all(&sample.data[..]).unwrap();
-
-            let sample_size = (avc_stream.data.len() - prev_data_len) as u32;
-            let sample_composition_time_offset = (sample.pts as i64 - sample.dts as i64) as i32;
-            avc_stream.samples.push(fmp4::Sample {
-                duration: None, // dummy
-                size: Some(sample_size),
-                flags: None,
-                composition_time_offset: Some(sample_composition_time_offset),
+            first_dts.get_or_insert(sample.dts);
+            avc_timestamps.push((sample.pts, i));
+
+            // sample data is already a sequence of length-prefixed NAL units
+            avc_stream.data.extend_from_slice(&sample.data[..]);
+            avc_stream.samples.push(mp4::FragmentSample {
+                duration: frame_duration,
+                size: sample.data.len() as u32,
+                is_sync: sample.is_sync(),
+                composition_time_offset: (sample.pts - sample.dts) as i32,
             });
         }
         avc_timestamps.sort();
         for (&(curr, _), &(next, i)) in avc_timestamps.iter().zip(avc_timestamps.iter().skip(1)) {
             let duration = next - curr;
-            avc_stream.samples[i].duration = Some(duration as u32);
+            avc_stream.samples[i].duration = duration as u32;
         }
         if !avc_stream.samples.is_empty() {
-            //avc_stream.samples[0].duration = Some(cmp::max(0, avc_stream.start_time()) as u32);
-            // TODO: calculate durations in some better manner!
-            avc_stream.samples[0].duration = Some(3600);
+            avc_stream.samples[0].duration = frame_duration;
         }
 
-        Ok((avc_timestamp_offset as u64, avc_stream))
+        (first_dts.unwrap_or(0), avc_stream)
     }
 
-    fn make_aac_segment(aac_track: &store::AacTrack, dts: i64) -> Result<fmp4::MediaSegment, mse_fmp4::Error> {
-        let (initial_dts, aac_stream) = Self::create_aac_stream(aac_track, dts, 0, std::usize::MAX).unwrap();
-
-        let mut segment = fmp4::MediaSegment::default();
-        if let Some(seq) = aac_track.segment_number_for(dts) {
-            segment.moof_box.mfhd_box.sequence_number = seq as u32;
-        }
-
-        let mut traf = fmp4::TrackFragmentBox::new(false);
-        traf.tfdt_box.base_media_decode_time = initial_dts as u32;
-        traf.tfhd_box.default_sample_duration = Some(aac::SAMPLES_IN_FRAME as u32);
-        traf.trun_box.data_offset = Some(0); // dummy
-        traf.trun_box.samples = aac_stream.samples;
-        segment.moof_box.traf_boxes.push(traf);
-
-        // mdat and offsets adjustment
-        let mut counter = mse_fmp4::io::ByteCounter::with_sink();
-        segment.moof_box.write_box(&mut counter)?;
-        segment.moof_box.traf_boxes[0].trun_box.data_offset = Some(counter.count() as i32 + 8);
-
-        segment.mdat_boxes.push(fmp4::MediaDataBox {
-            data: aac_stream.data,
-        });
-
-        Ok(segment)
+    fn make_audio_segment(timeline: &store::Timeline, sample_rate: u32, frame_samples: u32, dts: i64) -> Result<Vec<u8>, SegmentError> {
+        let (initial_dts, audio_stream) = Self::create_audio_stream(timeline.segment_samples(dts)?, frame_samples);
+        let seq = timeline.segment_number_for(dts).unwrap_or(0);
+        let mut data = vec![];
+        mp4::write_audio_fragment(&mut data, seq as u32, mp4::media_time(initial_dts, sample_rate), &audio_stream.samples, &audio_stream.data);
+        Ok(data)
     }
 
-    fn make_aac_part(aac_track: &store::AacTrack, dts: i64, part_id: u64) -> Result<fmp4::MediaSegment, mse_fmp4::Error> {
-        let (initial_dts, aac_stream) = Self::create_aac_stream(aac_track, dts, part_id as usize, store::AacTrack::AUDIO_FRAMES_PER_PART).unwrap(); // TODO
-
-        let mut segment = fmp4::MediaSegment::default();
-        if let Some(seq) = aac_track.part_number_for(dts, part_id) {
-            segment.moof_box.mfhd_box.sequence_number = seq as u32;
-        }
-
-        let mut traf = fmp4::TrackFragmentBox::new(false);
-        traf.tfdt_box.base_media_decode_time = initial_dts as u32;
-        traf.tfhd_box.default_sample_duration = Some(aac::SAMPLES_IN_FRAME as u32);
-        traf.trun_box.data_offset = Some(0); // dummy
-        traf.trun_box.samples = aac_stream.samples;
-        segment.moof_box.traf_boxes.push(traf);
-
-        // mdat and offsets adjustment
-        let mut counter = mse_fmp4::io::ByteCounter::with_sink();
-        segment.moof_box.write_box(&mut counter)?;
-        segment.moof_box.traf_boxes[0].trun_box.data_offset = Some(counter.count() as i32 + 8);
-
-        segment.mdat_boxes.push(fmp4::MediaDataBox {
-            data: aac_stream.data,
-        });
-
-        Ok(segment)
+    fn make_audio_part(timeline: &store::Timeline, sample_rate: u32, frame_samples: u32, dts: i64, part_id: u64) -> Result<Vec<u8>, SegmentError> {
+        let (initial_dts, audio_stream) = Self::create_audio_stream(timeline.part_samples(dts, part_id)?, frame_samples);
+        let seq = timeline.part_number_for(dts, part_id).unwrap_or(0);
+        let mut data = vec![];
+        mp4::write_audio_fragment(&mut data, seq as u32, mp4::media_time(initial_dts, sample_rate), &audio_stream.samples, &audio_stream.data);
+        Ok(data)
     }
 
-    fn create_aac_stream(avc_track: &store::AacTrack, dts: i64, offset: usize, limit: usize) -> Result<(u64, AacStream), SegmentError> {
-        let mut aac_stream = AacStream {
+    /// The track timescale is the sampling frequency, so each sample lasts for exactly one frame.
+    /// Returns the decode timestamp of the first sample, along with the samples.
+    fn create_audio_stream<'a>(samples: impl Iterator<Item=&'a store::Sample>, frame_samples: u32) -> (i64, AudioStream) {
+        let mut audio_stream = AudioStream {
             samples: vec![],
             data: vec![]
         };
-        let mut aac_timestamps = Vec::new();
-        let mut aac_timestamp_offset = 0;
-
-        for sample in avc_track.segment_samples(dts).skip(offset * store::AacTrack::AUDIO_FRAMES_PER_PART).take(limit) {
-            let i = aac_timestamps.len();
-            let mut timestamp = sample.pts;
-            if i == 0 {
-                aac_timestamp_offset = timestamp;
-            }
-            if timestamp < aac_timestamp_offset {
-                // TODO: this code for handling TS wrap is from mse_fmp4; maybe an underlying Timestamp type could handle this directly
-                timestamp += Timestamp::MAX.value() as i64;
-            }
-            aac_timestamps.push((timestamp - aac_timestamp_offset, i));
-
-            let prev_data_len = aac_stream.data.len();
-            aac_stream.data.write_all(&sample.data[..]).unwrap();
-
-            let sample_size = (aac_stream.data.len() - prev_data_len) as u32;
-            let sample_composition_time_offset = (sample.pts as i64 - sample.dts as i64) as i32;
-            aac_stream.samples.push(fmp4::Sample {
-                // TODO: calculate durations in some better manner!
-                duration: Some(1920),
-                size: Some(sample_size),
-                flags: None,
-                composition_time_offset: Some(sample_composition_time_offset),
+        let mut first_dts = None;
+
+        for sample in samples {
+            first_dts.get_or_insert(sample.dts);
+            audio_stream.data.extend_from_slice(&sample.data[..]);
+            audio_stream.samples.push(mp4::FragmentSample {
+                duration: frame_samples,
+                size: sample.data.len() as u32,
+                is_sync: true,
+                composition_time_offset: 0,
             });
         }
 
-        Ok((aac_timestamp_offset as u64, aac_stream))
+        (first_dts.unwrap_or(0), audio_stream)
     }
 }
 impl futures::IntoFuture for HlsService {
@@ -863,11 +1827,107 @@ impl futures::IntoFuture for HlsService {
     }
 }
 
-#[derive(Default, Clone, Copy)]
+/// The fMP4 fragments of each part of an in-progress segment, produced as the parts become
+/// available, and ending once the segment is complete
+struct SegmentChunks {
+    store: store::Store,
+    track_id: store::TrackId,
+    segment_dts: i64,
+    seq_stream: watch::Receiver<store::TrackSequence>,
+    /// the first part not yet sent
+    next_part: u64,
+    done: bool,
+}
+impl SegmentChunks {
+    /// Fragments for the parts that have been completed since the last call, and whether the
+    /// segment is now complete
+    fn available_parts(&mut self) -> (Vec<u8>, bool) {
+        let mut track_ref = self.store.get_track(self.track_id).unwrap();
+        let segment_dts = self.segment_dts;
+        let (part_count, complete) = {
+            let timeline = track_ref.track().timeline();
+            match timeline.segments().find(|s| s.id() == segment_dts ) {
+                Some(seg) => {
+                    let part_count = timeline.parts(segment_dts).map(|p| p.count() as u64 ).unwrap_or(0);
+                    (part_count, seg.duration_seconds().is_some())
+                },
+                // already removed from the timeline
+                None => return (vec![], true),
+            }
+        };
+        let mut data = vec![];
+        while self.next_part < part_count {
+            match HlsService::fmp4_part(&mut track_ref, segment_dts, self.next_part) {
+                Some(Ok(part)) => data.extend_from_slice(&part[..]),
+                Some(Err(_)) | None => return (data, true),
+            }
+            self.next_part += 1;
+        }
+        (data, complete)
+    }
+}
+impl Stream for SegmentChunks {
+    type Item = Vec<u8>;
+    type Error = HlsServiceError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while !self.done {
+            let seq = match self.seq_stream.poll() {
+                Ok(Async::Ready(seq)) => seq,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => panic!("Unexpected watch error {:?}", e),
+            };
+            if seq.is_none() {
+                break;
+            }
+            let (data, complete) = self.available_parts();
+            self.done = complete;
+            if !data.is_empty() {
+                return Ok(Async::Ready(Some(data)))
+            }
+        }
+        Ok(Async::Ready(None))
+    }
+}
+
+/// The details of a track which differ between the `AdaptationSet` elements of a DASH manifest
+struct DashRepresentation<'a> {
+    content_type: &'static str,
+    /// value for `urn:mpeg:dash:role:2011`
+    role: &'static str,
+    language: Option<&'a str>,
+    /// `Representation` attributes specific to the media type
+    attributes: String,
+    channels: Option<u32>,
+}
+
+#[derive(Default, Clone)]
 struct HlsRequest {
     msn: Option<u64>,
     part: Option<u16>,
-    push: Option<u16>,
+    skip: HlsSkip,
+    /// Tracks named by `_HLS_report` parameters, or `None` to report on all other tracks
+    report: Option<Vec<store::TrackId>>,
+}
+
+/// Value of the `_HLS_skip` query parameter, requesting a Playlist Delta Update
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HlsSkip {
+    No,
+    Yes,
+    /// Also permits omitting `EXT-X-DATERANGE` tags which ended before the first segment that is
+    /// not skipped, with any cancelled since listed by `RECENTLY-REMOVED-DATERANGES`
+    V2,
+}
+impl Default for HlsSkip {
+    fn default() -> Self {
+        HlsSkip::No
+    }
+}
+impl HlsSkip {
+    fn is_requested(&self) -> bool {
+        *self != HlsSkip::No
+    }
 }
 
 #[derive(Debug)]
@@ -900,44 +1960,340 @@ pub fn create_server(store: store::Store) -> impl Future<Item=(), Error=()> {
 
 #[derive(Debug)]
 struct AvcStream {
-    samples: Vec<fmp4::Sample>,
+    samples: Vec<mp4::FragmentSample>,
     data: Vec<u8>,
 }
-impl AvcStream {
-    fn duration(&self) -> Result<u32, mse_fmp4::Error> {
-        let mut duration: u32 = 0;
-        for sample in &self.samples {
-            let sample_duration = sample.duration.ok_or(mse_fmp4::ErrorKind::InvalidInput)?;
-            duration = duration.checked_add(sample_duration).ok_or(mse_fmp4::ErrorKind::InvalidInput)?;
-        }
-        Ok(duration)
-    }
-    fn start_time(&self) -> i32 {
-        self.samples
-            .first()
-            .and_then(|s| s.composition_time_offset)
-            .unwrap_or(0)
-    }
-}
 
 #[derive(Debug)]
-struct AacStream {
-    samples: Vec<fmp4::Sample>,
+struct AudioStream {
+    samples: Vec<mp4::FragmentSample>,
     data: Vec<u8>,
 }
-impl AacStream {
-    fn duration(&self) -> Result<u32, mse_fmp4::Error> {
-        let mut duration: u32 = 0;
-        for sample in &self.samples {
-            let sample_duration = sample.duration.ok_or(mse_fmp4::ErrorKind::InvalidInput)?;
-            duration = duration.checked_add(sample_duration).ok_or(mse_fmp4::ErrorKind::InvalidInput)?;
-        }
-        Ok(duration)
-    }
-    fn start_time(&self) -> i32 {
-        self.samples
-            .first()
-            .and_then(|s| s.composition_time_offset)
-            .unwrap_or(0)
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// frames last 1920 90kHz ticks, so segments have 90 frames and parts 15
+    fn aac_track(store: &mut store::Store) -> store::TrackId {
+        aac_track_with_info(store, store::AudioInfo::default())
+    }
+
+    fn aac_track_with_info(store: &mut store::Store, info: store::AudioInfo) -> store::TrackId {
+        store.allocate_aac_track(
+            adts_reader::AudioObjectType::AacLC,
+            adts_reader::SamplingFrequency::Freq48000,
+            adts_reader::ChannelConfiguration::Stereo,
+            None,
+            info,
+        )
+    }
+
+    fn add_frames(store: &mut store::Store, track_id: store::TrackId, frames: std::ops::Range<i64>) {
+        for i in frames {
+            store.add_aac_sample(track_id, store::Sample {
+                data: vec![0; 100],
+                pts: i * 1920,
+                dts: i * 1920,
+                header: store::SampleHeader::Aac,
+            });
+        }
+    }
+
+    #[test]
+    fn skippable_segments() {
+        let mut store = store::Store::new();
+        let track_id = aac_track(&mut store);
+        // ten complete segments of 1.92s, and the first frame of the next
+        add_frames(&mut store, track_id, 0..901);
+        let segments: Vec<_> = store.get_track(track_id).unwrap().track().timeline().segments().collect();
+        assert_eq!(11, segments.len());
+        // seven complete segments (13.44s) are needed to reach 12s from the live edge, so the
+        // three before them may be skipped
+        assert_eq!(3, HlsService::skippable_segments(&segments, 12.0));
+        // too few segments to skip any
+        assert_eq!(0, HlsService::skippable_segments(&segments, 20.0));
+    }
+
+    #[test]
+    fn report_uri() {
+        assert_eq!(Some(store::TrackId(3)), HlsService::parse_report_uri("../3/media.m3u8"));
+        assert_eq!(Some(store::TrackId(3)), HlsService::parse_report_uri("/track/3/media.m3u8"));
+        assert_eq!(None, HlsService::parse_report_uri("../3/init.mp4"));
+        assert_eq!(None, HlsService::parse_report_uri("../audio/media.m3u8"));
+    }
+
+    #[test]
+    fn rendition_reports() {
+        let mut store = store::Store::new();
+        let tracks = [aac_track(&mut store), aac_track(&mut store), aac_track(&mut store)];
+        for &track_id in &tracks {
+            add_frames(&mut store, track_id, 0..20);
+        }
+        let reported = |store: &mut store::Store, path: &str| -> Vec<store::TrackId> {
+            let req = HlsService::hls_request_params(&path.parse().unwrap());
+            HlsService::rendition_reports(store, tracks[0], &req)
+                .into_iter()
+                .map(|(track_id, _)| track_id )
+                .collect()
+        };
+        // every other track is reported by default,
+        assert_eq!(vec![tracks[1], tracks[2]], reported(&mut store, "/track/0/media.m3u8"));
+        // or just those named by _HLS_report
+        assert_eq!(vec![tracks[2]], reported(&mut store, "/track/0/media.m3u8?_HLS_report=../2/media.m3u8"));
+    }
+
+    #[test]
+    fn init_map_switching() {
+        let mut store = store::Store::new();
+        let track_id = aac_track(&mut store);
+        // three complete segments, and the first frame of the next
+        add_frames(&mut store, track_id, 0..271);
+        let mut track_ref = store.get_track(track_id).unwrap();
+        let timeline = track_ref.track().timeline();
+        let mut text = String::new();
+        HlsService::write_media_manifest(
+            &mut text,
+            SegmentFormat::Fmp4,
+            // as if the parameter sets changed at the start of the third segment
+            |seg| format!("init/{}.mp4", if seg.id() < 2 * 172800 { 0 } else { 1 }),
+            false,
+            HlsSkip::No,
+            &[],
+            timeline.target_duration(),
+            timeline.part_target_duration(),
+            timeline.discontinuity_sequence(),
+            timeline.segments(),
+            |_| None,
+        );
+        let tags: Vec<_> = text.lines()
+            .filter(|line| line.starts_with("#EXT-X-MAP:") || line.starts_with("segment/") )
+            .collect();
+        assert_eq!(
+            vec![
+                "#EXT-X-MAP:URI=\"init/0.mp4\"",
+                "segment/0/seg.mp4",
+                "segment/172800/seg.mp4",
+                "#EXT-X-MAP:URI=\"init/1.mp4\"",
+                "segment/345600/seg.mp4",
+            ],
+            tags
+        );
+    }
+
+    fn pending(store: &mut store::Store, track_id: store::TrackId, segment_id: &str, rest: &str) -> Option<store::TrackSequence> {
+        HlsService::pending_part(store.get_track(track_id).unwrap(), segment_id, Some(&rest.to_string()))
+    }
+
+    #[test]
+    fn pending_part() {
+        let mut store = store::Store::new();
+        let track_id = aac_track(&mut store);
+        add_frames(&mut store, track_id, 0..20);
+        // the part after the one available in the in-progress segment
+        let seq = pending(&mut store, track_id, "0", "part/1.mp4").unwrap();
+        assert_eq!((0, 1), (seq.seg, seq.part));
+        // already available
+        assert!(pending(&mut store, track_id, "0", "part/0.mp4").is_none());
+        // not the next part
+        assert!(pending(&mut store, track_id, "0", "part/2.mp4").is_none());
+        // no such segment
+        assert!(pending(&mut store, track_id, "1920", "part/1.mp4").is_none());
+        // once the segment is complete, none of its parts are pending
+        add_frames(&mut store, track_id, 20..91);
+        assert!(pending(&mut store, track_id, "0", "part/6.mp4").is_none());
+    }
+
+    #[test]
+    fn block_for_part() {
+        let mut store = store::Store::new();
+        let track_id = aac_track(&mut store);
+        add_frames(&mut store, track_id, 0..20);
+        let seq = pending(&mut store, track_id, "0", "part/1.mp4").unwrap();
+        let response = HlsService::block_for_part(
+            Request::new(Body::empty()),
+            &mut store,
+            track_id,
+            seq,
+            "0".to_string(),
+            Some("part/1.mp4".to_string()),
+        );
+        add_frames(&mut store, track_id, 20..35);
+        let response = response.wait().unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+    }
+
+    fn body_text(response: Response<Body>) -> String {
+        let body = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn audio_renditions() {
+        let mut store = store::Store::new();
+        let info = |language: &str, audio_type| store::AudioInfo {
+            language: Some(language.to_string()),
+            audio_type,
+        };
+        aac_track_with_info(&mut store, info("eng", store::AudioType::VisualImpairedCommentary));
+        aac_track_with_info(&mut store, info("eng", store::AudioType::Main));
+        aac_track_with_info(&mut store, info("eng", store::AudioType::Main));
+        aac_track_with_info(&mut store, info("und", store::AudioType::Main));
+        let mut service = HlsService { store };
+        let text = body_text(service.master_manifest(Request::new(Body::empty())).wait().unwrap());
+        let media: Vec<_> = text.lines().filter(|l| l.starts_with("#EXT-X-MEDIA:TYPE=AUDIO") ).collect();
+        assert_eq!(4, media.len());
+        assert!(media[0].contains(",NAME=\"eng (audio description)\",LANGUAGE=\"eng\",DEFAULT=NO,"));
+        assert!(media[0].contains(",CHARACTERISTICS=\"public.accessibility.describes-video\""));
+        // the first main audio track is the default, and names stay unique within the group
+        assert!(media[1].contains(",NAME=\"eng\",LANGUAGE=\"eng\",DEFAULT=YES,"));
+        assert!(media[2].contains(",NAME=\"eng 2\",LANGUAGE=\"eng\",DEFAULT=NO,"));
+        // an undetermined language is not signalled
+        assert!(!media[3].contains("LANGUAGE="));
+    }
+
+    #[test]
+    fn id3_emsg() {
+        use byteorder::{BigEndian, ByteOrder};
+
+        const TAG: &[u8] = b"ID3\x04\x00\x00\x00\x00\x00\x00";
+        let mut store = store::Store::new();
+        let track_id = aac_track(&mut store);
+        store.add_timed_metadata(90000, TAG.to_vec());
+        store.add_timed_metadata(270000, TAG.to_vec());
+        let track_ref = store.get_track(track_id).unwrap();
+        // only the first tag falls within the range
+        let data = HlsService::id3_emsg_boxes(&track_ref, Some((0, 180000)));
+        assert_eq!(data.len(), BigEndian::read_u32(&data[0..4]) as usize);
+        assert_eq!(b"emsg", &data[4..8]);
+        assert_eq!(1, data[8]);  // version
+        assert_eq!(90000, BigEndian::read_u32(&data[12..16]));  // timescale
+        assert_eq!(90000, BigEndian::read_u64(&data[16..24]));  // presentation_time
+        assert!(data.ends_with(TAG));
+        assert!(HlsService::id3_emsg_boxes(&track_ref, None).is_empty());
+    }
+
+    #[test]
+    fn dash_adaptation_set() {
+        let mut store = store::Store::new();
+        let track_id = aac_track(&mut store);
+        // three complete segments, and the first frame of the next
+        add_frames(&mut store, track_id, 0..271);
+        let mut track_ref = store.get_track(track_id).unwrap();
+        let representation = DashRepresentation {
+            content_type: "audio",
+            role: "main",
+            language: Some("und"),
+            attributes: HlsService::dash_audio_attributes("mp4a.40.2".to_string(), 128_000, 48000),
+            channels: Some(2),
+        };
+        let mut text = String::new();
+        let segments: Vec<_> = track_ref.track().timeline().segments().collect();
+        HlsService::write_adaptation_set(&mut text, track_id, &representation, track_ref.track(), 0, &segments[..]);
+        // an undetermined language is not signalled
+        assert!(!text.contains(" lang="));
+        assert!(text.contains("<Representation id=\"0\" codecs=\"mp4a.40.2\" bandwidth=\"128000\" audioSamplingRate=\"48000\">"));
+        assert!(text.contains("value=\"2\"/>"));
+        assert!(text.contains("<SegmentTemplate timescale=\"48000\" presentationTimeOffset=\"0\" initialization=\"track/0/init/0.mp4\" "));
+        let timeline: Vec<_> = text.lines()
+            .map(|line| line.trim() )
+            .filter(|line| line.starts_with("<S ") )
+            .collect();
+        // in units of the sampling frequency, with the in-progress segment expected to last for
+        // the target duration
+        assert_eq!(vec!["<S t=\"0\" d=\"92160\"/>", "<S d=\"92160\"/>", "<S d=\"92160\"/>", "<S d=\"92160\"/>"], timeline);
+    }
+
+    #[test]
+    fn dash_period_starts() {
+        let mut store = store::Store::new();
+        let tracks = [aac_track(&mut store), aac_track(&mut store)];
+        for &track_id in &tracks {
+            add_frames(&mut store, track_id, 0..100);
+        }
+        // the input restarts from zero, which the second track only sees a frame later
+        store.timestamp_discontinuity(tracks[0]);
+        add_frames(&mut store, tracks[0], 0..10);
+        store.timestamp_discontinuity(tracks[1]);
+        add_frames(&mut store, tracks[1], 1..10);
+        let mut service = HlsService { store };
+        let (starts, tolerance) = service.dash_period_starts();
+        assert_eq!(86400, tolerance);
+        // the discontinuity of the second track falls within the same period as the first
+        assert_eq!(vec![0, 99 * 1920 + 90000], starts);
+        assert_eq!(1, HlsService::dash_period_index(&starts, tolerance, 99 * 1920 + 90000 + 1920));
+        assert_eq!(0, HlsService::dash_period_index(&starts, tolerance, 172800));
+    }
+
+    /// 1280x720 Main profile, as used by the tests in `mpegts::h264`
+    const SPS: &[u8] = &[0x67, 0x4d, 0x40, 0x1e, 0xec, 0x80, 0x28, 0x02, 0xdc, 0x80];
+    const PPS: &[u8] = &[0x68, 0xce, 0x38, 0x80];
+    const IDR_SLICE: &[u8] = &[0x65, 0x88, 0x84, 0x03];
+    const P_SLICE: &[u8] = &[0x41, 0x9a, 0x21, 0x0c];
+
+    fn avc_track(store: &mut store::Store) -> (store::TrackId, h264_reader::Context<()>) {
+        let mut ctx = h264_reader::Context::new(());
+        let sps = h264_reader::nal::sps::SeqParameterSet::from_bytes(&SPS[1..]).unwrap();
+        ctx.put_seq_param_set(sps.clone());
+        let pps = h264_reader::nal::pps::PicParameterSet::from_bytes(&ctx, &PPS[1..]).unwrap();
+        ctx.put_pic_param_set(pps);
+        let track_id = store.allocate_avc_track(sps, vec![SPS.to_vec()], vec![PPS.to_vec()], None);
+        (track_id, ctx)
+    }
+
+    /// Adds frames at 25fps, with an IDR frame every 50 frames
+    fn add_video_frames(store: &mut store::Store, track_id: store::TrackId, ctx: &mut h264_reader::Context<()>, frames: std::ops::Range<i64>) {
+        for i in frames {
+            let slice = if i % 50 == 0 { IDR_SLICE } else { P_SLICE };
+            let nal_header = h264_reader::nal::NalHeader::new(slice[0]).unwrap();
+            let mut r = h264_reader::rbsp::RbspBitReader::new(&slice[1..]);
+            let (slice_header, _, _) = h264_reader::nal::slice::SliceHeader::read(ctx, &mut r, nal_header).unwrap();
+            let mut data = vec![0, 0, 0, slice.len() as u8];
+            data.extend_from_slice(slice);
+            store.add_avc_sample(track_id, store::Sample {
+                data,
+                pts: i * 3600,
+                dts: i * 3600,
+                header: store::SampleHeader::Avc(nal_header, slice_header),
+            });
+        }
+    }
+
+    #[test]
+    fn segment_chunks() {
+        let mut store = store::Store::new();
+        let (track_id, mut ctx) = avc_track(&mut store);
+        add_video_frames(&mut store, track_id, &mut ctx, 0..20);
+        let seq_stream = store.get_track(track_id).unwrap().track().timeline().sequence_stream();
+        let mut chunks = SegmentChunks {
+            store: store.clone(),
+            track_id,
+            segment_dts: 0,
+            seq_stream,
+            next_part: 0,
+            done: false,
+        };
+        // parts are 8 frames long, so two are complete
+        let (data, complete) = chunks.available_parts();
+        assert_eq!(b"moof", &data[4..8]);
+        assert!(!complete);
+        assert_eq!(2, chunks.next_part);
+        let (data, complete) = chunks.available_parts();
+        assert!(data.is_empty());
+        assert!(!complete);
+
+        // past the target segment duration, the segment carries on until the next IDR frame
+        add_video_frames(&mut store, track_id, &mut ctx, 20..50);
+        let (data, complete) = chunks.available_parts();
+        assert!(!data.is_empty());
+        assert!(!complete);
+        assert_eq!(6, chunks.next_part);
+
+        // which completes this segment, along with its final part
+        add_video_frames(&mut store, track_id, &mut ctx, 50..51);
+        let (data, complete) = chunks.available_parts();
+        assert!(!data.is_empty());
+        assert!(complete);
+        assert_eq!(7, chunks.next_part);
     }
 }
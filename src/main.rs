@@ -2,6 +2,13 @@ mod mpegts;
 mod net;
 mod store;
 mod http;
+mod hevc;
+mod ac3;
+mod mp4;
+mod ts;
+mod scte35;
+mod captions;
+mod teletext;
 //mod fmp4;
 
 fn main() {
@@ -0,0 +1,681 @@
+//! Minimal ISO BMFF box writing, for initialisation segments with sample entries that the
+//! `mse_fmp4` crate has no support for (including `avcC` records holding more than one SPS or
+//! PPS), and for media segments, since `mse_fmp4` can only write a 32-bit `tfdt`.
+
+use crate::ac3;
+use crate::hevc;
+use h264_reader::nal::sps;
+
+const VIDEO_TRACK_ID: u32 = 1;
+const AUDIO_TRACK_ID: u32 = 2;
+
+/// `sample_flags` for a sample which doesn't depend on others
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+/// `sample_flags` for a sample which depends on others, with `sample_is_non_sync_sample` set
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+/// `scheme_id_uri` for ID3 tags, per AOM 'Carriage of ID3 Timed Metadata in the Common Media
+/// Application Format'
+const ID3_SCHEME_ID_URI: &str = "https://aomedia.org/emsg/ID3";
+
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+fn write_box<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, box_type: &[u8; 4], f: F) {
+    let start = out.len();
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(box_type);
+    f(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, box_type: &[u8; 4], version: u8, flags: u32, f: F) {
+    write_box(out, box_type, |out| {
+        out.extend_from_slice(&(u32::from(version) << 24 | flags).to_be_bytes());
+        f(out);
+    })
+}
+
+fn u16(out: &mut Vec<u8>, val: u16) {
+    out.extend_from_slice(&val.to_be_bytes());
+}
+
+fn u32(out: &mut Vec<u8>, val: u32) {
+    out.extend_from_slice(&val.to_be_bytes());
+}
+
+fn u64(out: &mut Vec<u8>, val: u64) {
+    out.extend_from_slice(&val.to_be_bytes());
+}
+
+fn cstring(out: &mut Vec<u8>, val: &str) {
+    out.extend_from_slice(val.as_bytes());
+    out.push(0);
+}
+
+/// Writes a version 1 `emsg` box carrying the given ID3 tag, which applies from the given
+/// presentation time in 90kHz units.  The box must precede the `moof` of a media segment.
+pub fn write_id3_emsg(out: &mut Vec<u8>, id: u32, presentation_time: u64, id3: &[u8]) {
+    write_full_box(out, b"emsg", 1, 0, |out| {
+        u32(out, 90000);  // timescale
+        u64(out, presentation_time);
+        u32(out, 0xffff_ffff);  // event_duration (unknown)
+        u32(out, id);
+        cstring(out, ID3_SCHEME_ID_URI);
+        cstring(out, "");  // value
+        out.extend_from_slice(id3);
+    });
+}
+
+/// A sample of a media fragment, whose data is carried in the `mdat`
+#[derive(Debug, Clone, Copy)]
+pub struct FragmentSample {
+    /// in units of the track timescale
+    pub duration: u32,
+    pub size: u32,
+    /// ignored for audio, where every sample is a sync sample
+    pub is_sync: bool,
+    /// ignored for audio
+    pub composition_time_offset: i32,
+}
+
+/// Converts a 90kHz timestamp to the given track timescale, rounding to the nearest unit, since
+/// the interpolated timestamps of audio frames were truncated.  Every track keeps the full
+/// timestamp, so that decode times of tracks in different timescales stay in step.
+pub fn media_time(ts: i64, timescale: u32) -> u64 {
+    ((i128::from(ts) * i128::from(timescale) + 45000) / 90000) as u64
+}
+
+/// Writes the `moof` and `mdat` of a fragment of the video track, with the given decode time of
+/// the first sample in 90kHz units
+pub fn write_video_fragment(out: &mut Vec<u8>, sequence_number: u32, base_media_decode_time: u64, samples: &[FragmentSample], data: &[u8]) {
+    write_fragment(out, VIDEO_TRACK_ID, sequence_number, base_media_decode_time, samples, data)
+}
+
+/// Writes the `moof` and `mdat` of a fragment of the audio track, with the given decode time of
+/// the first sample in units of the sampling frequency
+pub fn write_audio_fragment(out: &mut Vec<u8>, sequence_number: u32, base_media_decode_time: u64, samples: &[FragmentSample], data: &[u8]) {
+    write_fragment(out, AUDIO_TRACK_ID, sequence_number, base_media_decode_time, samples, data)
+}
+
+fn write_fragment(out: &mut Vec<u8>, track_id: u32, sequence_number: u32, base_media_decode_time: u64, samples: &[FragmentSample], data: &[u8]) {
+    let video = track_id == VIDEO_TRACK_ID;
+    let start = out.len();
+    let mut data_offset_pos = 0;
+    write_box(out, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| u32(out, sequence_number) );
+        write_box(out, b"traf", |out| {
+            // default-base-is-moof
+            write_full_box(out, b"tfhd", 0, 0x02_0000, |out| u32(out, track_id) );
+            // version 1, for a 64-bit baseMediaDecodeTime
+            write_full_box(out, b"tfdt", 1, 0, |out| u64(out, base_media_decode_time) );
+            // data-offset, sample-duration and sample-size present, and for video also
+            // sample-flags and sample-composition-time-offset (signed, in version 1)
+            let flags = if video { 0x000f01 } else { 0x000301 };
+            write_full_box(out, b"trun", 1, flags, |out| {
+                u32(out, samples.len() as u32);
+                data_offset_pos = out.len();
+                u32(out, 0);  // data_offset, filled in once the size of the moof is known
+                for sample in samples {
+                    u32(out, sample.duration);
+                    u32(out, sample.size);
+                    if video {
+                        u32(out, if sample.is_sync { SYNC_SAMPLE_FLAGS } else { NON_SYNC_SAMPLE_FLAGS });
+                        out.extend_from_slice(&sample.composition_time_offset.to_be_bytes());
+                    }
+                }
+            });
+        });
+    });
+    // relative to the start of the moof, with the sample data following the mdat header
+    let data_offset = (out.len() - start + 8) as u32;
+    out[data_offset_pos..data_offset_pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+    write_box(out, b"mdat", |out| out.extend_from_slice(data) );
+}
+
+/// The parameter sets and metadata needed to describe an HEVC track
+pub struct HevcConfig<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub sps: &'a hevc::SeqParameterSet,
+    pub vps_bytes: &'a [u8],
+    pub sps_bytes: &'a [u8],
+    pub pps_bytes: &'a [u8],
+}
+
+pub fn hevc_initialisation_segment(config: &HevcConfig<'_>) -> Vec<u8> {
+    let media = Media::Video { width: config.width, height: config.height };
+    initialisation_segment(b"hvc1", media, 90000, |out| write_hvc1_sample_entry(out, config) )
+}
+
+/// The parameter sets and metadata needed to describe an AVC track
+pub struct AvcConfig<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub sps: &'a sps::SeqParameterSet,
+    /// `profile_idc`, constraint flags and `level_idc`
+    pub codec_bytes: [u8; 3],
+    pub sps_bytes: &'a [Vec<u8>],
+    pub pps_bytes: &'a [Vec<u8>],
+}
+
+pub fn avc_initialisation_segment(config: &AvcConfig<'_>) -> Vec<u8> {
+    let media = Media::Video { width: config.width, height: config.height };
+    initialisation_segment(b"avc1", media, 90000, |out| write_avc1_sample_entry(out, config) )
+}
+
+/// The configuration needed to describe an AAC track, including any SBR / PS extension
+pub struct AacConfig {
+    /// `audioObjectType` of the AAC core
+    pub object_type: u8,
+    /// `samplingFrequencyIndex` of the AAC core
+    pub frequency_index: u8,
+    pub sample_rate: u32,
+    pub channel_config: u8,
+    pub channels: u16,
+    /// 5 for SBR or 29 for SBR + PS, which are signalled explicitly
+    pub extension_object_type: Option<u8>,
+}
+
+/// The media segments of the track must use the core sampling frequency as their timescale
+pub fn aac_initialisation_segment(config: &AacConfig) -> Vec<u8> {
+    initialisation_segment(b"mp41", Media::Audio, config.sample_rate, |out| write_mp4a_sample_entry(out, config) )
+}
+
+/// The media segments of the track must use the sampling frequency as their timescale
+pub fn ac3_initialisation_segment(info: &ac3::StreamInfo) -> Vec<u8> {
+    initialisation_segment(b"mp41", Media::Audio, info.sample_rate, |out| write_ac3_sample_entry(out, info) )
+}
+
+#[derive(Clone, Copy)]
+enum Media {
+    Video { width: u32, height: u32 },
+    Audio,
+}
+impl Media {
+    fn track_id(&self) -> u32 {
+        match self {
+            Media::Video { .. } => VIDEO_TRACK_ID,
+            Media::Audio => AUDIO_TRACK_ID,
+        }
+    }
+}
+
+/// Writes `ftyp` and `moov` for a single track, with the given function writing the sample
+/// entry
+fn initialisation_segment<F>(brand: &[u8; 4], media: Media, timescale: u32, sample_entry: F) -> Vec<u8>
+    where
+        F: FnOnce(&mut Vec<u8>)
+{
+    let track_id = media.track_id();
+    let mut out = vec![];
+    write_box(&mut out, b"ftyp", |out| {
+        out.extend_from_slice(b"iso6");
+        u32(out, 0);
+        out.extend_from_slice(b"iso6");
+        out.extend_from_slice(b"mp41");
+        out.extend_from_slice(brand);
+    });
+    write_box(&mut out, b"moov", |out| {
+        write_full_box(out, b"mvhd", 0, 0, |out| {
+            u32(out, 0);  // creation_time
+            u32(out, 0);  // modification_time
+            u32(out, 1);  // timescale
+            u32(out, 0);  // duration
+            u32(out, 0x0001_0000);  // rate
+            u16(out, 0x0100);  // volume
+            out.extend_from_slice(&[0; 10]);
+            for &m in UNITY_MATRIX.iter() {
+                u32(out, m);
+            }
+            out.extend_from_slice(&[0; 24]);
+            u32(out, track_id + 1);  // next_track_ID
+        });
+        write_box(out, b"trak", |out| {
+            // track_enabled | track_in_movie
+            write_full_box(out, b"tkhd", 0, 3, |out| {
+                u32(out, 0);  // creation_time
+                u32(out, 0);  // modification_time
+                u32(out, track_id);
+                u32(out, 0);
+                u32(out, 0);  // duration
+                out.extend_from_slice(&[0; 8]);
+                u16(out, 0);  // layer
+                u16(out, 0);  // alternate_group
+                match media {
+                    Media::Video { .. } => u16(out, 0),
+                    Media::Audio => u16(out, 0x0100),
+                }  // volume
+                u16(out, 0);
+                for &m in UNITY_MATRIX.iter() {
+                    u32(out, m);
+                }
+                let (width, height) = match media {
+                    Media::Video { width, height } => (width, height),
+                    Media::Audio => (0, 0),
+                };
+                u32(out, width << 16);
+                u32(out, height << 16);
+            });
+            write_box(out, b"mdia", |out| {
+                write_full_box(out, b"mdhd", 0, 0, |out| {
+                    u32(out, 0);  // creation_time
+                    u32(out, 0);  // modification_time
+                    u32(out, timescale);
+                    u32(out, 0);  // duration
+                    u16(out, 0x55c4);  // language: 'und'
+                    u16(out, 0);
+                });
+                write_full_box(out, b"hdlr", 0, 0, |out| {
+                    u32(out, 0);
+                    match media {
+                        Media::Video { .. } => out.extend_from_slice(b"vide"),
+                        Media::Audio => out.extend_from_slice(b"soun"),
+                    }
+                    out.extend_from_slice(&[0; 12]);
+                    match media {
+                        Media::Video { .. } => out.extend_from_slice(b"VideoHandler\0"),
+                        Media::Audio => out.extend_from_slice(b"SoundHandler\0"),
+                    }
+                });
+                write_box(out, b"minf", |out| {
+                    match media {
+                        Media::Video { .. } => write_full_box(out, b"vmhd", 0, 1, |out| {
+                            out.extend_from_slice(&[0; 8]);  // graphicsmode + opcolor
+                        }),
+                        Media::Audio => write_full_box(out, b"smhd", 0, 0, |out| {
+                            u32(out, 0);  // balance + reserved
+                        }),
+                    }
+                    write_box(out, b"dinf", |out| {
+                        write_full_box(out, b"dref", 0, 0, |out| {
+                            u32(out, 1);
+                            // self-contained
+                            write_full_box(out, b"url ", 0, 1, |_| {});
+                        });
+                    });
+                    write_box(out, b"stbl", |out| {
+                        write_full_box(out, b"stsd", 0, 0, |out| {
+                            u32(out, 1);
+                            sample_entry(out);
+                        });
+                        write_full_box(out, b"stts", 0, 0, |out| u32(out, 0) );
+                        write_full_box(out, b"stsc", 0, 0, |out| u32(out, 0) );
+                        write_full_box(out, b"stsz", 0, 0, |out| {
+                            u32(out, 0);  // sample_size
+                            u32(out, 0);  // sample_count
+                        });
+                        write_full_box(out, b"stco", 0, 0, |out| u32(out, 0) );
+                    });
+                });
+            });
+        });
+        write_box(out, b"mvex", |out| {
+            write_full_box(out, b"trex", 0, 0, |out| {
+                u32(out, track_id);
+                u32(out, 1);  // default_sample_description_index
+                u32(out, 0);  // default_sample_duration
+                u32(out, 0);  // default_sample_size
+                u32(out, 0);  // default_sample_flags
+            });
+        });
+    });
+    out
+}
+
+/// Writes a `VisualSampleEntry` of the given type, with the given function writing the decoder
+/// configuration box
+fn write_visual_sample_entry<F>(out: &mut Vec<u8>, box_type: &[u8; 4], width: u32, height: u32, f: F)
+    where
+        F: FnOnce(&mut Vec<u8>)
+{
+    write_box(out, box_type, |out| {
+        out.extend_from_slice(&[0; 6]);
+        u16(out, 1);  // data_reference_index
+        out.extend_from_slice(&[0; 16]);
+        u16(out, width as u16);
+        u16(out, height as u16);
+        u32(out, 0x0048_0000);  // horizresolution, 72dpi
+        u32(out, 0x0048_0000);  // vertresolution, 72dpi
+        u32(out, 0);
+        u16(out, 1);  // frame_count
+        out.extend_from_slice(&[0; 32]);  // compressorname
+        u16(out, 0x0018);  // depth
+        u16(out, 0xffff);  // pre_defined = -1
+        f(out);
+    })
+}
+
+fn write_hvc1_sample_entry(out: &mut Vec<u8>, config: &HevcConfig<'_>) {
+    write_visual_sample_entry(out, b"hvc1", config.width, config.height, |out| {
+        write_box(out, b"hvcC", |out| write_hvcc(out, config) );
+    })
+}
+
+fn write_avc1_sample_entry(out: &mut Vec<u8>, config: &AvcConfig<'_>) {
+    write_visual_sample_entry(out, b"avc1", config.width, config.height, |out| {
+        write_box(out, b"avcC", |out| write_avcc(out, config) );
+    })
+}
+
+/// Writes an `AudioSampleEntry` of the given type, with the given function writing the decoder
+/// configuration box.  Rates that don't fit in 16.16 are left to the decoder configuration.
+fn write_audio_sample_entry<F>(out: &mut Vec<u8>, box_type: &[u8; 4], channels: u16, sample_rate: u32, f: F)
+    where
+        F: FnOnce(&mut Vec<u8>)
+{
+    write_box(out, box_type, |out| {
+        out.extend_from_slice(&[0; 6]);
+        u16(out, 1);  // data_reference_index
+        out.extend_from_slice(&[0; 8]);
+        u16(out, channels);
+        u16(out, 16);  // samplesize
+        u32(out, 0);
+        u32(out, if sample_rate > 0xffff { 0 } else { sample_rate << 16 });
+        f(out);
+    })
+}
+
+fn write_mp4a_sample_entry(out: &mut Vec<u8>, config: &AacConfig) {
+    // the output rate, which SBR doubles
+    let sample_rate = match config.extension_object_type {
+        Some(_) => config.sample_rate * 2,
+        None => config.sample_rate,
+    };
+    write_audio_sample_entry(out, b"mp4a", config.channels, sample_rate, |out| {
+        write_full_box(out, b"esds", 0, 0, |out| write_es_descriptor(out, config) );
+    })
+}
+
+fn write_ac3_sample_entry(out: &mut Vec<u8>, info: &ac3::StreamInfo) {
+    let channels = info.channels() as u16;
+    if info.enhanced {
+        write_audio_sample_entry(out, b"ec-3", channels, info.sample_rate, |out| {
+            write_box(out, b"dec3", |out| write_dec3(out, info) );
+        })
+    } else {
+        write_audio_sample_entry(out, b"ac-3", channels, info.sample_rate, |out| {
+            write_box(out, b"dac3", |out| write_dac3(out, info) );
+        })
+    }
+}
+
+/// AC3SpecificBox contents, per ETSI TS 102 366 annex F
+fn write_dac3(out: &mut Vec<u8>, info: &ac3::StreamInfo) {
+    let sub = &info.substreams[0];
+    let mut bits = BitWriter::default();
+    bits.put(u32::from(sub.fscod), 2);
+    bits.put(u32::from(sub.bsid), 5);
+    bits.put(u32::from(sub.bsmod), 3);
+    bits.put(u32::from(sub.acmod), 3);
+    bits.put(u32::from(sub.lfeon), 1);
+    bits.put(u32::from(info.bit_rate_code), 5);
+    bits.put(0, 5);  // reserved
+    out.extend_from_slice(&bits.finish());
+}
+
+/// EC3SpecificBox contents, per ETSI TS 102 366 annex F
+fn write_dec3(out: &mut Vec<u8>, info: &ac3::StreamInfo) {
+    let mut bits = BitWriter::default();
+    bits.put(u32::from(info.data_rate), 13);
+    bits.put(info.substreams.len() as u32 - 1, 3);  // num_ind_sub
+    for sub in &info.substreams {
+        bits.put(u32::from(sub.fscod), 2);
+        bits.put(u32::from(sub.bsid), 5);
+        bits.put(0, 1);  // reserved
+        bits.put(0, 1);  // asvc
+        bits.put(u32::from(sub.bsmod), 3);
+        bits.put(u32::from(sub.acmod), 3);
+        bits.put(u32::from(sub.lfeon), 1);
+        bits.put(0, 3);  // reserved
+        bits.put(u32::from(sub.num_dep_sub), 4);
+        if sub.num_dep_sub > 0 {
+            bits.put(u32::from(sub.chan_loc), 9);
+        } else {
+            bits.put(0, 1);  // reserved
+        }
+    }
+    out.extend_from_slice(&bits.finish());
+}
+
+/// Writes an MPEG-4 descriptor, which must be under 128 bytes long
+fn write_descriptor<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, tag: u8, f: F) {
+    out.push(tag);
+    let start = out.len();
+    out.push(0);
+    f(out);
+    let size = out.len() - start - 1;
+    assert!(size < 0x80, "descriptor too long: {}", size);
+    out[start] = size as u8;
+}
+
+/// ES_Descriptor, per ISO/IEC 14496-1
+fn write_es_descriptor(out: &mut Vec<u8>, config: &AacConfig) {
+    write_descriptor(out, 0x03, |out| {
+        u16(out, 0);  // ES_ID
+        out.push(0);  // no dependsOn / URL / OCR
+        // DecoderConfigDescriptor
+        write_descriptor(out, 0x04, |out| {
+            out.push(0x40);  // objectTypeIndication: ISO/IEC 14496-3 audio
+            out.push(0x05 << 2 | 1);  // streamType: audio
+            out.extend_from_slice(&[0; 3]);  // bufferSizeDB
+            u32(out, 0);  // maxBitrate
+            u32(out, 0);  // avgBitrate
+            // DecoderSpecificInfo
+            write_descriptor(out, 0x05, |out| write_audio_specific_config(out, config) );
+        });
+        // SLConfigDescriptor
+        write_descriptor(out, 0x06, |out| out.push(0x02) );
+    })
+}
+
+/// AudioSpecificConfig, per ISO/IEC 14496-3.  Any SBR / PS extension is signalled explicitly
+/// and hierarchically, which is what most players expect of HE-AAC in MP4.
+fn write_audio_specific_config(out: &mut Vec<u8>, config: &AacConfig) {
+    let mut bits = BitWriter::default();
+    match config.extension_object_type {
+        Some(extension_object_type) => {
+            bits.put(u32::from(extension_object_type), 5);
+            bits.put(u32::from(config.frequency_index), 4);
+            bits.put(u32::from(config.channel_config), 4);
+            // extensionSamplingFrequencyIndex: SBR doubles the core rate, and a lower index
+            // indicates a higher rate
+            bits.put(u32::from(config.frequency_index.saturating_sub(3)), 4);
+            bits.put(u32::from(config.object_type), 5);
+        },
+        None => {
+            bits.put(u32::from(config.object_type), 5);
+            bits.put(u32::from(config.frequency_index), 4);
+            bits.put(u32::from(config.channel_config), 4);
+        },
+    }
+    // GASpecificConfig: frameLengthFlag, dependsOnCoreCoder, extensionFlag
+    bits.put(0, 3);
+    out.extend_from_slice(&bits.finish());
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u32,
+    len: u32,
+}
+impl BitWriter {
+    fn put(&mut self, val: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            self.acc = self.acc << 1 | (val >> i & 1);
+            self.len += 1;
+            if self.len == 8 {
+                self.bytes.push(self.acc as u8);
+                self.acc = 0;
+                self.len = 0;
+            }
+        }
+    }
+
+    /// Pads with zero bits to a whole number of bytes
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.bytes.push((self.acc << (8 - self.len)) as u8);
+        }
+        self.bytes
+    }
+}
+
+/// AVCDecoderConfigurationRecord, per ISO/IEC 14496-15
+fn write_avcc(out: &mut Vec<u8>, config: &AvcConfig<'_>) {
+    out.push(1);  // configurationVersion
+    out.extend_from_slice(&config.codec_bytes);
+    out.push(0xfc | 3);  // lengthSizeMinusOne
+    out.push(0xe0 | config.sps_bytes.len() as u8);
+    for nal in config.sps_bytes {
+        u16(out, nal.len() as u16);
+        out.extend_from_slice(nal);
+    }
+    out.push(config.pps_bytes.len() as u8);
+    for nal in config.pps_bytes {
+        u16(out, nal.len() as u16);
+        out.extend_from_slice(nal);
+    }
+    // the 'high' profiles have some extra fields
+    match config.codec_bytes[0] {
+        100 | 110 | 122 | 144 => {
+            let chroma = &config.sps.chroma_info;
+            let chroma_format_idc = match chroma.chroma_format {
+                sps::ChromaFormat::Monochrome => 0,
+                sps::ChromaFormat::YUV420 => 1,
+                sps::ChromaFormat::YUV422 => 2,
+                sps::ChromaFormat::YUV444 => 3,
+                sps::ChromaFormat::Invalid(v) => v as u8,
+            };
+            out.push(0xfc | chroma_format_idc);
+            out.push(0xf8 | chroma.bit_depth_luma_minus8);
+            out.push(0xf8 | chroma.bit_depth_chroma_minus8);
+            out.push(0);  // numOfSequenceParameterSetExt
+        },
+        _ => (),
+    }
+}
+
+/// HEVCDecoderConfigurationRecord, per ISO/IEC 14496-15
+fn write_hvcc(out: &mut Vec<u8>, config: &HevcConfig<'_>) {
+    let sps = config.sps;
+    let ptl = &sps.profile_tier_level;
+    out.push(1);  // configurationVersion
+    out.push(ptl.profile_space << 6 | (ptl.tier_flag as u8) << 5 | ptl.profile_idc);
+    u32(out, ptl.profile_compatibility_flags);
+    out.extend_from_slice(&ptl.constraint_indicator_flags.to_be_bytes()[2..]);
+    out.push(ptl.level_idc);
+    u16(out, 0xf000);  // min_spatial_segmentation_idc = 0
+    out.push(0xfc);  // parallelismType = 0
+    out.push(0xfc | sps.chroma_format_idc as u8);
+    out.push(0xf8 | sps.bit_depth_luma_minus8 as u8);
+    out.push(0xf8 | sps.bit_depth_chroma_minus8 as u8);
+    u16(out, 0);  // avgFrameRate
+    // constantFrameRate = 0, numTemporalLayers, temporalIdNested, lengthSizeMinusOne = 3
+    out.push((sps.max_sub_layers_minus1 + 1) << 3 | (sps.temporal_id_nesting_flag as u8) << 2 | 3);
+    let arrays = [
+        (hevc::NalHeader::VPS, config.vps_bytes),
+        (hevc::NalHeader::SPS, config.sps_bytes),
+        (hevc::NalHeader::PPS, config.pps_bytes),
+    ];
+    out.push(arrays.len() as u8);
+    for &(nal_unit_type, nal) in arrays.iter() {
+        out.push(0b1000_0000 | nal_unit_type);  // array_completeness = 1
+        u16(out, 1);  // numNalus
+        u16(out, nal.len() as u16);
+        out.extend_from_slice(nal);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn audio_specific_config() {
+        let mut config = AacConfig {
+            object_type: 2,
+            frequency_index: 3,
+            sample_rate: 48000,
+            channel_config: 2,
+            channels: 2,
+            extension_object_type: None,
+        };
+        let mut out = vec![];
+        write_audio_specific_config(&mut out, &config);
+        assert_eq!(vec![0x11, 0x90], out);
+
+        // HE-AAC, 24kHz core
+        config.frequency_index = 6;
+        config.sample_rate = 24000;
+        config.extension_object_type = Some(5);
+        let mut out = vec![];
+        write_audio_specific_config(&mut out, &config);
+        assert_eq!(vec![0x2b, 0x11, 0x88, 0x00], out);
+    }
+
+    fn find_box(data: &[u8], box_type: &[u8; 4]) -> usize {
+        data.windows(4).position(|w| w == box_type ).unwrap() - 4
+    }
+
+    fn tfdt(fragment: &[u8]) -> u64 {
+        let pos = find_box(fragment, b"tfdt");
+        assert_eq!(1, fragment[pos + 8], "tfdt version");
+        u64::from_be_bytes([
+            fragment[pos + 12], fragment[pos + 13], fragment[pos + 14], fragment[pos + 15],
+            fragment[pos + 16], fragment[pos + 17], fragment[pos + 18], fragment[pos + 19],
+        ])
+    }
+
+    #[test]
+    fn fragment_decode_times() {
+        // a UTC-based timestamp, well beyond 32 bits in either timescale, and falling on a
+        // 1024-sample boundary at 48kHz
+        let dts = 82_500_000_000 * 1920;
+        let sample = FragmentSample { duration: 1024, size: 4, is_sync: true, composition_time_offset: 0 };
+        let mut video = vec![];
+        write_video_fragment(&mut video, 1, media_time(dts, 90000), &[sample], &[1, 2, 3, 4]);
+        let mut audio = vec![];
+        write_audio_fragment(&mut audio, 1, media_time(dts, 48000), &[sample], &[1, 2, 3, 4]);
+        assert_eq!(dts as u64, tfdt(&video));
+        assert_eq!(u128::from(tfdt(&audio)) * 90000, u128::from(tfdt(&video)) * 48000);
+    }
+
+    #[test]
+    fn fragment_data_offset() {
+        let samples = [
+            FragmentSample { duration: 3000, size: 2, is_sync: true, composition_time_offset: 6000 },
+            FragmentSample { duration: 3000, size: 3, is_sync: false, composition_time_offset: -3000 },
+        ];
+        let mut out = vec![];
+        write_video_fragment(&mut out, 7, 0, &samples, &[1, 2, 3, 4, 5]);
+        let trun = find_box(&out, b"trun");
+        let offset = u32::from_be_bytes([out[trun + 16], out[trun + 17], out[trun + 18], out[trun + 19]]) as usize;
+        assert_eq!(&[1, 2, 3, 4, 5], &out[offset..]);
+        assert_eq!(&b"mdat"[..], &out[offset - 4..offset]);
+    }
+
+    #[test]
+    fn dac3() {
+        let info = ac3::StreamInfo {
+            enhanced: false,
+            sample_rate: 48000,
+            bit_rate_code: 14,
+            data_rate: 384,
+            substreams: vec![ac3::Substream {
+                fscod: 0,
+                bsid: 8,
+                bsmod: 0,
+                acmod: 7,
+                lfeon: true,
+                num_dep_sub: 0,
+                chan_loc: 0,
+                channels: 6,
+            }],
+        };
+        let mut out = vec![];
+        write_dac3(&mut out, &info);
+        assert_eq!(vec![0x10, 0x3d, 0xc0], out);
+    }
+}
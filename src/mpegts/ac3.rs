@@ -0,0 +1,350 @@
+use mpeg2ts_reader::{packet, pes, psi, descriptor, StreamType};
+use crate::mpegts::IngestDemuxContext;
+use crate::{ac3, store};
+
+/// `stream_type` values for AC-3 and E-AC-3 in ATSC A/52 systems
+pub const AC3_STREAM_TYPE: StreamType = StreamType::Private(0x81);
+pub const EAC3_STREAM_TYPE: StreamType = StreamType::Private(0x87);
+
+/// Tags of the DVB `AC-3_descriptor` and `enhanced_AC-3_descriptor`, which DVB systems use to
+/// signal AC-3 and E-AC-3 streams of the `H2220PesPrivateData` type
+const AC3_DESCRIPTOR_TAG: u8 = 0x6a;
+const ENHANCED_AC3_DESCRIPTOR_TAG: u8 = 0x7a;
+
+/// Does the given PES private data stream carry AC-3 or E-AC-3?
+pub fn is_dvb_ac3(stream_info: &psi::pmt::StreamInfo) -> bool {
+    stream_info.descriptors::<descriptor::CoreDescriptors>()
+        .any(|desc| match desc {
+            Ok(descriptor::CoreDescriptors::UserPrivate(d)) => d.tag == AC3_DESCRIPTOR_TAG || d.tag == ENHANCED_AC3_DESCRIPTOR_TAG,
+            _ => false,
+        })
+}
+
+/// Splits the elementary stream into syncframes, and combines those making up each audio frame
+/// (i.e. E-AC-3 dependent substreams, and syncframes of fewer than six blocks) into a sample
+pub struct Ac3ElementaryStreamConsumer {
+    store: store::Store,
+    pid: packet::Pid,
+    track_id: Option<store::TrackId>,
+    max_bitrate: Option<u32>,
+    info: store::AudioInfo,
+    unwrap_ts: super::UnwrapTimestamp,
+    discontinuity: super::DiscontinuityIndicator,
+    /// set when data is lost, until the start of the next PES packet
+    discarding: bool,
+    buf: Vec<u8>,
+    /// the timestamp of the most recent PES packet, and the offset into `buf` at which its
+    /// payload starts, until applied to a frame
+    pes_ts: Option<(i64, usize)>,
+    /// the timestamp frames are being interpolated from, and the number of frames since then
+    interpolation: Option<(i64, i64)>,
+    /// the syncframes of the audio frame being collected, with their data and timestamp
+    frames: Vec<ac3::FrameHeader>,
+    data: Vec<u8>,
+    ts: i64,
+    blocks: u8,
+}
+impl Ac3ElementaryStreamConsumer {
+    pub fn construct(stream_info: &psi::pmt::StreamInfo, store: store::Store, discontinuity: super::DiscontinuityIndicator) -> pes::PesPacketFilter<IngestDemuxContext, Ac3ElementaryStreamConsumer> {
+        let (max_bitrate, info) = super::audio_descriptors(stream_info, "AC-3", |_, d| {
+            println!("  AC-3 {:?}: {:?}", stream_info.elementary_pid(), d);
+        });
+        pes::PesPacketFilter::new(
+            Ac3ElementaryStreamConsumer {
+                store,
+                pid: stream_info.elementary_pid(),
+                track_id: None,
+                max_bitrate,
+                info,
+                unwrap_ts: super::UnwrapTimestamp::default(),
+                discontinuity,
+                discarding: false,
+                buf: vec![],
+                pes_ts: None,
+                interpolation: None,
+                frames: vec![],
+                data: vec![],
+                ts: 0,
+                blocks: 0,
+            }
+        )
+    }
+
+    fn set_pts(&mut self, pts: pes::Timestamp) {
+        if self.discontinuity.take() || self.unwrap_ts.is_discontinuous(pts) {
+            println!("AC-3 {:?}: timestamp discontinuity", self.pid);
+            // frames already collected belong to the old timeline
+            self.flush();
+            self.unwrap_ts = super::UnwrapTimestamp::default();
+            self.interpolation = None;
+            if let Some(track_id) = self.track_id {
+                self.store.timestamp_discontinuity(track_id);
+            }
+        }
+        self.unwrap_ts.update(pts);
+        self.pes_ts = Some((self.unwrap_ts.unwrap(pts), self.buf.len()));
+    }
+
+    /// Consumes as many whole syncframes from the buffer as possible
+    fn process(&mut self) {
+        loop {
+            match self.buf.windows(2).position(|w| w == ac3::SYNC_WORD ) {
+                Some(0) => (),
+                Some(pos) => {
+                    println!("AC-3 {:?}: skipping {} bytes to next syncframe", self.pid, pos);
+                    self.consume(pos);
+                },
+                None => {
+                    // keep any final byte, in case it's the start of the sync word
+                    let len = self.buf.len().saturating_sub(1);
+                    self.consume(len);
+                    return;
+                },
+            }
+            let header = match ac3::FrameHeader::parse(&self.buf[..]) {
+                Ok(header) => header,
+                Err(ac3::HeaderError::TooShort) => return,
+                Err(e) => {
+                    println!("AC-3 {:?}: bad syncframe header: {:?}", self.pid, e);
+                    self.consume(1);
+                    continue;
+                },
+            };
+            if self.buf.len() < header.frame_size {
+                return;
+            }
+            self.push_frame(header);
+        }
+    }
+
+    fn push_frame(&mut self, header: ac3::FrameHeader) {
+        let size = header.frame_size;
+        let starts_frame = header.substream_type != ac3::SubstreamType::Dependent && header.substream_id == 0;
+        if starts_frame {
+            if self.blocks >= 6 {
+                self.flush();
+            }
+            if self.frames.is_empty() {
+                match self.next_frame_timestamp(header.sample_rate) {
+                    Some(ts) => self.ts = ts,
+                    None => {
+                        println!("AC-3 {:?}: dropping syncframe which has no timestamp", self.pid);
+                        self.consume(size);
+                        return;
+                    },
+                }
+            }
+            self.blocks += header.blocks;
+        } else if self.frames.is_empty() {
+            // the substream this belongs to was lost
+            self.consume(size);
+            return;
+        }
+        self.data.extend_from_slice(&self.buf[..size]);
+        self.frames.push(header);
+        self.consume(size);
+        // without E-AC-3, nothing more can belong to this frame, so there's no need to wait for
+        // the next one to know that it's complete
+        if !self.frames[0].enhanced && self.blocks >= 6 {
+            self.flush();
+        }
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buf.drain(..len);
+        if let Some((_, ref mut offset)) = self.pes_ts {
+            *offset = offset.saturating_sub(len);
+        }
+    }
+
+    /// The timestamp of the next audio frame, which is interpolated from the start of the
+    /// earliest PES packet possible, so long as the timestamps of later PES packets don't drift
+    /// from it by more than half a frame.  `None` if there's been no PES timestamp to start from.
+    fn next_frame_timestamp(&mut self, sample_rate: u32) -> Option<i64> {
+        let pes_ts = match self.pes_ts {
+            // the PES packet started at or before this syncframe
+            Some((ts, 0)) => {
+                self.pes_ts = None;
+                Some(ts)
+            },
+            _ => None,
+        };
+        let frame_duration = i64::from(ac3::SAMPLES_PER_FRAME) * 90000 / i64::from(sample_rate);
+        let expected = self.interpolation
+            .map(|(start, frames)| start + frames * i64::from(ac3::SAMPLES_PER_FRAME) * 90000 / i64::from(sample_rate) );
+        let ts = match (expected, pes_ts) {
+            (Some(expected), Some(pes_ts)) => {
+                let drift = pes_ts - expected;
+                if drift.abs() > frame_duration / 2 {
+                    println!("AC-3 {:?}: PES timestamp drifted by {} from interpolated frame timestamps", self.pid, drift);
+                    self.interpolation = Some((pes_ts, 0));
+                    pes_ts
+                } else {
+                    expected
+                }
+            },
+            (Some(expected), None) => expected,
+            (None, Some(pes_ts)) => {
+                self.interpolation = Some((pes_ts, 0));
+                pes_ts
+            },
+            (None, None) => return None,
+        };
+        if let Some((_, ref mut frames)) = self.interpolation {
+            *frames += 1;
+        }
+        Some(ts)
+    }
+
+    /// Passes the syncframes collected so far to the store as a single sample
+    fn flush(&mut self) {
+        let frames = std::mem::replace(&mut self.frames, vec![]);
+        let data = std::mem::replace(&mut self.data, vec![]);
+        let blocks = std::mem::replace(&mut self.blocks, 0);
+        if blocks < 6 {
+            // a partial frame, due to lost data
+            return;
+        }
+        let stream_info = match ac3::StreamInfo::from_frames(&frames) {
+            Some(stream_info) => stream_info,
+            None => return,
+        };
+        let track_id = match self.track_id {
+            Some(track_id) => {
+                self.store.set_ac3_config(track_id, stream_info);
+                track_id
+            },
+            None => {
+                println!("AC-3 {:?} config: {:?}", self.pid, stream_info);
+                let track_id = self.store.allocate_ac3_track(stream_info, self.max_bitrate, self.info.clone());
+                self.track_id = Some(track_id);
+                track_id
+            },
+        };
+        self.store.add_ac3_sample(track_id, store::Sample {
+            header: store::SampleHeader::Ac3,
+            data,
+            pts: self.ts,
+            dts: self.ts,
+        });
+    }
+}
+impl pes::ElementaryStreamConsumer for Ac3ElementaryStreamConsumer {
+    fn start_stream(&mut self) { }
+    fn begin_packet(&mut self, header: pes::PesHeader) {
+        self.discarding = false;
+        match header.contents() {
+            pes::PesContents::Parsed(Some(parsed)) => {
+                match parsed.pts_dts() {
+                    Ok(pes::PtsDts::PtsOnly(Ok(pts))) | Ok(pes::PtsDts::Both { pts: Ok(pts), .. }) => self.set_pts(pts),
+                    _ => (),
+                }
+                self.buf.extend_from_slice(parsed.payload());
+                self.process();
+            },
+            pes::PesContents::Parsed(None) => (),
+            pes::PesContents::Payload(payload) => {
+                self.buf.extend_from_slice(payload);
+                self.process();
+            },
+        }
+    }
+    fn continue_packet(&mut self, data: &[u8]) {
+        if !self.discarding {
+            self.buf.extend_from_slice(data);
+            self.process();
+        }
+    }
+    fn end_packet(&mut self) { }
+    fn continuity_error(&mut self) {
+        println!("AC-3 {:?}: continuity error", self.pid);
+        // drop any partial frame, and resynchronise at the start of the next PES packet
+        self.buf.clear();
+        self.pes_ts = None;
+        self.frames.clear();
+        self.data.clear();
+        self.blocks = 0;
+        self.discarding = true;
+        self.interpolation = None;
+        if let Some(track_id) = self.track_id {
+            self.store.mark_gap(track_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mpeg2ts_reader::pes::Timestamp;
+
+    /// at 48kHz, each frame lasts 2880 90kHz ticks
+    fn consumer() -> Ac3ElementaryStreamConsumer {
+        Ac3ElementaryStreamConsumer {
+            store: store::Store::new(),
+            pid: packet::Pid::new(0x102),
+            track_id: None,
+            max_bitrate: None,
+            info: store::AudioInfo::default(),
+            unwrap_ts: crate::mpegts::UnwrapTimestamp::default(),
+            discontinuity: crate::mpegts::DiscontinuityIndicator::default(),
+            discarding: false,
+            buf: vec![],
+            pes_ts: None,
+            interpolation: None,
+            frames: vec![],
+            data: vec![],
+            ts: 0,
+            blocks: 0,
+        }
+    }
+
+    #[test]
+    fn frames_in_one_pes() {
+        let mut consumer = consumer();
+        consumer.set_pts(Timestamp::from_u64(1000));
+        assert_eq!(Some(1000), consumer.next_frame_timestamp(48000));
+        assert_eq!(Some(3880), consumer.next_frame_timestamp(48000));
+        assert_eq!(Some(6760), consumer.next_frame_timestamp(48000));
+    }
+
+    #[test]
+    fn drift() {
+        let mut consumer = consumer();
+        consumer.set_pts(Timestamp::from_u64(1000));
+        assert_eq!(Some(1000), consumer.next_frame_timestamp(48000));
+        // less than half a frame out, so the interpolated timestamp is kept
+        consumer.set_pts(Timestamp::from_u64(3880 + 1400));
+        assert_eq!(Some(3880), consumer.next_frame_timestamp(48000));
+        // more than half a frame out, so interpolation restarts from the PES timestamp
+        consumer.set_pts(Timestamp::from_u64(6760 + 1500));
+        assert_eq!(Some(8260), consumer.next_frame_timestamp(48000));
+    }
+
+    #[test]
+    fn padded_frames() {
+        // 44.1kHz frames alternate in size, without any change to the configuration
+        let mut consumer = consumer();
+        consumer.set_pts(Timestamp::from_u64(0));
+        for i in 0..4u8 {
+            let mut frame = vec![0x0b, 0x77, 0x00, 0x00, 0x40 | (i & 1), 0x40, 0x43, 0x00];
+            frame.resize(if i & 1 == 0 { 138 } else { 140 }, 0);
+            consumer.buf.extend_from_slice(&frame);
+        }
+        consumer.process();
+        let track_id = consumer.track_id.unwrap();
+        let mut track_ref = consumer.store.get_track(track_id).unwrap();
+        let ac3_track = match track_ref.track() {
+            store::Track::Ac3(ac3_track) => ac3_track,
+            _ => panic!("expected an AC-3 track"),
+        };
+        assert_eq!(0, ac3_track.configs().latest().version());
+        assert_eq!(32, ac3_track.configs().latest().data_rate);
+        assert_eq!(4, ac3_track.timeline().samples().count());
+    }
+
+    #[test]
+    fn no_timestamp() {
+        let mut consumer = consumer();
+        assert_eq!(None, consumer.next_frame_timestamp(48000));
+    }
+}
@@ -2,6 +2,15 @@ use mpeg2ts_reader::{packet, pes, psi, descriptor};
 use crate::mpegts::IngestDemuxContext;
 use crate::store;
 
+/// Tag of the DVB `AAC_descriptor`
+const AAC_DESCRIPTOR_TAG: u8 = 0x7c;
+
+/// Each ADTS frame holds this many samples per channel
+const SAMPLES_PER_FRAME: i64 = 1024;
+
+/// Size of the fixed and variable ADTS headers, without any CRC
+const ADTS_HEADER_SIZE: usize = 7;
+
 struct IngestAdtsConsumer {
     store: store::Store,
     pid: packet::Pid,
@@ -9,10 +18,19 @@ struct IngestAdtsConsumer {
     last_pts: Option<pes::Timestamp>,
     last_dts: Option<pes::Timestamp>,
     max_bitrate: Option<u32>,
+    info: store::AudioInfo,
     unwrap_ts: super::UnwrapTimestamp,
+    discontinuity: super::DiscontinuityIndicator,
+    /// sampling frequency in Hz, from the most recent ADTS header
+    frequency: Option<u32>,
+    /// the timestamp of the most recent PES packet, and the offset into the buffer of the
+    /// `AdtsElementaryStreamConsumer` at which its payload starts, until applied to a frame
+    pes_ts: Option<(i64, usize)>,
+    /// the timestamp frames are being interpolated from, and the number of frames since then
+    interpolation: Option<(i64, i64)>,
 }
 impl IngestAdtsConsumer {
-    fn set_pts_dts(&mut self, pts: Option<pes::Timestamp>, dts: Option<pes::Timestamp>) {
+    fn set_pts_dts(&mut self, pts: Option<pes::Timestamp>, dts: Option<pes::Timestamp>, offset: usize) {
         if let (Some(last_pts), Some(pts)) = (self.last_pts, pts) {
             if pts.likely_wrapped_since(last_pts) {
                 eprint!("Oh no!  PTS wrap!");
@@ -25,37 +43,87 @@ impl IngestAdtsConsumer {
         }
         self.last_pts = pts;
         self.last_dts = dts;
+        if let Some(ts) = dts.or(pts) {
+            if self.discontinuity.take() || self.unwrap_ts.is_discontinuous(ts) {
+                println!("ADTS {:?}: timestamp discontinuity", self.pid);
+                self.unwrap_ts = super::UnwrapTimestamp::default();
+                self.interpolation = None;
+                if let Some(track_id) = self.track_id {
+                    self.store.timestamp_discontinuity(track_id);
+                }
+            }
+            self.unwrap_ts.update(ts);
+            self.pes_ts = Some((self.unwrap_ts.unwrap(ts), offset));
+        }
+    }
+
+    /// The timestamp of the next frame, which is interpolated from the start of the earliest PES
+    /// packet possible, so long as the timestamps of later PES packets don't drift from it by
+    /// more than half a frame.  `None` if there's been no PES timestamp to start from.
+    fn next_frame_timestamp(&mut self) -> Option<i64> {
+        let pes_ts = match self.pes_ts {
+            // the PES packet started at or before this frame
+            Some((ts, 0)) => {
+                self.pes_ts = None;
+                Some(ts)
+            },
+            _ => None,
+        };
+        let frequency = match self.frequency {
+            Some(frequency) => i64::from(frequency),
+            None => return pes_ts,
+        };
+        let expected = self.interpolation
+            .map(|(start, frames)| start + frames * SAMPLES_PER_FRAME * 90000 / frequency );
+        let ts = match (expected, pes_ts) {
+            (Some(expected), Some(pes_ts)) => {
+                let drift = pes_ts - expected;
+                if drift.abs() > SAMPLES_PER_FRAME * 90000 / frequency / 2 {
+                    println!("ADTS {:?}: PES timestamp drifted by {} from interpolated frame timestamps", self.pid, drift);
+                    self.interpolation = Some((pes_ts, 0));
+                    pes_ts
+                } else {
+                    expected
+                }
+            },
+            (Some(expected), None) => expected,
+            (None, Some(pes_ts)) => {
+                self.interpolation = Some((pes_ts, 0));
+                pes_ts
+            },
+            (None, None) => return None,
+        };
+        if let Some((_, ref mut frames)) = self.interpolation {
+            *frames += 1;
+        }
+        Some(ts)
     }
 }
 impl adts_reader::AdtsConsumer for IngestAdtsConsumer {
     fn new_config(&mut self, mpeg_version: adts_reader::MpegVersion, protection: adts_reader::ProtectionIndicator, aot: adts_reader::AudioObjectType, freq: adts_reader::SamplingFrequency, private_bit: u8, channels: adts_reader::ChannelConfiguration, originality: adts_reader::Originality, home: u8) {
-        self.track_id = Some(self.store.allocate_aac_track(aot, freq, channels, self.max_bitrate));
+        // the parser reports the config again after resynchronising, so keep using the same
+        // track, which will version its initialisation segment should the config really change
+        match self.track_id {
+            Some(track_id) => self.store.set_aac_config(track_id, aot, freq, channels),
+            None => self.track_id = Some(self.store.allocate_aac_track(aot, freq, channels, self.max_bitrate, self.info.clone())),
+        }
+        self.frequency = freq.freq();
+        self.interpolation = None;
         println!("ADTS {:?} new config: {:?} {:?} {:?} {:?} {:?} {:?} home={:?}", self.pid, mpeg_version, protection, aot, freq, channels, originality, home);
     }
     fn payload(&mut self, buffer_fullness: u16, no_of_blocks: u8, buf: &[u8]) {
-        let (dts, pts) = if let Some(dts) = self.last_dts {
-            self.unwrap_ts.update(dts);
-            (
-                self.unwrap_ts.unwrap(dts),
-                self.last_pts.map(|pts| self.unwrap_ts.unwrap(pts)).unwrap_or(0),
-            )
-        } else {
-            if let Some(pts) = self.last_pts {
-                self.unwrap_ts.update(pts);
-                let pts = self.unwrap_ts.unwrap(pts);
-                (
-                    pts,
-                    pts,
-                )
-            } else {
-                (0, 0)
-            }
+        let ts = match self.next_frame_timestamp() {
+            Some(ts) => ts,
+            None => {
+                println!("ADTS {:?}: dropping frame which has no timestamp", self.pid);
+                return;
+            },
         };
         self.store.add_aac_sample(self.track_id.unwrap(), store::Sample {
             header: store::SampleHeader::Aac,
             data: buf.to_vec(),
-            pts,
-            dts,
+            pts: ts,
+            dts: ts,
         })
     }
     fn error(&mut self, err: adts_reader::AdtsParseError) {
@@ -63,26 +131,24 @@ impl adts_reader::AdtsConsumer for IngestAdtsConsumer {
     }
 }
 
+/// Splits the elementary stream into ADTS frames, passing each whole frame to the parser, so that
+/// the timestamp of a PES packet can be applied to the first frame starting within it
 pub struct AdtsElementaryStreamConsumer {
     parser: adts_reader::AdtsParser<IngestAdtsConsumer>,
+    /// set when data is lost, until the start of the next PES packet
+    discarding: bool,
+    buf: Vec<u8>,
 }
 impl AdtsElementaryStreamConsumer {
-    pub fn construct(stream_info: &psi::pmt::StreamInfo, store: store::Store) -> pes::PesPacketFilter<IngestDemuxContext, AdtsElementaryStreamConsumer> {
-        let mut max_bitrate = None;
-        for desc in stream_info.descriptors::<descriptor::CoreDescriptors>() {
-            match desc {
-                Ok(d) => match d {
-                    mpeg2ts_reader::descriptor::CoreDescriptors::MaximumBitrate(max) => {
-                        // TODO: if we could already have allocated a store::AvcTrack by here,
-                        //       we could pass the data in more directly, rather than bouncing it
-                        //       via the IngestH264Context instance,
-                        max_bitrate = Some(max.maximum_bits_per_second());
-                    }
-                    _ => println!("  ADTS {:?}: {:?}", stream_info.elementary_pid(), d),
+    pub fn construct(stream_info: &psi::pmt::StreamInfo, store: store::Store, discontinuity: super::DiscontinuityIndicator) -> pes::PesPacketFilter<IngestDemuxContext, AdtsElementaryStreamConsumer> {
+        let (max_bitrate, info) = super::audio_descriptors(stream_info, "ADTS", |info, desc| match desc {
+            descriptor::CoreDescriptors::UserPrivate(d) if d.tag == AAC_DESCRIPTOR_TAG => {
+                if let Some(&profile_and_level) = d.payload.first() {
+                    info.aac_extension = aac_extension(profile_and_level);
                 }
-                Err(e) => println!("  ADTS {:?}: Error reading descriptor: {:?}", stream_info.elementary_pid(), e),
             }
-        }
+            d => println!("  ADTS {:?}: {:?}", stream_info.elementary_pid(), d),
+        });
         pes::PesPacketFilter::new(
             AdtsElementaryStreamConsumer {
                 parser: adts_reader::AdtsParser::new(IngestAdtsConsumer {
@@ -92,38 +158,265 @@ impl AdtsElementaryStreamConsumer {
                     last_pts: None,
                     last_dts: None,
                     max_bitrate,
+                    info,
                     unwrap_ts: super::UnwrapTimestamp::default(),
-                })
+                    discontinuity,
+                    frequency: None,
+                    pes_ts: None,
+                    interpolation: None,
+                }),
+                discarding: false,
+                buf: vec![],
             }
         )
 
     }
+
+    fn start_pes(&mut self, pts: Option<pes::Timestamp>, dts: Option<pes::Timestamp>) {
+        self.parser.consumer.set_pts_dts(pts, dts, self.buf.len());
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        self.process();
+    }
+
+    /// Passes as many whole ADTS frames from the buffer to the parser as possible
+    fn process(&mut self) {
+        loop {
+            match self.buf.windows(2).position(|w| w[0] == 0xff && w[1] & 0xf0 == 0xf0 ) {
+                Some(0) => (),
+                Some(pos) => {
+                    println!("ADTS {:?}: skipping {} bytes to next frame", self.parser.consumer.pid, pos);
+                    self.consume(pos);
+                },
+                None => {
+                    // keep any final byte, in case it's the start of the syncword
+                    let len = self.buf.len().saturating_sub(1);
+                    self.consume(len);
+                    return;
+                },
+            }
+            if self.buf.len() < ADTS_HEADER_SIZE {
+                return;
+            }
+            let frame_length = usize::from(self.buf[3] & 0b11) << 11
+                | usize::from(self.buf[4]) << 3
+                | usize::from(self.buf[5]) >> 5;
+            if frame_length < ADTS_HEADER_SIZE {
+                println!("ADTS {:?}: bad frame_length {}", self.parser.consumer.pid, frame_length);
+                self.consume(1);
+                continue;
+            }
+            if self.buf.len() < frame_length {
+                return;
+            }
+            self.parser.push(&self.buf[..frame_length]);
+            self.consume(frame_length);
+        }
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buf.drain(..len);
+        if let Some((_, ref mut offset)) = self.parser.consumer.pes_ts {
+            *offset = offset.saturating_sub(len);
+        }
+    }
 }
+/// The use of SBR / PS implied by an MPEG-4 audio `profile_and_level` value (ISO/IEC 14496-3
+/// table 1.14), as carried in the DVB `AAC_descriptor`.  ADTS headers only give the AAC core
+/// profile, so without this signalling the extensions would go unnoticed.
+fn aac_extension(profile_and_level: u8) -> store::AacExtension {
+    match profile_and_level {
+        // High Efficiency AAC Profile
+        0x2c..=0x2f => store::AacExtension::Sbr,
+        // High Efficiency AAC v2 Profile
+        0x30..=0x33 => store::AacExtension::SbrPs,
+        _ => store::AacExtension::None,
+    }
+}
+
 impl pes::ElementaryStreamConsumer for AdtsElementaryStreamConsumer {
     fn start_stream(&mut self) { println!("ADTS start_steam()"); }
     fn begin_packet(&mut self, header: pes::PesHeader) {
+        self.discarding = false;
         match header.contents() {
             pes::PesContents::Parsed(Some(parsed)) => {
                 match parsed.pts_dts() {
-                    Ok(pes::PtsDts::PtsOnly(Ok(pts))) => self.parser.consumer.set_pts_dts(Some(pts), None),
-                    Ok(pes::PtsDts::Both{pts:Ok(pts), dts:Ok(dts)}) => self.parser.consumer.set_pts_dts(Some(pts), Some(dts)),
-                    _ => self.parser.consumer.set_pts_dts(None, None),
+                    Ok(pes::PtsDts::PtsOnly(Ok(pts))) => self.start_pes(Some(pts), None),
+                    Ok(pes::PtsDts::Both{pts:Ok(pts), dts:Ok(dts)}) => self.start_pes(Some(pts), Some(dts)),
+                    _ => self.start_pes(None, None),
                 }
-                self.parser.start();
-                self.parser.push(parsed.payload());
+                self.push(parsed.payload());
             },
             pes::PesContents::Parsed(None) => println!("ADTS: Parsed(None)"),
             pes::PesContents::Payload(payload) => {
                 println!("ADTS {:?} payload", self.parser.consumer.pid);
-                self.parser.start();
-                self.parser.push(payload);
+                self.push(payload);
             },
         }
     }
     fn continue_packet(&mut self, data: &[u8]) {
         //println!("ADTS: continue_packet() {}", data.len());
-        self.parser.push(data);
+        if !self.discarding {
+            self.push(data);
+        }
     }
     fn end_packet(&mut self) { }
-    fn continuity_error(&mut self) { }
+    fn continuity_error(&mut self) {
+        println!("ADTS {:?}: continuity error", self.parser.consumer.pid);
+        // drop the partial frame, and resynchronise at the start of the next PES packet
+        self.parser.start();
+        self.buf.clear();
+        self.discarding = true;
+        let consumer = &mut self.parser.consumer;
+        consumer.pes_ts = None;
+        consumer.interpolation = None;
+        if let Some(track_id) = consumer.track_id {
+            consumer.store.mark_gap(track_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use adts_reader::AdtsConsumer;
+    use mpeg2ts_reader::pes::Timestamp;
+
+    fn consumer(store: &store::Store) -> IngestAdtsConsumer {
+        IngestAdtsConsumer {
+            store: store.clone(),
+            pid: packet::Pid::new(0x101),
+            track_id: None,
+            last_pts: None,
+            last_dts: None,
+            max_bitrate: None,
+            info: store::AudioInfo::default(),
+            unwrap_ts: crate::mpegts::UnwrapTimestamp::default(),
+            discontinuity: crate::mpegts::DiscontinuityIndicator::default(),
+            frequency: None,
+            pes_ts: None,
+            interpolation: None,
+        }
+    }
+
+    /// at 48kHz, each frame lasts 1920 90kHz ticks
+    fn consumer_48k() -> IngestAdtsConsumer {
+        IngestAdtsConsumer {
+            frequency: Some(48000),
+            ..consumer(&store::Store::new())
+        }
+    }
+
+    fn pes(consumer: &mut IngestAdtsConsumer, pts: Option<u64>) {
+        consumer.set_pts_dts(pts.map(Timestamp::from_u64), None, 0);
+    }
+
+    #[test]
+    fn frames_in_one_pes() {
+        let mut consumer = consumer_48k();
+        pes(&mut consumer, Some(1000));
+        assert_eq!(Some(1000), consumer.next_frame_timestamp());
+        assert_eq!(Some(2920), consumer.next_frame_timestamp());
+        assert_eq!(Some(4840), consumer.next_frame_timestamp());
+    }
+
+    #[test]
+    fn pes_without_pts() {
+        let mut consumer = consumer_48k();
+        pes(&mut consumer, Some(1000));
+        assert_eq!(Some(1000), consumer.next_frame_timestamp());
+        pes(&mut consumer, None);
+        assert_eq!(Some(2920), consumer.next_frame_timestamp());
+    }
+
+    #[test]
+    fn drift() {
+        let mut consumer = consumer_48k();
+        pes(&mut consumer, Some(1000));
+        assert_eq!(Some(1000), consumer.next_frame_timestamp());
+        // less than half a frame out, so the interpolated timestamp is kept
+        pes(&mut consumer, Some(2920 + 900));
+        assert_eq!(Some(2920), consumer.next_frame_timestamp());
+        // more than half a frame out, so interpolation restarts from the PES timestamp
+        pes(&mut consumer, Some(4840 + 1000));
+        assert_eq!(Some(5840), consumer.next_frame_timestamp());
+        assert_eq!(Some(7760), consumer.next_frame_timestamp());
+    }
+
+    #[test]
+    fn no_timestamp() {
+        let mut consumer = consumer_48k();
+        pes(&mut consumer, None);
+        assert_eq!(None, consumer.next_frame_timestamp());
+    }
+
+    /// AAC-LC, 48kHz stereo, without CRC, and with 9 bytes of (silent) payload
+    fn adts_frame() -> Vec<u8> {
+        let mut frame = vec![0xff, 0xf1, 0x4c, 0x80, 0x02, 0x1f, 0xfc];
+        frame.resize(16, 0);
+        frame
+    }
+
+    #[test]
+    fn pes_starting_mid_frame() {
+        let store = store::Store::new();
+        let mut es = AdtsElementaryStreamConsumer {
+            parser: adts_reader::AdtsParser::new(consumer(&store)),
+            discarding: false,
+            buf: vec![],
+        };
+        let frame = adts_frame();
+        es.start_pes(Some(Timestamp::from_u64(0)), None);
+        es.push(&[&frame[..], &frame[..10]].concat());
+        // the second PES packet starts part way through the second frame, so its timestamp
+        // (drifting far enough to be noticed) applies to the third
+        es.start_pes(Some(Timestamp::from_u64(2 * 1920 + 1500)), None);
+        es.push(&[&frame[10..], &frame[..]].concat());
+        let track_id = es.parser.consumer.track_id.unwrap();
+        let mut track_ref = es.parser.consumer.store.get_track(track_id).unwrap();
+        let dts: Vec<i64> = track_ref.track().timeline().samples().map(|s| s.dts ).collect();
+        assert_eq!(vec![0, 1920, 2 * 1920 + 1500], dts);
+    }
+
+    fn new_config(consumer: &mut IngestAdtsConsumer, freq: adts_reader::SamplingFrequency) {
+        consumer.new_config(
+            adts_reader::MpegVersion::Mpeg4,
+            adts_reader::ProtectionIndicator::CrcAbsent,
+            adts_reader::AudioObjectType::AacLC,
+            freq,
+            0,
+            adts_reader::ChannelConfiguration::Stereo,
+            adts_reader::Originality::Original,
+            0,
+        );
+    }
+
+    #[test]
+    fn config_reused() {
+        let mut store = store::Store::new();
+        let mut consumer = consumer(&store);
+        consumer.set_pts_dts(Some(Timestamp::from_u64(0)), None, 0);
+        new_config(&mut consumer, adts_reader::SamplingFrequency::Freq48000);
+        let track_id = consumer.track_id.unwrap();
+        consumer.payload(0, 0, &[0; 10]);
+        // the parser repeats the config after resynchronising, which is not a change
+        new_config(&mut consumer, adts_reader::SamplingFrequency::Freq48000);
+        consumer.payload(0, 0, &[0; 10]);
+        consumer.set_pts_dts(Some(Timestamp::from_u64(3840)), None, 0);
+        new_config(&mut consumer, adts_reader::SamplingFrequency::Freq44100);
+        consumer.payload(0, 0, &[0; 10]);
+        assert_eq!(Some(track_id), consumer.track_id);
+        let mut track_ref = store.get_track(track_id).unwrap();
+        let aac_track = match track_ref.track() {
+            store::Track::Aac(aac_track) => aac_track,
+            _ => panic!("expected an AAC track"),
+        };
+        assert_eq!(1, aac_track.configs().latest().version());
+        assert_eq!(44100, aac_track.sample_rate());
+        assert_eq!(48000, aac_track.configs().at(1920).sample_rate());
+        assert_eq!(48000, aac_track.configs().version(0).unwrap().sample_rate());
+        assert_eq!(3, aac_track.timeline().samples().count());
+    }
 }
@@ -9,6 +9,8 @@ use std::collections::HashMap;
 use h264_reader::nal::pps::ParamSetId;
 use std::time::{SystemTime, SystemTimeError, Duration};
 use mpeg2ts_reader::pes::Timestamp;
+use byteorder::WriteBytesExt;
+use crate::captions;
 
 enum SliceType {
     Idr,
@@ -65,18 +67,10 @@ impl h264_reader::nal::NalHandler for SliceIngest {
             Ok((header, sps, pps)) => {
                 //println!("{:#?}", header);
                 let sps = sps.clone();
-                let pps = pps.clone();
-                let mut sps_bytes = vec![];
-                sps_bytes.extend_from_slice(ctx.user_context.sps_bytes(sps.seq_parameter_set_id).unwrap());
-                let mut pps_bytes = vec![];
-                pps_bytes.extend_from_slice(ctx.user_context.pps_bytes(pps.seq_parameter_set_id).unwrap());
                 ctx.user_context.add_slice(
                     current_slice.header,
                     header,
                     sps,
-                    pps,
-                    sps_bytes,
-                    pps_bytes,
                     current_slice.buf
                 );
             },
@@ -146,7 +140,10 @@ impl nal::sei::pic_timing::PicTimingHandler for PicTimingIngest {
     fn handle(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>, pic_timing: nal::sei::pic_timing::PicTiming) {
         // Ordering: this depends on the PTS for this frame having already been
         // placed into the context by H264ElementaryStreamConsumer
-        const FRAME_RATE: u64 = 25;
+        let frame_rate = match ctx.user_context.frame_rate() {
+            Some(frame_rate) => frame_rate.timecode_rate(),
+            None => return,
+        };
         if let Some(pic_struct) = pic_timing.pic_struct {
             if !pic_struct.clock_timestamps.is_empty() {
                 if let Some(ref clock_timestamp) = pic_struct.clock_timestamps[0] {
@@ -157,8 +154,8 @@ impl nal::sei::pic_timing::PicTimingHandler for PicTimingIngest {
                                     clock_timestamp.smh.hours() as u64
                                 ) * 60 + clock_timestamp.smh.minutes() as u64
                             ) * 60 + clock_timestamp.smh.seconds() as u64
-                        ) * FRAME_RATE + clock_timestamp.n_frames as u64
-                    ) * 1_000_000 / FRAME_RATE;
+                        ) * frame_rate + clock_timestamp.n_frames as u64
+                    ) * 1_000_000 / frame_rate;
 
                     let date_time = DateTime::now().unwrap(/*TODO*/);
                     let time_diff = date_time.time_of_day_micros as i64 - time_of_day_micros as i64;
@@ -183,12 +180,43 @@ impl nal::sei::pic_timing::PicTimingHandler for PicTimingIngest {
         }
     }
 }
+/// Looks for ATSC A/53 caption data in `user_data_registered_itu_t_t35` SEI messages, noting
+/// the services present and decoding `CC1` into subtitle cues
+#[derive(Default)]
+struct CaptionIngest {
+    buf: Vec<u8>,
+}
+impl h264_reader::nal::sei::SeiIncrementalPayloadReader for CaptionIngest {
+    type Ctx = IngestH264Context;
+
+    fn start(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>, payload_type: h264_reader::nal::sei::HeaderType, payload_size: u32) {
+        self.buf.clear();
+    }
+
+    fn push(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>, buf: &[u8]) {
+        self.buf.extend_from_slice(buf);
+    }
+
+    fn end(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>) {
+        if ctx.user_context.captions.push_sei(&self.buf[..]) {
+            ctx.user_context.update_captions();
+        }
+        if let Some(cc_data) = captions::a53_cc_data(&self.buf[..]) {
+            ctx.user_context.add_cc_data(cc_data);
+        }
+        self.buf.clear();
+    }
+
+    fn reset(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>) {
+        self.buf.clear();
+    }
+}
 h264_reader::sei_switch!{
     SeiSwitch<IngestH264Context> {
         //BufferingPeriod: h264_reader::nal::sei::buffering_period::BufferingPeriodPayloadReader
         //    => h264_reader::nal::sei::buffering_period::BufferingPeriodPayloadReader::new(),
-        //UserDataRegisteredItuTT35: h264_reader::nal::sei::user_data_registered_itu_t_t35::UserDataRegisteredItuTT35Reader<TT35Switch>
-        //    => h264_reader::nal::sei::user_data_registered_itu_t_t35::UserDataRegisteredItuTT35Reader::new(TT35Switch::default()),
+        UserDataRegisteredItuTT35: CaptionIngest
+            => CaptionIngest::default(),
         PicTiming: h264_reader::nal::sei::pic_timing::PicTimingReader<PicTimingIngest>
             => h264_reader::nal::sei::pic_timing::PicTimingReader::new(PicTimingIngest::default()),
     }
@@ -219,8 +247,20 @@ impl h264_reader::nal::sei::SeiIncrementalPayloadReader for IngestSeiPayoadReade
 
 
 
+/// The slices of a single coded picture, accumulated until the start of the next access unit is
+/// seen
+struct AccessUnit {
+    nal_header: nal::NalHeader,
+    slice_header: nal::slice::SliceHeader,
+    pts: i64,
+    dts: i64,
+    /// each NAL unit, prefixed with its 4-byte length
+    data: Vec<u8>,
+}
+
 struct IngestH264Context {
     store: store::Store,
+    pid: packet::Pid,
     track_id: Option<store::TrackId>,
     last_pts: Option<i64>,
     last_dts: Option<i64>,
@@ -228,11 +268,24 @@ struct IngestH264Context {
     pps_bytes: HashMap<nal::pps::ParamSetId, Vec<u8>>,
     max_bitrate: Option<u32>,
     unwrap_ts: super::UnwrapTimestamp,
+    discontinuity: super::DiscontinuityIndicator,
+    /// frame rate signalled in the most recent SPS
+    sps_frame_rate: Option<store::FrameRate>,
+    /// in 90kHz units, used in case the SPS does not signal a frame rate
+    measured_frame_duration: Option<i64>,
+    access_unit: Option<AccessUnit>,
+    /// length-prefixed SEI NAL units, to be included in the next access unit
+    pending_sei: Vec<u8>,
+    captions: captions::CaptionDetector,
+    cc1: captions::Cea608Decoder,
+    /// allocated once `CC1` is found to contain some text
+    cc1_track_id: Option<store::TrackId>,
 }
 impl IngestH264Context {
-    fn new(store: store::Store, max_bitrate: Option<u32>) -> Self {
+    fn new(store: store::Store, pid: packet::Pid, max_bitrate: Option<u32>, discontinuity: super::DiscontinuityIndicator) -> Self {
         IngestH264Context {
             store,
+            pid,
             track_id: None,
             last_pts: None,
             last_dts: None,
@@ -240,10 +293,34 @@ impl IngestH264Context {
             pps_bytes: HashMap::new(),
             max_bitrate,
             unwrap_ts: super::UnwrapTimestamp::default(),
+            discontinuity,
+            sps_frame_rate: None,
+            measured_frame_duration: None,
+            access_unit: None,
+            pending_sei: vec![],
+            captions: captions::CaptionDetector::default(),
+            cc1: captions::Cea608Decoder::default(),
+            cc1_track_id: None,
         }
     }
 
+    fn frame_rate(&self) -> Option<store::FrameRate> {
+        self.sps_frame_rate.or_else(|| self.measured_frame_duration.map(|duration| store::FrameRate {
+            num: 90000,
+            den: duration as u32,
+        }))
+    }
+
     fn set_pts_dts(&mut self, pts: Option<pes::Timestamp>, dts: Option<pes::Timestamp>) {
+        if pts.is_some() {
+            // a PES packet carrying a PTS must begin a new access unit
+            self.flush_access_unit();
+        }
+        if let Some(ts) = dts.or(pts) {
+            if self.discontinuity.take() || self.unwrap_ts.is_discontinuous(ts) {
+                self.timestamp_discontinuity();
+            }
+        }
         let (dts, pts) = if let Some(dts) = dts {
             self.unwrap_ts.update(dts);
             (
@@ -262,6 +339,14 @@ impl IngestH264Context {
                 (None, None)
             }
         };
+        if let (Some(last), Some(this)) = (self.last_dts, dts) {
+            let delta = this - last;
+            if delta > 0 {
+                // the smallest delta seen is taken to be the frame duration, in case a PES
+                // packet is missing
+                self.measured_frame_duration = Some(self.measured_frame_duration.map(|d| d.min(delta) ).unwrap_or(delta));
+            }
+        }
         self.last_pts = pts;
         self.last_dts = dts;
     }
@@ -270,18 +355,30 @@ impl IngestH264Context {
                  nal_header: nal::NalHeader,
                  slice_header: nal::slice::SliceHeader,
                  sps: nal::sps::SeqParameterSet,
-                 pps: nal::pps::PicParameterSet,
-                 sps_bytes: Vec<u8>,
-                 pps_bytes: Vec<u8>,
                  slice_data: Vec<u8>,
     ) {
-        let track_id = if let Some(tid) = self.track_id {
-            tid
-        } else {
-            let tid = self.store.allocate_avc_track(sps, pps, sps_bytes, pps_bytes, self.max_bitrate);
-            self.track_id = Some(tid);
-            tid
-        };
+        if slice_header.first_mb_in_slice == 0 {
+            // the first slice of a new picture
+            self.flush_access_unit();
+        } else if self.access_unit.is_none() {
+            // the start of this picture was lost
+            return;
+        }
+        match self.track_id {
+            None => {
+                let (sps_bytes, pps_bytes) = self.parameter_sets();
+                self.track_id = Some(self.store.allocate_avc_track(sps, sps_bytes, pps_bytes, self.max_bitrate));
+                self.update_captions();
+            },
+            Some(track_id) => {
+                // the encoder may switch to new parameter sets (e.g. a new resolution) at an IDR
+                let idr = nal_header.nal_unit_type() == nal::UnitType::SliceLayerWithoutPartitioningIdr;
+                if idr && self.access_unit.is_none() {
+                    let (sps_bytes, pps_bytes) = self.parameter_sets();
+                    self.store.set_avc_parameter_sets(track_id, sps, sps_bytes, pps_bytes);
+                }
+            },
+        }
         let (dts, pts) = if let Some(dts) = self.last_dts {
             (
                 dts,
@@ -297,20 +394,145 @@ impl IngestH264Context {
                 (0, 0)
             }
         };
-        self.store.add_avc_sample(track_id, store::Sample {
-            header: store::SampleHeader::Avc(nal_header, slice_header),
-            data: slice_data,
+        let pending_sei = &mut self.pending_sei;
+        let access_unit = self.access_unit.get_or_insert_with(|| AccessUnit {
+            nal_header,
+            slice_header,
             pts,
             dts,
+            // SEI precedes the first slice of the picture to which it applies
+            data: std::mem::replace(pending_sei, vec![]),
         });
+        access_unit.data.write_u32::<byteorder::BigEndian>(slice_data.len() as u32).unwrap();
+        access_unit.data.extend_from_slice(&slice_data[..]);
+    }
+
+    /// Pass any slices accumulated so far to the store as a single sample
+    fn flush_access_unit(&mut self) {
+        if let Some(access_unit) = self.access_unit.take() {
+            if let Some(track_id) = self.track_id {
+                self.store.add_avc_sample(track_id, store::Sample {
+                    header: store::SampleHeader::Avc(access_unit.nal_header, access_unit.slice_header),
+                    data: access_unit.data,
+                    pts: access_unit.pts,
+                    dts: access_unit.dts,
+                });
+            }
+        }
+    }
+
+    /// Discards the picture being assembled, since some of its data was lost, and has the store
+    /// drop samples until the next IDR
+    fn media_lost(&mut self) {
+        self.access_unit = None;
+        self.pending_sei.clear();
+        if let Some(track_id) = self.track_id {
+            self.store.mark_gap(track_id);
+        }
+    }
+
+    /// Starts afresh with the new timeline of input timestamps, having had the store arrange for
+    /// the output timeline to carry on regardless
+    fn timestamp_discontinuity(&mut self) {
+        println!("H264 {:?}: timestamp discontinuity", self.pid);
+        self.unwrap_ts = super::UnwrapTimestamp::default();
+        self.last_pts = None;
+        self.last_dts = None;
+        if let Some(track_id) = self.track_id {
+            self.store.timestamp_discontinuity(track_id);
+        }
+    }
+
+    fn add_sei(&mut self, sei_data: &[u8]) {
+        self.pending_sei.write_u32::<byteorder::BigEndian>(sei_data.len() as u32).unwrap();
+        self.pending_sei.extend_from_slice(sei_data);
+    }
+
+    fn update_captions(&mut self) {
+        let services = self.captions.services();
+        if let (Some(track_id), false) = (self.track_id, services.is_empty()) {
+            self.store.set_avc_captions(track_id, services);
+        }
+    }
+
+    fn add_cc_data(&mut self, cc_data: &[u8]) {
+        let pts = match self.last_pts {
+            Some(pts) => pts,
+            None => return,
+        };
+        for (pts, text) in self.cc1.push(pts, cc_data) {
+            let track_id = match self.cc1_track_id {
+                Some(track_id) => track_id,
+                None => {
+                    if text.is_none() {
+                        continue;
+                    }
+                    let track_id = self.store.allocate_subtitle_track(store::SubtitleInfo {
+                        language: None,
+                        name: "CC1".to_string(),
+                        hearing_impaired: false,
+                    });
+                    self.cc1_track_id = Some(track_id);
+                    track_id
+                },
+            };
+            self.store.set_subtitle_display(track_id, pts, text);
+        }
+    }
+
+    /// Every SPS and PPS NAL unit seen so far, each ordered by id, since pictures may refer to
+    /// any of them
+    fn parameter_sets(&self) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        fn by_id(sets: &HashMap<ParamSetId, Vec<u8>>) -> Vec<Vec<u8>> {
+            let mut sets: Vec<_> = sets.iter().collect();
+            sets.sort_by_key(|(id, _)| id.id() );
+            sets.into_iter().map(|(_, bytes)| bytes.clone() ).collect()
+        }
+        (by_id(&self.sps_bytes), by_id(&self.pps_bytes))
+    }
+}
+
+/// An access unit delimiter marks the start of a new access unit
+#[derive(Default)]
+struct AudIngestNalHandler;
+impl NalHandler for AudIngestNalHandler {
+    type Ctx = IngestH264Context;
+
+    fn start(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>, header: NalHeader) {
+        ctx.user_context.flush_access_unit();
+    }
+
+    fn push(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>, buf: &[u8]) {
+    }
+
+    fn end(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>) {
+    }
+}
+
+/// Keeps a copy of each SEI NAL unit, so that it remains in the sample data (players need the
+/// caption data it may carry), while passing it on to be parsed
+struct SeiIngestNalHandler {
+    sei: h264_reader::nal::sei::SeiNalHandler<IngestSeiPayoadReader>,
+    buf: Vec<u8>,
+}
+impl NalHandler for SeiIngestNalHandler {
+    type Ctx = IngestH264Context;
+
+    fn start(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>, header: NalHeader) {
+        self.buf.clear();
+        self.buf.push(header.into());
+        self.sei.start(ctx, header);
     }
 
-    pub fn sps_bytes(&self, sps_id: ParamSetId) -> Option<&[u8]> {
-        self.sps_bytes.get(&sps_id).map(|v| &v[..] )
+    fn push(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>, buf: &[u8]) {
+        self.buf.extend_from_slice(buf);
+        self.sei.push(ctx, buf);
     }
 
-    pub fn pps_bytes(&self, pps_id: ParamSetId) -> Option<&[u8]> {
-        self.pps_bytes.get(&pps_id).map(|v| &v[..] )
+    fn end(&mut self, ctx: &mut h264_reader::Context<Self::Ctx>) {
+        self.sei.end(ctx);
+        ctx.user_context.add_sei(&self.buf[..]);
+        self.buf.clear();
     }
 }
 
@@ -342,6 +564,9 @@ impl NalHandler for SpsIngestNalHandler {
         let capture = decode.into_handler();
         let sps = nal::sps::SeqParameterSet::from_bytes(&capture.buf[..]);
         if let Ok(sps) = sps {
+            if let Some(frame_rate) = store::FrameRate::from_sps(&sps) {
+                ctx.user_context.sps_frame_rate = Some(frame_rate);
+            }
             ctx.user_context.sps_bytes.insert(sps.seq_parameter_set_id, self.buf.clone());
             ctx.put_seq_param_set(sps);
         }
@@ -390,7 +615,7 @@ pub struct H264ElementaryStreamConsumer {
     parser: h264_reader::annexb::AnnexBReader<h264_reader::nal::NalSwitch<IngestH264Context>, IngestH264Context>,
 }
 impl H264ElementaryStreamConsumer {
-    pub fn construct(stream_info: &psi::pmt::StreamInfo, store: store::Store) -> pes::PesPacketFilter<IngestDemuxContext, H264ElementaryStreamConsumer> {
+    pub fn construct(stream_info: &psi::pmt::StreamInfo, store: store::Store, discontinuity: super::DiscontinuityIndicator) -> pes::PesPacketFilter<IngestDemuxContext, H264ElementaryStreamConsumer> {
         let mut max_bitrate = None;
         for desc in stream_info.descriptors::<descriptor::CoreDescriptors>() {
             match desc {
@@ -406,25 +631,32 @@ impl H264ElementaryStreamConsumer {
                 Err(e) => println!("  H264 {:?}: Error reading descriptor: {:?}", stream_info.elementary_pid(), e),
             }
         }
-        let ctx = IngestH264Context::new(store, max_bitrate);
+        let ctx = IngestH264Context::new(store, stream_info.elementary_pid(), max_bitrate, discontinuity);
+        pes::PesPacketFilter::new(H264ElementaryStreamConsumer::new(stream_info.elementary_pid(), ctx))
+    }
+
+    fn new(pid: packet::Pid, ctx: IngestH264Context) -> H264ElementaryStreamConsumer {
         let mut switch = h264_reader::nal::NalSwitch::new();
-        let sei_handler = h264_reader::nal::sei::SeiNalHandler::new(IngestSeiPayoadReader { switch: SeiSwitch::default() });
+        let sei_handler = SeiIngestNalHandler {
+            sei: h264_reader::nal::sei::SeiNalHandler::new(IngestSeiPayoadReader { switch: SeiSwitch::default() }),
+            buf: vec![],
+        };
+        let aud_handler = AudIngestNalHandler::default();
         let sps_handler = SpsIngestNalHandler::default();
         let pps_handler = PpsIngestNalHandler::default();
         let slice_wout_part_idr_handler = SliceIngest::new(SliceType::Idr);
         let slice_wout_part_nonidr_handler = SliceIngest::new(SliceType::NonIdr);
+        switch.put_handler(h264_reader::nal::UnitType::AccessUnitDelimiter, Box::new(RefCell::new(aud_handler)));
         switch.put_handler(h264_reader::nal::UnitType::SEI, Box::new(RefCell::new(sei_handler)));
         switch.put_handler(h264_reader::nal::UnitType::SeqParameterSet, Box::new(RefCell::new(sps_handler)));
         switch.put_handler(h264_reader::nal::UnitType::PicParameterSet, Box::new(RefCell::new(pps_handler)));
         switch.put_handler(h264_reader::nal::UnitType::SliceLayerWithoutPartitioningIdr, Box::new(RefCell::new(slice_wout_part_idr_handler)));
         switch.put_handler(h264_reader::nal::UnitType::SliceLayerWithoutPartitioningNonIdr, Box::new(RefCell::new(slice_wout_part_nonidr_handler)));
-        pes::PesPacketFilter::new(
-            H264ElementaryStreamConsumer {
-                pid: stream_info.elementary_pid(),
-                ctx: h264_reader::Context::new(ctx),
-                parser: h264_reader::annexb::AnnexBReader::new(switch)
-            }
-        )
+        H264ElementaryStreamConsumer {
+            pid,
+            ctx: h264_reader::Context::new(ctx),
+            parser: h264_reader::annexb::AnnexBReader::new(switch)
+        }
     }
 }
 impl pes::ElementaryStreamConsumer for H264ElementaryStreamConsumer {
@@ -465,6 +697,134 @@ impl pes::ElementaryStreamConsumer for H264ElementaryStreamConsumer {
         self.parser.end_units(&mut self.ctx)
     }
     fn continuity_error(&mut self) {
-        // TODO: self.parser.reset(ctx);
+        println!("H264 {:?}: continuity error", self.pid);
+        // finish off the NAL unit in progress, so that the parser goes on to look for the next
+        // start code, and then throw away the (likely corrupt) picture it belonged to
+        self.parser.end_units(&mut self.ctx);
+        self.parser.start(&mut self.ctx);
+        self.ctx.user_context.media_lost();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use byteorder::{BigEndian, ByteOrder};
+
+    /// 1280x720 Main profile, so pictures of 80x45 macroblocks
+    const SPS: &[u8] = &[0x67, 0x4d, 0x40, 0x1e, 0xec, 0x80, 0x28, 0x02, 0xdc, 0x80];
+    const PPS: &[u8] = &[0x68, 0xce, 0x38, 0x80];
+    const AUD: &[u8] = &[0x09, 0xf0];
+    /// the slices of an IDR picture, starting at the first and second rows of macroblocks
+    const IDR_SLICE_0: &[u8] = &[0x65, 0x88, 0x84, 0x03];
+    const IDR_SLICE_80: &[u8] = &[0x65, 0x02, 0x88, 0x88, 0x40, 0x30];
+    /// the slices of the following P picture
+    const P_SLICE_0: &[u8] = &[0x41, 0x9a, 0x21, 0x0c];
+    const P_SLICE_80: &[u8] = &[0x41, 0x02, 0x89, 0xa2, 0x10, 0xc0];
+
+    fn consumer() -> (H264ElementaryStreamConsumer, store::Store) {
+        let store = store::Store::new();
+        let pid = packet::Pid::new(0x100);
+        let ctx = IngestH264Context::new(store.clone(), pid, None, crate::mpegts::DiscontinuityIndicator::default());
+        (H264ElementaryStreamConsumer::new(pid, ctx), store)
+    }
+
+    /// Passes the given NAL units to the consumer as the payload of a single PES packet
+    fn pes(consumer: &mut H264ElementaryStreamConsumer, pts: Option<u64>, nal_units: &[&[u8]]) {
+        consumer.ctx.user_context.set_pts_dts(pts.map(Timestamp::from_u64), None);
+        consumer.parser.start(&mut consumer.ctx);
+        for nal_unit in nal_units {
+            consumer.parser.push(&mut consumer.ctx, &[0, 0, 0, 1]);
+            consumer.parser.push(&mut consumer.ctx, nal_unit);
+        }
+        consumer.parser.end_units(&mut consumer.ctx);
+    }
+
+    /// The PTS of each sample so far, along with the length of each NAL unit it contains
+    fn samples(consumer: &H264ElementaryStreamConsumer, store: &mut store::Store) -> Vec<(i64, Vec<usize>)> {
+        let track_id = match consumer.ctx.user_context.track_id {
+            Some(track_id) => track_id,
+            None => return vec![],
+        };
+        let mut track = store.get_track(track_id).unwrap();
+        let samples = track.track().timeline().samples().map(|sample| {
+            let mut lengths = vec![];
+            let mut data = &sample.data[..];
+            while !data.is_empty() {
+                let len = BigEndian::read_u32(&data[..4]) as usize;
+                lengths.push(len);
+                data = &data[4 + len..];
+            }
+            (sample.pts, lengths)
+        }).collect();
+        samples
+    }
+
+    #[test]
+    fn pes_with_pts_starts_access_unit() {
+        let (mut consumer, mut store) = consumer();
+        pes(&mut consumer, Some(0), &[SPS, PPS, IDR_SLICE_0, IDR_SLICE_80]);
+        // further slices of the picture may yet follow
+        assert!(samples(&consumer, &mut store).is_empty());
+        pes(&mut consumer, Some(3003), &[P_SLICE_0, P_SLICE_80]);
+        assert_eq!(vec![(0, vec![IDR_SLICE_0.len(), IDR_SLICE_80.len()])], samples(&consumer, &mut store));
+    }
+
+    #[test]
+    fn first_slice_starts_access_unit() {
+        let (mut consumer, mut store) = consumer();
+        pes(&mut consumer, Some(0), &[SPS, PPS, IDR_SLICE_0]);
+        // PES packets without a PTS carry on with the picture in progress,
+        pes(&mut consumer, None, &[IDR_SLICE_80]);
+        assert!(samples(&consumer, &mut store).is_empty());
+        // until one holds the first slice of another picture
+        pes(&mut consumer, None, &[P_SLICE_0]);
+        assert_eq!(vec![(0, vec![IDR_SLICE_0.len(), IDR_SLICE_80.len()])], samples(&consumer, &mut store));
+    }
+
+    #[test]
+    fn aud_starts_access_unit() {
+        let (mut consumer, mut store) = consumer();
+        pes(&mut consumer, Some(0), &[AUD, SPS, PPS, IDR_SLICE_0, IDR_SLICE_80, AUD]);
+        assert_eq!(vec![(0, vec![IDR_SLICE_0.len(), IDR_SLICE_80.len()])], samples(&consumer, &mut store));
+    }
+
+    #[test]
+    fn slice_without_picture_start() {
+        let (mut consumer, mut store) = consumer();
+        // joining the stream part way through a picture, the remainder of it is ignored
+        pes(&mut consumer, Some(0), &[SPS, PPS, IDR_SLICE_80]);
+        assert!(consumer.ctx.user_context.access_unit.is_none());
+        pes(&mut consumer, Some(3003), &[IDR_SLICE_0, IDR_SLICE_80]);
+        pes(&mut consumer, Some(6006), &[P_SLICE_0]);
+        assert_eq!(vec![(3003, vec![IDR_SLICE_0.len(), IDR_SLICE_80.len()])], samples(&consumer, &mut store));
+    }
+
+    #[test]
+    fn parameter_set_change() {
+        let (mut consumer, mut store) = consumer();
+        // the same picture size, but signalling level 3.1 rather than 3.0
+        let sps_level_31: &[u8] = &[0x67, 0x4d, 0x40, 0x1f, 0xec, 0x80, 0x28, 0x02, 0xdc, 0x80];
+        pes(&mut consumer, Some(0), &[SPS, PPS, IDR_SLICE_0, IDR_SLICE_80]);
+        // repeating the same parameter sets is not a change
+        pes(&mut consumer, Some(3003), &[SPS, PPS, IDR_SLICE_0, IDR_SLICE_80]);
+        pes(&mut consumer, Some(6006), &[sps_level_31, PPS, IDR_SLICE_0, IDR_SLICE_80]);
+        pes(&mut consumer, Some(9009), &[P_SLICE_0, P_SLICE_80]);
+        assert_eq!(3, samples(&consumer, &mut store).len());
+        let mut track_ref = store.get_track(consumer.ctx.user_context.track_id.unwrap()).unwrap();
+        let avc_track = match track_ref.track() {
+            store::Track::Avc(avc_track) => avc_track,
+            _ => panic!("expected an AVC track"),
+        };
+        assert_eq!(1, avc_track.parameter_sets().latest().version());
+        assert_eq!(0, avc_track.parameter_sets().at(3003).version());
+        assert_eq!(1, avc_track.parameter_sets().at(6006).version());
+        assert_eq!(&[SPS.to_vec()][..], avc_track.parameter_sets().version(0).unwrap().sps_bytes());
+        assert_eq!(&[sps_level_31.to_vec()][..], avc_track.parameter_sets().version(1).unwrap().sps_bytes());
+        // the new parameter sets start a new segment
+        let segments: Vec<_> = avc_track.timeline().segments().collect();
+        assert_eq!(2, segments.len());
+        assert_eq!(6006, segments[1].id());
+        assert!(!segments[1].is_continuous());
     }
 }
@@ -0,0 +1,252 @@
+use mpeg2ts_reader::{pes, packet, psi, descriptor};
+use crate::mpegts::IngestDemuxContext;
+use crate::store;
+use crate::hevc;
+use byteorder::WriteBytesExt;
+
+/// The VCL NAL units of a single coded picture, accumulated until the start of the next access
+/// unit is seen
+struct AccessUnit {
+    header: hevc::NalHeader,
+    pts: i64,
+    dts: i64,
+    /// each NAL unit, prefixed with its 4-byte length
+    data: Vec<u8>,
+}
+
+pub struct H265ElementaryStreamConsumer {
+    pid: packet::Pid,
+    store: store::Store,
+    track_id: Option<store::TrackId>,
+    max_bitrate: Option<u32>,
+    unwrap_ts: super::UnwrapTimestamp,
+    discontinuity: super::DiscontinuityIndicator,
+    last_pts: Option<i64>,
+    last_dts: Option<i64>,
+    /// the Annex B data of the current PES packet
+    buf: Vec<u8>,
+    vps_bytes: Option<Vec<u8>>,
+    sps: Option<(hevc::SeqParameterSet, Vec<u8>)>,
+    pps_bytes: Option<Vec<u8>>,
+    access_unit: Option<AccessUnit>,
+    /// set when data is lost, until the start of the next PES packet
+    discarding: bool,
+}
+impl H265ElementaryStreamConsumer {
+    pub fn construct(stream_info: &psi::pmt::StreamInfo, store: store::Store, discontinuity: super::DiscontinuityIndicator) -> pes::PesPacketFilter<IngestDemuxContext, H265ElementaryStreamConsumer> {
+        let mut max_bitrate = None;
+        for desc in stream_info.descriptors::<descriptor::CoreDescriptors>() {
+            match desc {
+                Ok(d) => match d {
+                    mpeg2ts_reader::descriptor::CoreDescriptors::MaximumBitrate(max) => {
+                        max_bitrate = Some(max.maximum_bits_per_second());
+                    }
+                    _ => println!("  H265 {:?}: {:?}", stream_info.elementary_pid(), d),
+                }
+                Err(e) => println!("  H265 {:?}: Error reading descriptor: {:?}", stream_info.elementary_pid(), e),
+            }
+        }
+        pes::PesPacketFilter::new(
+            H265ElementaryStreamConsumer {
+                pid: stream_info.elementary_pid(),
+                store,
+                track_id: None,
+                max_bitrate,
+                unwrap_ts: super::UnwrapTimestamp::default(),
+                discontinuity,
+                last_pts: None,
+                last_dts: None,
+                buf: vec![],
+                vps_bytes: None,
+                sps: None,
+                pps_bytes: None,
+                access_unit: None,
+                discarding: false,
+            }
+        )
+    }
+
+    fn set_pts_dts(&mut self, pts: Option<pes::Timestamp>, dts: Option<pes::Timestamp>) {
+        if pts.is_some() {
+            // a PES packet carrying a PTS must begin a new access unit
+            self.flush_access_unit();
+        }
+        if let Some(ts) = dts.or(pts) {
+            if self.discontinuity.take() || self.unwrap_ts.is_discontinuous(ts) {
+                println!("H265 {:?}: timestamp discontinuity", self.pid);
+                self.unwrap_ts = super::UnwrapTimestamp::default();
+                if let Some(track_id) = self.track_id {
+                    self.store.timestamp_discontinuity(track_id);
+                }
+            }
+        }
+        let (dts, pts) = if let Some(dts) = dts {
+            self.unwrap_ts.update(dts);
+            (
+                Some(self.unwrap_ts.unwrap(dts)),
+                pts.map(|pts| self.unwrap_ts.unwrap(pts)),
+            )
+        } else {
+            if let Some(pts) = pts {
+                self.unwrap_ts.update(pts);
+                let pts = self.unwrap_ts.unwrap(pts);
+                (
+                    None,
+                    Some(pts),
+                )
+            } else {
+                (None, None)
+            }
+        };
+        self.last_pts = pts;
+        self.last_dts = dts;
+    }
+
+    /// Handles the NAL units of the PES packet just completed.  We assume that NAL units are not
+    /// split across PES packets, which is the case for any encoder we've seen.
+    fn process_packet(&mut self) {
+        let buf = std::mem::replace(&mut self.buf, vec![]);
+        for nal in hevc::split_annexb(&buf[..]) {
+            let header = match hevc::NalHeader::new(nal) {
+                Ok(header) => header,
+                Err(e) => {
+                    println!("H265 {:?}: bad NAL header: {:?}", self.pid, e);
+                    continue;
+                }
+            };
+            match header.nal_unit_type() {
+                hevc::NalHeader::VPS => self.vps_bytes = Some(nal.to_vec()),
+                hevc::NalHeader::SPS => {
+                    match hevc::SeqParameterSet::from_bytes(&hevc::decode_rbsp(&nal[2..])[..]) {
+                        Ok(sps) => self.sps = Some((sps, nal.to_vec())),
+                        Err(e) => println!("H265 {:?}: problem parsing SPS: {:?}", self.pid, e),
+                    }
+                },
+                hevc::NalHeader::PPS => self.pps_bytes = Some(nal.to_vec()),
+                hevc::NalHeader::AUD => self.flush_access_unit(),
+                _ if header.is_vcl() => self.add_slice(header, nal),
+                _ => (),
+            }
+        }
+        self.buf = buf;
+        self.buf.clear();
+    }
+
+    fn add_slice(&mut self, header: hevc::NalHeader, nal: &[u8]) {
+        // first_slice_segment_in_pic_flag
+        let first_slice = nal.len() > 2 && nal[2] & 0b1000_0000 != 0;
+        if first_slice {
+            self.flush_access_unit();
+        }
+        let (vps_bytes, sps, sps_bytes, pps_bytes) = match (&self.vps_bytes, &self.sps, &self.pps_bytes) {
+            (Some(vps_bytes), Some((sps, sps_bytes)), Some(pps_bytes)) => (vps_bytes, sps, sps_bytes, pps_bytes),
+            // can't do anything with slices until we've seen the parameter sets
+            _ => return,
+        };
+        match self.track_id {
+            None => {
+                self.track_id = Some(self.store.allocate_hevc_track(
+                    sps.clone(),
+                    vps_bytes.clone(),
+                    sps_bytes.clone(),
+                    pps_bytes.clone(),
+                    self.max_bitrate,
+                ));
+            },
+            Some(track_id) => {
+                // the encoder may switch to new parameter sets (e.g. a new resolution) at an IRAP
+                // picture
+                if first_slice && header.is_irap() {
+                    self.store.set_hevc_parameter_sets(
+                        track_id,
+                        sps.clone(),
+                        vps_bytes.clone(),
+                        sps_bytes.clone(),
+                        pps_bytes.clone(),
+                    );
+                }
+            },
+        }
+        let (dts, pts) = if let Some(dts) = self.last_dts {
+            (
+                dts,
+                self.last_pts.unwrap_or(0),
+            )
+        } else {
+            if let Some(pts) = self.last_pts {
+                (
+                    pts,
+                    pts,
+                )
+            } else {
+                (0, 0)
+            }
+        };
+        let access_unit = self.access_unit.get_or_insert_with(|| AccessUnit {
+            header,
+            pts,
+            dts,
+            data: vec![],
+        });
+        access_unit.data.write_u32::<byteorder::BigEndian>(nal.len() as u32).unwrap();
+        access_unit.data.extend_from_slice(nal);
+    }
+
+    /// Pass any slices accumulated so far to the store as a single sample
+    fn flush_access_unit(&mut self) {
+        if let Some(access_unit) = self.access_unit.take() {
+            if let Some(track_id) = self.track_id {
+                self.store.add_hevc_sample(track_id, store::Sample {
+                    header: store::SampleHeader::Hevc(access_unit.header),
+                    data: access_unit.data,
+                    pts: access_unit.pts,
+                    dts: access_unit.dts,
+                });
+            }
+        }
+    }
+}
+impl pes::ElementaryStreamConsumer for H265ElementaryStreamConsumer {
+    fn start_stream(&mut self) {
+        println!("H265 start_steam()");
+    }
+    fn begin_packet(&mut self, header: pes::PesHeader) {
+        self.discarding = false;
+        match header.contents() {
+            pes::PesContents::Parsed(Some(parsed)) => {
+                match parsed.pts_dts() {
+                    Ok(pes::PtsDts::PtsOnly(Ok(pts))) => self.set_pts_dts(Some(pts), None),
+                    Ok(pes::PtsDts::Both{pts:Ok(pts), dts:Ok(dts)}) => self.set_pts_dts(Some(pts), Some(dts)),
+                    _ => self.set_pts_dts(None, None),
+                }
+                self.buf.extend_from_slice(parsed.payload());
+            },
+            pes::PesContents::Parsed(None) => println!("H265: Parsed(None)"),
+            pes::PesContents::Payload(payload) => {
+                println!("H265 {:?} payload", self.pid);
+                self.buf.extend_from_slice(payload);
+            },
+        }
+    }
+    fn continue_packet(&mut self, data: &[u8]) {
+        if !self.discarding {
+            self.buf.extend_from_slice(data);
+        }
+    }
+    fn end_packet(&mut self) {
+        if !self.discarding {
+            self.process_packet();
+        }
+    }
+    fn continuity_error(&mut self) {
+        println!("H265 {:?}: continuity error", self.pid);
+        // the rest of this PES packet, and the picture in progress, can't be trusted; the store
+        // will drop samples until the next IRAP picture
+        self.buf.clear();
+        self.discarding = true;
+        self.access_unit = None;
+        if let Some(track_id) = self.track_id {
+            self.store.mark_gap(track_id);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use mpeg2ts_reader::{packet, pes, psi};
+use crate::mpegts::IngestDemuxContext;
+use crate::store;
+
+/// Collects the ID3 tags carried in a timed metadata PES stream
+pub struct Id3ElementaryStreamConsumer {
+    store: store::Store,
+    pid: packet::Pid,
+    pts: Option<i64>,
+    buf: Vec<u8>,
+    unwrap_ts: super::UnwrapTimestamp,
+    discontinuity: super::DiscontinuityIndicator,
+}
+impl Id3ElementaryStreamConsumer {
+    pub fn construct(stream_info: &psi::pmt::StreamInfo, store: store::Store, discontinuity: super::DiscontinuityIndicator) -> pes::PesPacketFilter<IngestDemuxContext, Id3ElementaryStreamConsumer> {
+        pes::PesPacketFilter::new(
+            Id3ElementaryStreamConsumer {
+                store,
+                pid: stream_info.elementary_pid(),
+                pts: None,
+                buf: vec![],
+                unwrap_ts: super::UnwrapTimestamp::default(),
+                discontinuity,
+            }
+        )
+    }
+}
+impl pes::ElementaryStreamConsumer for Id3ElementaryStreamConsumer {
+    fn start_stream(&mut self) { }
+    fn begin_packet(&mut self, header: pes::PesHeader) {
+        self.buf.clear();
+        match header.contents() {
+            pes::PesContents::Parsed(Some(parsed)) => {
+                self.pts = match parsed.pts_dts() {
+                    Ok(pes::PtsDts::PtsOnly(Ok(pts))) | Ok(pes::PtsDts::Both { pts: Ok(pts), .. }) => {
+                        // tags may legitimately be many seconds apart, so unlike audio and video, a
+                        // jump in timestamps is not taken to be a discontinuity
+                        if self.discontinuity.take() {
+                            self.unwrap_ts = super::UnwrapTimestamp::default();
+                        }
+                        self.unwrap_ts.update(pts);
+                        Some(self.unwrap_ts.unwrap(pts))
+                    },
+                    _ => None,
+                };
+                self.buf.extend_from_slice(parsed.payload());
+            },
+            pes::PesContents::Parsed(None) => (),
+            pes::PesContents::Payload(_) => {
+                println!("ID3 {:?}: unexpected PES packet without a header", self.pid);
+                self.pts = None;
+            },
+        }
+    }
+    fn continue_packet(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+    fn end_packet(&mut self) {
+        match self.pts {
+            Some(pts) if self.buf.starts_with(b"ID3") => {
+                let data = std::mem::replace(&mut self.buf, vec![]);
+                self.store.add_timed_metadata(pts, data);
+            },
+            Some(_) => println!("ID3 {:?}: ignoring PES packet that doesn't start with an ID3 tag", self.pid),
+            None => println!("ID3 {:?}: ignoring PES packet without a PTS", self.pid),
+        }
+        self.buf.clear();
+    }
+    fn continuity_error(&mut self) {
+        self.buf.clear();
+        self.pts = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mpeg2ts_reader::pes::ElementaryStreamConsumer;
+
+    /// an empty ID3v2.4 tag
+    const TAG: &[u8] = b"ID3\x04\x00\x00\x00\x00\x00\x00";
+
+    /// A private_stream_1 PES packet with the given PTS, as carried in a timed metadata stream
+    fn pes_packet(pts: u64, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![
+            0x00, 0x00, 0x01, 0xbd, 0x00, 0x00,
+            0x84, 0x80, 0x05,
+            0x21 | ((pts >> 29) & 0x0e) as u8,
+            (pts >> 22) as u8,
+            0x01 | ((pts >> 14) & 0xfe) as u8,
+            (pts >> 7) as u8,
+            0x01 | ((pts << 1) & 0xfe) as u8,
+        ];
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn consumer(store: &store::Store) -> Id3ElementaryStreamConsumer {
+        Id3ElementaryStreamConsumer {
+            store: store.clone(),
+            pid: packet::Pid::new(0x102),
+            pts: None,
+            buf: vec![],
+            unwrap_ts: crate::mpegts::UnwrapTimestamp::default(),
+            discontinuity: crate::mpegts::DiscontinuityIndicator::default(),
+        }
+    }
+
+    fn metadata(store: &mut store::Store, track_id: store::TrackId) -> Vec<store::TimedMetadata> {
+        store.get_track(track_id).unwrap().timed_metadata_between(0, std::i64::MAX)
+    }
+
+    #[test]
+    fn tag_across_ts_packets() {
+        let mut store = store::Store::new();
+        let track_id = store.allocate_aac_track(
+            adts_reader::AudioObjectType::AacLC,
+            adts_reader::SamplingFrequency::Freq48000,
+            adts_reader::ChannelConfiguration::Stereo,
+            None,
+            store::AudioInfo::default(),
+        );
+        let mut consumer = consumer(&store);
+        let packet = pes_packet(90000, &TAG[..4]);
+        consumer.begin_packet(pes::PesHeader::from_bytes(&packet[..]).unwrap());
+        consumer.continue_packet(&TAG[4..]);
+        consumer.end_packet();
+        let metadata = metadata(&mut store, track_id);
+        assert_eq!(1, metadata.len());
+        assert_eq!(90000, metadata[0].pts);
+        assert_eq!(TAG, &metadata[0].data[..]);
+
+        // anything other than an ID3 tag is ignored
+        let packet = pes_packet(180000, b"not ID3");
+        consumer.begin_packet(pes::PesHeader::from_bytes(&packet[..]).unwrap());
+        consumer.end_packet();
+        assert_eq!(1, metadata(&mut store, track_id).len());
+    }
+}
@@ -1,22 +1,37 @@
 use mpeg2ts_reader::{
     StreamType,
     demultiplex,
+    descriptor,
     packet,
     pes,
+    psi,
 };
+use mpeg2ts_reader::descriptor::iso_639_language::AudioType;
 use crate::store;
 use mpeg2ts_reader::pes::Timestamp;
+use std::cell::Cell;
+use std::rc::Rc;
 
 mod h264;
+mod h265;
 mod adts;
+mod ac3;
+mod scte35;
+mod teletext;
+mod id3;
 
 mpeg2ts_reader::packet_filter_switch! {
     IngestFilterSwitch<IngestDemuxContext> {
         Pat: demultiplex::PatPacketFilter<IngestDemuxContext>,
         Pmt: demultiplex::PmtPacketFilter<IngestDemuxContext>,
         Null: demultiplex::NullPacketFilter<IngestDemuxContext>,
-        H264: pes::PesPacketFilter<IngestDemuxContext, h264::H264ElementaryStreamConsumer>,
-        Adts: pes::PesPacketFilter<IngestDemuxContext, adts::AdtsElementaryStreamConsumer>,
+        H264: DiscontinuityWatch<pes::PesPacketFilter<IngestDemuxContext, h264::H264ElementaryStreamConsumer>>,
+        H265: DiscontinuityWatch<pes::PesPacketFilter<IngestDemuxContext, h265::H265ElementaryStreamConsumer>>,
+        Adts: DiscontinuityWatch<pes::PesPacketFilter<IngestDemuxContext, adts::AdtsElementaryStreamConsumer>>,
+        Ac3: DiscontinuityWatch<pes::PesPacketFilter<IngestDemuxContext, ac3::Ac3ElementaryStreamConsumer>>,
+        Scte35: scte35::Scte35PacketFilter,
+        Teletext: pes::PesPacketFilter<IngestDemuxContext, teletext::TeletextElementaryStreamConsumer>,
+        Id3: DiscontinuityWatch<pes::PesPacketFilter<IngestDemuxContext, id3::Id3ElementaryStreamConsumer>>,
     }
 }
 pub struct IngestDemuxContext {
@@ -55,11 +70,39 @@ impl demultiplex::DemuxContext for IngestDemuxContext {
 
             demultiplex::FilterRequest::ByStream {
                 program_pid, stream_type: StreamType::H264, pmt, stream_info,
-            } => IngestFilterSwitch::H264(h264::H264ElementaryStreamConsumer::construct(stream_info, self.store.clone())),
+            } => IngestFilterSwitch::H264(DiscontinuityWatch::construct(|d| h264::H264ElementaryStreamConsumer::construct(stream_info, self.store.clone(), d))),
+
+            demultiplex::FilterRequest::ByStream {
+                program_pid, stream_type: StreamType::H265, pmt, stream_info,
+            } => IngestFilterSwitch::H265(DiscontinuityWatch::construct(|d| h265::H265ElementaryStreamConsumer::construct(stream_info, self.store.clone(), d))),
 
             demultiplex::FilterRequest::ByStream {
                 program_pid, stream_type: StreamType::Adts, pmt, stream_info,
-            } => IngestFilterSwitch::Adts(adts::AdtsElementaryStreamConsumer::construct(stream_info, self.store.clone())),
+            } => IngestFilterSwitch::Adts(DiscontinuityWatch::construct(|d| adts::AdtsElementaryStreamConsumer::construct(stream_info, self.store.clone(), d))),
+
+            demultiplex::FilterRequest::ByStream {
+                program_pid, stream_type: ac3::AC3_STREAM_TYPE, pmt, stream_info,
+            } | demultiplex::FilterRequest::ByStream {
+                program_pid, stream_type: ac3::EAC3_STREAM_TYPE, pmt, stream_info,
+            } => IngestFilterSwitch::Ac3(DiscontinuityWatch::construct(|d| ac3::Ac3ElementaryStreamConsumer::construct(stream_info, self.store.clone(), d))),
+
+            demultiplex::FilterRequest::ByStream {
+                program_pid, stream_type: scte35::SCTE35_STREAM_TYPE, pmt, stream_info,
+            } => IngestFilterSwitch::Scte35(scte35::Scte35PacketFilter::construct(stream_info, self.store.clone())),
+
+            demultiplex::FilterRequest::ByStream {
+                program_pid, stream_type: StreamType::H2220PesPrivateData, pmt, stream_info,
+            } if !teletext::subtitle_pages(stream_info).is_empty()
+                => IngestFilterSwitch::Teletext(teletext::TeletextElementaryStreamConsumer::construct(stream_info, self.store.clone())),
+
+            demultiplex::FilterRequest::ByStream {
+                program_pid, stream_type: StreamType::H2220PesPrivateData, pmt, stream_info,
+            } if ac3::is_dvb_ac3(stream_info)
+                => IngestFilterSwitch::Ac3(DiscontinuityWatch::construct(|d| ac3::Ac3ElementaryStreamConsumer::construct(stream_info, self.store.clone(), d))),
+
+            demultiplex::FilterRequest::ByStream {
+                program_pid, stream_type: StreamType::MetadataInPes, pmt, stream_info,
+            } => IngestFilterSwitch::Id3(DiscontinuityWatch::construct(|d| id3::Id3ElementaryStreamConsumer::construct(stream_info, self.store.clone(), d))),
 
             demultiplex::FilterRequest::ByStream { .. } => {
                 eprintln!("Ignoring {:?}", req);
@@ -83,6 +126,91 @@ pub fn create_demux(store: store::Store) -> (IngestDemuxContext, demultiplex::De
     (ctx, demux)
 }
 
+/// Set when a TS packet carrying an elementary stream has the adaptation field
+/// `discontinuity_indicator` set, until taken by the consumer of that stream
+#[derive(Clone, Default)]
+pub struct DiscontinuityIndicator(Rc<Cell<bool>>);
+impl DiscontinuityIndicator {
+    /// Returns `true` if a discontinuity has been indicated since the last call
+    pub fn take(&self) -> bool {
+        self.0.replace(false)
+    }
+}
+
+/// Wraps the packet filter of an elementary stream, to note any `discontinuity_indicator` in the
+/// stream's TS packets
+pub struct DiscontinuityWatch<F> {
+    indicator: DiscontinuityIndicator,
+    inner: F,
+}
+impl<F> DiscontinuityWatch<F> {
+    fn construct(f: impl FnOnce(DiscontinuityIndicator) -> F) -> DiscontinuityWatch<F> {
+        let indicator = DiscontinuityIndicator::default();
+        DiscontinuityWatch {
+            inner: f(indicator.clone()),
+            indicator,
+        }
+    }
+}
+impl<F: demultiplex::PacketFilter<Ctx = IngestDemuxContext>> demultiplex::PacketFilter for DiscontinuityWatch<F> {
+    type Ctx = IngestDemuxContext;
+
+    fn consume(&mut self, ctx: &mut Self::Ctx, pk: &packet::Packet<'_>) {
+        if pk.adaptation_field().map(|af| af.discontinuity_indicator() ).unwrap_or(false) {
+            self.indicator.0.set(true);
+        }
+        self.inner.consume(ctx, pk);
+    }
+}
+
+/// Reads the descriptors of an audio stream that apply whatever the codec, returning the value of
+/// any `maximum_bitrate_descriptor` along with the audio metadata.  Other descriptors are passed
+/// to the given function.
+fn audio_descriptors<F>(stream_info: &psi::pmt::StreamInfo, name: &str, mut other: F) -> (Option<u32>, store::AudioInfo)
+    where
+        F: FnMut(&mut store::AudioInfo, descriptor::CoreDescriptors<'_>)
+{
+    let mut max_bitrate = None;
+    let mut info = store::AudioInfo::default();
+    for desc in stream_info.descriptors::<descriptor::CoreDescriptors>() {
+        match desc {
+            Ok(d) => match d {
+                descriptor::CoreDescriptors::MaximumBitrate(max) => {
+                    max_bitrate = Some(max.maximum_bits_per_second());
+                }
+                descriptor::CoreDescriptors::ISO639Language(lang) => {
+                    // the descriptor may list several languages, but we only expect one per
+                    // audio stream in practice, so take the first
+                    match lang.languages().next() {
+                        Some(Ok(l)) => {
+                            match l.code(encoding::DecoderTrap::Replace) {
+                                Ok(code) => info.language = Some(code),
+                                Err(e) => println!("  {} {:?}: Bad language code: {:?}", name, stream_info.elementary_pid(), e),
+                            }
+                            info.audio_type = match l.audio_type() {
+                                AudioType::CleanEffects => store::AudioType::CleanEffects,
+                                AudioType::HearingImpaired => store::AudioType::HearingImpaired,
+                                AudioType::VisualImpairedCommentary => store::AudioType::VisualImpairedCommentary,
+                                AudioType::Undefined | AudioType::Reserved(_) => store::AudioType::Main,
+                            };
+                        },
+                        Some(Err(e)) => println!("  {} {:?}: Bad language descriptor: {:?}", name, stream_info.elementary_pid(), e),
+                        None => (),
+                    }
+                }
+                d => other(&mut info, d),
+            }
+            Err(e) => println!("  {} {:?}: Error reading descriptor: {:?}", name, stream_info.elementary_pid(), e),
+        }
+    }
+    (max_bitrate, info)
+}
+
+/// Jumps in timestamps larger than these (in 90kHz units) are taken to be discontinuities in the
+/// input (e.g. due to an encoder restart) rather than just missing data
+const MAX_TIMESTAMP_JUMP_FORWARD: i64 = 5 * 90000;
+const MAX_TIMESTAMP_JUMP_BACKWARD: i64 = 90000;
+
 struct UnwrapTimestamp {
     last: Option<Timestamp>,
     carry: u64,
@@ -113,6 +241,17 @@ impl UnwrapTimestamp {
         }
     }
 
+    /// Is the given timestamp implausibly far from the one passed to the last `update()`?
+    fn is_discontinuous(&self, ts: Timestamp) -> bool {
+        match self.last {
+            Some(last) => {
+                let diff = self.unwrap(ts) - self.unwrap(last);
+                diff > MAX_TIMESTAMP_JUMP_FORWARD || diff < -MAX_TIMESTAMP_JUMP_BACKWARD
+            },
+            None => false,
+        }
+    }
+
     fn update(&mut self, ts: Timestamp) {
         if let Some (last) = self.last {
             let half = (Timestamp::MAX.value() / 2) as i64;
@@ -163,4 +302,31 @@ mod test {
         assert_eq!(c, -1 as i64);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn discontinuous() {
+        let mut unwrap = UnwrapTimestamp::default();
+        assert!(!unwrap.is_discontinuous(Timestamp::from_u64(0)));
+        unwrap.update(Timestamp::MAX);
+        assert!(!unwrap.is_discontinuous(Timestamp::from_u64(3600)));
+        assert!(unwrap.is_discontinuous(Timestamp::from_u64(90000 * 10)));
+        assert!(unwrap.is_discontinuous(Timestamp::from_u64(Timestamp::MAX.value() - 90000 * 10)));
+    }
+
+    #[test]
+    fn language_descriptor() {
+        // a PMT listing a single ADTS stream, with an ISO_639_language_descriptor ("eng", visual
+        // impaired commentary) and a maximum_bitrate_descriptor (400kbit/s)
+        let data = [
+            0xe1, 0x00, 0xf0, 0x00,
+            0x0f, 0xe1, 0x01, 0xf0, 0x0b,
+            0x0a, 0x04, b'e', b'n', b'g', 0x03,
+            0x0e, 0x03, 0xc0, 0x03, 0xe8,
+        ];
+        let pmt = psi::pmt::PmtSection::from_bytes(&data[..]).unwrap();
+        let stream_info = pmt.streams().next().unwrap().unwrap();
+        let (max_bitrate, info) = audio_descriptors(&stream_info, "ADTS", |_, _| ());
+        assert_eq!(Some(400_000), max_bitrate);
+        assert_eq!(Some("eng"), info.language.as_ref().map(|l| &l[..] ));
+        assert_eq!(store::AudioType::VisualImpairedCommentary, info.audio_type);
+    }
+}
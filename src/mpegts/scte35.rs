@@ -0,0 +1,112 @@
+use mpeg2ts_reader::{demultiplex, packet, psi};
+use mpeg2ts_reader::pes::Timestamp;
+use crate::mpegts::IngestDemuxContext;
+use crate::scte35;
+use crate::store;
+
+/// SCTE-35 is carried in a private stream type
+pub const SCTE35_STREAM_TYPE: mpeg2ts_reader::StreamType = mpeg2ts_reader::StreamType::Private(0x86);
+
+pub struct SpliceInfoProcessor {
+    pid: packet::Pid,
+    store: store::Store,
+}
+impl SpliceInfoProcessor {
+    /// The splice time is left as a 33-bit value, for the store to unwrap relative to the
+    /// samples it has received
+    fn splice_pts(pts_time: Option<u64>, pts_adjustment: u64) -> Option<i64> {
+        pts_time.map(|pts_time| ((pts_time + pts_adjustment) & Timestamp::MAX.value()) as i64 )
+    }
+
+    fn process(&mut self, data: &[u8]) {
+        let section = match scte35::SpliceInfoSection::parse(data) {
+            Ok(section) => section,
+            Err(e) => {
+                println!("SCTE-35 {:?}: problem parsing splice_info_section: {:?}", self.pid, e);
+                return;
+            }
+        };
+        let pts_adjustment = section.pts_adjustment;
+        match section.command {
+            scte35::SpliceCommand::SpliceInsert(insert) => {
+                let id = format!("splice-{}", insert.splice_event_id);
+                if insert.splice_event_cancel {
+                    self.store.cancel_splice_event(&id);
+                    return;
+                }
+                let pts = if insert.splice_immediate {
+                    None
+                } else {
+                    Self::splice_pts(insert.pts_time, pts_adjustment)
+                };
+                self.store.add_splice_event(store::SpliceEvent {
+                    id,
+                    kind: if insert.out_of_network { store::SpliceKind::Out } else { store::SpliceKind::In },
+                    pts,
+                    duration: insert.break_duration.map(|d| d as i64 ),
+                    section: data.to_vec(),
+                });
+            },
+            scte35::SpliceCommand::TimeSignal { pts_time } => {
+                let pts = Self::splice_pts(pts_time, pts_adjustment);
+                let id = match pts {
+                    Some(pts) => format!("signal-{}", pts),
+                    // time_signal is not expected to be 'immediate'
+                    None => return,
+                };
+                self.store.add_splice_event(store::SpliceEvent {
+                    id,
+                    kind: store::SpliceKind::Cmd,
+                    pts,
+                    duration: None,
+                    section: data.to_vec(),
+                });
+            },
+            // splice_null is just a heartbeat
+            scte35::SpliceCommand::SpliceNull => (),
+            scte35::SpliceCommand::Other(command_type) => {
+                println!("SCTE-35 {:?}: ignoring splice_command_type {:#x}", self.pid, command_type);
+            },
+        }
+    }
+}
+impl psi::WholeCompactSyntaxPayloadParser for SpliceInfoProcessor {
+    type Context = IngestDemuxContext;
+
+    fn section<'a>(&mut self, _ctx: &mut Self::Context, header: &psi::SectionCommonHeader, data: &'a [u8]) {
+        if header.table_id == scte35::SpliceInfoSection::TABLE_ID {
+            self.process(data);
+        }
+    }
+}
+
+pub struct Scte35PacketFilter {
+    consumer: psi::SectionPacketConsumer<
+        psi::CompactSyntaxSectionProcessor<
+            psi::BufferCompactSyntaxParser<SpliceInfoProcessor>
+        >
+    >,
+}
+impl Scte35PacketFilter {
+    pub fn construct(stream_info: &psi::pmt::StreamInfo, store: store::Store) -> Scte35PacketFilter {
+        Scte35PacketFilter {
+            consumer: psi::SectionPacketConsumer::new(
+                psi::CompactSyntaxSectionProcessor::new(
+                    psi::BufferCompactSyntaxParser::new(
+                        SpliceInfoProcessor {
+                            pid: stream_info.elementary_pid(),
+                            store,
+                        }
+                    )
+                )
+            ),
+        }
+    }
+}
+impl demultiplex::PacketFilter for Scte35PacketFilter {
+    type Ctx = IngestDemuxContext;
+
+    fn consume(&mut self, ctx: &mut Self::Ctx, pk: &packet::Packet<'_>) {
+        self.consumer.consume(ctx, pk);
+    }
+}
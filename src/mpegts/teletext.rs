@@ -0,0 +1,118 @@
+use mpeg2ts_reader::{packet, pes, psi, descriptor};
+use crate::mpegts::IngestDemuxContext;
+use crate::store;
+use crate::teletext;
+
+/// Tag of the DVB `teletext_descriptor`
+const TELETEXT_DESCRIPTOR_TAG: u8 = 0x56;
+
+/// The subtitle pages signalled in the `teletext_descriptor` of the given stream, if any
+pub fn subtitle_pages(stream_info: &psi::pmt::StreamInfo) -> Vec<teletext::SubtitlePage> {
+    let mut pages = vec![];
+    for desc in stream_info.descriptors::<descriptor::CoreDescriptors>() {
+        match desc {
+            Ok(descriptor::CoreDescriptors::UserPrivate(d)) if d.tag == TELETEXT_DESCRIPTOR_TAG => {
+                pages.extend(teletext::subtitle_pages(d.payload));
+            },
+            Ok(_) => (),
+            Err(e) => println!("  Teletext {:?}: Error reading descriptor: {:?}", stream_info.elementary_pid(), e),
+        }
+    }
+    pages
+}
+
+struct Page {
+    decoder: teletext::PageDecoder,
+    track_id: store::TrackId,
+}
+
+pub struct TeletextElementaryStreamConsumer {
+    store: store::Store,
+    pid: packet::Pid,
+    pages: Vec<Page>,
+    pts: Option<i64>,
+    buf: Vec<u8>,
+    unwrap_ts: super::UnwrapTimestamp,
+}
+impl TeletextElementaryStreamConsumer {
+    pub fn construct(stream_info: &psi::pmt::StreamInfo, mut store: store::Store) -> pes::PesPacketFilter<IngestDemuxContext, TeletextElementaryStreamConsumer> {
+        let pages = subtitle_pages(stream_info)
+            .iter()
+            .map(|page| {
+                println!("Teletext {:?}: subtitle page {:x}{:02x} ({})", stream_info.elementary_pid(), page.magazine, page.page, page.language);
+                let track_id = store.allocate_subtitle_track(store::SubtitleInfo {
+                    language: Some(page.language.clone()),
+                    name: format!("Teletext {:x}{:02x}", page.magazine, page.page),
+                    hearing_impaired: page.hearing_impaired,
+                });
+                Page {
+                    decoder: teletext::PageDecoder::new(page),
+                    track_id,
+                }
+            })
+            .collect();
+        pes::PesPacketFilter::new(
+            TeletextElementaryStreamConsumer {
+                store,
+                pid: stream_info.elementary_pid(),
+                pages,
+                pts: None,
+                buf: vec![],
+                unwrap_ts: super::UnwrapTimestamp::default(),
+            }
+        )
+    }
+
+    fn process(&mut self) {
+        let pts = match self.pts {
+            Some(pts) => pts,
+            None => return,
+        };
+        let pages = &mut self.pages;
+        let store = &mut self.store;
+        teletext::for_each_packet(&self.buf[..], |packet| {
+            for page in pages.iter_mut() {
+                if let Some((pts, text)) = page.decoder.push(pts, packet) {
+                    store.set_subtitle_display(page.track_id, pts, text);
+                }
+            }
+        });
+    }
+}
+impl pes::ElementaryStreamConsumer for TeletextElementaryStreamConsumer {
+    fn start_stream(&mut self) { }
+    fn begin_packet(&mut self, header: pes::PesHeader) {
+        self.buf.clear();
+        match header.contents() {
+            pes::PesContents::Parsed(Some(parsed)) => {
+                self.pts = match parsed.pts_dts() {
+                    Ok(pes::PtsDts::PtsOnly(Ok(pts))) | Ok(pes::PtsDts::Both { pts: Ok(pts), .. }) => {
+                        if self.unwrap_ts.is_discontinuous(pts) {
+                            self.unwrap_ts = super::UnwrapTimestamp::default();
+                        }
+                        self.unwrap_ts.update(pts);
+                        Some(self.unwrap_ts.unwrap(pts))
+                    },
+                    _ => None,
+                };
+                self.buf.extend_from_slice(parsed.payload());
+            },
+            pes::PesContents::Parsed(None) => (),
+            pes::PesContents::Payload(_) => {
+                println!("Teletext {:?}: unexpected PES packet without a header", self.pid);
+                self.pts = None;
+            },
+        }
+    }
+    fn continue_packet(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+    fn end_packet(&mut self) {
+        self.process();
+        self.buf.clear();
+    }
+    fn continuity_error(&mut self) {
+        self.buf.clear();
+        self.pts = None;
+    }
+}
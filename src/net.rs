@@ -25,6 +25,33 @@ fn for_each_rtp<F>(socket: UdpSocket, f: F) -> impl Future<Item=(), Error=()>
         .map_err(|_| () )
 }
 
+/// The segment and part target durations, which may be given in seconds by the
+/// `SEGMENT_DURATION` and `PART_DURATION` environment variables
+fn segment_targets() -> store::SegmentTargets {
+    let defaults = store::SegmentTargets::default();
+    let segment = duration_from_env("SEGMENT_DURATION").unwrap_or(defaults.segment);
+    let part = duration_from_env("PART_DURATION").unwrap_or(defaults.part);
+    if part > segment {
+        println!("Part duration {} exceeds segment duration {}; using the segment duration", part, segment);
+    }
+    store::SegmentTargets {
+        segment,
+        part: std::cmp::min(part, segment),
+    }
+}
+
+/// The duration in seconds given by the named environment variable, in 90kHz units
+fn duration_from_env(name: &str) -> Option<i64> {
+    let value = std::env::var(name).ok()?;
+    match value.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs <= 60.0 => Some((secs * 90000.0).round() as i64),
+        _ => {
+            println!("Ignoring bad {} value {:?}", name, value);
+            None
+        },
+    }
+}
+
 pub fn tokio_main() {
     let addr = "0.0.0.0:5000".parse::<SocketAddr>().unwrap();
     let mut core = Core::new().unwrap();
@@ -36,7 +63,7 @@ pub fn tokio_main() {
     socket.join_multicast_v4(&group, &iface).expect("failed to join multicast group");
     */
 
-    let store = store::Store::new();
+    let store = store::Store::with_targets(segment_targets());
 
     let mut expected_seq = None;
     let (mut ctx, mut demux) = crate::mpegts::create_demux(store.clone());
@@ -4,14 +4,38 @@ use h264_reader::nal;
 use std::collections::vec_deque;
 use std::iter::Peekable;
 use h264_reader::nal::UnitType;
-use itertools::Itertools;
 use tokio_sync::watch;
+use crate::hevc;
+use crate::ac3;
+use crate::captions::CaptionServices;
 use std::cmp;
-use itertools::misc::Slice;
 
 pub const SEG_DURATION_PTS: u64 = 172800;
 
-const ARCHIVE_LIMIT: u64 = 60 * 60 * 90000;  // 1 hour
+/// Default duration of parts, if not otherwise configured
+pub const PART_DURATION_PTS: u64 = 28800;
+
+pub const ARCHIVE_LIMIT: u64 = 60 * 60 * 90000;  // 1 hour
+
+/// Number of the most recent segments over which the bitrate of a track is measured
+const BITRATE_WINDOW_SEGMENTS: usize = 30;
+
+/// Allowance for the PES header of each sample carried in MPEG-2 TS, along with any access unit
+/// delimiter and PCR
+const TS_SAMPLE_OVERHEAD: usize = 32;
+/// The PAT and PMT at the start of each MPEG-2 TS segment
+const TS_SEGMENT_OVERHEAD: usize = 2 * 188;
+/// Allowance for the `trun` entry describing each sample carried in fMP4
+const FMP4_SAMPLE_OVERHEAD: usize = 16;
+/// Allowance for the `moof` and `mdat` box headers of each fMP4 segment
+const FMP4_SEGMENT_OVERHEAD: usize = 128;
+
+/// Space left on the output timeline at a discontinuity in the input timestamps, so that tracks
+/// which had been running slightly behind the others don't appear to go backwards
+const DISCONTINUITY_MARGIN_PTS: i64 = 90000;
+
+/// The point at which 33-bit MPEG timestamps wrap around
+const PTS_WRAP: i64 = 1 << 33;
 
 pub struct Sample {
     pub data: Vec<u8>,
@@ -20,9 +44,25 @@ pub struct Sample {
     pub header: SampleHeader,
 }
 
+impl Sample {
+    /// True for samples at which decoding can begin
+    pub fn is_sync(&self) -> bool {
+        is_sync(self)
+    }
+}
+
 pub enum SampleHeader {
     Avc(nal::NalHeader, nal::slice::SliceHeader),
+    /// header of the first VCL NAL unit in the access unit
+    Hevc(hevc::NalHeader),
     Aac,
+    Ac3,
+    /// Stands in for a video sample in the timeline of a subtitle track, so that subtitle
+    /// segments line up with video segments
+    Subtitle { sync: bool },
+    /// Stands in for media that was lost from the input (e.g. due to a continuity error), so
+    /// that the missing period can be advertised as a segment with `EXT-X-GAP`
+    Gap,
 }
 
 #[derive(Debug)]
@@ -34,6 +74,25 @@ pub enum SegmentError {
     /// Tried to inspect the parts for a segment, but the segment does not have any parts (hls
     /// says only the very most recent segments should present parts)
     NoPartsForSegment,
+    /// The requested part of the segment does not exist (yet)
+    PartNotFound(u64),
+}
+
+/// The durations (in 90kHz units) that ingested media is divided against when producing
+/// segments and parts.  Actual durations will vary depending on the timing of samples, and the
+/// positions of IDR frames in video tracks.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentTargets {
+    pub segment: i64,
+    pub part: i64,
+}
+impl Default for SegmentTargets {
+    fn default() -> Self {
+        SegmentTargets {
+            segment: SEG_DURATION_PTS as i64,
+            part: PART_DURATION_PTS as i64,
+        }
+    }
 }
 
 /// Notification value used to describe updates to a track in the face of media being ingested
@@ -43,68 +102,242 @@ pub struct TrackSequence {
     pub part: u16,
 }
 
-pub struct AvcTrack {
-    sps: nal::sps::SeqParameterSet,
-    pps: nal::pps::PicParameterSet,
-    sps_bytes: Vec<u8>,
-    pps_bytes: Vec<u8>,
+/// The container in which samples are delivered, which determines how much the packaging adds to
+/// the size of the media
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Packaging {
+    Fmp4,
+    Ts,
+}
+impl Packaging {
+    fn segment_overhead(self) -> usize {
+        match self {
+            Packaging::Fmp4 => FMP4_SEGMENT_OVERHEAD,
+            Packaging::Ts => TS_SEGMENT_OVERHEAD,
+        }
+    }
+
+    /// The approximate size of a sample once packaged
+    fn sample_size(self, sample: &Sample) -> usize {
+        match self {
+            Packaging::Fmp4 => sample.data.len() + FMP4_SAMPLE_OVERHEAD,
+            Packaging::Ts => {
+                let payload = sample.data.len() + TS_SAMPLE_OVERHEAD;
+                (payload + 183) / 184 * 188
+            },
+        }
+    }
+}
+
+/// The bitrate of a track, in bits per second, measured from the sizes of recent segments
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bitrate {
+    /// the highest bitrate of any single segment
+    pub peak: u32,
+    pub average: u32,
+}
+
+/// A frame rate, expressed as the ratio `num / den` frames per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameRate {
+    pub num: u32,
+    pub den: u32,
+}
+impl FrameRate {
+    /// Frame rate as signalled in the SPS VUI `timing_info`, if present
+    pub fn from_sps(sps: &nal::sps::SeqParameterSet) -> Option<FrameRate> {
+        let timing_info = sps.vui_parameters.as_ref()?.timing_info.as_ref()?;
+        if timing_info.num_units_in_tick == 0 || timing_info.time_scale == 0 {
+            return None;
+        }
+        // there are two ticks per frame (one per field); a frame duration too long to represent
+        // is as good as no frame rate at all
+        Some(FrameRate {
+            num: timing_info.time_scale,
+            den: timing_info.num_units_in_tick.checked_mul(2)?,
+        })
+    }
+
+    /// Frame rate as signalled in the HEVC SPS VUI `timing_info`, if present; unlike AVC, each tick
+    /// is a whole picture
+    pub fn from_hevc_sps(sps: &hevc::SeqParameterSet) -> Option<FrameRate> {
+        let timing_info = sps.timing_info.as_ref()?;
+        if timing_info.num_units_in_tick == 0 || timing_info.time_scale == 0 {
+            return None;
+        }
+        Some(FrameRate {
+            num: timing_info.time_scale,
+            den: timing_info.num_units_in_tick,
+        })
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    /// The duration of a single frame, in 90kHz units
+    pub fn frame_duration(&self) -> u32 {
+        (90000 * self.den as u64 / self.num as u64) as u32
+    }
+
+    /// The whole number of frames per second counted by timecodes (e.g. 30 for 29.97fps)
+    pub fn timecode_rate(&self) -> u64 {
+        self.as_f64().round() as u64
+    }
+}
+
+/// The samples of a single track, and the division of those samples into segments and parts
+pub struct Timeline {
     samples: VecDeque<Sample>,
-    max_bitrate: Option<u32>,
     watch: (watch::Sender<TrackSequence>, watch::Receiver<TrackSequence>),
     first_seg_num: usize,
+    targets: SegmentTargets,
+    /// if true, segments may only start with a sync sample (i.e. a video IDR frame)
+    sync_segments: bool,
+    /// timestamps at which a new segment should start, regardless of the target duration
+    splice_points: Vec<i64>,
+    /// the next sample is the first following a discontinuity in the input
+    pending_discontinuity: bool,
+    /// timestamps of the segments that follow a discontinuity
+    discontinuities: Vec<i64>,
+    /// number of discontinuities in segments that have been removed from the timeline
+    discontinuity_sequence: u64,
 }
-impl AvcTrack {
-    fn new(
-        sps: nal::sps::SeqParameterSet,
-        pps: nal::pps::PicParameterSet,
-        sps_bytes: Vec<u8>,
-        pps_bytes: Vec<u8>,
-        max_bitrate: Option<u32>
-    ) -> AvcTrack {
-        AvcTrack {
-            sps,
-            pps,
-            sps_bytes,
-            pps_bytes,
+impl Timeline {
+    fn new(targets: SegmentTargets, sync_segments: bool) -> Timeline {
+        Timeline {
             samples: VecDeque::new(),
-            max_bitrate,
             watch: watch::channel(TrackSequence::default()),
             first_seg_num: 0,
+            targets,
+            sync_segments,
+            splice_points: vec![],
+            pending_discontinuity: false,
+            discontinuities: vec![],
+            discontinuity_sequence: 0,
         }
     }
 
-    pub fn push(&mut self, sample: Sample) {
+    fn push(&mut self, sample: Sample) {
+        if self.sync_segments && self.samples.is_empty() && !is_sync(&sample) {
+            // segments must start with a sync sample, so media prior to the first one is
+            // useless to us
+            return;
+        }
+        if let Some(gap) = self.samples.back().filter(|s| is_gap(s) ) {
+            if (self.sync_segments && !is_sync(&sample)) || sample.dts <= gap.dts {
+                // decoding can't resume until the next sync sample
+                return;
+            }
+            // the first sample after a gap must start a new segment
+            self.add_splice_point(sample.dts);
+        }
+        if self.pending_discontinuity {
+            if self.sync_segments && !is_sync(&sample) {
+                return;
+            }
+            self.pending_discontinuity = false;
+            if !self.samples.is_empty() {
+                self.add_splice_point(sample.dts);
+                self.discontinuities.push(sample.dts);
+            }
+        }
         self.samples.push_back(sample);
+        while self.duration() > ARCHIVE_LIMIT {
+            self.remove_one_segment();
+        }
         // TODO: pretty inefficient!
-        if let Some(this_seg) = self.segments().last() {
+        let (prev_seg, this_seg) = self.segments().fold((None, None), |(_, last), seg| (last, Some(seg)) );
+        if let Some(this_seg) = this_seg {
             let parts = self.parts(this_seg.id());
             if let Err(e) = parts {
                 // How is this even possible?
                 println!("Problem trying to get parts for segment {:?}: {:?}", this_seg, e);
             } else {
                 let count = parts.unwrap().count();
-                if count > 0 {
-                    let this_part_num = count - 1;
+                // a sample starting a new segment completes the final part of the previous one
+                let latest = if count > 0 {
+                    Some((this_seg.sequence_number(), count))
+                } else {
+                    prev_seg.and_then(|prev| {
+                        let count = self.parts(prev.id()).ok()?.count();
+                        Some((prev.sequence_number(), count))
+                    })
+                };
+                if let Some((seg, count)) = latest.filter(|&(_, count)| count > 0 ) {
                     let seq = TrackSequence {
-                        seg: this_seg.sequence_number(),
-                        part: this_part_num as u16,
+                        seg,
+                        part: (count - 1) as u16,
                     };
                     self.watch.0.broadcast(seq).unwrap()
                 }
             }
         }
-        while self.duration() > ARCHIVE_LIMIT {
-            self.remove_one_segment();
-        }
     }
+
     fn remove_one_segment(&mut self) {
-        let mut i = 0;
-        while i == 0 || (!self.samples.is_empty() && !is_idr(&self.samples[0])) {
+        let count = match self.samples.front() {
+            Some(first) => segment_range(&self.samples, first.dts, self.starts_segment()).unwrap().count,
+            None => return,
+        };
+        for _ in 0..count {
             self.samples.pop_front();
-            i += 1;
         }
         self.first_seg_num += 1;
+        if let Some(first) = self.samples.front() {
+            let first_dts = first.dts;
+            self.splice_points.retain(|&p| p > first_dts );
+            let removed = self.discontinuities.iter().filter(|&&d| d < first_dts ).count();
+            self.discontinuity_sequence += removed as u64;
+            self.discontinuities.retain(|&d| d >= first_dts );
+        }
+    }
+
+    /// Arranges for the next sample to start a new segment, which will be marked as not being
+    /// continuous with the one before.  For tracks which require it, samples are dropped until
+    /// the next sync sample.
+    fn mark_discontinuity(&mut self) {
+        self.pending_discontinuity = true;
     }
+
+    /// Value for `EXT-X-DISCONTINUITY-SEQUENCE`
+    pub fn discontinuity_sequence(&self) -> u64 {
+        self.discontinuity_sequence
+    }
+
+    /// Notes that media was lost following the most recent sample.  A placeholder is added so
+    /// that the missing period becomes a segment of its own, and samples are then dropped until
+    /// decoding is able to resume.
+    fn mark_gap(&mut self) {
+        let len = self.samples.len();
+        if len < 2 || is_gap(&self.samples[len - 1]) {
+            return;
+        }
+        // assume that the last sample received was complete
+        let last_dts = self.samples[len - 1].dts;
+        let dts = last_dts + (last_dts - self.samples[len - 2].dts);
+        self.push(Sample {
+            data: vec![],
+            pts: dts,
+            dts,
+            header: SampleHeader::Gap,
+        });
+    }
+
+    /// Arranges for a segment to start at the given time (or at the first sync sample following
+    /// it).  Splice points that are not later than the samples already received are ignored,
+    /// since the segments concerned may already have been published.
+    fn add_splice_point(&mut self, dts: i64) {
+        if let Ok(latest) = self.latest_dts() {
+            if dts <= latest {
+                return;
+            }
+        }
+        if !self.splice_points.contains(&dts) {
+            self.splice_points.push(dts);
+        }
+    }
+
     fn duration(&self) -> u64 {
         let len = self.samples.len();
         if len < 2 {
@@ -113,24 +346,50 @@ impl AvcTrack {
             (self.samples[len - 1].dts - self.samples[0].dts) as u64
         }
     }
-    pub fn pps(&self) -> &h264_reader::nal::pps::PicParameterSet {
-        &self.pps
-    }
-    pub fn sps(&self) -> &h264_reader::nal::sps::SeqParameterSet {
-        &self.sps
+
+    /// A new segment may start once the segment target duration has elapsed since the start of
+    /// the previous segment, or once a splice point is reached (at a sync sample, if the track
+    /// requires that)
+    fn starts_segment(&self) -> impl Fn(&Sample, i64) -> bool {
+        let target = self.targets.segment;
+        let sync_segments = self.sync_segments;
+        let splice_points = self.splice_points.clone();
+        move |sample: &Sample, segment_start: i64| {
+            is_gap(sample)
+                || (!sync_segments || is_sync(sample))
+                    && (sample.dts - segment_start >= target
+                        || splice_points.iter().any(|&p| p > segment_start && p <= sample.dts ))
+        }
     }
 
     pub fn samples(&self) -> impl Iterator<Item = &Sample> {
         self.samples.iter()
     }
 
+    pub fn sample(&self, dts: i64) -> Option<&Sample> {
+        self.samples
+            .iter()
+            .find(|sample| sample.dts == dts )
+    }
+
+    fn latest_dts(&self) -> Result<i64, SegmentError> {
+        let latest = self.samples.iter().last().map(|s| s.dts );
+        if latest.is_none() {
+            return Err(SegmentError::NoSegments)
+        }
+        Ok(latest.unwrap())
+    }
+
+    pub fn sequence_stream(&self) -> watch::Receiver<TrackSequence> {
+        self.watch.1.clone()
+    }
+
     pub fn segment_number_for(&self, dts: i64) -> Option<usize> {
-        // TODO: assert first sample dts exactly equals given value, and that it is_idr()
+        // TODO: assert first sample dts exactly equals given value
         self.segments()
             .enumerate()
             .find(|(i, seg)| seg.dts == dts)
             .map(|(i, _)| i + self.first_seg_num )
-
     }
 
     pub fn part_number_for(&self, dts: i64, part_id: u64) -> Option<usize> {
@@ -148,57 +407,102 @@ impl AvcTrack {
             .count())
     }
 
-    pub fn segment_samples(&self, dts: i64) -> Result<impl Iterator<Item = &Sample>, SegmentError> {
-        let mut iter = self.samples()
-            .skip_while(move |sample| sample.dts < dts )
-            .enumerate()
-            .take_while(|(i, sample)| *i==0 || !is_idr(sample) )
-            .map(|(i, sample)| sample )
-            .peekable();
-        if let Some(sample) = iter.peek() {
-            if sample.dts == dts && is_idr(sample) {
-                Ok(iter)
-            } else {
-                if sample.dts != dts {
-                    println!("segment_samples({}) gor first sample with dts {}?!?", dts, sample.dts);
-                }
-                Err(SegmentError::NotAnIdrSample(dts))
-            }
+    fn segment_range(&self, dts: i64) -> Result<SegmentRange, SegmentError> {
+        let range = segment_range(&self.samples, dts, self.starts_segment())?;
+        let first = &self.samples[range.start];
+        if self.sync_segments && !is_sync(first) && !is_gap(first) {
+            Err(SegmentError::NotAnIdrSample(dts))
         } else {
-            Err(SegmentError::SampleNotFound(dts))
+            Ok(range)
         }
     }
 
-    pub fn sequence_stream(&self) -> watch::Receiver<TrackSequence> {
-        self.watch.1.clone()
+    pub fn segment_samples(&self, dts: i64) -> Result<impl Iterator<Item = &Sample>, SegmentError> {
+        let range = self.segment_range(dts)?;
+        Ok(self.samples()
+            .skip(range.start)
+            .take(range.count))
+    }
+
+    pub fn part_samples(&self, dts: i64, part_id: u64) -> Result<impl Iterator<Item = &Sample>, SegmentError> {
+        let range = self.segment_range(dts)?;
+        let parts = split_parts(self.samples().skip(range.start).take(range.count), range.next_dts, self.targets.part);
+        let part = parts.get(part_id as usize).ok_or(SegmentError::PartNotFound(part_id))?;
+        Ok(self.samples()
+            .skip(range.start + part.start)
+            .take(part.count))
+    }
+
+    /// Measured over the most recent complete segments, once packaged as given, or until there
+    /// are any, estimated from the segment in progress.  Gap segments are skipped, since their
+    /// media was never received.
+    pub fn bitrate(&self, packaging: Packaging) -> Option<Bitrate> {
+        let segments: Vec<(usize, f64)> = self.segments()
+            .filter(|seg| !seg.is_gap() )
+            .filter_map(|seg| seg.duration_seconds().filter(|&d| d > 0.0 ).map(|d| (seg.size(packaging), d) ) )
+            .collect();
+        let window = &segments[segments.len().saturating_sub(BITRATE_WINDOW_SEGMENTS)..];
+        if window.is_empty() {
+            return self.provisional_bitrate(packaging);
+        }
+        let peak = window.iter()
+            .map(|&(size, duration)| size as f64 * 8.0 / duration )
+            .fold(0.0, f64::max);
+        let (size, duration) = window.iter()
+            .fold((0, 0.0), |(total_size, total_duration), &(size, duration)| (total_size + size, total_duration + duration) );
+        Some(Bitrate {
+            peak: peak.round() as u32,
+            average: (size as f64 * 8.0 / duration).round() as u32,
+        })
+    }
+
+    /// Estimated from the samples of the in-progress segment, or `None` if there are too few to
+    /// measure
+    fn provisional_bitrate(&self, packaging: Packaging) -> Option<Bitrate> {
+        let seg = self.segments().last().filter(|seg| !seg.is_gap() )?;
+        let samples: Vec<&Sample> = self.segment_samples(seg.id()).ok()?.collect();
+        let duration = (samples.last()?.dts - samples.first()?.dts) as f64 / 90000.0;
+        if duration <= 0.0 {
+            return None;
+        }
+        // the duration of the final sample isn't known until the next arrives, so its size is
+        // not counted
+        let size = samples[..samples.len() - 1].iter()
+            .fold(packaging.segment_overhead(), |size, sample| size + packaging.sample_size(sample) );
+        let bitrate = (size as f64 * 8.0 / duration).round() as u32;
+        Some(Bitrate {
+            peak: bitrate,
+            average: bitrate,
+        })
     }
 
-    pub fn sample(&self, dts: i64) -> Option<&Sample> {
-        self.samples
-            .iter()
-            .find(|sample| sample.dts == dts )
+    pub fn targets(&self) -> SegmentTargets {
+        self.targets
     }
 
-    pub fn bandwidth(&self) -> Option<u32> {
-        // TODO: measure max bandwidth per GOP, and report here
-        self.max_bitrate
+    /// The longest duration of any segment, in whole seconds
+    pub fn max_chunk_duration(&self) -> u32 {
+        max_segment_duration(self.segments(), self.targets)
     }
 
-    pub fn rfc6381_codec(&self) -> String {
-        let bytes = make_avc_codec_bytes(&self.sps);
-        format!("avc1.{:02x}{:02x}{:02x}", bytes[0], bytes[1], bytes[2])
+    /// Value for `EXT-X-TARGETDURATION`, in whole seconds.  The spec doesn't allow this to
+    /// change during the life of the playlist, so it comes from the configured targets rather
+    /// than from the segments in the archive at the time.
+    pub fn target_duration(&self) -> u32 {
+        (self.targets.segment as f64 / 90000.0).ceil() as u32
     }
 
-    pub fn max_chunk_duration(&self) -> u32 {
-        // TODO: measure GOP duration during ingest, and report here
-        //       also, consider proper time bases etc
-        2
+    /// Value for `PART-TARGET`, in seconds, which parts are divided so as not to exceed
+    pub fn part_target_duration(&self) -> f64 {
+        self.targets.part as f64 / 90000.0
     }
+
     pub fn segments<'track>(&'track self) -> impl Iterator<Item = SegmentInfo> + 'track {
-        AvcSegmentIterator{
+        SegmentIterator {
             samples: self.samples.iter().peekable(),
-            max_ts: self.samples.iter().last().map(|s| s.dts ),
             sequence_number: self.first_seg_num as u64,
+            starts_segment: self.starts_segment(),
+            discontinuities: &self.discontinuities[..],
         }
     }
 
@@ -206,55 +510,169 @@ impl AvcTrack {
         self.segments().filter(|s| s.duration.is_some() ).last().unwrap().seq
     }
 
-    // TODO: this should be,
-    //  a) in terms of duration, not samples
-    //  b) configured, not hardcoded
-    pub const VIDEO_SAMPLES_PER_PART: usize = 8;
-
     pub fn has_parts(&self, dts: i64) -> bool {
-        let latest = self.samples.iter().last().map(|s| s.dts );
-        if latest.is_none() {
-            return false
-        }
-        let latest = latest.unwrap();
-        let earliest_segment_with_parts = latest - (SEG_DURATION_PTS * 3) as i64;
+        let latest = match self.latest_dts() {
+            Ok(latest) => latest,
+            Err(_) => return false,
+        };
+        let earliest_segment_with_parts = latest - self.targets.segment * 3;
         dts >= earliest_segment_with_parts
     }
 
     pub fn parts<'track>(&'track self, dts: i64) -> Result<impl Iterator<Item = PartInfo> + 'track, SegmentError> {
-        Ok(self.segment_samples(dts)?
-            .enumerate()
-            .group_by(|(i, _)| i / Self::VIDEO_SAMPLES_PER_PART )
+        let range = self.segment_range(dts)?;
+        let gap = is_gap(&self.samples[range.start]);
+        Ok(split_parts(self.samples().skip(range.start).take(range.count), range.next_dts, self.targets.part)
             .into_iter()
-            .map(|(key, group)| {  // TODO: can we avoid allocating for 'group'?
-                // now, check that we have all the samples needed for a complete part, and remember
-                // if there's an IDR frame, so that the INDEPENDENT flag can be set in the HLS
-                // media-manifest
-                let (count, idr) = group
-                    .iter()
-                    .map(|(_, s)| s )
-                    .fold((0, false), |(count, idr), sample| (count+1, idr | is_idr(sample)) );
-                if count == Self::VIDEO_SAMPLES_PER_PART {
-                    Some(PartInfo {
-                        part_id: key as u64,
-                        duration: Some(0.32),  // TODO: don't hardcode
-                        continuous: true,
-                        independent: idr,
-                    })
-                } else {
-                    None
-                }
+            .enumerate()
+            .map(|(i, part)| PartInfo {
+                part_id: i as u64,
+                duration: Some(part.duration as f64 / 90000.0),
+                continuous: true,
+                gap,
+                // remember if there's an IDR frame, so that the INDEPENDENT flag can be set in
+                // the HLS media-manifest
+                independent: part.independent,
+            }))
+    }
+
+    /// Frame rate measured from the sample timestamps
+    fn measured_frame_rate(&self) -> Option<FrameRate> {
+        // PTS deltas vary in the presence of B-frames, but DTS should advance by one frame
+        // duration per sample
+        self.samples.iter()
+            .zip(self.samples.iter().skip(1))
+            .take(250)
+            .map(|(a, b)| b.dts - a.dts )
+            .filter(|&delta| delta > 0 )
+            .min()
+            .map(|delta| FrameRate {
+                num: 90000,
+                den: delta as u32,
             })
-            .flat_map(|x| x))
     }
+}
 
-    pub fn sps_bytes(&self) -> &[u8] {
+/// One version of a `Versioned` value, which dereferences to the value itself
+pub struct Version<T> {
+    version: u32,
+    /// the timestamp of the first sample to use this version, once there is one
+    start: Option<i64>,
+    value: T,
+}
+impl<T> Version<T> {
+    /// Increases each time the value changes, starting from zero
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+}
+impl<T> std::ops::Deref for Version<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// A value, such as the parameter sets or codec configuration of a track, which may change over
+/// the course of the timeline.  Each version is kept for as long as some sample still uses it.
+pub struct Versioned<T> {
+    /// oldest first; never empty
+    versions: VecDeque<Version<T>>,
+}
+impl<T> Versioned<T> {
+    fn new(value: T) -> Versioned<T> {
+        let mut versions = VecDeque::new();
+        versions.push_back(Version { version: 0, start: None, value });
+        Versioned { versions }
+    }
+
+    /// The most recent version
+    pub fn latest(&self) -> &Version<T> {
+        self.versions.back().unwrap()
+    }
+
+    /// The given version, if it still applies to some sample in the timeline
+    pub fn version(&self, version: u32) -> Option<&Version<T>> {
+        self.versions.iter().find(|v| v.version == version )
+    }
+
+    /// The version which applies to the sample with the given timestamp
+    pub fn at(&self, dts: i64) -> &Version<T> {
+        self.versions.iter()
+            .rev()
+            .find(|v| v.start.map(|start| start <= dts ).unwrap_or(false) )
+            .unwrap_or_else(|| self.versions.front().unwrap() )
+    }
+
+    /// Returns `true` if the value was different to that already in use (as decided by
+    /// `is_same`), in which case the next sample will start a new segment
+    fn set(&mut self, value: T, is_same: impl FnOnce(&T, &T) -> bool) -> bool {
+        let latest = self.versions.back_mut().unwrap();
+        if is_same(&latest.value, &value) {
+            return false;
+        }
+        let next = Version { version: latest.version + 1, start: None, value };
+        if latest.start.is_none() {
+            // no samples used the previous version
+            *latest = next;
+            false
+        } else {
+            self.versions.push_back(next);
+            true
+        }
+    }
+
+    /// To be called once a sample with the given timestamp has been pushed to the timeline, so
+    /// that the latest version starts with it, and versions no longer used are forgotten
+    fn sample_pushed(&mut self, timeline: &Timeline, dts: i64) {
+        if timeline.latest_dts().ok() == Some(dts) {
+            let latest = self.versions.back_mut().unwrap();
+            if latest.start.is_none() {
+                latest.start = Some(dts);
+            }
+        }
+        // forget versions once all the samples using them have been removed
+        if let Some(first) = timeline.segments().next() {
+            while self.versions.len() > 1 && self.versions[1].start.map(|s| s <= first.id() ).unwrap_or(false) {
+                self.versions.pop_front();
+            }
+        }
+    }
+}
+
+/// The SPS and PPS NAL units in effect for a range of the samples of an `AvcTrack`
+pub struct AvcParameterSets {
+    /// the SPS referenced by the samples, which determines the codec and dimensions signalled
+    sps: nal::sps::SeqParameterSet,
+    /// every SPS, in order of `seq_parameter_set_id`
+    sps_bytes: Vec<Vec<u8>>,
+    /// every PPS, in order of `pic_parameter_set_id`
+    pps_bytes: Vec<Vec<u8>>,
+}
+impl AvcParameterSets {
+    pub fn sps(&self) -> &h264_reader::nal::sps::SeqParameterSet {
+        &self.sps
+    }
+
+    pub fn sps_bytes(&self) -> &[Vec<u8>] {
         &self.sps_bytes[..]
     }
-    pub fn pps_bytes(&self) -> &[u8] {
+    pub fn pps_bytes(&self) -> &[Vec<u8>] {
         &self.pps_bytes[..]
     }
 
+    /// `profile_idc`, the constraint flags and `level_idc`, as used in both the RFC 6381 codec
+    /// string and the `avcC` box
+    pub fn codec_bytes(&self) -> [u8; 3] {
+        make_avc_codec_bytes(&self.sps)
+    }
+
+    pub fn rfc6381_codec(&self) -> String {
+        let bytes = self.codec_bytes();
+        format!("avc1.{:02x}{:02x}{:02x}", bytes[0], bytes[1], bytes[2])
+    }
+
     pub fn dimensions(&self) -> (u32, u32) {
         let sps = &self.sps;
         let width = (sps.pic_width_in_mbs_minus1 + 1) * 16;
@@ -276,76 +694,347 @@ impl AvcTrack {
     }
 }
 
-struct AvcPartIterator<'track> {
-    samples: Peekable<vec_deque::Iter<'track, Sample>>,
+pub struct AvcTrack {
+    parameter_sets: Versioned<AvcParameterSets>,
+    max_bitrate: Option<u32>,
+    captions: CaptionServices,
+    timeline: Timeline,
+}
+impl AvcTrack {
+    fn new(
+        sps: nal::sps::SeqParameterSet,
+        sps_bytes: Vec<Vec<u8>>,
+        pps_bytes: Vec<Vec<u8>>,
+        max_bitrate: Option<u32>,
+        targets: SegmentTargets,
+    ) -> AvcTrack {
+        AvcTrack {
+            parameter_sets: Versioned::new(AvcParameterSets { sps, sps_bytes, pps_bytes }),
+            max_bitrate,
+            captions: CaptionServices::default(),
+            timeline: Timeline::new(targets, true),
+        }
+    }
+
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    /// The closed caption services seen so far within the video
+    pub fn captions(&self) -> CaptionServices {
+        self.captions
+    }
+
+    /// Every version of the parameter sets still used by some sample in the timeline
+    pub fn parameter_sets(&self) -> &Versioned<AvcParameterSets> {
+        &self.parameter_sets
+    }
+
+    pub fn sps(&self) -> &h264_reader::nal::sps::SeqParameterSet {
+        self.parameter_sets.latest().sps()
+    }
+
+    /// The frame rate signalled in the SPS, or failing that, measured from the sample timestamps
+    pub fn frame_rate(&self) -> Option<FrameRate> {
+        FrameRate::from_sps(self.sps()).or_else(|| self.timeline.measured_frame_rate() )
+    }
+
+    pub fn rfc6381_codec(&self) -> String {
+        self.parameter_sets.latest().rfc6381_codec()
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.parameter_sets.latest().dimensions()
+    }
+
+    /// Returns `true` if the parameter sets were different to those already in use, in which
+    /// case the next sample will start a new segment
+    fn set_parameter_sets(
+        &mut self,
+        sps: nal::sps::SeqParameterSet,
+        sps_bytes: Vec<Vec<u8>>,
+        pps_bytes: Vec<Vec<u8>>,
+    ) -> bool {
+        let sets = AvcParameterSets { sps, sps_bytes, pps_bytes };
+        self.parameter_sets.set(sets, |a, b| a.sps_bytes == b.sps_bytes && a.pps_bytes == b.pps_bytes )
+    }
+
+    fn push(&mut self, sample: Sample) {
+        let dts = sample.dts;
+        self.timeline.push(sample);
+        self.parameter_sets.sample_pushed(&self.timeline, dts);
+    }
+}
+
+/// The VPS, SPS and PPS NAL units in effect for a range of the samples of an `HevcTrack`
+pub struct HevcParameterSets {
+    sps: hevc::SeqParameterSet,
+    vps_bytes: Vec<u8>,
+    sps_bytes: Vec<u8>,
+    pps_bytes: Vec<u8>,
+}
+impl HevcParameterSets {
+    pub fn sps(&self) -> &hevc::SeqParameterSet {
+        &self.sps
+    }
+
+    pub fn vps_bytes(&self) -> &[u8] {
+        &self.vps_bytes[..]
+    }
+    pub fn sps_bytes(&self) -> &[u8] {
+        &self.sps_bytes[..]
+    }
+    pub fn pps_bytes(&self) -> &[u8] {
+        &self.pps_bytes[..]
+    }
+
+    pub fn rfc6381_codec(&self) -> String {
+        self.sps.rfc6381_codec()
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.sps.dimensions()
+    }
 }
 
-struct AvcSegmentIterator<'track> {
+pub struct HevcTrack {
+    parameter_sets: Versioned<HevcParameterSets>,
+    max_bitrate: Option<u32>,
+    timeline: Timeline,
+}
+impl HevcTrack {
+    fn new(
+        sps: hevc::SeqParameterSet,
+        vps_bytes: Vec<u8>,
+        sps_bytes: Vec<u8>,
+        pps_bytes: Vec<u8>,
+        max_bitrate: Option<u32>,
+        targets: SegmentTargets,
+    ) -> HevcTrack {
+        HevcTrack {
+            parameter_sets: Versioned::new(HevcParameterSets { sps, vps_bytes, sps_bytes, pps_bytes }),
+            max_bitrate,
+            timeline: Timeline::new(targets, true),
+        }
+    }
+
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    /// Every version of the parameter sets still used by some sample in the timeline
+    pub fn parameter_sets(&self) -> &Versioned<HevcParameterSets> {
+        &self.parameter_sets
+    }
+
+    pub fn sps(&self) -> &hevc::SeqParameterSet {
+        self.parameter_sets.latest().sps()
+    }
+
+    /// The frame rate signalled in the SPS, or failing that, measured from the sample timestamps
+    pub fn frame_rate(&self) -> Option<FrameRate> {
+        FrameRate::from_hevc_sps(self.sps()).or_else(|| self.timeline.measured_frame_rate() )
+    }
+
+    pub fn rfc6381_codec(&self) -> String {
+        self.parameter_sets.latest().rfc6381_codec()
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.parameter_sets.latest().dimensions()
+    }
+
+    /// Returns `true` if the parameter sets were different to those already in use, in which
+    /// case the next sample will start a new segment
+    fn set_parameter_sets(
+        &mut self,
+        sps: hevc::SeqParameterSet,
+        vps_bytes: Vec<u8>,
+        sps_bytes: Vec<u8>,
+        pps_bytes: Vec<u8>,
+    ) -> bool {
+        let sets = HevcParameterSets { sps, vps_bytes, sps_bytes, pps_bytes };
+        self.parameter_sets.set(sets, |a, b| {
+            a.vps_bytes == b.vps_bytes && a.sps_bytes == b.sps_bytes && a.pps_bytes == b.pps_bytes
+        })
+    }
+
+    fn push(&mut self, sample: Sample) {
+        let dts = sample.dts;
+        self.timeline.push(sample);
+        self.parameter_sets.sample_pushed(&self.timeline, dts);
+    }
+}
+
+/// Iterates over the segments of a track, where `starts_segment` decides if the given sample is
+/// able to start a new segment, given the timestamp of the start of the current segment
+struct SegmentIterator<'track, F> {
     samples: Peekable<vec_deque::Iter<'track, Sample>>,
-    max_ts: Option<i64>,
     sequence_number: u64,
+    starts_segment: F,
+    discontinuities: &'track [i64],
 }
-impl<'track> Iterator for AvcSegmentIterator<'track> {
+impl<'track, F> Iterator for SegmentIterator<'track, F>
+    where
+        F: Fn(&Sample, i64) -> bool
+{
     type Item = SegmentInfo;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.max_ts.is_none() {
-            return None;
-        }
-        let max_ts = self.max_ts.unwrap();
-
-        let mut skipped = false;
-        let mut discontinuity = false;
-        let mut dts = None;
+        let first = self.samples.next()?;
+        let start = first.dts;
+        let gap = is_gap(first);
+        let continuous = !self.discontinuities.contains(&start);
+        let seq = self.sequence_number;
+        self.sequence_number += 1;
+        let mut fmp4_size = Packaging::Fmp4.segment_overhead() + Packaging::Fmp4.sample_size(first);
+        let mut ts_size = Packaging::Ts.segment_overhead() + Packaging::Ts.sample_size(first);
         loop {
-            match self.samples.next() {
-                Some(sample) => {
-                    if is_idr(sample) {
-                        dts = Some(sample.dts);
-                    }
-
-                    if let Some(last_idr_dts) = dts {
-                        match self.samples.peek() {
-                            Some(peek) => {
-                                if is_idr(peek) {
-                                    let duration = peek.dts - last_idr_dts;
-                                    let seq = self.sequence_number;
-                                    self.sequence_number += 1;
-                                    return Some(SegmentInfo {
-                                        dts: last_idr_dts,
-                                        seq,
-                                        duration: Some(duration as f64 / 90000.0),
-                                        continuous: true
-                                    })
-                                }
-                            },
-                            // Then we don't have enough samples to announce this segment yet;
-                            // we do indicate the possibility of a segment, but we don't indicate
-                            // it's duration yet,
-                            None => {
-                                let seq = self.sequence_number;
-                                self.sequence_number += 1;
-                                return Some(SegmentInfo {
-                                    dts: last_idr_dts,
-                                    seq,
-                                    duration: None,
-                                    continuous: true
-                                })
-                            }
-                        }
-                    }
+            match self.samples.peek() {
+                Some(peek) if (self.starts_segment)(peek, start) => {
+                    return Some(SegmentInfo {
+                        dts: start,
+                        seq,
+                        duration: Some((peek.dts - start) as f64 / 90000.0),
+                        fmp4_size,
+                        ts_size,
+                        continuous,
+                        gap,
+                    })
+                },
+                Some(_) => {
+                    let sample = self.samples.next().unwrap();
+                    fmp4_size += Packaging::Fmp4.sample_size(sample);
+                    ts_size += Packaging::Ts.sample_size(sample);
                 },
-                None => return None,
+                // Then we don't have enough samples to announce this segment yet;
+                // we do indicate the possibility of a segment, but we don't indicate
+                // it's duration yet,
+                None => {
+                    return Some(SegmentInfo {
+                        dts: start,
+                        seq,
+                        duration: None,
+                        fmp4_size,
+                        ts_size,
+                        continuous,
+                        gap,
+                    })
+                }
             }
         }
     }
 }
 
-fn is_idr(sample: &Sample) -> bool {
+/// The location of a segment's samples within a track
+struct SegmentRange {
+    /// index of the first sample in the segment
+    start: usize,
+    /// number of samples in the segment
+    count: usize,
+    /// timestamp of the first sample of the following segment, if it has been ingested yet
+    next_dts: Option<i64>,
+}
+
+fn segment_range<F>(samples: &VecDeque<Sample>, dts: i64, starts_segment: F) -> Result<SegmentRange, SegmentError>
+    where
+        F: Fn(&Sample, i64) -> bool
+{
+    let start = binary_search_by(samples, |sample| sample.dts.cmp(&dts))
+        .map_err(|_| SegmentError::SampleNotFound(dts) )?;
+    let next = samples.iter()
+        .enumerate()
+        .skip(start + 1)
+        .find(|(_, sample)| starts_segment(sample, dts) );
+    Ok(match next {
+        Some((i, sample)) => SegmentRange {
+            start,
+            count: i - start,
+            next_dts: Some(sample.dts),
+        },
+        None => SegmentRange {
+            start,
+            count: samples.len() - start,
+            next_dts: None,
+        },
+    })
+}
+
+/// The location of a part's samples, relative to the start of the containing segment
+struct PartRange {
+    start: usize,
+    count: usize,
+    /// in 90kHz units
+    duration: i64,
+    independent: bool,
+}
+
+/// Divides the samples of a single segment into parts, with a new part starting at the first
+/// sample where continuing the current part by a sample of the same duration as the last would
+/// take it beyond `part_target`.
+///
+/// Only complete parts are produced.  The final part of the segment is only complete once the
+/// start of the following segment is known (`next_dts`).
+fn split_parts<'a>(samples: impl Iterator<Item = &'a Sample>, next_dts: Option<i64>, part_target: i64) -> Vec<PartRange> {
+    let mut parts = vec![];
+    // start index, start dts and independence of the part currently being accumulated
+    let mut current: Option<(usize, i64, bool)> = None;
+    let mut count = 0;
+    let mut prev_dts = None;
+    for (i, sample) in samples.enumerate() {
+        let last_duration = prev_dts.map(|prev| sample.dts - prev ).unwrap_or(0);
+        prev_dts = Some(sample.dts);
+        current = match current {
+            Some((start, start_dts, independent)) if sample.dts - start_dts + last_duration > part_target => {
+                parts.push(PartRange {
+                    start,
+                    count: i - start,
+                    duration: sample.dts - start_dts,
+                    independent,
+                });
+                Some((i, sample.dts, is_sync(sample)))
+            },
+            Some((start, start_dts, independent)) => Some((start, start_dts, independent | is_sync(sample))),
+            None => Some((i, sample.dts, is_sync(sample))),
+        };
+        count = i + 1;
+    }
+    if let (Some((start, start_dts, independent)), Some(next_dts)) = (current, next_dts) {
+        parts.push(PartRange {
+            start,
+            count: count - start,
+            duration: next_dts - start_dts,
+            independent,
+        });
+    }
+    parts
+}
+
+/// The longest of the given segment durations once rounded to the nearest whole second, and no
+/// less than the configured target
+fn max_segment_duration(segments: impl Iterator<Item = SegmentInfo>, targets: SegmentTargets) -> u32 {
+    let configured = (targets.segment as f64 / 90000.0).ceil();
+    segments
+        .filter_map(|seg| seg.duration )
+        .map(f64::round)
+        .fold(configured, f64::max) as u32
+}
+
+/// True for samples at which decoding can begin
+fn is_sync(sample: &Sample) -> bool {
     match sample.header {
         SampleHeader::Avc(nal_header, _) => {
             nal_header.nal_unit_type() == UnitType::SliceLayerWithoutPartitioningIdr
         },
+        SampleHeader::Hevc(nal_header) => nal_header.is_irap(),
+        SampleHeader::Subtitle { sync } => sync,
+        _ => false,
+    }
+}
+
+fn is_gap(sample: &Sample) -> bool {
+    match sample.header {
+        SampleHeader::Gap => true,
         _ => false,
     }
 }
@@ -366,220 +1055,337 @@ fn binary_search_by<T, F: FnMut(&T) -> cmp::Ordering>(v: &VecDeque<T>, mut f: F)
     }
 }
 
-pub struct AacTrack {
-    samples: VecDeque<Sample>,
+/// The purpose of an audio stream, per the `audio_type` field of the MPEG-TS
+/// `ISO_639_language_descriptor`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioType {
+    Main,
+    CleanEffects,
+    HearingImpaired,
+    VisualImpairedCommentary,
+}
+impl Default for AudioType {
+    fn default() -> Self {
+        AudioType::Main
+    }
+}
+
+/// Tools that extend the AAC core, which can't be signalled in ADTS headers.  With these, the
+/// ADTS sampling frequency is that of the core, which is half the output sampling frequency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AacExtension {
+    None,
+    /// Spectral Band Replication (HE-AAC)
+    Sbr,
+    /// SBR plus Parametric Stereo (HE-AACv2), where the core is mono
+    SbrPs,
+}
+impl Default for AacExtension {
+    fn default() -> Self {
+        AacExtension::None
+    }
+}
+
+/// Descriptive metadata about an audio stream, as signalled by the source
+#[derive(Debug, Clone, Default)]
+pub struct AudioInfo {
+    /// ISO 639-2 language code, if known
+    pub language: Option<String>,
+    pub audio_type: AudioType,
+    pub aac_extension: AacExtension,
+}
+
+/// The configuration from the ADTS headers in effect for a range of the samples of an `AacTrack`
+pub struct AdtsConfig {
     profile: adts_reader::AudioObjectType,
     frequency: adts_reader::SamplingFrequency,
     channel_config: adts_reader::ChannelConfiguration,
+    extension: AacExtension,
+}
+impl AdtsConfig {
+    /// The number of channels (including any LFE channel), if signalled in the ADTS header
+    pub fn channels(&self) -> Option<u32> {
+        if self.extension == AacExtension::SbrPs {
+            // parametric stereo upmixes the mono core
+            return Some(2);
+        }
+        match self.channel_config as u8 {
+            // the layout is given in a program_config_element within the AAC data
+            0 => None,
+            // 7.1
+            7 => Some(8),
+            n => Some(u32::from(n)),
+        }
+    }
+
+    /// Sampling frequency in Hz
+    pub fn sample_rate(&self) -> u32 {
+        // reserved sampling_frequency_index values are not expected in ADTS headers; assume the
+        // most common rate
+        self.frequency.freq().unwrap_or(48000)
+    }
+
+    pub fn profile(&self) -> adts_reader::AudioObjectType {
+        self.profile
+    }
+
+    pub fn frequency(&self) -> adts_reader::SamplingFrequency {
+        self.frequency
+    }
+
+    pub fn channel_config(&self) -> adts_reader::ChannelConfiguration {
+        self.channel_config
+    }
+
+    /// The MPEG-4 `audioObjectType` of the AAC core
+    pub fn object_type(&self) -> u8 {
+        match self.profile {
+            adts_reader::AudioObjectType::AacMain => 1,
+            adts_reader::AudioObjectType::AacLC => 2,
+            adts_reader::AudioObjectType::AacSSR => 3,
+            adts_reader::AudioObjectType::AacLTP => 4,
+        }
+    }
+
+    pub fn extension(&self) -> AacExtension {
+        self.extension
+    }
+
+    pub fn rfc6381_codec(&self) -> String {
+        let object_type = match self.extension {
+            AacExtension::None => self.object_type(),
+            AacExtension::Sbr => 5,
+            AacExtension::SbrPs => 29,
+        };
+        format!("mp4a.40.{}", object_type)
+    }
+
+    fn is_same(&self, other: &AdtsConfig) -> bool {
+        self.profile as u8 == other.profile as u8
+            && self.frequency as u8 == other.frequency as u8
+            && self.channel_config as u8 == other.channel_config as u8
+    }
+}
+
+pub struct AacTrack {
+    configs: Versioned<AdtsConfig>,
     max_bitrate: Option<u32>,
-    watch: (watch::Sender<TrackSequence>, watch::Receiver<TrackSequence>),
-    first_seg_num: usize,
+    info: AudioInfo,
+    timeline: Timeline,
 }
 impl AacTrack {
-    pub const AUDIO_FRAMES_PER_PART: usize = 15;  // TODO
-
     fn new(
         profile: adts_reader::AudioObjectType,
         frequency: adts_reader::SamplingFrequency,
         channel_config: adts_reader::ChannelConfiguration,
         max_bitrate: Option<u32>,
+        info: AudioInfo,
+        targets: SegmentTargets,
     ) -> AacTrack {
-        AacTrack {
-            samples: VecDeque::new(),
+        let config = AdtsConfig {
             profile,
             frequency,
             channel_config,
+            extension: info.aac_extension,
+        };
+        AacTrack {
+            configs: Versioned::new(config),
             max_bitrate,
-            watch: watch::channel(TrackSequence::default()),
-            first_seg_num: 0,
+            info,
+            timeline: Timeline::new(targets, false),
         }
     }
 
-    pub fn push(&mut self, sample: Sample) {
-        self.samples.push_back(sample);
-        while self.duration() > ARCHIVE_LIMIT {
-            self.remove_one_segment()
-        }
-        // TODO: pretty inefficient!
-        if let Some(this_seg) = self.segments().last() {
-            let count = self.parts(this_seg.id()).unwrap().count();
-            if count > 0 {
-                let this_part = count - 1;
-                let seq = TrackSequence {
-                    seg: this_seg.sequence_number(),
-                    part: this_part as u16,
-                };
-                self.watch.0.broadcast(seq).unwrap()
-            }
-        }
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
     }
 
-    fn remove_one_segment(&mut self) {
-        for _ in 0..Self::AAC_SAMPLES_PER_SEGMENT {
-            self.samples.pop_front();
-        }
-        self.first_seg_num += 1;
+    /// Every version of the configuration still used by some sample in the timeline
+    pub fn configs(&self) -> &Versioned<AdtsConfig> {
+        &self.configs
     }
 
-    fn duration(&self) -> u64 {
-        let len = self.samples.len();
-        if len < 2 {
-            0
-        } else {
-            (self.samples[len - 1].dts - self.samples[0].dts) as u64
-        }
+    pub fn channels(&self) -> Option<u32> {
+        self.configs.latest().channels()
     }
 
-    pub fn channels(&self) -> Option<u32> {
-        // TODO
-        Some(2)
+    pub fn sample_rate(&self) -> u32 {
+        self.configs.latest().sample_rate()
     }
 
-    pub fn max_chunk_duration(&self) -> u32 {
-        // TODO: some way of determining a sensible value ('similar' to video GOP duration / just
-        //       a configured value?)
-        2
+    pub fn rfc6381_codec(&self) -> String {
+        self.configs.latest().rfc6381_codec()
     }
 
-    pub fn samples(&self) -> impl Iterator<Item = &Sample> {
-        self.samples.iter()
+    pub fn language(&self) -> Option<&str> {
+        self.info.language.as_ref().map(|l| &l[..] )
     }
 
-    fn latest_dts(&self) -> Result<i64, SegmentError> {
-        let latest = self.samples.iter().last().map(|s| s.dts );
-        if latest.is_none() {
-            return Err(SegmentError::NoSegments)
-        }
-        Ok(latest.unwrap())
+    pub fn audio_type(&self) -> AudioType {
+        self.info.audio_type
     }
 
-    pub fn has_parts(&self, dts: i64) -> bool {
-        let latest = match self.latest_dts() {
-            Ok(latest) => latest,
-            Err(_) => return false,
+    /// Returns `true` if the configuration was different to that already in use, in which case
+    /// the next sample will start a new segment
+    fn set_config(
+        &mut self,
+        profile: adts_reader::AudioObjectType,
+        frequency: adts_reader::SamplingFrequency,
+        channel_config: adts_reader::ChannelConfiguration,
+    ) -> bool {
+        let config = AdtsConfig {
+            profile,
+            frequency,
+            channel_config,
+            extension: self.info.aac_extension,
         };
-        let earliest_segment_with_parts = latest - (SEG_DURATION_PTS * 3) as i64;
-        dts >= earliest_segment_with_parts
+        self.configs.set(config, AdtsConfig::is_same)
     }
 
-    pub fn parts<'track>(&'track self, dts: i64) -> Result<impl Iterator<Item = PartInfo> + 'track, SegmentError> {
-        // TODO: this should be,
-        //  a) in terms of duration, not samples
-        //  b) configured, not hardcoded
-        const AUDIO_SAMPLES_PER_PART: usize = 15;
+    fn push(&mut self, sample: Sample) {
+        let dts = sample.dts;
+        self.timeline.push(sample);
+        self.configs.sample_pushed(&self.timeline, dts);
+    }
+}
 
-        let sample_count = self.samples.len();
-        Ok(self.segment_samples(dts)
-            .enumerate()
-            .step(AUDIO_SAMPLES_PER_PART)
-            .into_iter()
-            .map(move |(index, sample)| {
-                let key = index / AUDIO_SAMPLES_PER_PART;
-                // now, check that we have all the samples needed for a complete part
-                if sample_count - index >= AUDIO_SAMPLES_PER_PART {
-                    Some(PartInfo {
-                        part_id: key as u64,
-                        duration: Some(0.32),  // TODO: don't hardcode
-                        continuous: true,
-                        independent: false,  // arguably could be true, and an audio media-manifest just ignores?
-                    })
-                } else {
-                    None
-                }
-            })
-            .flat_map(|x| x))
+/// An AC-3 or E-AC-3 track, each sample of which is a complete audio frame (including any
+/// dependent substreams)
+pub struct Ac3Track {
+    /// the AC-3 / E-AC-3 stream configuration in effect for each range of the samples
+    configs: Versioned<ac3::StreamInfo>,
+    max_bitrate: Option<u32>,
+    info: AudioInfo,
+    timeline: Timeline,
+}
+impl Ac3Track {
+    fn new(
+        stream_info: ac3::StreamInfo,
+        max_bitrate: Option<u32>,
+        info: AudioInfo,
+        targets: SegmentTargets,
+    ) -> Ac3Track {
+        Ac3Track {
+            configs: Versioned::new(stream_info),
+            max_bitrate,
+            info,
+            timeline: Timeline::new(targets, false),
+        }
     }
 
-    pub fn segment_number_for(&self, dts: i64) -> Option<usize> {
-        // TODO: assert first sample dts exactly equals given value, and that it is_idr()
-        self.segments()
-            .enumerate()
-            .find(|(i, seg)| seg.dts == dts)
-            .map(|(i, _)| i + self.first_seg_num )
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
     }
 
-    pub fn part_number_for(&self, dts: i64, part_id: u64) -> Option<usize> {
-        Some(self.segments()
-            .take_while(|seg| seg.dts <= dts)
-            .flat_map(|seg| {
-                let limit = if seg.dts == dts {
-                    part_id
-                } else {
-                    std::u64::MAX
-                };
-                self.parts(seg.id()).unwrap(/*TODO*/)
-                    .take_while(move |part| part.id() <= limit)
-            })
-            .count())
+    /// Every version of the configuration still used by some sample in the timeline
+    pub fn configs(&self) -> &Versioned<ac3::StreamInfo> {
+        &self.configs
     }
 
-    const AAC_SAMPLES_PER_SEGMENT: usize = 90;  // TODO: can't be hardcoded
+    pub fn channels(&self) -> u32 {
+        self.configs.latest().channels()
+    }
 
-    pub fn segments<'track>(&'track self) -> impl Iterator<Item = SegmentInfo> + 'track {
-        const AAC_SEGMENT_DURATION: f64 = 1.92;  // TODO: can't be hardcoded
+    pub fn sample_rate(&self) -> u32 {
+        self.configs.latest().sample_rate
+    }
 
-        let seg_num = self.first_seg_num;
-        self.samples
-            .iter()
-            .enumerate()
-            .step(Self::AAC_SAMPLES_PER_SEGMENT )
-            .map(move |(index, sample)| {
-                let key = index / Self::AAC_SAMPLES_PER_SEGMENT;
-                if self.samples.len() - index >= Self::AAC_SAMPLES_PER_SEGMENT {
-                    SegmentInfo {
-                        dts: sample.dts,
-                        seq: key as u64 + seg_num as u64,
-                        duration: Some(AAC_SEGMENT_DURATION),
-                        continuous: true, // TODO check for timing gaps etc.
-                    }
-                } else {
-                    SegmentInfo {
-                        dts: sample.dts,
-                        seq: key as u64 + seg_num as u64,
-                        duration: None,
-                        continuous: true, // TODO check for timing gaps etc.
-                    }
-                }
-            })
+    pub fn rfc6381_codec(&self) -> String {
+        self.configs.latest().rfc6381_codec().to_string()
     }
 
-    pub fn media_sequence_number(&self) -> u64 {
-        self.segments().filter(|s| s.duration.is_some() ).last().unwrap().seq
+    pub fn language(&self) -> Option<&str> {
+        self.info.language.as_ref().map(|l| &l[..] )
     }
 
-    pub fn sample(&self, dts: i64) -> Option<&Sample> {
-        self.samples
-            .iter()
-            .find(|sample| sample.dts == dts )
+    pub fn audio_type(&self) -> AudioType {
+        self.info.audio_type
+    }
+
+    /// Returns `true` if the configuration was different to that already in use, in which case
+    /// the next sample will start a new segment
+    fn set_config(&mut self, stream_info: ac3::StreamInfo) -> bool {
+        self.configs.set(stream_info, ac3::StreamInfo::is_same_config)
+    }
+
+    fn push(&mut self, sample: Sample) {
+        let dts = sample.dts;
+        self.timeline.push(sample);
+        self.configs.sample_pushed(&self.timeline, dts);
     }
+}
+
+/// Descriptive metadata about a subtitle track
+#[derive(Debug, Clone)]
+pub struct SubtitleInfo {
+    /// ISO 639-2 language code, if known
+    pub language: Option<String>,
+    /// name distinguishing this track from others in the same language (e.g. `"CC1"`)
+    pub name: String,
+    /// the subtitles describe sounds as well as dialogue
+    pub hearing_impaired: bool,
+}
+
+/// Text displayed over a period of the timeline
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub start: i64,
+    /// `None` while the text is still being displayed
+    pub end: Option<i64>,
+    pub text: String,
+}
 
-    pub fn segment_samples(&self, dts: i64) -> impl Iterator<Item = &Sample> {
-        // TODO: assert first sample is_idr()
-        if let Ok(idx) = binary_search_by(&self.samples, |sample| sample.dts.cmp(&dts)) {
-            self.samples()
-                .skip(idx)
-                .take(Self::AAC_SAMPLES_PER_SEGMENT)
-        } else {
-            self.samples()
-                .skip(0)
-                .take(0)
+pub struct SubtitleTrack {
+    info: SubtitleInfo,
+    cues: VecDeque<Cue>,
+    /// samples carry no data, and just mirror the timing of the video track
+    timeline: Timeline,
+}
+impl SubtitleTrack {
+    fn new(info: SubtitleInfo, targets: SegmentTargets) -> SubtitleTrack {
+        SubtitleTrack {
+            info,
+            cues: VecDeque::new(),
+            timeline: Timeline::new(targets, true),
         }
     }
 
-    pub fn sequence_stream(&self) -> watch::Receiver<TrackSequence> {
-        self.watch.1.clone()
+    pub fn timeline(&self) -> &Timeline {
+        &self.timeline
     }
 
-    pub fn profile(&self) -> adts_reader::AudioObjectType {
-        self.profile
+    pub fn info(&self) -> &SubtitleInfo {
+        &self.info
     }
 
-    pub fn frequency(&self) -> adts_reader::SamplingFrequency {
-        self.frequency
+    /// The cues which are displayed at some point between `start` (inclusive) and `end`
+    /// (exclusive)
+    pub fn cues_between(&self, start: i64, end: i64) -> impl Iterator<Item = &Cue> {
+        self.cues
+            .iter()
+            .filter(move |cue| cue.start < end && cue.end.map(|e| e > start ).unwrap_or(true) )
     }
 
-    pub fn channel_config(&self) -> adts_reader::ChannelConfiguration {
-        self.channel_config
+    fn set_display(&mut self, pts: i64, text: Option<String>) {
+        if let Some(last) = self.cues.back_mut() {
+            if last.end.is_none() {
+                last.end = Some(pts);
+            }
+        }
+        if let Some(Cue { start, end: Some(end), .. }) = self.cues.back() {
+            if end <= start {
+                self.cues.pop_back();
+            }
+        }
+        if let Some(text) = text {
+            self.cues.push_back(Cue { start: pts, end: None, text });
+        }
+        if let Some(first) = self.timeline.samples().next() {
+            let first_dts = first.dts;
+            while self.cues.front().map(|cue| cue.end.map(|e| e < first_dts ).unwrap_or(false) ).unwrap_or(false) {
+                self.cues.pop_front();
+            }
+        }
     }
 }
 
@@ -588,7 +1394,12 @@ pub struct SegmentInfo {
     dts: i64,
     seq: u64,
     duration: Option<f64>,
+    /// approximate size of the segment so far, once packaged as fMP4
+    fmp4_size: usize,
+    /// approximate size of the segment so far, once packaged as MPEG-2 TS
+    ts_size: usize,
     continuous: bool,
+    gap: bool,
 }
 impl SegmentInfo {
     pub fn id(&self) -> i64 {
@@ -597,9 +1408,20 @@ impl SegmentInfo {
     pub fn duration_seconds(&self) -> Option<f64> {
         self.duration
     }
+    /// The approximate number of bytes in the segment so far, once packaged as given
+    pub fn size(&self, packaging: Packaging) -> usize {
+        match packaging {
+            Packaging::Fmp4 => self.fmp4_size,
+            Packaging::Ts => self.ts_size,
+        }
+    }
     pub fn is_continuous(&self) -> bool {
         self.continuous
     }
+    /// true if the media for this segment was lost from the input
+    pub fn is_gap(&self) -> bool {
+        self.gap
+    }
     pub fn sequence_number(&self) -> u64 {
         self.seq
     }
@@ -609,6 +1431,7 @@ pub struct PartInfo {
     part_id: u64,
     duration: Option<f64>,
     continuous: bool,
+    gap: bool,
     independent: bool,
 }
 impl PartInfo {
@@ -621,6 +1444,9 @@ impl PartInfo {
     pub fn is_continuous(&self) -> bool {
         self.continuous
     }
+    pub fn is_gap(&self) -> bool {
+        self.gap
+    }
     pub fn is_independent(&self) -> bool {
         self.independent
     }
@@ -630,7 +1456,7 @@ fn make_avc_codec_bytes(sps: &nal::sps::SeqParameterSet) -> [u8; 3] {
     let flags = sps.constraint_flags
         .iter()
         .enumerate()
-        .fold(0, |acc, (i, f)| acc | if *f { 1 << i } else { 0 } );
+        .fold(0, |acc, (i, f)| acc | if *f { 0x80 >> i } else { 0 } );
     [
         sps.profile_idc.into(),
         flags,
@@ -639,13 +1465,227 @@ fn make_avc_codec_bytes(sps: &nal::sps::SeqParameterSet) -> [u8; 3] {
 }
 pub enum Track {
     Avc(AvcTrack),
+    Hevc(HevcTrack),
     Aac(AacTrack),
+    Ac3(Ac3Track),
+    Subtitle(SubtitleTrack),
+}
+impl Track {
+    pub fn timeline(&self) -> &Timeline {
+        match self {
+            Track::Avc(ref avc_track) => avc_track.timeline(),
+            Track::Hevc(ref hevc_track) => hevc_track.timeline(),
+            Track::Aac(ref aac_track) => aac_track.timeline(),
+            Track::Ac3(ref ac3_track) => ac3_track.timeline(),
+            Track::Subtitle(ref subtitle_track) => subtitle_track.timeline(),
+        }
+    }
+
+    /// The bitrate signalled by the `maximum_bitrate_descriptor` of the input, if any
+    fn max_bitrate(&self) -> Option<u32> {
+        match self {
+            Track::Avc(ref avc_track) => avc_track.max_bitrate,
+            Track::Hevc(ref hevc_track) => hevc_track.max_bitrate,
+            Track::Aac(ref aac_track) => aac_track.max_bitrate,
+            Track::Ac3(ref ac3_track) => ac3_track.max_bitrate,
+            Track::Subtitle(_) => None,
+        }
+    }
+
+    /// Peak bitrate, as signalled by the `maximum_bitrate_descriptor` of the input, or else as
+    /// measured once packaged as given
+    pub fn bandwidth(&self, packaging: Packaging) -> Option<u32> {
+        self.max_bitrate().or_else(|| self.timeline().bitrate(packaging).map(|b| b.peak ) )
+    }
+
+    /// Average bitrate, as measured once packaged as given, but no more than the peak
+    pub fn average_bandwidth(&self, packaging: Packaging) -> Option<u32> {
+        let average = self.timeline().bitrate(packaging)?.average;
+        Some(self.max_bitrate().map_or(average, |max| cmp::min(max, average) ))
+    }
+
+    fn timeline_mut(&mut self) -> &mut Timeline {
+        match self {
+            Track::Avc(ref mut avc_track) => &mut avc_track.timeline,
+            Track::Hevc(ref mut hevc_track) => &mut hevc_track.timeline,
+            Track::Aac(ref mut aac_track) => &mut aac_track.timeline,
+            Track::Ac3(ref mut ac3_track) => &mut ac3_track.timeline,
+            Track::Subtitle(ref mut subtitle_track) => &mut subtitle_track.timeline,
+        }
+    }
+}
+
+/// The kind of splice signalled by an SCTE-35 message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpliceKind {
+    /// `splice_insert` out of the network feed (i.e. the start of a break)
+    Out,
+    /// `splice_insert` returning to the network feed
+    In,
+    /// any other command carrying a splice time (i.e. `time_signal`)
+    Cmd,
+}
+
+/// A splice signalled in the source stream
+#[derive(Debug)]
+pub struct SpliceEvent {
+    pub id: String,
+    pub kind: SpliceKind,
+    /// the 33-bit time of the splice, with the `pts_adjustment` applied but not unwrapped, or
+    /// `None` if the splice is to happen immediately
+    pub pts: Option<i64>,
+    /// planned duration of the break, in 90kHz units
+    pub duration: Option<i64>,
+    /// the complete `splice_info_section` as received
+    pub section: Vec<u8>,
+}
+
+/// A period of the timeline described by one or more splice events, sharing the same id
+#[derive(Debug, Clone)]
+pub struct DateRange {
+    pub id: String,
+    pub start: i64,
+    pub end: Option<i64>,
+    pub planned_duration: Option<i64>,
+    pub scte35_out: Option<Vec<u8>>,
+    pub scte35_in: Option<Vec<u8>>,
+    pub scte35_cmd: Option<Vec<u8>>,
+}
+
+/// An ID3 tag from a timed metadata stream
+#[derive(Debug, Clone)]
+pub struct TimedMetadata {
+    /// unique among the metadata in the store
+    pub id: u32,
+    pub pts: i64,
+    pub data: Vec<u8>,
 }
 
 #[derive(Default)]
 struct State {
     tracks: Vec<Track>,
-    pts_to_utc: Option<i64>
+    /// for each continuous period of input timestamps, the offset that maps them onto the
+    /// continuous output timeline, established by the first sample of the period
+    epoch_offsets: Vec<Option<i64>>,
+    /// index into `epoch_offsets` of the period that each track's input is currently in
+    track_epochs: Vec<usize>,
+    pts_to_utc: Option<i64>,
+    /// the wall-clock time (in 90kHz units since the Unix epoch) at which the first sample was
+    /// received, less its timestamp, placing the timeline so that media was then at the live edge
+    received_epoch: Option<i64>,
+    targets: SegmentTargets,
+    date_ranges: Vec<DateRange>,
+    /// ids of cancelled date ranges, along with the time they would have ended, so that players
+    /// skipping date ranges in a delta update can be told of their removal
+    removed_date_ranges: Vec<(String, i64)>,
+    metadata: VecDeque<TimedMetadata>,
+    next_metadata_id: u32,
+}
+impl State {
+    fn add_track(&mut self, track: Track) -> TrackId {
+        let id = TrackId(self.tracks.len());
+        self.tracks.push(track);
+        self.track_epochs.push(self.epoch_offsets.len() - 1);
+        id
+    }
+
+    /// Maps the timestamps of a sample of the given track onto the output timeline
+    fn rebase_sample(&mut self, track_id: TrackId, sample: &mut Sample) {
+        let epoch = self.track_epochs[track_id.0];
+        let offset = match self.epoch_offsets[epoch] {
+            Some(offset) => offset,
+            None => {
+                // carry on from where the output timeline had got to
+                let utc = self.pts_to_utc.unwrap_or(0);
+                let offset = self.latest_dts()
+                    .map(|latest| latest + DISCONTINUITY_MARGIN_PTS - utc - sample.dts )
+                    .unwrap_or(0);
+                self.epoch_offsets[epoch] = Some(offset);
+                offset
+            },
+        };
+        let diff = offset + self.pts_to_utc.unwrap_or(0);
+        sample.dts += diff;
+        sample.pts += diff;
+        if self.received_epoch.is_none() {
+            let now = chrono::Utc::now().timestamp_millis() * 90000 / 1_000;
+            self.received_epoch = Some(now - sample.dts);
+        }
+    }
+
+    /// Has the next sample of the given track start a new, discontinuous segment, along with
+    /// any subtitle tracks that follow its segmentation
+    fn mark_discontinuity(&mut self, track_id: TrackId) {
+        self.tracks[track_id.0].timeline_mut().mark_discontinuity();
+        if self.is_subtitle_reference(track_id) {
+            for track in self.tracks.iter_mut() {
+                if let Track::Subtitle(ref mut subtitle_track) = track {
+                    subtitle_track.timeline.mark_discontinuity();
+                }
+            }
+        }
+    }
+
+    /// The offset for the most recent period of input timestamps to have been established
+    fn current_offset(&self) -> i64 {
+        self.epoch_offsets.iter().rev().filter_map(|o| *o ).next().unwrap_or(0)
+    }
+
+    /// Maps a timestamp from a stream that isn't a track in its own right onto the output
+    /// timeline, assuming that it belongs to the most recent period of input timestamps
+    fn rebase(&self, ts: i64) -> i64 {
+        ts + self.current_offset() + self.pts_to_utc.unwrap_or(0)
+    }
+
+    /// Like `rebase()`, but for a 33-bit timestamp that has not been unwrapped, choosing the
+    /// wrap that falls closest to the latest sample received
+    fn rebase_wrapped(&self, ts: i64) -> i64 {
+        let rebased = self.rebase(ts);
+        match self.latest_dts() {
+            Some(latest) => {
+                let wraps = (latest - rebased + PTS_WRAP / 2).div_euclid(PTS_WRAP);
+                rebased + wraps * PTS_WRAP
+            },
+            None => rebased,
+        }
+    }
+
+    fn latest_dts(&self) -> Option<i64> {
+        self.tracks.iter()
+            .filter_map(|track| track.timeline().latest_dts().ok() )
+            .max()
+    }
+
+    fn earliest_dts(&self) -> Option<i64> {
+        self.tracks.iter()
+            .filter_map(|track| track.timeline().samples().next().map(|s| s.dts ) )
+            .min()
+    }
+
+    /// Subtitle track timelines follow the first video track
+    fn is_subtitle_reference(&self, track_id: TrackId) -> bool {
+        let first_video = self.tracks.iter().position(|track| match track {
+            Track::Avc(_) | Track::Hevc(_) => true,
+            _ => false,
+        });
+        first_video == Some(track_id.0)
+    }
+
+    fn mirror_to_subtitles(&mut self, track_id: TrackId, sample: &Sample) {
+        if !self.is_subtitle_reference(track_id) {
+            return;
+        }
+        for track in self.tracks.iter_mut() {
+            if let Track::Subtitle(ref mut subtitle_track) = track {
+                subtitle_track.timeline.push(Sample {
+                    data: vec![],
+                    pts: sample.pts,
+                    dts: sample.dts,
+                    header: SampleHeader::Subtitle { sync: is_sync(sample) },
+                });
+            }
+        }
+    }
 }
 
 pub struct TrackInfo {
@@ -663,9 +1703,18 @@ impl<'store> TrackRef<'store> {
     pub fn track(&mut self) -> &Track {
         &self.state.tracks[self.track_id.0]
     }
+
+    /// Timed metadata with a timestamp from `start` (inclusive) to `end` (exclusive)
+    pub fn timed_metadata_between(&self, start: i64, end: i64) -> Vec<TimedMetadata> {
+        self.state.metadata
+            .iter()
+            .filter(|m| m.pts >= start && m.pts < end )
+            .cloned()
+            .collect()
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct TrackId(pub usize);
 
 #[derive(Clone)]
@@ -676,8 +1725,16 @@ pub struct Store {
 }
 impl Store {
     pub fn new() -> Store {
+        Self::with_targets(SegmentTargets::default())
+    }
+
+    pub fn with_targets(targets: SegmentTargets) -> Store {
         Store {
-            state: Arc::new(Mutex::new(State::default())),
+            state: Arc::new(Mutex::new(State {
+                targets,
+                epoch_offsets: vec![Some(0)],
+                ..State::default()
+            })),
         }
     }
 
@@ -685,9 +1742,13 @@ impl Store {
         self.state.lock().unwrap()
     }
 
+    /// Establishes the mapping from input timestamps to wall-clock time.  The mapping is fixed
+    /// once established, so that the output timeline remains continuous.
     pub fn set_pts_to_utc(&mut self, diff: i64) {
         let mut state = self.get_state_mut();
-        state.pts_to_utc = Some(diff);
+        if state.pts_to_utc.is_none() {
+            state.pts_to_utc = Some(diff - state.current_offset());
+        }
     }
 
     pub fn has_pts_to_utc(&mut self) -> bool {
@@ -695,27 +1756,34 @@ impl Store {
         state.pts_to_utc.is_some()
     }
 
+    /// The wall-clock time (in 90kHz units since the Unix epoch) of timestamp zero on the output
+    /// timeline.  Without a mapping from input timestamps to wall-clock time, this is fixed when
+    /// the first sample arrives, so that the media was then at the live edge.  `None` until then.
+    pub fn availability_start(&mut self) -> Option<i64> {
+        let state = self.get_state_mut();
+        if state.pts_to_utc.is_some() {
+            Some(0)
+        } else {
+            state.received_epoch
+        }
+    }
+
     pub fn allocate_avc_track(
         &mut self,
         sps: nal::sps::SeqParameterSet,
-        pps: nal::pps::PicParameterSet,
-        sps_bytes: Vec<u8>,
-        pps_bytes: Vec<u8>,
+        sps_bytes: Vec<Vec<u8>>,
+        pps_bytes: Vec<Vec<u8>>,
         max_bitrate: Option<u32>
     ) -> TrackId {
         let mut state = self.get_state_mut();
-        let track = AvcTrack::new(sps, pps, sps_bytes, pps_bytes, max_bitrate);
-        let id = TrackId(state.tracks.len());
-        state.tracks.push(Track::Avc(track));
-        id
+        let track = AvcTrack::new(sps, sps_bytes, pps_bytes, max_bitrate, state.targets);
+        state.add_track(Track::Avc(track))
     }
 
     pub fn add_avc_sample(&mut self, track_id: TrackId, mut sample: Sample) {
         let mut state = self.get_state_mut();
-        if let Some(diff) = state.pts_to_utc {
-            sample.dts += diff;
-            sample.pts += diff;
-        }
+        state.rebase_sample(track_id, &mut sample);
+        state.mirror_to_subtitles(track_id, &sample);
         if let Track::Avc(ref mut track) = state.tracks[track_id.0] {
             track.push(sample);
         } else {
@@ -723,12 +1791,42 @@ impl Store {
         }
     }
 
-    pub fn add_aac_sample(&mut self, track_id: TrackId, mut sample: Sample) {
+    pub fn set_avc_captions(&mut self, track_id: TrackId, captions: CaptionServices) {
+        let mut state = self.get_state_mut();
+        if let Track::Avc(ref mut track) = state.tracks[track_id.0] {
+            track.captions = captions;
+        } else {
+            panic!("Not an AVC track {:?}", track_id)
+        }
+    }
+
+    pub fn allocate_hevc_track(
+        &mut self,
+        sps: hevc::SeqParameterSet,
+        vps_bytes: Vec<u8>,
+        sps_bytes: Vec<u8>,
+        pps_bytes: Vec<u8>,
+        max_bitrate: Option<u32>
+    ) -> TrackId {
+        let mut state = self.get_state_mut();
+        let track = HevcTrack::new(sps, vps_bytes, sps_bytes, pps_bytes, max_bitrate, state.targets);
+        state.add_track(Track::Hevc(track))
+    }
+
+    pub fn add_hevc_sample(&mut self, track_id: TrackId, mut sample: Sample) {
         let mut state = self.get_state_mut();
-        if let Some(diff) = state.pts_to_utc {
-            sample.dts += diff;
-            sample.pts += diff;
+        state.rebase_sample(track_id, &mut sample);
+        state.mirror_to_subtitles(track_id, &sample);
+        if let Track::Hevc(ref mut track) = state.tracks[track_id.0] {
+            track.push(sample);
+        } else {
+            panic!("Not an HEVC track {:?}", track_id)
         }
+    }
+
+    pub fn add_aac_sample(&mut self, track_id: TrackId, mut sample: Sample) {
+        let mut state = self.get_state_mut();
+        state.rebase_sample(track_id, &mut sample);
         if let Track::Aac(ref mut track) = state.tracks[track_id.0] {
             track.push(sample);
         } else {
@@ -742,12 +1840,164 @@ impl Store {
         frequency: adts_reader::SamplingFrequency,
         channel_config: adts_reader::ChannelConfiguration,
         max_bitrate: Option<u32>,
+        info: AudioInfo,
     ) -> TrackId {
         let mut state = self.get_state_mut();
-        let track = AacTrack::new(profile, frequency, channel_config, max_bitrate);
-        let id = TrackId(state.tracks.len());
-        state.tracks.push(Track::Aac(track));
-        id
+        let track = AacTrack::new(profile, frequency, channel_config, max_bitrate, info, state.targets);
+        state.add_track(Track::Aac(track))
+    }
+
+    pub fn add_ac3_sample(&mut self, track_id: TrackId, mut sample: Sample) {
+        let mut state = self.get_state_mut();
+        state.rebase_sample(track_id, &mut sample);
+        if let Track::Ac3(ref mut track) = state.tracks[track_id.0] {
+            track.push(sample);
+        } else {
+            panic!("Not an AC-3 track {:?}", track_id)
+        }
+    }
+
+    pub fn allocate_ac3_track(
+        &mut self,
+        stream_info: ac3::StreamInfo,
+        max_bitrate: Option<u32>,
+        info: AudioInfo,
+    ) -> TrackId {
+        let mut state = self.get_state_mut();
+        let track = Ac3Track::new(stream_info, max_bitrate, info, state.targets);
+        state.add_track(Track::Ac3(track))
+    }
+
+    /// Records the configuration of the given AC-3 / E-AC-3 track, which will apply from the
+    /// next sample.  If it differs from that in use so far, the next sample will start a new
+    /// segment (referencing a new initialisation segment) which is marked as a discontinuity.
+    pub fn set_ac3_config(&mut self, track_id: TrackId, stream_info: ac3::StreamInfo) {
+        let mut state = self.get_state_mut();
+        let changed = if let Track::Ac3(ref mut track) = state.tracks[track_id.0] {
+            track.set_config(stream_info)
+        } else {
+            panic!("Track {:?} is not AC-3", track_id);
+        };
+        if changed {
+            state.mark_discontinuity(track_id);
+        }
+    }
+
+    /// Records that media was lost from the given track (e.g. due to a continuity error), so
+    /// that samples are dropped until decoding can resume, and the loss is signalled to players
+    pub fn mark_gap(&mut self, track_id: TrackId) {
+        let mut state = self.get_state_mut();
+        state.tracks[track_id.0].timeline_mut().mark_gap();
+        if state.is_subtitle_reference(track_id) {
+            for track in state.tracks.iter_mut() {
+                if let Track::Subtitle(ref mut subtitle_track) = track {
+                    subtitle_track.timeline.mark_gap();
+                }
+            }
+        }
+    }
+
+    /// Records that the input timestamps of the given track have jumped (e.g. because the
+    /// encoder restarted).  Following samples are mapped onto the output timeline so that it
+    /// remains continuous, and start a new segment that is marked as a discontinuity.
+    pub fn timestamp_discontinuity(&mut self, track_id: TrackId) {
+        let mut state = self.get_state_mut();
+        let latest_epoch = state.epoch_offsets.len() - 1;
+        if state.track_epochs[track_id.0] == latest_epoch {
+            // this is the first track to see the discontinuity; any others will join the same
+            // new period once they see it too
+            state.epoch_offsets.push(None);
+        }
+        state.track_epochs[track_id.0] = state.epoch_offsets.len() - 1;
+        state.mark_discontinuity(track_id);
+    }
+
+    /// Records the SPS and PPS NAL units (ordered by id) of the given AVC track, which will apply
+    /// from the next sample.  If they differ from those in use so far, the next sample will start a new segment
+    /// (referencing a new initialisation segment) which is marked as a discontinuity.
+    pub fn set_avc_parameter_sets(
+        &mut self,
+        track_id: TrackId,
+        sps: nal::sps::SeqParameterSet,
+        sps_bytes: Vec<Vec<u8>>,
+        pps_bytes: Vec<Vec<u8>>,
+    ) {
+        let mut state = self.get_state_mut();
+        let changed = if let Track::Avc(ref mut track) = state.tracks[track_id.0] {
+            track.set_parameter_sets(sps, sps_bytes, pps_bytes)
+        } else {
+            panic!("Track {:?} is not AVC", track_id);
+        };
+        if changed {
+            state.mark_discontinuity(track_id);
+        }
+    }
+
+    /// Records the VPS, SPS and PPS NAL units of the given HEVC track, which will apply from the
+    /// next sample.  If they differ from those in use so far, the next sample will start a new
+    /// segment (referencing a new initialisation segment) which is marked as a discontinuity.
+    pub fn set_hevc_parameter_sets(
+        &mut self,
+        track_id: TrackId,
+        sps: hevc::SeqParameterSet,
+        vps_bytes: Vec<u8>,
+        sps_bytes: Vec<u8>,
+        pps_bytes: Vec<u8>,
+    ) {
+        let mut state = self.get_state_mut();
+        let changed = if let Track::Hevc(ref mut track) = state.tracks[track_id.0] {
+            track.set_parameter_sets(sps, vps_bytes, sps_bytes, pps_bytes)
+        } else {
+            panic!("Track {:?} is not HEVC", track_id);
+        };
+        if changed {
+            state.mark_discontinuity(track_id);
+        }
+    }
+
+    /// Records the configuration from the ADTS headers of the given AAC track, which will apply
+    /// from the next sample.  If it differs from that in use so far, the next sample will start a
+    /// new segment (referencing a new initialisation segment) which is marked as a discontinuity.
+    pub fn set_aac_config(
+        &mut self,
+        track_id: TrackId,
+        profile: adts_reader::AudioObjectType,
+        frequency: adts_reader::SamplingFrequency,
+        channel_config: adts_reader::ChannelConfiguration,
+    ) {
+        let mut state = self.get_state_mut();
+        let changed = if let Track::Aac(ref mut track) = state.tracks[track_id.0] {
+            track.set_config(profile, frequency, channel_config)
+        } else {
+            panic!("Track {:?} is not AAC", track_id);
+        };
+        if changed {
+            state.mark_discontinuity(track_id);
+        }
+    }
+
+    pub fn allocate_subtitle_track(&mut self, info: SubtitleInfo) -> TrackId {
+        let mut state = self.get_state_mut();
+        let track = SubtitleTrack::new(info, state.targets);
+        state.add_track(Track::Subtitle(track))
+    }
+
+    /// Changes the text displayed by the given subtitle track from the given time, ending the
+    /// cue previously displayed (if any)
+    pub fn set_subtitle_display(&mut self, track_id: TrackId, pts: i64, text: Option<String>) {
+        let mut state = self.get_state_mut();
+        let pts = state.rebase(pts);
+        if let Track::Subtitle(ref mut track) = state.tracks[track_id.0] {
+            track.set_display(pts, text);
+        } else {
+            panic!("Not a subtitle track {:?}", track_id)
+        }
+    }
+
+    /// The decode timestamp of the most recent sample of any track
+    pub fn latest_dts(&mut self) -> Option<i64> {
+        let state = self.get_state_mut();
+        state.latest_dts()
     }
 
     pub fn track_list(&mut self) -> impl Iterator<Item = TrackInfo> {
@@ -760,6 +2010,95 @@ impl Store {
             .into_iter()
     }
 
+    /// Records a splice, and arranges for all tracks to start a new segment at the splice point.
+    /// An `In` event for the id of an earlier `Out` event completes the existing `DateRange`.
+    pub fn add_splice_event(&mut self, event: SpliceEvent) {
+        let mut state = self.get_state_mut();
+        let start = match event.pts {
+            Some(pts) => state.rebase_wrapped(pts),
+            None => match state.latest_dts() {
+                Some(dts) => dts,
+                // nothing to position the splice against
+                None => return,
+            },
+        };
+        if let Some(range) = state.date_ranges.iter_mut().find(|r| r.id == event.id ) {
+            if event.kind == SpliceKind::In && range.scte35_in.is_none() {
+                range.end = Some(start);
+                range.scte35_in = Some(event.section);
+            } else {
+                // splice messages are typically repeated several times ahead of the splice
+                // point, so this is most likely one we've already seen
+                return;
+            }
+        } else {
+            let mut range = DateRange {
+                id: event.id,
+                start,
+                end: None,
+                planned_duration: event.duration,
+                scte35_out: None,
+                scte35_in: None,
+                scte35_cmd: None,
+            };
+            match event.kind {
+                SpliceKind::Out => range.scte35_out = Some(event.section),
+                SpliceKind::In => range.scte35_in = Some(event.section),
+                SpliceKind::Cmd => range.scte35_cmd = Some(event.section),
+            }
+            state.removed_date_ranges.retain(|(id, _)| *id != range.id );
+            state.date_ranges.push(range);
+        }
+        for track in state.tracks.iter_mut() {
+            track.timeline_mut().add_splice_point(start);
+        }
+        if let Some(earliest) = state.earliest_dts() {
+            state.date_ranges.retain(|r| r.end.unwrap_or(r.start) >= earliest );
+            state.removed_date_ranges.retain(|&(_, end)| end >= earliest );
+        }
+    }
+
+    /// Discards a splice that was signalled, but then cancelled before it took place
+    pub fn cancel_splice_event(&mut self, id: &str) {
+        let mut state = self.get_state_mut();
+        if let Some(range) = state.date_ranges.iter().find(|r| r.id == id ) {
+            let removed = (range.id.clone(), range.end.unwrap_or(range.start));
+            state.removed_date_ranges.push(removed);
+        }
+        state.date_ranges.retain(|r| r.id != id );
+    }
+
+    pub fn date_ranges(&mut self) -> Vec<DateRange> {
+        let state = self.get_state_mut();
+        state.date_ranges.clone()
+    }
+
+    /// The ids of date ranges which were cancelled, and are no longer returned by
+    /// `date_ranges()`, but which might still be known to players
+    pub fn removed_date_ranges(&mut self) -> Vec<String> {
+        let state = self.get_state_mut();
+        state.removed_date_ranges.iter().map(|(id, _)| id.clone() ).collect()
+    }
+
+    pub fn add_timed_metadata(&mut self, pts: i64, data: Vec<u8>) {
+        let mut state = self.get_state_mut();
+        let pts = state.rebase(pts);
+        let id = state.next_metadata_id;
+        state.next_metadata_id = id.wrapping_add(1);
+        state.metadata.push_back(TimedMetadata { id, pts, data });
+        if let Some(earliest) = state.earliest_dts() {
+            while state.metadata.front().map(|m| m.pts < earliest ).unwrap_or(false) {
+                state.metadata.pop_front();
+            }
+        }
+    }
+
+    /// The most recent segment and part sequence numbers available for the given track
+    pub fn track_sequence(&mut self, track_id: TrackId) -> Option<TrackSequence> {
+        let state = self.get_state_mut();
+        state.tracks.get(track_id.0).map(|track| *track.timeline().sequence_stream().get_ref() )
+    }
+
     pub fn get_track<'store>(&mut self, track_id: TrackId) -> Option<TrackRef> {
         let state = self.get_state_mut();
         if track_id.0 >= state.tracks.len() {
@@ -773,7 +2112,17 @@ impl Store {
 #[cfg(test)]
 mod test {
     use std::collections::VecDeque;
-    use crate::store::binary_search_by;
+    use h264_reader::nal::sps::SeqParameterSet;
+    use crate::store::{binary_search_by, make_avc_codec_bytes, split_parts, AudioInfo, AvcParameterSets, Packaging, Sample, SampleHeader, SegmentTargets, SpliceEvent, SpliceKind, Store, SubtitleInfo, SubtitleTrack, Timeline};
+
+    fn aac_samples(count: i64, duration: i64) -> Vec<Sample> {
+        (0..count).map(|i| Sample {
+            data: vec![],
+            pts: i * duration,
+            dts: i * duration,
+            header: SampleHeader::Aac,
+        }).collect()
+    }
 
     #[test]
     fn binary_search() {
@@ -783,4 +2132,206 @@ mod test {
         v.push_front(1);
         assert_eq!(1, binary_search_by(&v, |item| item.cmp(&2) ).unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parts_by_duration() {
+        let samples = aac_samples(40, 1920);
+        // without knowing the start of the next segment, the final part is incomplete
+        let parts = split_parts(samples.iter(), None, 28800);
+        assert_eq!(2, parts.len());
+        assert_eq!(0, parts[0].start);
+        assert_eq!(15, parts[0].count);
+        assert_eq!(28800, parts[0].duration);
+        assert_eq!(15, parts[1].start);
+        // once the following segment exists, the remainder becomes a final, shorter part
+        let parts = split_parts(samples.iter(), Some(40 * 1920), 28800);
+        assert_eq!(3, parts.len());
+        assert_eq!(10, parts[2].count);
+        assert_eq!(10 * 1920, parts[2].duration);
+        // where samples don't divide the target exactly, parts stop short of it
+        let samples = aac_samples(40, 2090);
+        let parts = split_parts(samples.iter(), Some(40 * 2090), 28800);
+        assert_eq!(13, parts[0].count);
+        assert!(parts.iter().all(|part| part.duration <= 28800 ));
+    }
+
+    #[test]
+    fn splice_point_starts_segment() {
+        let mut timeline = Timeline::new(SegmentTargets::default(), false);
+        let mut samples = aac_samples(60, 1920).into_iter();
+        for sample in samples.by_ref().take(10) {
+            timeline.push(sample);
+        }
+        // well short of the segment target duration
+        timeline.add_splice_point(20 * 1920);
+        for sample in samples {
+            timeline.push(sample);
+        }
+        let segments: Vec<_> = timeline.segments().collect();
+        assert_eq!(2, segments.len());
+        assert_eq!(20 * 1920, segments[1].id());
+    }
+
+    #[test]
+    fn gap_segment() {
+        let mut timeline = Timeline::new(SegmentTargets::default(), false);
+        let mut samples = aac_samples(60, 1920).into_iter();
+        for sample in samples.by_ref().take(10) {
+            timeline.push(sample);
+        }
+        timeline.mark_gap();
+        for sample in samples.skip(5) {
+            timeline.push(sample);
+        }
+        let segments: Vec<_> = timeline.segments().collect();
+        assert_eq!(3, segments.len());
+        assert!(!segments[0].is_gap());
+        assert!(segments[1].is_gap());
+        assert_eq!(10 * 1920, segments[1].id());
+        assert_eq!(Some(5.0 * 1920.0 / 90000.0), segments[1].duration_seconds());
+        assert!(!segments[2].is_gap());
+        assert_eq!(15 * 1920, segments[2].id());
+    }
+
+    #[test]
+    fn discontinuity() {
+        let mut timeline = Timeline::new(SegmentTargets::default(), false);
+        for sample in aac_samples(100, 1920) {
+            timeline.push(sample);
+        }
+        timeline.mark_discontinuity();
+        for mut sample in aac_samples(100, 1920) {
+            sample.dts += 200 * 1920;
+            sample.pts += 200 * 1920;
+            timeline.push(sample);
+        }
+        let segments: Vec<_> = timeline.segments().collect();
+        let discontinuous: Vec<_> = segments.iter().filter(|s| !s.is_continuous() ).map(|s| s.id() ).collect();
+        assert_eq!(vec![200 * 1920], discontinuous);
+        assert_eq!(0, timeline.discontinuity_sequence());
+        while timeline.segments().next().map(|s| s.id() <= 200 * 1920 ).unwrap_or(false) {
+            timeline.remove_one_segment();
+        }
+        assert_eq!(1, timeline.discontinuity_sequence());
+    }
+
+    #[test]
+    fn bitrate() {
+        let mut timeline = Timeline::new(SegmentTargets::default(), false);
+        assert_eq!(None, timeline.bitrate(Packaging::Ts));
+        for (i, mut sample) in aac_samples(200, 1920).into_iter().enumerate() {
+            // the first segment (of 90 frames, given the default 1.92s target) is twice the size,
+            // with each sample taking two TS packets, rather than one
+            sample.data = vec![0; if i < 90 { 200 } else { 100 }];
+            timeline.push(sample);
+            if i == 9 {
+                // estimated from the first nine samples, while the first segment is incomplete
+                let bitrate = timeline.bitrate(Packaging::Ts).unwrap();
+                assert_eq!(((9 * 376 + 376) as f64 * 8.0 / (9.0 * 1920.0 / 90000.0)).round() as u32, bitrate.peak);
+                assert_eq!(bitrate.peak, bitrate.average);
+            }
+        }
+        // the incomplete final segment isn't measured
+        let bitrate = timeline.bitrate(Packaging::Ts).unwrap();
+        assert_eq!(((90 * 376 + 376) as f64 * 8.0 / 1.92).round() as u32, bitrate.peak);
+        assert_eq!(((90 * 376 + 90 * 188 + 2 * 376) as f64 * 8.0 / 3.84).round() as u32, bitrate.average);
+        // fMP4 adds much less to the size of each sample
+        let bitrate = timeline.bitrate(Packaging::Fmp4).unwrap();
+        assert_eq!(((90 * 216 + 128) as f64 * 8.0 / 1.92).round() as u32, bitrate.peak);
+        assert_eq!(((90 * 216 + 90 * 116 + 2 * 128) as f64 * 8.0 / 3.84).round() as u32, bitrate.average);
+    }
+
+    #[test]
+    fn subtitle_cues() {
+        let info = SubtitleInfo { language: None, name: "CC1".to_string(), hearing_impaired: false };
+        let mut track = SubtitleTrack::new(info, SegmentTargets::default());
+        track.set_display(1000, Some("one".to_string()));
+        track.set_display(2000, Some("two".to_string()));
+        track.set_display(3000, None);
+        track.set_display(4000, Some("three".to_string()));
+        let cues: Vec<&str> = track.cues_between(1500, 3000).map(|c| &c.text[..] ).collect();
+        assert_eq!(vec!["one", "two"], cues);
+        let cues: Vec<&str> = track.cues_between(5000, 6000).map(|c| &c.text[..] ).collect();
+        assert_eq!(vec!["three"], cues);
+    }
+
+    #[test]
+    fn avc_codec_bytes() {
+        // 1280x720 Main profile, level 3.0, with constraint_set1_flag
+        let main = SeqParameterSet::from_bytes(&[0x4d, 0x40, 0x1e, 0xec, 0x80, 0x28, 0x02, 0xdc, 0x80]).unwrap();
+        assert_eq!([0x4d, 0x40, 0x1e], make_avc_codec_bytes(&main));
+        // 1280x720 High profile, level 3.1, with constraint_set4_flag
+        let high = SeqParameterSet::from_bytes(&[0x64, 0x08, 0x1f, 0xac, 0xd9, 0x00, 0x50, 0x05, 0xb9]).unwrap();
+        assert_eq!([0x64, 0x08, 0x1f], make_avc_codec_bytes(&high));
+        let sets = AvcParameterSets { sps: main, sps_bytes: vec![], pps_bytes: vec![] };
+        assert_eq!("avc1.4d401e", sets.rfc6381_codec());
+        assert_eq!((1280, 720), sets.dimensions());
+    }
+
+    #[test]
+    fn cancelled_date_range() {
+        let mut store = Store::new();
+        for id in &["1", "2"] {
+            store.add_splice_event(SpliceEvent {
+                id: id.to_string(),
+                kind: SpliceKind::Out,
+                pts: Some(90000),
+                duration: None,
+                section: vec![],
+            });
+        }
+        store.cancel_splice_event("1");
+        let ids: Vec<String> = store.date_ranges().into_iter().map(|r| r.id ).collect();
+        assert_eq!(vec!["2"], ids);
+        assert_eq!(vec!["1"], store.removed_date_ranges());
+    }
+
+    #[test]
+    fn splice_pts_wrapped() {
+        let mut store = Store::new();
+        let track_id = store.allocate_aac_track(
+            adts_reader::AudioObjectType::AacLC,
+            adts_reader::SamplingFrequency::Freq48000,
+            adts_reader::ChannelConfiguration::Stereo,
+            None,
+            AudioInfo::default(),
+        );
+        for mut sample in aac_samples(10, 1920) {
+            sample.dts += PTS_WRAP;
+            sample.pts += PTS_WRAP;
+            store.add_aac_sample(track_id, sample);
+        }
+        let latest = store.latest_dts().unwrap();
+        // a splice one second ahead, signalled in terms of the wrapped 33-bit timestamp
+        let pts = (latest + 90000) % PTS_WRAP;
+        store.add_splice_event(SpliceEvent {
+            id: "1".to_string(),
+            kind: SpliceKind::Out,
+            pts: Some(pts),
+            duration: None,
+            section: vec![],
+        });
+        assert_eq!(latest + 90000, store.date_ranges()[0].start);
+    }
+
+    #[test]
+    fn availability_start() {
+        let mut store = Store::new();
+        assert_eq!(None, store.availability_start());
+        let track_id = store.allocate_aac_track(
+            adts_reader::AudioObjectType::AacLC,
+            adts_reader::SamplingFrequency::Freq48000,
+            adts_reader::ChannelConfiguration::Stereo,
+            None,
+            AudioInfo::default(),
+        );
+        let mut samples = aac_samples(100, 1920).into_iter();
+        store.add_aac_sample(track_id, samples.next().unwrap());
+        let start = store.availability_start().unwrap();
+        // later samples don't move it
+        for sample in samples {
+            store.add_aac_sample(track_id, sample);
+        }
+        assert_eq!(Some(start), store.availability_start());
+    }
+}
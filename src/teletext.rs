@@ -0,0 +1,228 @@
+//! Decoding of teletext subtitle pages, as carried in DVB streams per EN 300 472, into the
+//! sequence of texts displayed.
+//!
+//! Only the G0 Latin character set is supported (national option characters are given their
+//! ASCII equivalents), and enhancement packets (26 to 31) are ignored.
+
+use crate::captions::DisplayChange;
+
+/// `teletext_type` values from the `teletext_descriptor` which indicate subtitles
+const TELETEXT_TYPE_SUBTITLE: u8 = 0x02;
+const TELETEXT_TYPE_SUBTITLE_HEARING_IMPAIRED: u8 = 0x05;
+
+/// A subtitle page listed in the `teletext_descriptor` of an elementary stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubtitlePage {
+    pub language: String,
+    pub hearing_impaired: bool,
+    /// 1 to 8
+    pub magazine: u8,
+    /// two BCD digits
+    pub page: u8,
+}
+
+/// Lists the subtitle pages in the payload of a `teletext_descriptor` (tag 0x56)
+pub fn subtitle_pages(descriptor_payload: &[u8]) -> Vec<SubtitlePage> {
+    descriptor_payload.chunks(5)
+        .filter(|entry| entry.len() == 5 )
+        .filter_map(|entry| {
+            let teletext_type = entry[3] >> 3;
+            let hearing_impaired = match teletext_type {
+                TELETEXT_TYPE_SUBTITLE => false,
+                TELETEXT_TYPE_SUBTITLE_HEARING_IMPAIRED => true,
+                _ => return None,
+            };
+            let magazine = match entry[3] & 0b111 {
+                0 => 8,
+                m => m,
+            };
+            Some(SubtitlePage {
+                language: String::from_utf8_lossy(&entry[..3]).into_owned(),
+                hearing_impaired,
+                magazine,
+                page: entry[4],
+            })
+        })
+        .collect()
+}
+
+/// Calls the given function with each 42 byte teletext packet in the given PES packet payload,
+/// having reversed the bit order of each byte so that they can be interpreted as per
+/// ETS 300 706
+pub fn for_each_packet<F: FnMut(&[u8])>(pes_payload: &[u8], mut f: F) {
+    match pes_payload.first() {
+        // data_identifier values for EBU data
+        Some(0x10..=0x1f) => (),
+        _ => return,
+    }
+    let mut data = &pes_payload[1..];
+    while data.len() >= 2 {
+        let data_unit_id = data[0];
+        let data_unit_length = data[1] as usize;
+        let unit = match data.get(2..2 + data_unit_length) {
+            Some(unit) => unit,
+            None => break,
+        };
+        // EBU teletext non-subtitle and subtitle data, which must be 44 bytes, starting with
+        // the field_parity/line_offset byte and the framing_code
+        if (data_unit_id == 0x02 || data_unit_id == 0x03) && unit.len() == 44 && unit[1] == 0xe4 {
+            let mut packet = [0u8; 42];
+            for (dst, src) in packet.iter_mut().zip(&unit[2..]) {
+                *dst = src.reverse_bits();
+            }
+            f(&packet[..]);
+        }
+        data = &data[2 + data_unit_length..];
+    }
+}
+
+/// Decodes a Hamming 8/4 protected byte, without attempting error correction
+fn unham(b: u8) -> u8 {
+    (b >> 1 & 1) | (b >> 3 & 1) << 1 | (b >> 5 & 1) << 2 | (b >> 7 & 1) << 3
+}
+
+/// Builds up the text of a single subtitle page from the packets of its magazine.
+///
+/// The page is assumed to be displayed from the time its header is received, which is when the
+/// previous content is removed.  The content of the page is reported once its transmission is
+/// complete, which is signalled by the next page header in the magazine.
+pub struct PageDecoder {
+    magazine: u8,
+    page: u8,
+    /// the time the page being received started to be displayed, and its rows so far
+    receiving: Option<(i64, Vec<Option<String>>)>,
+    current_text: Option<String>,
+}
+impl PageDecoder {
+    pub fn new(page: &SubtitlePage) -> PageDecoder {
+        PageDecoder {
+            magazine: page.magazine,
+            page: page.page,
+            receiving: None,
+            current_text: None,
+        }
+    }
+
+    /// Accepts a teletext packet, as produced by `for_each_packet()`, which was part of the PES
+    /// packet with the given PTS
+    pub fn push(&mut self, pts: i64, packet: &[u8]) -> Option<DisplayChange> {
+        let address = unham(packet[0]) | unham(packet[1]) << 4;
+        let magazine = match address & 0b111 {
+            0 => 8,
+            m => m,
+        };
+        let packet_number = address >> 3;
+        let serial = packet_number == 0 && unham(packet[9]) & 1 != 0;
+        if magazine != self.magazine && !serial {
+            return None;
+        }
+        if packet_number == 0 {
+            // in serial mode, any page header ends transmission of the previous page, whatever
+            // its magazine
+            let change = self.page_complete();
+            let page = unham(packet[2]) | unham(packet[3]) << 4;
+            if magazine == self.magazine && page == self.page {
+                self.receiving = Some((pts, vec![None; 24]));
+            }
+            change
+        } else if packet_number <= 23 {
+            if let Some((_, ref mut rows)) = self.receiving {
+                let text: String = packet[2..].iter()
+                    .map(|&b| match b & 0x7f {
+                        c @ 0x20..=0x7e => c as char,
+                        // spacing attributes, and the 'block' character
+                        _ => ' ',
+                    })
+                    .collect();
+                rows[packet_number as usize] = Some(text);
+            }
+            None
+        } else {
+            None
+        }
+    }
+
+    fn page_complete(&mut self) -> Option<DisplayChange> {
+        let (pts, rows) = self.receiving.take()?;
+        let lines: Vec<&str> = rows.iter()
+            .filter_map(|row| row.as_ref() )
+            .map(|row| row.trim() )
+            .filter(|row| !row.is_empty() )
+            .collect();
+        let text = if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        };
+        if text == self.current_text {
+            return None;
+        }
+        self.current_text = text.clone();
+        Some((pts, text))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Hamming 8/4 encodes the given nibble
+    fn ham(d: u8) -> u8 {
+        let d1 = d & 1;
+        let d2 = d >> 1 & 1;
+        let d3 = d >> 2 & 1;
+        let d4 = d >> 3 & 1;
+        let p1 = 1 ^ d1 ^ d3 ^ d4;
+        let p2 = 1 ^ d1 ^ d2 ^ d4;
+        let p3 = 1 ^ d1 ^ d2 ^ d3;
+        let p4 = 1 ^ p1 ^ d1 ^ p2 ^ d2 ^ p3 ^ d3 ^ d4;
+        p1 | d1 << 1 | p2 << 2 | d2 << 3 | p3 << 4 | d3 << 5 | p4 << 6 | d4 << 7
+    }
+
+    fn packet(magazine: u8, packet_number: u8, data: &[u8]) -> Vec<u8> {
+        let address = (magazine & 0b111) | packet_number << 3;
+        let mut packet = vec![ham(address & 0xf), ham(address >> 4)];
+        packet.extend_from_slice(data);
+        packet.resize(42, 0x20);
+        packet
+    }
+
+    fn header(magazine: u8, page: u8) -> Vec<u8> {
+        packet(magazine, 0, &[ham(page & 0xf), ham(page >> 4), ham(0), ham(0), ham(0), ham(0), ham(0), ham(0)])
+    }
+
+    #[test]
+    fn descriptor() {
+        let pages = subtitle_pages(b"eng\x11\x88fra\x0a\x01deu\x28\x88");
+        assert_eq!(vec![
+            SubtitlePage { language: "eng".to_string(), hearing_impaired: false, magazine: 1, page: 0x88 },
+            SubtitlePage { language: "deu".to_string(), hearing_impaired: true, magazine: 8, page: 0x88 },
+        ], pages);
+    }
+
+    #[test]
+    fn page() {
+        let page = SubtitlePage { language: "eng".to_string(), hearing_impaired: false, magazine: 8, page: 0x88 };
+        let mut decoder = PageDecoder::new(&page);
+        assert_eq!(None, decoder.push(1000, &header(8, 0x88)));
+        assert_eq!(None, decoder.push(1000, &packet(8, 20, b"\x0d\x0b\x0b Hello \x0a\x0a")));
+        assert_eq!(None, decoder.push(1000, &packet(1, 22, b"another magazine")));
+        assert_eq!(None, decoder.push(1000, &packet(8, 22, b"  world")));
+        assert_eq!(Some((1000, Some("Hello\nworld".to_string()))), decoder.push(2000, &header(8, 0x01)));
+        assert_eq!(None, decoder.push(3000, &header(8, 0x88)));
+        assert_eq!(Some((3000, None)), decoder.push(4000, &header(8, 0x88)));
+    }
+
+    #[test]
+    fn pes_payload() {
+        let mut pes = vec![0x10, 0x03, 44, 0x00, 0xe4];
+        pes.extend(header(8, 0x88).iter().map(|b| b.reverse_bits() ));
+        pes.extend_from_slice(&[0xff, 0x02, 0xff, 0xff]);
+        let mut count = 0;
+        for_each_packet(&pes[..], |packet| {
+            assert_eq!(header(8, 0x88), packet);
+            count += 1;
+        });
+        assert_eq!(1, count);
+    }
+}
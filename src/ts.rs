@@ -0,0 +1,328 @@
+//! Minimal MPEG-2 transport stream writing, for players that can't handle fMP4 segments.  Each
+//! segment (or part) is self-contained, carrying a single elementary stream described by a PAT
+//! and PMT at its start, with continuity counters starting from zero.
+
+const PACKET_SIZE: usize = 188;
+const HEADER_SIZE: usize = 4;
+const PAT_PID: u16 = 0;
+const PMT_PID: u16 = 0x1000;
+/// Also carries the PCR
+const ES_PID: u16 = 0x100;
+const PROGRAM_NUMBER: u16 = 1;
+const TRANSPORT_STREAM_ID: u16 = 1;
+
+/// The PCR runs this far (in 90kHz units) behind the DTS of the PES packet that carries it,
+/// leaving decoders time to receive each access unit
+const PCR_DELAY: i64 = 9000;
+
+/// Timestamps are only 33 bits long
+pub const TIMESTAMP_MASK: u64 = 0x1_ffff_ffff;
+
+const REGISTRATION_DESCRIPTOR_TAG: u8 = 0x05;
+
+/// `access_unit_delimiter_rbsp()` with `primary_pic_type` allowing any slice type, which is
+/// expected at the start of each AVC access unit in TS
+pub const AVC_ACCESS_UNIT_DELIMITER: [u8; 2] = [0x09, 0xf0];
+/// The HEVC equivalent of `AVC_ACCESS_UNIT_DELIMITER`
+pub const HEVC_ACCESS_UNIT_DELIMITER: [u8; 3] = [0x46, 0x01, 0x50];
+
+/// How an elementary stream is signalled in the PMT and PES headers
+pub struct Stream {
+    pub stream_type: u8,
+    pub stream_id: u8,
+    /// the descriptors for the `ES_info` of the PMT
+    pub descriptors: Vec<u8>,
+}
+impl Stream {
+    pub fn avc() -> Stream {
+        Stream { stream_type: 0x1b, stream_id: 0xe0, descriptors: vec![] }
+    }
+
+    pub fn hevc() -> Stream {
+        Stream { stream_type: 0x24, stream_id: 0xe0, descriptors: vec![] }
+    }
+
+    pub fn adts() -> Stream {
+        Stream { stream_type: 0x0f, stream_id: 0xc0, descriptors: vec![] }
+    }
+
+    /// Signalled as in ATSC A/52, which is what HLS players expect
+    pub fn ac3(enhanced: bool) -> Stream {
+        let (stream_type, format_identifier) = if enhanced {
+            (0x87, b"EAC3")
+        } else {
+            (0x81, b"AC-3")
+        };
+        let mut descriptors = vec![REGISTRATION_DESCRIPTOR_TAG, 4];
+        descriptors.extend_from_slice(format_identifier);
+        Stream { stream_type, stream_id: 0xbd, descriptors }
+    }
+}
+
+pub struct Muxer {
+    stream: Stream,
+    out: Vec<u8>,
+    pat_cc: u8,
+    pmt_cc: u8,
+    es_cc: u8,
+}
+impl Muxer {
+    pub fn new(stream: Stream) -> Muxer {
+        let mut muxer = Muxer {
+            stream,
+            out: vec![],
+            pat_cc: 0,
+            pmt_cc: 0,
+            es_cc: 0,
+        };
+        muxer.write_pat();
+        muxer.write_pmt();
+        muxer
+    }
+
+    fn write_pat(&mut self) {
+        let mut section = vec![];
+        u16(&mut section, PROGRAM_NUMBER);
+        u16(&mut section, 0xe000 | PMT_PID);
+        let section = psi_section(0x00, TRANSPORT_STREAM_ID, &section);
+        let cc = next_cc(&mut self.pat_cc);
+        write_psi_packet(&mut self.out, PAT_PID, cc, &section);
+    }
+
+    fn write_pmt(&mut self) {
+        let mut section = vec![];
+        u16(&mut section, 0xe000 | ES_PID);  // PCR_PID
+        u16(&mut section, 0xf000);  // program_info_length
+        section.push(self.stream.stream_type);
+        u16(&mut section, 0xe000 | ES_PID);
+        u16(&mut section, 0xf000 | self.stream.descriptors.len() as u16);
+        section.extend_from_slice(&self.stream.descriptors);
+        let section = psi_section(0x02, PROGRAM_NUMBER, &section);
+        let cc = next_cc(&mut self.pmt_cc);
+        write_psi_packet(&mut self.out, PMT_PID, cc, &section);
+    }
+
+    /// Writes the given access unit as a PES packet, the first TS packet of which carries a PCR
+    pub fn write_pes(&mut self, pts: i64, dts: i64, data: &[u8], random_access: bool) {
+        let mut pes = vec![0, 0, 1, self.stream.stream_id];
+        let header_data_len = if pts == dts { 5 } else { 10 };
+        let len = 3 + header_data_len + data.len();
+        // only video streams may leave the length unspecified
+        u16(&mut pes, if len > 0xffff { 0 } else { len as u16 });
+        pes.push(0x84);  // data_alignment_indicator
+        if pts == dts {
+            pes.push(0x80);
+            pes.push(header_data_len as u8);
+            timestamp(&mut pes, 0b0010, pts);
+        } else {
+            pes.push(0xc0);
+            pes.push(header_data_len as u8);
+            timestamp(&mut pes, 0b0011, pts);
+            timestamp(&mut pes, 0b0001, dts);
+        }
+        pes.extend_from_slice(data);
+
+        let mut first = true;
+        let mut remaining = &pes[..];
+        while !remaining.is_empty() {
+            let mut adaptation = vec![];
+            if first {
+                let mut flags = 0x10;  // PCR_flag
+                if random_access {
+                    flags |= 0x40;
+                }
+                adaptation.push(flags);
+                pcr(&mut adaptation, dts - PCR_DELAY);
+            }
+            let mut adaptation_len = if adaptation.is_empty() { 0 } else { 1 + adaptation.len() };
+            let space = PACKET_SIZE - HEADER_SIZE - adaptation_len;
+            if remaining.len() < space {
+                let stuffing = space - remaining.len();
+                if adaptation_len == 0 {
+                    // an adaptation field of just its length byte gives a single byte of stuffing
+                    if stuffing > 1 {
+                        adaptation.push(0);  // no flags
+                    }
+                    adaptation.extend(std::iter::repeat(0xff).take(stuffing.saturating_sub(2)));
+                } else {
+                    adaptation.extend(std::iter::repeat(0xff).take(stuffing));
+                }
+                adaptation_len += stuffing;
+            }
+            let payload_len = PACKET_SIZE - HEADER_SIZE - adaptation_len;
+            let cc = next_cc(&mut self.es_cc);
+            let adaptation_field_control = if adaptation_len > 0 { 0b11 } else { 0b01 };
+            packet_header(&mut self.out, first, ES_PID, adaptation_field_control, cc);
+            if adaptation_len > 0 {
+                self.out.push((adaptation_len - 1) as u8);
+                self.out.extend_from_slice(&adaptation);
+            }
+            self.out.extend_from_slice(&remaining[..payload_len]);
+            remaining = &remaining[payload_len..];
+            first = false;
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.out
+    }
+}
+
+/// Converts the given sequence of NAL units, each prefixed with its 4-byte length, to the Annex B
+/// byte stream format used in TS
+pub fn write_annex_b(out: &mut Vec<u8>, length_prefixed: &[u8]) {
+    let mut remaining = length_prefixed;
+    while remaining.len() >= 4 {
+        let len = u32::from_be_bytes([remaining[0], remaining[1], remaining[2], remaining[3]]) as usize;
+        let nal = &remaining[4..(4 + len).min(remaining.len())];
+        write_nal(out, nal);
+        remaining = &remaining[4 + nal.len()..];
+    }
+}
+
+/// Writes the given NAL unit preceded by a start code
+pub fn write_nal(out: &mut Vec<u8>, nal: &[u8]) {
+    out.extend_from_slice(&[0, 0, 0, 1]);
+    out.extend_from_slice(nal);
+}
+
+/// The ADTS header to precede an AAC frame of the given length, which will have been removed on
+/// ingest
+pub fn adts_header(object_type: u8, frequency_index: u8, channel_config: u8, payload_len: usize) -> [u8; 7] {
+    let len = payload_len + 7;
+    [
+        0xff,
+        0xf1,  // MPEG-4, no CRC
+        (object_type - 1) << 6 | frequency_index << 2 | channel_config >> 2,
+        (channel_config & 3) << 6 | (len >> 11) as u8,
+        (len >> 3) as u8,
+        (len as u8 & 7) << 5 | 0x1f,
+        0xfc,  // buffer fullness 0x7ff (VBR), one raw data block
+    ]
+}
+
+fn next_cc(cc: &mut u8) -> u8 {
+    let val = *cc;
+    *cc = (*cc + 1) & 0xf;
+    val
+}
+
+fn packet_header(out: &mut Vec<u8>, payload_unit_start: bool, pid: u16, adaptation_field_control: u8, cc: u8) {
+    out.push(0x47);
+    u16(out, if payload_unit_start { 0x4000 } else { 0 } | pid);
+    out.push(adaptation_field_control << 4 | cc);
+}
+
+/// Writes a long-form PSI section with the given `table_id` and `table_id_extension`, followed
+/// by its CRC
+fn psi_section(table_id: u8, table_id_extension: u16, data: &[u8]) -> Vec<u8> {
+    let mut section = vec![table_id];
+    // section_syntax_indicator, and a length including the remaining header and the CRC
+    u16(&mut section, 0xb000 | (5 + data.len() + 4) as u16);
+    u16(&mut section, table_id_extension);
+    section.push(0xc1);  // version_number 0, current_next_indicator
+    section.push(0);  // section_number
+    section.push(0);  // last_section_number
+    section.extend_from_slice(data);
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+/// Writes the given section (which must fit) in a single TS packet
+fn write_psi_packet(out: &mut Vec<u8>, pid: u16, cc: u8, section: &[u8]) {
+    let start = out.len();
+    packet_header(out, true, pid, 0b01, cc);
+    out.push(0);  // pointer_field
+    out.extend_from_slice(section);
+    out.resize(start + PACKET_SIZE, 0xff);
+}
+
+fn timestamp(out: &mut Vec<u8>, prefix: u8, ts: i64) {
+    let ts = ts as u64 & TIMESTAMP_MASK;
+    out.push(prefix << 4 | (ts >> 29) as u8 & 0x0e | 1);
+    out.push((ts >> 22) as u8);
+    out.push((ts >> 14) as u8 & 0xfe | 1);
+    out.push((ts >> 7) as u8);
+    out.push((ts << 1) as u8 & 0xfe | 1);
+}
+
+/// Writes a PCR with the given 90kHz base, and no extension
+fn pcr(out: &mut Vec<u8>, ts: i64) {
+    let base = ts as u64 & TIMESTAMP_MASK;
+    out.push((base >> 25) as u8);
+    out.push((base >> 17) as u8);
+    out.push((base >> 9) as u8);
+    out.push((base >> 1) as u8);
+    out.push((base << 7) as u8 | 0x7e);
+    out.push(0);
+}
+
+fn u16(out: &mut Vec<u8>, val: u16) {
+    out.extend_from_slice(&val.to_be_bytes());
+}
+
+/// CRC-32/MPEG-2, as used by PSI sections
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= u32::from(b) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                crc << 1 ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pat() {
+        let muxer = Muxer::new(Stream::adts());
+        let out = muxer.finish();
+        assert_eq!(out.len(), 2 * PACKET_SIZE);
+        assert_eq!(
+            &out[..21],
+            &[0x47, 0x40, 0x00, 0x10, 0x00, 0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00, 0x2a, 0xb1, 0x04, 0xb2][..]
+        );
+    }
+
+    #[test]
+    fn pes_packets() {
+        for &len in [0, 1, 150, 169, 170, 171, 176, 1000].iter() {
+            let mut muxer = Muxer::new(Stream::avc());
+            muxer.write_pes(18000, 15000, &vec![0xaa; len], true);
+            let out = muxer.finish();
+            assert_eq!(out.len() % PACKET_SIZE, 0, "length {}", len);
+            let packets: Vec<_> = out.chunks(PACKET_SIZE).skip(2).collect();
+            for (i, packet) in packets.iter().enumerate() {
+                assert_eq!(packet[0], 0x47);
+                assert_eq!(packet[3] & 0xf, i as u8 & 0xf);
+                if packet[3] & 0x20 != 0 {
+                    assert!(usize::from(packet[4]) < PACKET_SIZE - HEADER_SIZE);
+                }
+            }
+            // the PCR, and the PES header following the adaptation field
+            assert_eq!(packets[0][5], 0x50);
+            let pes_start = HEADER_SIZE + 1 + usize::from(packets[0][4]);
+            assert_eq!(&packets[0][pes_start..pes_start + 4], &[0, 0, 1, 0xe0]);
+            // all the payload made it
+            let payload: usize = packets.iter()
+                .map(|p| if p[3] & 0x20 != 0 { PACKET_SIZE - HEADER_SIZE - 1 - usize::from(p[4]) } else { PACKET_SIZE - HEADER_SIZE } )
+                .sum();
+            assert_eq!(payload, 19 + len, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn adts() {
+        // AAC-LC, 48kHz, stereo
+        assert_eq!(adts_header(2, 3, 2, 100), [0xff, 0xf1, 0x4c, 0x80, 0x0d, 0x7f, 0xfc]);
+    }
+}